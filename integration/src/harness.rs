@@ -64,7 +64,7 @@ pub fn endpoint_url(port: u16, path: &str) -> UAString {
     format!("opc.tcp://{}:{}{}", hostname(), port, path).into()
 }
 
-fn v1_node_id() -> NodeId {
+pub fn v1_node_id() -> NodeId {
     NodeId::new(2, "v1")
 }
 
@@ -402,6 +402,40 @@ fn connect_basic128rsa15_with_x509_token() {
     );
 }
 
+/// Connect several sessions with different tokens at once and read a variable from each
+#[test]
+#[ignore]
+fn connect_parallel() {
+    let port = next_port();
+    let client_endpoint = endpoint_basic128rsa15_sign_encrypt(port);
+    connect_with_client_test(
+        port,
+        move |_rx_client_command: mpsc::Receiver<ClientCommand>, mut client: Client| {
+            let identity_tokens = vec![
+                IdentityToken::Anonymous,
+                client_user_token(),
+                client_x509_token(),
+                client_invalid_user_token(),
+            ];
+            let mut sessions =
+                client.connect_to_endpoint_parallel(client_endpoint, identity_tokens);
+            assert_eq!(sessions.len(), 4);
+
+            // The session with the invalid token fails without affecting the others
+            assert!(sessions.pop().unwrap().is_err());
+            for session in sessions {
+                let session = session.unwrap();
+                let session = session.read();
+                let results = session
+                    .read(&[v1_node_id().into()], TimestampsToReturn::Both, 1.0)
+                    .unwrap();
+                assert_eq!(results[0].value, Some(Variant::from(100)));
+                session.disconnect();
+            }
+        },
+    );
+}
+
 /// Connect to a server, read a variable, write a value to the variable, read the variable to verify it changed
 #[test]
 #[ignore]
@@ -6,6 +6,7 @@ use std::path::PathBuf;
 
 use crate::client::{client::Client, config::*};
 use crate::core::config::Config;
+use crate::types::DecodeUnknownPolicy;
 
 /// The `ClientBuilder` is a builder for producing a [`Client`]. It is an alternative to constructing
 /// a [`ClientConfig`] from file or from scratch.
@@ -285,6 +286,18 @@ impl ClientBuilder {
         self.config.decoding_options.max_chunk_count = max_chunk_count;
         self
     }
+
+    /// Set the maximum number of bytes that decoding a single message may allocate
+    pub fn max_decoded_size(mut self, max_decoded_size: usize) -> Self {
+        self.config.decoding_options.max_decoded_size = Some(max_decoded_size);
+        self
+    }
+
+    /// Set what to do with an extension object whose type is not recognized
+    pub fn decode_unknown_policy(mut self, decode_unknown_policy: DecodeUnknownPolicy) -> Self {
+        self.config.decoding_options.decode_unknown_policy = decode_unknown_policy;
+        self
+    }
 }
 
 #[test]
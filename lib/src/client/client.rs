@@ -4,7 +4,7 @@
 
 //! Client setup and session creation.
 
-use std::{path::PathBuf, str::FromStr, sync::Arc};
+use std::{path::PathBuf, str::FromStr, sync::Arc, thread};
 
 use chrono::Duration;
 
@@ -248,7 +248,84 @@ impl Client {
         T: Into<EndpointDescription>,
     {
         let endpoint = endpoint.into();
+        let server_endpoint = self.find_server_endpoint(&endpoint)?;
 
+        // Create a session
+        let session = self
+            .new_session_from_info((server_endpoint, user_identity_token))
+            .unwrap();
+
+        {
+            // Connect to the server
+            let mut session = session.write();
+            session.connect_and_activate().map_err(|err| {
+                error!("Got an error while creating the default session - {}", err);
+                err
+            })?;
+        }
+
+        Ok(session)
+    }
+
+    /// Connects to an ad-hoc server endpoint description and creates / activates a [`Session`]
+    /// for each of the user identity tokens. The sessions are established in parallel, each over
+    /// its own secure channel, which is much quicker than one after another when there are many
+    /// of them, e.g. for load testing.
+    ///
+    /// Returns with the session that has been established or an error, for each token in the
+    /// order they were supplied. The same note as for [`Client::connect_to_endpoint`] about
+    /// scoping locks on the sessions applies.
+    ///
+    /// [`Session`]: ../session/struct.Session.html
+    ///
+    pub fn connect_to_endpoint_parallel<T>(
+        &mut self,
+        endpoint: T,
+        user_identity_tokens: Vec<IdentityToken>,
+    ) -> Vec<Result<Arc<RwLock<Session>>, StatusCode>>
+    where
+        T: Into<EndpointDescription>,
+    {
+        let endpoint = endpoint.into();
+        let server_endpoint = match self.find_server_endpoint(&endpoint) {
+            Ok(server_endpoint) => server_endpoint,
+            Err(status_code) => return vec![Err(status_code); user_identity_tokens.len()],
+        };
+
+        // Create the sessions, then connect them all at once, each on a thread of its own
+        let connections = user_identity_tokens
+            .into_iter()
+            .map(|user_identity_token| {
+                let session = self
+                    .new_session_from_info((server_endpoint.clone(), user_identity_token))
+                    .unwrap();
+                thread::spawn(move || {
+                    {
+                        let mut session = session.write();
+                        session.connect_and_activate().inspect_err(|err| {
+                            error!("Got an error while creating a parallel session - {}", err);
+                        })?;
+                    }
+                    Ok(session)
+                })
+            })
+            .collect::<Vec<_>>();
+        connections
+            .into_iter()
+            .map(|connection| {
+                connection
+                    .join()
+                    .unwrap_or(Err(StatusCode::BadUnexpectedError))
+            })
+            .collect()
+    }
+
+    /// Finds the endpoint offered by the server that matches the one desired, i.e. has the same
+    /// url, security policy and security mode.
+    fn find_server_endpoint(
+        &self,
+        endpoint: &EndpointDescription,
+    ) -> Result<EndpointDescription, StatusCode> {
         // Get the server endpoints
         let server_url = endpoint.endpoint_url.as_ref();
 
@@ -268,30 +345,27 @@ impl Client {
             security_policy,
             endpoint.security_mode,
         )
-        .ok_or(StatusCode::BadTcpEndpointUrlInvalid)
-        .map_err(|status_code| {
-            error!(
-                "Cannot find matching endpoint for {}",
-                endpoint.endpoint_url.as_ref()
-            );
-            status_code
+        .ok_or_else(|| {
+            let endpoint_url = endpoint.endpoint_url.as_ref();
+            if server_endpoints
+                .iter()
+                .any(|e| url_matches_except_host(endpoint_url, e.endpoint_url.as_ref()))
+            {
+                // The url is right, so it is the security that doesn't match
+                error!(
+                    "Cannot find matching endpoint for {}, {:?} / {:?} is not offered by the server, which offers {}",
+                    endpoint_url,
+                    security_policy,
+                    endpoint.security_mode,
+                    Client::offered_security(&server_endpoints)
+                );
+                StatusCode::BadSecurityPolicyRejected
+            } else {
+                error!("Cannot find matching endpoint for {}", endpoint_url);
+                StatusCode::BadTcpEndpointUrlInvalid
+            }
         })?;
-
-        // Create a session
-        let session = self
-            .new_session_from_info((server_endpoint, user_identity_token))
-            .unwrap();
-
-        {
-            // Connect to the server
-            let mut session = session.write();
-            session.connect_and_activate().map_err(|err| {
-                error!("Got an error while creating the default session - {}", err);
-                err
-            })?;
-        }
-
-        Ok(session)
+        Ok(server_endpoint)
     }
 
     /// Gets the [`ClientEndpoint`] information for the default endpoint, as defined
@@ -431,6 +505,8 @@ impl Client {
             max_string_length: decoding_options.max_string_length,
             max_byte_string_length: decoding_options.max_byte_string_length,
             max_array_length: decoding_options.max_array_length,
+            max_decoded_size: decoding_options.max_decoded_size,
+            decode_unknown_policy: decoding_options.decode_unknown_policy,
             client_offset: Duration::zero(),
             ..Default::default()
         }
@@ -659,6 +735,22 @@ impl Client {
         }
     }
 
+    /// Describes the security policy and mode of each endpoint, e.g. "Basic256Sha256 / Sign", for
+    /// an error that none of them match what the client wants.
+    pub(crate) fn offered_security(endpoints: &[EndpointDescription]) -> String {
+        endpoints
+            .iter()
+            .map(|e| {
+                format!(
+                    "{:?} / {:?}",
+                    SecurityPolicy::from_uri(e.security_policy_uri.as_ref()),
+                    e.security_mode
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     /// Find an endpoint supplied from the list of endpoints that matches the input criteria
     pub fn find_matching_endpoint(
         endpoints: &[EndpointDescription],
@@ -719,7 +811,7 @@ impl Client {
                     security_mode,
                 );
                 if endpoint.is_none() {
-                    Err(format!("Endpoint {}, {:?} / {:?} does not match against any supplied by the server", endpoint_url, security_policy, security_mode))
+                    Err(format!("Endpoint {}, {:?} / {:?} does not match against any supplied by the server, which offers {}", endpoint_url, security_policy, security_mode, Self::offered_security(endpoints)))
                 } else if let Some(user_identity_token) =
                     self.client_identity_token(client_endpoint.user_token_id.clone())
                 {
@@ -42,7 +42,10 @@ use crate::client::{
     callbacks::OnSessionClosed,
     comms::transport::Transport,
     message_queue::{self, MessageQueue},
-    session::session_state::{ConnectionState, ConnectionStateMgr, SessionState},
+    session::{
+        diagnostics::SessionCounters,
+        session_state::{ConnectionState, ConnectionStateMgr, SessionState},
+    },
 };
 
 //todo move this struct to core module
@@ -52,41 +55,27 @@ struct MessageChunkWithChunkInfo {
     data_with_header: Vec<u8>,
 }
 
-struct ReadState {
-    pub state: ConnectionStateMgr,
-    pub secure_channel: Arc<RwLock<SecureChannel>>,
-    pub message_queue: Arc<RwLock<MessageQueue>>,
-    pub max_chunk_count: usize,
+/// Collects the chunks of messages received from the server, keyed by request id, until the
+/// final chunk of a message arrives and the message can be reassembled and decoded.
+pub(crate) struct ReceivedChunks {
+    secure_channel: Arc<RwLock<SecureChannel>>,
+    /// Maximum number of chunks in a message, or 0 for no limit
+    max_chunk_count: usize,
     /// Last decoded sequence number
     last_received_sequence_number: u32,
     chunks: HashMap<u32, Vec<MessageChunkWithChunkInfo>>,
-    pub framed_read: FramedRead<ReadHalf<TcpStream>, TcpCodec>,
-}
-
-impl Drop for ReadState {
-    fn drop(&mut self) {
-        info!("ReadState has dropped");
-    }
 }
 
-impl ReadState {
-    fn new(
-        connection_state: ConnectionStateMgr,
-        secure_channel: Arc<RwLock<SecureChannel>>,
-        message_queue: Arc<RwLock<MessageQueue>>,
-        session_state: &SessionState,
-        framed_read: FramedRead<ReadHalf<TcpStream>, TcpCodec>,
-    ) -> Self {
-        ReadState {
+impl ReceivedChunks {
+    pub(crate) fn new(secure_channel: Arc<RwLock<SecureChannel>>, max_chunk_count: usize) -> Self {
+        ReceivedChunks {
             secure_channel,
-            state: connection_state,
-            max_chunk_count: session_state.max_chunk_count(),
+            max_chunk_count,
             last_received_sequence_number: 0,
-            message_queue,
             chunks: HashMap::new(),
-            framed_read,
         }
     }
+
     fn turn_received_chunks_into_message(
         &mut self,
         chunks: &[MessageChunk],
@@ -102,7 +91,8 @@ impl ReadState {
         Chunker::decode(chunks, &secure_channel, None)
     }
 
-    fn process_chunk(
+    /// Processes a chunk, returning the message once its final chunk has been received
+    pub(crate) fn process_chunk(
         &mut self,
         chunk: MessageChunk,
     ) -> Result<Option<SupportedMessage>, StatusCode> {
@@ -129,13 +119,16 @@ impl ReadState {
                     header: chunk_info,
                     data_with_header: chunk.data,
                 });
-                let chunks_len = self.chunks.len();
+                // The limit applies to the chunks of this message, not to how many messages are
+                // being received at once, which can legitimately be several large responses.
+                let chunks_len = chunks.len();
                 if self.max_chunk_count > 0 && chunks_len > self.max_chunk_count {
-                    error!("too many chunks {}> {}", chunks_len, self.max_chunk_count);
-                    // TODO this code should return an error to be safe
-                    //remove first
-                    let first_req_id = *self.chunks.iter().next().unwrap().0;
-                    self.chunks.remove(&first_req_id);
+                    error!(
+                        "too many chunks {} > {} for request {}",
+                        chunks_len, self.max_chunk_count, req_id
+                    );
+                    self.chunks.remove(&req_id);
+                    return Err(StatusCode::BadTcpMessageTooLarge);
                 }
                 return Ok(None);
             }
@@ -183,12 +176,20 @@ impl ReadState {
             .sequence_header
             .sequence_number;
         for c in chunks {
-            if c.header.sequence_header.sequence_number != expect_sequence_number {
+            let sequence_number = c.header.sequence_header.sequence_number;
+            if sequence_number < expect_sequence_number {
                 info!(
+                    "receive duplicate chunk expect seq={},got={}",
+                    expect_sequence_number, sequence_number
+                );
+                continue;
+            } else if sequence_number > expect_sequence_number {
+                // A missing chunk cannot be skipped over without corrupting the message
+                error!(
                     "receive wrong chunk expect seq={},got={}",
-                    expect_sequence_number, c.header.sequence_header.sequence_number
+                    expect_sequence_number, sequence_number
                 );
-                continue; //may be duplicate chunk
+                return Err(StatusCode::BadSequenceNumberInvalid);
             }
             expect_sequence_number += 1;
             ret.push(MessageChunk {
@@ -199,6 +200,51 @@ impl ReadState {
     }
 }
 
+struct ReadState {
+    pub state: ConnectionStateMgr,
+    pub message_queue: Arc<RwLock<MessageQueue>>,
+    received_chunks: ReceivedChunks,
+    pub framed_read: FramedRead<ReadHalf<TcpStream>, TcpCodec>,
+    /// Counts the bytes and responses received
+    counters: Arc<SessionCounters>,
+}
+
+impl Drop for ReadState {
+    fn drop(&mut self) {
+        info!("ReadState has dropped");
+    }
+}
+
+impl ReadState {
+    fn new(
+        connection_state: ConnectionStateMgr,
+        secure_channel: Arc<RwLock<SecureChannel>>,
+        message_queue: Arc<RwLock<MessageQueue>>,
+        session_state: &SessionState,
+        framed_read: FramedRead<ReadHalf<TcpStream>, TcpCodec>,
+    ) -> Self {
+        ReadState {
+            state: connection_state,
+            message_queue,
+            received_chunks: ReceivedChunks::new(secure_channel, session_state.max_chunk_count()),
+            framed_read,
+            counters: session_state.counters.clone(),
+        }
+    }
+
+    fn process_chunk(
+        &mut self,
+        chunk: MessageChunk,
+    ) -> Result<Option<SupportedMessage>, StatusCode> {
+        self.counters.add_bytes_received(chunk.data.len());
+        let response = self.received_chunks.process_chunk(chunk)?;
+        if response.is_some() {
+            self.counters.response_received();
+        }
+        Ok(response)
+    }
+}
+
 struct WriteState {
     /// The url to connect to
     pub secure_channel: Arc<RwLock<SecureChannel>>,
@@ -207,6 +253,8 @@ struct WriteState {
     /// The send buffer
     pub send_buffer: MessageWriter,
     pub receiver: UnboundedReceiver<message_queue::Message>,
+    /// Counts the bytes sent
+    counters: Arc<SessionCounters>,
 }
 
 impl Drop for WriteState {
@@ -237,6 +285,7 @@ impl WriteState {
             writer,
             message_queue,
             receiver,
+            counters: session_state.counters.clone(),
         }
     }
     /// Sends the supplied request asynchronously. The returned value is the request id for the
@@ -360,7 +409,7 @@ impl TcpTransport {
             endpoint_url,
             session_state.clone(),
             secure_channel,
-            message_queue,
+            message_queue.clone(),
         );
         let runtime = self.runtime.clone();
         thread::spawn(move || {
@@ -372,7 +421,20 @@ impl TcpTransport {
                     status = Self::spawn_looping_tasks(read, write).await.err().unwrap_or(StatusCode::Good);
                 }
                 connection_state.set_finished(status);
-                trace_write_lock!(session_state).on_session_closed(status);
+                // Wake anything waiting for a response that will now never come. A synchronous
+                // caller holds the session state lock while it waits, so its request is aborted
+                // through the message queue first. The requests fail with the error the
+                // connection finished with, e.g. the server rejecting the security of a new
+                // secure channel, so the caller can tell why.
+                let abort_status = if status.is_bad() {
+                    status
+                } else {
+                    StatusCode::BadConnectionClosed
+                };
+                trace_write_lock!(message_queue).abort_all_requests(abort_status);
+                let mut session_state = trace_write_lock!(session_state);
+                session_state.abort_all_requests(abort_status);
+                session_state.on_session_closed(status);
             });
         });
         connection_status_receiver.recv().expect("channel should never be dropped here")
@@ -471,7 +533,9 @@ impl TcpTransport {
         write_state.writer.write_all(&bytes_to_write).await.map_err(|e| {
             error!("write bytes task failed: {}", e);
             StatusCode::BadCommunicationError
-        })
+        })?;
+        write_state.counters.add_bytes_sent(bytes_to_write.len());
+        Ok(())
     }
 
     async fn spawn_reading_task(mut read_state: ReadState) -> Result<(), StatusCode> {
@@ -492,9 +556,14 @@ impl TcpTransport {
                                 Ok(response) => {
                                     if let Some(response) = response {
                                         // Store the response
-                                        let mut message_queue =
-                                            trace_write_lock!(read_state.message_queue);
-                                        message_queue.store_response(response);
+                                        let unmatched_response = {
+                                            let mut message_queue =
+                                                trace_write_lock!(read_state.message_queue);
+                                            message_queue.store_response(response)
+                                        };
+                                        if let Some(unmatched_response) = unmatched_response {
+                                            unmatched_response.dispatch();
+                                        }
                                     }
                                 }
                                 Err(err) => session_status_code = err,
@@ -552,13 +621,13 @@ impl TcpTransport {
                     // Write it to the outgoing buffer
                     let request_handle = request.request_handle();
                     write_state.send_request(request)?;
-                    // Indicate the request was processed
+                    Self::write_bytes_task(&mut write_state).await?;
+                    // Indicate the request was written
                     {
                         let mut message_queue =
                             trace_write_lock!(write_state.message_queue);
                         message_queue.request_was_processed(request_handle);
                     }
-                    Self::write_bytes_task(&mut write_state).await?;
                     if close_connection {
                         debug!("Writer is setting the connection state to finished(good)");
                         return Ok(());
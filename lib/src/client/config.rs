@@ -14,7 +14,7 @@ use std::{
 use crate::{
     core::config::Config,
     crypto::SecurityPolicy,
-    types::{ApplicationType, MessageSecurityMode, UAString},
+    types::{ApplicationType, DecodeUnknownPolicy, MessageSecurityMode, UAString},
 };
 
 use super::session_retry_policy::SessionRetryPolicy;
@@ -139,7 +139,8 @@ impl ClientEndpoint {
 pub struct DecodingOptions {
     /// Maximum size of a message chunk in bytes. 0 means no limit
     pub max_message_size: usize,
-    /// Maximum number of chunks in a message. 0 means no limit
+    /// Maximum number of chunks in a message. This is advertised to the server and a response
+    /// in more chunks is rejected. 0 means no limit
     pub max_chunk_count: usize,
     /// Maximum length in bytes (not chars!) of a string. 0 actually means 0, i.e. no string permitted
     pub max_string_length: usize,
@@ -147,6 +148,13 @@ pub struct DecodingOptions {
     pub max_byte_string_length: usize,
     /// Maximum number of array elements. 0 actually means 0, i.e. no array permitted
     pub max_array_length: usize,
+    /// Maximum number of bytes that decoding a message may allocate. None means no limit
+    #[serde(default)]
+    pub max_decoded_size: Option<usize>,
+    /// What to do with an extension object whose type is not recognized. The default is to keep
+    /// its raw body
+    #[serde(default)]
+    pub decode_unknown_policy: DecodeUnknownPolicy,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -338,6 +346,8 @@ impl ClientConfig {
                 max_byte_string_length: decoding_options.max_byte_string_length,
                 max_chunk_count: decoding_options.max_chunk_count,
                 max_message_size: decoding_options.max_message_size,
+                max_decoded_size: decoding_options.max_decoded_size,
+                decode_unknown_policy: decoding_options.decode_unknown_policy,
             },
             performance: Performance {
                 ignore_clock_skew: false,
@@ -2,21 +2,224 @@
 // SPDX-License-Identifier: MPL-2.0
 // Copyright (C) 2017-2022 Adam Lock
 
-use std::{collections::HashMap, sync::mpsc::SyncSender};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{mpsc::SyncSender, Arc},
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
 
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
 
-use crate::core::supported_message::SupportedMessage;
+use crate::{
+    core::supported_message::SupportedMessage,
+    sync::*,
+    types::{
+        status_code::StatusCode, DateTime, DiagnosticInfo, ExtensionObject, ResponseHeader,
+        ServiceFault,
+    },
+};
+
+/// What the message queue does with a response whose request handle does not match any request
+/// in flight, e.g. a response that arrives after its request has timed out.
+#[derive(Default)]
+pub enum UnmatchedResponsePolicy {
+    /// Discard the response.
+    Drop,
+    /// Discard the response after logging it as an error. This is the default.
+    #[default]
+    Log,
+    /// Pass the response to the supplied function. The function is called after the message
+    /// queue has been unlocked, so it may use the session.
+    Callback(Arc<dyn Fn(SupportedMessage) + Send + Sync>),
+}
+
+impl fmt::Debug for UnmatchedResponsePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnmatchedResponsePolicy::Drop => write!(f, "Drop"),
+            UnmatchedResponsePolicy::Log => write!(f, "Log"),
+            UnmatchedResponsePolicy::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+/// A response that matched no request, which is to be handed to the callback of the
+/// `UnmatchedResponsePolicy` once the message queue has been unlocked.
+pub(crate) struct UnmatchedResponse {
+    callback: Arc<dyn Fn(SupportedMessage) + Send + Sync>,
+    response: SupportedMessage,
+}
+
+impl UnmatchedResponse {
+    /// Passes the response to the callback. Call this without holding the message queue lock.
+    pub(crate) fn dispatch(self) {
+        (self.callback)(self.response);
+    }
+}
+
+/// Where the response to a request in flight is delivered when it arrives.
+#[derive(Debug)]
+pub(crate) enum ResponseSender {
+    /// A channel, e.g. of a caller that supplied its own
+    Channel(SyncSender<SupportedMessage>),
+    /// The sending half of a `ResponseFuture`
+    Future(oneshot::Sender<SupportedMessage>),
+}
+
+impl From<SyncSender<SupportedMessage>> for ResponseSender {
+    fn from(sender: SyncSender<SupportedMessage>) -> Self {
+        ResponseSender::Channel(sender)
+    }
+}
+
+impl ResponseSender {
+    /// Delivers the response, returning false if nothing is waiting for it any more.
+    pub(crate) fn send(self, response: SupportedMessage) -> bool {
+        match self {
+            ResponseSender::Channel(sender) => sender.send(response).is_ok(),
+            ResponseSender::Future(sender) => sender.send(response).is_ok(),
+        }
+    }
+}
+
+/// A future that resolves to the response to a request, returned by
+/// `Session::async_send_request_future()`. It can be awaited on a tokio runtime rather than
+/// blocking a thread until the response arrives.
+///
+/// The future resolves to `BadConnectionClosed` if the request is abandoned before a response
+/// arrives, e.g. because the session disconnected. It does not time out by itself, so wrap it in
+/// `tokio::time::timeout()` or similar. Dropping the future abandons the request and a response
+/// that arrives afterwards is treated as unmatched.
+pub struct ResponseFuture {
+    request_handle: u32,
+    receiver: oneshot::Receiver<SupportedMessage>,
+    message_queue: Arc<RwLock<MessageQueue>>,
+    complete: bool,
+}
+
+/// Wakes a thread blocked in `ResponseFuture::wait()`
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl ResponseFuture {
+    pub(crate) fn new(
+        request_handle: u32,
+        receiver: oneshot::Receiver<SupportedMessage>,
+        message_queue: Arc<RwLock<MessageQueue>>,
+    ) -> ResponseFuture {
+        ResponseFuture {
+            request_handle,
+            receiver,
+            message_queue,
+            complete: false,
+        }
+    }
+
+    /// The request handle of the request
+    pub fn request_handle(&self) -> u32 {
+        self.request_handle
+    }
+
+    /// Blocks the current thread until the response arrives or the timeout elapses, in which
+    /// case the result is `BadTimeout`. This does not need a runtime so synchronous callers can
+    /// use it from any thread.
+    pub(crate) fn wait(&mut self, timeout: Duration) -> Result<SupportedMessage, StatusCode> {
+        let deadline = Instant::now() + timeout;
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(result) = Pin::new(&mut *self).poll(&mut cx) {
+                return result;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(StatusCode::BadTimeout);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<SupportedMessage, StatusCode>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(response)) => Ok(response),
+            Poll::Ready(Err(_)) => Err(StatusCode::BadConnectionClosed),
+            Poll::Pending => return Poll::Pending,
+        };
+        self.complete = true;
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if !self.complete {
+            let mut message_queue = trace_write_lock!(self.message_queue);
+            message_queue.abandon_request(self.request_handle);
+        }
+    }
+}
+
+/// Counts of the requests and responses that have passed through the message queue of a
+/// session, returned by `Session::metrics()`. The counts are totals since the session was
+/// created.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MessageQueueMetrics {
+    /// Requests queued for sending
+    pub requests_sent: u64,
+    /// Responses received that matched a request, including service faults
+    pub responses_received: u64,
+    /// Requests that timed out waiting for a response
+    pub timeouts: u64,
+    /// Responses to asynchronous requests, e.g. publish responses, that have been processed
+    pub async_messages_consumed: u64,
+    /// Requests in flight now, i.e. sent and waiting for a response
+    pub pending_requests: usize,
+    /// The average time from queueing a request to receiving its response over the most recent
+    /// responses, or `None` if no response has been received
+    pub average_response_latency: Option<Duration>,
+}
 
 pub(crate) struct MessageQueue {
     /// The requests that are in-flight, defined by their request handle and optionally a sender that will be notified with the response.
     /// Basically, the sent requests reside here until the response returns at which point the entry is removed.
     /// If a response is received for which there is no entry, the response will be discarded.
-    inflight_requests: HashMap<u32, Option<SyncSender<SupportedMessage>>>,
+    inflight_requests: HashMap<u32, Option<ResponseSender>>,
     /// A map of incoming responses waiting to be processed
     responses: HashMap<u32, SupportedMessage>,
     /// This is the queue that messages will be sent onto the transport for sending
     sender: Option<UnboundedSender<Message>>,
+    /// What to do with responses that don't belong to any request
+    unmatched_response_policy: UnmatchedResponsePolicy,
+    /// Requests that have been queued for sending but not yet written to the transport
+    unsent_requests: usize,
+    /// When the last response was received from the server
+    last_response: Option<Instant>,
+    /// When each request in flight was queued, to measure the latency of its response
+    sent_at: HashMap<u32, Instant>,
+    /// The latencies of the most recent responses, oldest first
+    latencies: VecDeque<Duration>,
+    /// Counts reported by `metrics()`
+    requests_sent: u64,
+    responses_received: u64,
+    timeouts: u64,
+    async_messages_consumed: u64,
 }
 
 #[derive(Debug)]
@@ -26,17 +229,54 @@ pub enum Message {
 }
 
 impl MessageQueue {
+    /// How many of the most recent response latencies are averaged
+    const LATENCY_WINDOW: usize = 100;
+
     pub fn new() -> MessageQueue {
         MessageQueue {
             inflight_requests: HashMap::new(),
             responses: HashMap::new(),
             sender: None,
+            unmatched_response_policy: UnmatchedResponsePolicy::default(),
+            unsent_requests: 0,
+            last_response: None,
+            sent_at: HashMap::new(),
+            latencies: VecDeque::new(),
+            requests_sent: 0,
+            responses_received: 0,
+            timeouts: 0,
+            async_messages_consumed: 0,
         }
     }
 
+    pub(crate) fn set_unmatched_response_policy(
+        &mut self,
+        unmatched_response_policy: UnmatchedResponsePolicy,
+    ) {
+        self.unmatched_response_policy = unmatched_response_policy;
+    }
+
     pub(crate) fn clear(&mut self) {
         self.inflight_requests.clear();
         self.responses.clear();
+        self.sent_at.clear();
+    }
+
+    /// Returns the counts of requests and responses so far.
+    pub(crate) fn metrics(&self) -> MessageQueueMetrics {
+        let average_response_latency = if self.latencies.is_empty() {
+            None
+        } else {
+            Some(self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32)
+        };
+        MessageQueueMetrics {
+            requests_sent: self.requests_sent,
+            responses_received: self.responses_received,
+            timeouts: self.timeouts,
+            async_messages_consumed: self.async_messages_consumed,
+            pending_requests: self.inflight_requests.len(),
+            average_response_latency,
+        }
     }
 
     // Creates the transmission queue that outgoing requests will be sent over
@@ -45,11 +285,35 @@ impl MessageQueue {
     ) -> UnboundedReceiver<Message> {
         let (tx, rx) = mpsc::unbounded_channel();
         self.sender = Some(tx.clone());
+        // Anything queued on the previous channel will never be sent
+        self.unsent_requests = 0;
         rx
     }
 
+    /// Called by the transport when a request has been written to the server.
     pub(crate) fn request_was_processed(&mut self, request_handle: u32) {
-        debug!("Request {} was processed by the server", request_handle);
+        debug!("Request {} was written to the transport", request_handle);
+        self.unsent_requests = self.unsent_requests.saturating_sub(1);
+    }
+
+    /// The number of requests that have been queued for sending but not yet written to the
+    /// transport.
+    pub(crate) fn unsent_requests(&self) -> usize {
+        self.unsent_requests
+    }
+
+    /// When the last response was received from the server, if any has been.
+    pub(crate) fn last_response(&self) -> Option<Instant> {
+        self.last_response
+    }
+
+    /// Tests if the receiving end of the transmission queue has gone, i.e. nothing more will be
+    /// sent.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender
+            .as_ref()
+            .map(|sender| sender.is_closed())
+            .unwrap_or(true)
     }
 
     fn send_message(&self, message: Message) -> bool {
@@ -67,17 +331,34 @@ impl MessageQueue {
     }
 
     /// Called by the session to add a request to be sent. The sender parameter
-    /// is supplied by synchronous callers to be notified the moment the response is received.
-    /// Async callers, e.g. publish requests can supply None.
+    /// is supplied by synchronous callers and futures to be notified the moment the response is
+    /// received. Async callers, e.g. publish requests can supply None.
+    ///
+    /// A request whose handle is the same as one still in flight is rejected with
+    /// `BadRequestHeaderInvalid`, since its response could not be told apart from the other
+    /// request's, e.g. if it was built by hand with a handle that was already used.
     pub(crate) fn add_request(
         &mut self,
         request: SupportedMessage,
-        sender: Option<SyncSender<SupportedMessage>>,
-    ) {
+        sender: Option<ResponseSender>,
+    ) -> Result<(), StatusCode> {
         let request_handle = request.request_handle();
+        if self.inflight_requests.contains_key(&request_handle) {
+            error!(
+                "Request {} {} is rejected because a request with the same handle is in flight",
+                request.type_name(),
+                request_handle
+            );
+            return Err(StatusCode::BadRequestHeaderInvalid);
+        }
         trace!("Sending request {:?} to be sent", request);
         self.inflight_requests.insert(request_handle, sender);
-        let _ = self.send_message(Message::SupportedMessage(request));
+        self.sent_at.insert(request_handle, Instant::now());
+        self.requests_sent += 1;
+        if self.send_message(Message::SupportedMessage(request)) {
+            self.unsent_requests += 1;
+        }
+        Ok(())
     }
 
     pub(crate) fn quit(&self) {
@@ -93,36 +374,118 @@ impl MessageQueue {
             request_handle
         );
         let _ = self.inflight_requests.remove(&request_handle);
+        let _ = self.sent_at.remove(&request_handle);
+        self.timeouts += 1;
+    }
+
+    /// Called when the future waiting for a request's response is dropped. Any response that
+    /// arrives for it is treated as unmatched.
+    pub(crate) fn abandon_request(&mut self, request_handle: u32) {
+        let _ = self.sent_at.remove(&request_handle);
+        if self.inflight_requests.remove(&request_handle).is_some() {
+            debug!(
+                "Request {} was abandoned and any response will be ignored",
+                request_handle
+            );
+        }
     }
 
-    /// Called by the connection to store a response for the consumption of the session.
-    pub(crate) fn store_response(&mut self, response: SupportedMessage) {
+    /// Completes every request in flight with a service fault of the supplied status, e.g.
+    /// because the connection has closed and no responses will come, so anything waiting for a
+    /// response wakes straight away instead of waiting for its request to time out. Requests that
+    /// nothing is waiting for, e.g. publish requests, are forgotten. Returns the number of
+    /// requests that were aborted.
+    pub(crate) fn abort_all_requests(&mut self, status_code: StatusCode) -> usize {
+        let aborted = self.inflight_requests.len();
+        if aborted > 0 {
+            debug!(
+                "Aborting {} requests in flight with status {}",
+                aborted, status_code
+            );
+        }
+        self.sent_at.clear();
+        for (request_handle, sender) in self.inflight_requests.drain() {
+            if let Some(sender) = sender {
+                let response = ServiceFault {
+                    response_header: ResponseHeader {
+                        timestamp: DateTime::now(),
+                        request_handle,
+                        service_result: status_code,
+                        service_diagnostics: DiagnosticInfo::default(),
+                        string_table: None,
+                        additional_header: ExtensionObject::null(),
+                    },
+                };
+                // The waiter may have given up already
+                let _ = sender.send(response.into());
+            }
+        }
+        aborted
+    }
+
+    /// Called by the connection to store a response for the consumption of the session. A
+    /// response that matches no request is returned when the policy passes it to a callback,
+    /// which the caller must dispatch after releasing the lock on the queue.
+    pub(crate) fn store_response(
+        &mut self,
+        response: SupportedMessage,
+    ) -> Option<UnmatchedResponse> {
         // Remove corresponding request handle from inflight queue, add to responses
         let request_handle = response.request_handle();
         trace!("Received response {:?}", response);
         debug!("Response to Request {} has been stored", request_handle);
+        self.last_response = Some(Instant::now());
         // Remove the inflight request
         // This true / false is slightly clunky.
         if let Some(sender) = self.inflight_requests.remove(&request_handle) {
+            self.responses_received += 1;
+            if let Some(sent_at) = self.sent_at.remove(&request_handle) {
+                if self.latencies.len() == Self::LATENCY_WINDOW {
+                    let _ = self.latencies.pop_front();
+                }
+                self.latencies.push_back(sent_at.elapsed());
+            }
             if let Some(sender) = sender {
-                // Synchronous request
-                if let Err(e) = sender.send(response) {
+                // Synchronous request or future
+                if !sender.send(response) {
                     error!(
-                        "Cannot send a response to a synchronous request {} because send failed, error = {}",
-                        request_handle,
-                        e
+                        "Cannot send a response to request {} because nothing is waiting for it",
+                        request_handle
                     );
                 }
             } else {
                 self.responses.insert(request_handle, response);
             }
+            None
         } else {
-            error!("A response with request handle {} doesn't belong to any request and will be ignored, inflight requests = {:?}, request = {:?}", request_handle, self.inflight_requests, response);
-            if let SupportedMessage::ServiceFault(response) = response {
-                error!(
-                    "Unhandled response is a service fault, service result = {}",
-                    response.response_header.service_result
-                )
+            match self.unmatched_response_policy {
+                UnmatchedResponsePolicy::Drop => {
+                    debug!(
+                        "A response with request handle {} doesn't belong to any request and will be ignored",
+                        request_handle
+                    );
+                    None
+                }
+                UnmatchedResponsePolicy::Log => {
+                    error!("A response with request handle {} doesn't belong to any request and will be ignored, inflight requests = {:?}, request = {:?}", request_handle, self.inflight_requests, response);
+                    if let SupportedMessage::ServiceFault(response) = response {
+                        error!(
+                            "Unhandled response is a service fault, service result = {}",
+                            response.response_header.service_result
+                        )
+                    }
+                    None
+                }
+                UnmatchedResponsePolicy::Callback(ref callback) => {
+                    debug!(
+                        "A response with request handle {} doesn't belong to any request and will be passed to the callback",
+                        request_handle
+                    );
+                    Some(UnmatchedResponse {
+                        callback: callback.clone(),
+                        response,
+                    })
+                }
             }
         }
     }
@@ -135,6 +498,7 @@ impl MessageQueue {
 
         // Order them from oldest to latest (except if handles wrap)
         async_handles.sort();
+        self.async_messages_consumed += async_handles.len() as u64;
 
         // Remove each item from the map and return to caller
         async_handles
@@ -99,6 +99,7 @@ use crate::types::{response_header::ResponseHeader, status_code::StatusCode};
 
 mod comms;
 mod message_queue;
+mod rate_limiter;
 mod subscription;
 mod subscription_state;
 
@@ -107,6 +108,7 @@ mod builder;
 mod callbacks;
 mod client;
 mod config;
+mod monitored_item_builder;
 mod session;
 mod session_retry_policy;
 
@@ -152,7 +154,19 @@ pub mod prelude {
         callbacks::*,
         client::*,
         config::*,
-        session::{services::*, session::*},
+        message_queue::{MessageQueueMetrics, ResponseFuture, UnmatchedResponsePolicy},
+        monitored_item_builder::*,
+        rate_limiter::RateLimitPolicy,
+        session::{
+            diagnostics::{SessionDiagnostics, SessionDiagnosticsDelta},
+            services::*,
+            session::*,
+            session_state::{
+                PartialResultsPolicy, PreActivationPolicy, PublishFuture, ReconnectPolicy,
+                RequestError, RetryPolicy, SecureChannelRetryPolicy, SessionStateConfig,
+                SubscriptionSummary,
+            },
+        },
         subscription::MonitoredItem,
     };
 }
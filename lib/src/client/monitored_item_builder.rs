@@ -0,0 +1,307 @@
+// OPCUA for Rust
+// SPDX-License-Identifier: MPL-2.0
+// Copyright (C) 2017-2022 Adam Lock
+
+use crate::types::{
+    node_ids::{ObjectId, ObjectTypeId},
+    service_types::*,
+    *,
+};
+
+/// The `DataChangeMonitoredItemBuilder` is a builder for producing a [`MonitoredItemCreateRequest`]
+/// that reports changes to the value of a node, e.g. to pass to `create_monitored_items`.
+///
+/// # Example
+///
+/// ```
+/// use opcua::client::prelude::*;
+///
+/// let item_to_create = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1"))
+///     .sampling_interval(500.0)
+///     .trigger(DataChangeTrigger::Status)
+///     .build();
+/// ```
+///
+/// [`MonitoredItemCreateRequest`]: ../types/struct.MonitoredItemCreateRequest.html
+pub struct DataChangeMonitoredItemBuilder {
+    item_to_monitor: ReadValueId,
+    monitoring_mode: MonitoringMode,
+    client_handle: u32,
+    sampling_interval: f64,
+    queue_size: u32,
+    discard_oldest: bool,
+    trigger: DataChangeTrigger,
+}
+
+impl DataChangeMonitoredItemBuilder {
+    /// Creates a builder that monitors the value attribute of the node
+    pub fn new<T>(item_to_monitor: T) -> Self
+    where
+        T: Into<ReadValueId>,
+    {
+        let parameters = MonitoringParameters::default();
+        DataChangeMonitoredItemBuilder {
+            item_to_monitor: item_to_monitor.into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            client_handle: parameters.client_handle,
+            sampling_interval: parameters.sampling_interval,
+            queue_size: parameters.queue_size,
+            discard_oldest: parameters.discard_oldest,
+            trigger: DataChangeTrigger::StatusValue,
+        }
+    }
+
+    /// Set the monitoring mode. The default is `Reporting`
+    pub fn monitoring_mode(mut self, monitoring_mode: MonitoringMode) -> Self {
+        self.monitoring_mode = monitoring_mode;
+        self
+    }
+
+    /// Set the client handle. The session assigns one if this is left as 0
+    pub fn client_handle(mut self, client_handle: u32) -> Self {
+        self.client_handle = client_handle;
+        self
+    }
+
+    /// Set the sampling interval in milliseconds. The default of -1 means the publishing interval
+    /// of the subscription.
+    ///
+    /// An interval of 0 asks the server to sample as fast as is practical, which for a server
+    /// that is told of changes to the value, rather than polling for them, means every change is
+    /// reported when it happens, i.e. exception based monitoring. The 0 is sent as it is and it
+    /// is up to the server whether it revises it.
+    pub fn sampling_interval(mut self, sampling_interval: f64) -> Self {
+        self.sampling_interval = sampling_interval;
+        self
+    }
+
+    /// Set the queue size
+    pub fn queue_size(mut self, queue_size: u32) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Set whether the server discards the oldest notification when the queue overflows. The
+    /// default is true. Set it to false to discard the newest instead, e.g. to keep the earliest
+    /// changes to a counter or accumulating value.
+    pub fn discard_oldest(mut self, discard_oldest: bool) -> Self {
+        self.discard_oldest = discard_oldest;
+        self
+    }
+
+    /// Set what counts as a change worth reporting. The default is `StatusValue`, i.e. a change to
+    /// the status or the value.
+    ///
+    /// `StatusValueTimestamp` also reports a change to the source timestamp, so a value that is
+    /// rewritten unchanged is reported every time it is sampled. That can be a lot of
+    /// notifications. `Status` only reports a change to the status, i.e. the quality of the value,
+    /// and ignores changes to the value itself.
+    ///
+    /// A trigger held as a number, e.g. read from configuration, can be validated with
+    /// `DataChangeTrigger::try_from()`.
+    pub fn trigger(mut self, trigger: DataChangeTrigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Produces the request
+    pub fn build(self) -> MonitoredItemCreateRequest {
+        let filter = DataChangeFilter {
+            trigger: self.trigger,
+            deadband_type: DeadbandType::None as u32,
+            deadband_value: 0f64,
+        };
+        MonitoredItemCreateRequest::new(
+            self.item_to_monitor,
+            self.monitoring_mode,
+            MonitoringParameters {
+                client_handle: self.client_handle,
+                sampling_interval: self.sampling_interval,
+                filter: ExtensionObject::from_encodable(
+                    ObjectId::DataChangeFilter_Encoding_DefaultBinary,
+                    &filter,
+                ),
+                queue_size: self.queue_size,
+                discard_oldest: self.discard_oldest,
+            },
+        )
+    }
+}
+
+/// The `EventMonitoredItemBuilder` is a builder for producing a [`MonitoredItemCreateRequest`]
+/// that reports the events of an event source, e.g. the `Server` object, with an `EventFilter`
+/// choosing the fields of each event and optionally which events are reported.
+///
+/// The fields of each event arrive in the order of the select clauses, and
+/// [`decode_event_fields`] pairs them up with the names of the fields.
+///
+/// # Example
+///
+/// ```
+/// use opcua::client::prelude::*;
+///
+/// let builder = EventMonitoredItemBuilder::new(ObjectId::Server)
+///     .select("Message")
+///     .select("Severity");
+/// let select_clauses = builder.selected().to_vec();
+/// let item_to_create = builder.build();
+/// ```
+///
+/// [`MonitoredItemCreateRequest`]: ../types/struct.MonitoredItemCreateRequest.html
+/// [`decode_event_fields`]: ./fn.decode_event_fields.html
+pub struct EventMonitoredItemBuilder {
+    event_source: NodeId,
+    monitoring_mode: MonitoringMode,
+    client_handle: u32,
+    queue_size: u32,
+    discard_oldest: bool,
+    select_clauses: Vec<SimpleAttributeOperand>,
+    where_clause: ContentFilter,
+}
+
+impl EventMonitoredItemBuilder {
+    /// Creates a builder that monitors the events of the event source, with no fields selected
+    /// and no where clause
+    pub fn new<T>(event_source: T) -> Self
+    where
+        T: Into<NodeId>,
+    {
+        let parameters = MonitoringParameters::default();
+        EventMonitoredItemBuilder {
+            event_source: event_source.into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            client_handle: parameters.client_handle,
+            queue_size: parameters.queue_size,
+            discard_oldest: parameters.discard_oldest,
+            select_clauses: Vec::new(),
+            where_clause: ContentFilter { elements: None },
+        }
+    }
+
+    /// Set the monitoring mode. The default is `Reporting`
+    pub fn monitoring_mode(mut self, monitoring_mode: MonitoringMode) -> Self {
+        self.monitoring_mode = monitoring_mode;
+        self
+    }
+
+    /// Set the client handle. The session assigns one if this is left as 0
+    pub fn client_handle(mut self, client_handle: u32) -> Self {
+        self.client_handle = client_handle;
+        self
+    }
+
+    /// Set the queue size, i.e. how many events the server holds between publishes
+    pub fn queue_size(mut self, queue_size: u32) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Set whether the server discards the oldest event when the queue overflows. The default
+    /// is true.
+    pub fn discard_oldest(mut self, discard_oldest: bool) -> Self {
+        self.discard_oldest = discard_oldest;
+        self
+    }
+
+    /// Select the value of a field of `BaseEventType` by its browse name, e.g. `"Message"`,
+    /// `"Severity"` or `"SourceName"`
+    pub fn select<T>(mut self, browse_name: T) -> Self
+    where
+        T: Into<QualifiedName>,
+    {
+        self.select_clauses.push(SimpleAttributeOperand {
+            type_definition_id: ObjectTypeId::BaseEventType.into(),
+            browse_path: Some(vec![browse_name.into()]),
+            attribute_id: AttributeId::Value as u32,
+            index_range: UAString::null(),
+        });
+        self
+    }
+
+    /// Select fields with select clauses of their own, e.g. a field of a subtype of
+    /// `BaseEventType` or one further down the event's hierarchy. They follow any fields that
+    /// are already selected.
+    pub fn select_clauses(mut self, select_clauses: Vec<SimpleAttributeOperand>) -> Self {
+        self.select_clauses.extend(select_clauses);
+        self
+    }
+
+    /// Set the where clause that an event must pass to be reported. By default every event is
+    /// reported.
+    pub fn where_clause(mut self, where_clause: ContentFilter) -> Self {
+        self.where_clause = where_clause;
+        self
+    }
+
+    /// The select clauses chosen so far, in the order the fields of each event arrive in
+    pub fn selected(&self) -> &[SimpleAttributeOperand] {
+        &self.select_clauses
+    }
+
+    /// Produces the request
+    pub fn build(self) -> MonitoredItemCreateRequest {
+        let filter = EventFilter {
+            select_clauses: Some(self.select_clauses),
+            where_clause: self.where_clause,
+        };
+        let item_to_monitor = ReadValueId {
+            attribute_id: AttributeId::EventNotifier as u32,
+            ..self.event_source.into()
+        };
+        MonitoredItemCreateRequest::new(
+            item_to_monitor,
+            self.monitoring_mode,
+            MonitoringParameters {
+                client_handle: self.client_handle,
+                // The sampling interval of an event monitored item is ignored
+                sampling_interval: 0f64,
+                filter: ExtensionObject::from_encodable(
+                    ObjectId::EventFilter_Encoding_DefaultBinary,
+                    &filter,
+                ),
+                queue_size: self.queue_size,
+                discard_oldest: self.discard_oldest,
+            },
+        )
+    }
+}
+
+/// Pairs the fields of an event with the names of the select clauses they were chosen by, i.e.
+/// the browse names of each clause's path joined with `/`, e.g. `"Message"` or
+/// `"EnabledState/Id"`.
+///
+/// # Returns
+///
+/// * `Ok(Vec<(String, Variant)>)` - The name and value of each field, in the order selected.
+/// * `Err(StatusCode)` - `BadUnexpectedError` if the event does not have a field for each
+///   select clause.
+///
+pub fn decode_event_fields(
+    select_clauses: &[SimpleAttributeOperand],
+    event: &EventFieldList,
+) -> Result<Vec<(String, Variant)>, StatusCode> {
+    let event_fields = event.event_fields.as_deref().unwrap_or(&[]);
+    if event_fields.len() != select_clauses.len() {
+        error!(
+            "Event for client handle {} has {} fields but {} were selected",
+            event.client_handle,
+            event_fields.len(),
+            select_clauses.len()
+        );
+        return Err(StatusCode::BadUnexpectedError);
+    }
+    Ok(select_clauses
+        .iter()
+        .zip(event_fields)
+        .map(|(select_clause, value)| {
+            let name = select_clause
+                .browse_path
+                .iter()
+                .flatten()
+                .map(|browse_name| browse_name.name.as_ref())
+                .collect::<Vec<_>>()
+                .join("/");
+            (name, value.clone())
+        })
+        .collect())
+}
@@ -0,0 +1,63 @@
+// OPCUA for Rust
+// SPDX-License-Identifier: MPL-2.0
+// Copyright (C) 2017-2022 Adam Lock
+
+use std::time::{Duration, Instant};
+
+/// What a session does with a request when it has used up its allowance of requests per second.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum RateLimitPolicy {
+    /// Block until the request is allowed. This is the default.
+    #[default]
+    Wait,
+    /// Fail the request with `BadResourceUnavailable`.
+    Reject,
+}
+
+/// A token bucket that allows a number of requests per second. The bucket holds up to a second
+/// of requests, so a client that has been quiet can send a short burst.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// Tokens added to the bucket per second
+    requests_per_second: f64,
+    /// The most tokens the bucket can hold
+    capacity: f64,
+    /// Tokens in the bucket, each of which allows one request
+    tokens: f64,
+    /// When the bucket was last refilled
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> RateLimiter {
+        let capacity = requests_per_second.max(1.0);
+        RateLimiter {
+            requests_per_second,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second
+    }
+
+    /// Takes a token from the bucket for a request at the specified time. If the bucket is empty,
+    /// the time until there is a token is returned instead.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        if now > self.last_refill {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.requests_per_second).min(self.capacity);
+            self.last_refill = now;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.requests_per_second,
+            ))
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// OPCUA for Rust
+// SPDX-License-Identifier: MPL-2.0
+// Copyright (C) 2017-2022 Adam Lock
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Counters of the traffic of a session, shared by the session state and its transport.
+#[derive(Debug, Default)]
+pub(crate) struct SessionCounters {
+    requests_sent: AtomicU64,
+    responses_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl SessionCounters {
+    pub fn request_sent(&self) {
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response_received(&self) {
+        self.responses_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn timed_out(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes a snapshot of the counters as they are now
+    pub fn snapshot(&self) -> SessionDiagnostics {
+        SessionDiagnostics {
+            timestamp: Instant::now(),
+            requests_sent: self.requests_sent.load(Ordering::Relaxed),
+            responses_received: self.responses_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the traffic of a session since it was created. The counts are totals, so two
+/// snapshots can be compared with [`SessionDiagnostics::diff`] to find the rates in between.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SessionDiagnostics {
+    /// When the snapshot was taken
+    pub timestamp: Instant,
+    /// Requests sent, including publish requests
+    pub requests_sent: u64,
+    /// Responses received, including service faults
+    pub responses_received: u64,
+    /// Bytes written to the server, including message headers and security
+    pub bytes_sent: u64,
+    /// Bytes read from the server, including message headers and security
+    pub bytes_received: u64,
+    /// Requests that timed out waiting for a response
+    pub timeouts: u64,
+}
+
+impl SessionDiagnostics {
+    /// Returns the change from an earlier snapshot of the same session to this one.
+    pub fn diff(&self, earlier: &SessionDiagnostics) -> SessionDiagnosticsDelta {
+        SessionDiagnosticsDelta {
+            elapsed: self.timestamp.saturating_duration_since(earlier.timestamp),
+            requests_sent: self.requests_sent.saturating_sub(earlier.requests_sent),
+            responses_received: self
+                .responses_received
+                .saturating_sub(earlier.responses_received),
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            timeouts: self.timeouts.saturating_sub(earlier.timeouts),
+        }
+    }
+}
+
+/// The change in the traffic of a session between two snapshots.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SessionDiagnosticsDelta {
+    /// The time between the snapshots
+    pub elapsed: Duration,
+    pub requests_sent: u64,
+    pub responses_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub timeouts: u64,
+}
+
+impl SessionDiagnosticsDelta {
+    /// Requests sent per second. This is 0 if no time elapsed.
+    pub fn requests_per_second(&self) -> f64 {
+        self.per_second(self.requests_sent)
+    }
+
+    /// Bytes sent per second. This is 0 if no time elapsed.
+    pub fn bytes_sent_per_second(&self) -> f64 {
+        self.per_second(self.bytes_sent)
+    }
+
+    /// Bytes received per second. This is 0 if no time elapsed.
+    pub fn bytes_received_per_second(&self) -> f64 {
+        self.per_second(self.bytes_received)
+    }
+
+    fn per_second(&self, count: u64) -> f64 {
+        let elapsed = self.elapsed.as_secs_f64();
+        if elapsed > 0.0 {
+            count as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+}
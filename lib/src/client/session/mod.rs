@@ -1,3 +1,4 @@
+pub mod diagnostics;
 pub mod services;
 pub mod session;
 pub mod session_state;
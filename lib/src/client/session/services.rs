@@ -2,13 +2,19 @@
 // SPDX-License-Identifier: MPL-2.0
 // Copyright (C) 2017-2022 Adam Lock
 
-use std::{convert::TryFrom, sync::mpsc::SyncSender};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::mpsc::SyncSender,
+    time::{Duration, Instant},
+};
 
 use crate::{
     client::callbacks::OnSubscriptionNotification,
     core::supported_message::SupportedMessage,
     types::{
-        node_ids::{MethodId, ObjectId},
+        node_ids::{MethodId, ObjectId, VariableId},
+        service_types::Argument,
         status_code::StatusCode,
         *,
     },
@@ -77,6 +83,218 @@ impl From<&HistoryUpdateAction> for ExtensionObject {
     }
 }
 
+/// The type metadata of a variable node, returned by Session::read_variable_metadata()
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableMetadata {
+    /// The node id of the variable's data type
+    pub data_type: NodeId,
+    /// The value rank, e.g. -1 for a scalar, 1 for a one dimensional array
+    pub value_rank: i32,
+    /// The length of each dimension, if the node declares it
+    pub array_dimensions: Option<Vec<u32>>,
+}
+
+/// The scaling and display information of an analog item, returned by
+/// Session::read_analog_metadata(). A field is `None` if the node doesn't have the property.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AnalogMetadata {
+    /// The range the value is expected to be in, from the `EURange` property
+    pub eu_range: Option<Range>,
+    /// The units of the value, from the `EngineeringUnits` property
+    pub engineering_units: Option<EUInformation>,
+    /// The range the instrument can measure, from the `InstrumentRange` property
+    pub instrument_range: Option<Range>,
+}
+
+/// The arguments of a method, returned by Session::read_method_arguments(). Each argument has its
+/// name, data type, value rank, array dimensions and description. A method without inputs or
+/// outputs has no `InputArguments` or `OutputArguments` property and its list is empty.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MethodArguments {
+    /// The arguments passed to the method, from the `InputArguments` property
+    pub input_arguments: Vec<Argument>,
+    /// The arguments returned by the method, from the `OutputArguments` property
+    pub output_arguments: Vec<Argument>,
+}
+
+impl MethodArguments {
+    /// Checks the inputs of a call match the input arguments in number, data type and value
+    /// rank, returning `BadInvalidArgument` if they don't. Only the built-in data types and the
+    /// abstract `BaseDataType`, `Number`, `Integer`, `UInteger`, `Enumeration` and `Structure`
+    /// types are checked. An argument of any other data type, e.g. a subtype or one defined by
+    /// the server, is taken to match as telling would mean reading the type hierarchy.
+    pub fn validate_inputs(&self, inputs: &[Variant]) -> Result<(), StatusCode> {
+        if inputs.len() != self.input_arguments.len() {
+            error!(
+                "Method expects {} input arguments, got {}",
+                self.input_arguments.len(),
+                inputs.len()
+            );
+            return Err(StatusCode::BadInvalidArgument);
+        }
+        self.input_arguments
+            .iter()
+            .zip(inputs)
+            .try_for_each(|(argument, input)| {
+                if Self::is_match(argument, input) {
+                    Ok(())
+                } else {
+                    error!(
+                        "Method input argument {} expects data type {} with value rank {}, got {:?}",
+                        argument.name, argument.data_type, argument.value_rank, input
+                    );
+                    Err(StatusCode::BadInvalidArgument)
+                }
+            })
+    }
+
+    /// Tests if the input matches the value rank and data type of the argument
+    fn is_match(argument: &Argument, input: &Variant) -> bool {
+        match input {
+            Variant::Array(array) => {
+                // Scalar only
+                argument.value_rank != -1
+                    && array
+                        .values
+                        .iter()
+                        .all(|value| Self::is_data_type(&argument.data_type, value))
+            }
+            // Other than scalar or one dimension, some dimensions or any
+            input => {
+                matches!(argument.value_rank, -3..=-1)
+                    && Self::is_data_type(&argument.data_type, input)
+            }
+        }
+    }
+
+    /// Tests if a scalar value is of the data type, or the data type is one that isn't checked
+    fn is_data_type(data_type: &NodeId, value: &Variant) -> bool {
+        let type_id = match data_type.identifier {
+            Identifier::Numeric(type_id) if data_type.namespace == 0 => type_id,
+            _ => return true,
+        };
+        match type_id {
+            type_id if type_id == DataTypeId::BaseDataType as u32 => true,
+            type_id if type_id == DataTypeId::Number as u32 => value.is_numeric(),
+            type_id if type_id == DataTypeId::Integer as u32 => matches!(
+                value,
+                Variant::SByte(_) | Variant::Int16(_) | Variant::Int32(_) | Variant::Int64(_)
+            ),
+            type_id if type_id == DataTypeId::UInteger as u32 => matches!(
+                value,
+                Variant::Byte(_) | Variant::UInt16(_) | Variant::UInt32(_) | Variant::UInt64(_)
+            ),
+            type_id if type_id == DataTypeId::Enumeration as u32 => {
+                matches!(value, Variant::Int32(_))
+            }
+            type_id if type_id == DataTypeId::Structure as u32 => {
+                matches!(value, Variant::ExtensionObject(_))
+            }
+            // The other built-in types
+            1..=25 => value.scalar_data_type().as_ref() == Some(data_type),
+            _ => true,
+        }
+    }
+}
+
+/// The server's diagnostics for the current session, returned by
+/// Session::read_server_session_diagnostics(). The counts are the server's view of the activity
+/// of the session and can be compared with the client's own `SessionDiagnostics`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerSessionDiagnostics {
+    /// The name of the session
+    pub session_name: UAString,
+    /// When the client connected
+    pub client_connection_time: DateTime,
+    /// When the server last heard from the client
+    pub client_last_contact_time: DateTime,
+    /// Subscriptions of the session
+    pub current_subscriptions_count: u32,
+    /// Monitored items of the session
+    pub current_monitored_items_count: u32,
+    /// Publish requests the server is holding for the session
+    pub current_publish_requests_in_queue: u32,
+    /// Requests received from the session, of any service
+    pub total_request_count: u32,
+    /// Requests received from the session that failed
+    pub total_request_error_count: u32,
+    /// Requests rejected because the session was not authorized to make them
+    pub unauthorized_request_count: u32,
+    /// Read requests
+    pub read_count: u32,
+    /// Write requests
+    pub write_count: u32,
+    /// Call requests
+    pub call_count: u32,
+    /// Browse requests
+    pub browse_count: u32,
+    /// Create subscription requests
+    pub create_subscription_count: u32,
+    /// Create monitored items requests
+    pub create_monitored_items_count: u32,
+    /// Publish requests
+    pub publish_count: u32,
+}
+
+impl From<SessionDiagnosticsDataType> for ServerSessionDiagnostics {
+    fn from(diagnostics: SessionDiagnosticsDataType) -> Self {
+        ServerSessionDiagnostics {
+            session_name: diagnostics.session_name,
+            client_connection_time: diagnostics.client_connection_time,
+            client_last_contact_time: diagnostics.client_last_contact_time,
+            current_subscriptions_count: diagnostics.current_subscriptions_count,
+            current_monitored_items_count: diagnostics.current_monitored_items_count,
+            current_publish_requests_in_queue: diagnostics.current_publish_requests_in_queue,
+            total_request_count: diagnostics.total_request_count.total_count,
+            total_request_error_count: diagnostics.total_request_count.error_count,
+            unauthorized_request_count: diagnostics.unauthorized_request_count,
+            read_count: diagnostics.read_count.total_count,
+            write_count: diagnostics.write_count.total_count,
+            call_count: diagnostics.call_count.total_count,
+            browse_count: diagnostics.browse_count.total_count,
+            create_subscription_count: diagnostics.create_subscription_count.total_count,
+            create_monitored_items_count: diagnostics.create_monitored_items_count.total_count,
+            publish_count: diagnostics.publish_count.total_count,
+        }
+    }
+}
+
+/// Which services the server appears to support, returned by
+/// Session::server_service_capabilities(). Each is inferred from a node that a server supporting
+/// the service would normally have, so it is a hint and not a guarantee.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct ServiceCapabilities {
+    /// The server has the `GetMonitoredItems` method on its `Server` object
+    pub supports_method_call: bool,
+    /// The server's `AccessHistoryDataCapability` is true
+    pub supports_historical_data: bool,
+    /// The `Server` object's EventNotifier allows subscribing to events
+    pub supports_events: bool,
+    /// The server reports a `CurrentSubscriptionCount` in its diagnostics summary
+    pub supports_subscriptions: bool,
+}
+
+/// The parameters of a subscription as revised by the server, returned by
+/// Session::modify_subscription()
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModifiedSubscription {
+    /// The publishing interval in milliseconds
+    pub revised_publishing_interval: f64,
+    /// The lifetime count
+    pub revised_lifetime_count: u32,
+    /// The max keep alive count
+    pub revised_max_keep_alive_count: u32,
+}
+
+/// The outcome of each link added or removed by Session::set_triggering()
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetTriggeringResult {
+    /// The result for each link to add, in the same order as the links to add
+    pub add_results: Vec<StatusCode>,
+    /// The result for each link to remove, in the same order as the links to remove
+    pub remove_results: Vec<StatusCode>,
+}
+
 pub trait Service {
     fn make_request_header(&self) -> RequestHeader;
 
@@ -93,6 +311,12 @@ pub trait Service {
     ) -> Result<u32, StatusCode>
     where
         T: Into<SupportedMessage>;
+
+    /// Returns the options for decoding structures in responses, e.g. the body of an extension
+    /// object. The defaults are used unless the implementation has its own.
+    fn decoding_options(&self) -> DecodingOptions {
+        DecodingOptions::default()
+    }
 }
 
 /// Discovery Service set
@@ -205,7 +429,9 @@ pub trait SessionService: Service {
     /// # Returns
     ///
     /// * `Ok(())` - Success
-    /// * `Err(StatusCode)` - Request failed, status code is the reason for failure
+    /// * `Err(StatusCode)` - Request failed, status code is the reason for failure. If the server
+    ///   does not respond within the connect timeout the reason is `BadSessionNotActivated`
+    ///   rather than `BadTimeout`, e.g. so the caller can retry with a different identity token.
     ///
     /// [`ActivateSessionRequest`]: ./struct.ActivateSessionRequest.html
     ///
@@ -437,6 +663,9 @@ pub trait ViewService: Service {
 pub trait AttributeService: Service {
     /// Reads the value of nodes by sending a [`ReadRequest`] to the server.
     ///
+    /// Passing a large `max_age` when polling slowly changing data lets the server answer from
+    /// its cache, which can considerably reduce the load on the server.
+    ///
     /// See OPC UA Part 4 - Services 5.10.2 for complete description of the service and error responses.
     ///
     /// # Arguments
@@ -451,7 +680,8 @@ pub trait AttributeService: Service {
     /// # Returns
     ///
     /// * `Ok(Vec<DataValue>)` - A list of [`DataValue`] corresponding to each read operation.
-    /// * `Err(StatusCode)` - Status code reason for failure.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadUnexpectedError` if the
+    ///   server returned the wrong number of results, see `Session::set_partial_results_policy`.
     ///
     /// [`ReadRequest`]: ./struct.ReadRequest.html
     /// [`ReadValueId`]: ./struct.ReadValueId.html
@@ -506,7 +736,8 @@ pub trait AttributeService: Service {
     /// # Returns
     ///
     /// * `Ok(Vec<StatusCode>)` - A list of `StatusCode` results corresponding to each write operation.
-    /// * `Err(StatusCode)` - Status code reason for failure.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadUnexpectedError` if the
+    ///   server returned the wrong number of results, see `Session::set_partial_results_policy`.
     ///
     /// [`WriteRequest`]: ./struct.WriteRequest.html
     /// [`WriteValue`]: ./struct.WriteValue.html
@@ -538,6 +769,410 @@ pub trait AttributeService: Service {
         &self,
         history_update_details: &[HistoryUpdateAction],
     ) -> Result<Vec<HistoryUpdateResult>, StatusCode>;
+
+    /// Reads the value attribute of a single node. See [`AttributeService::read`] for how
+    /// `max_age` is used. A `max_age` of 0 always reads a fresh value, while a larger value lets
+    /// the server return a cached value no older than that.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The node to read the value of.
+    /// * `max_age` - The maximum age of the value to read in milliseconds, 0 for a fresh value.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(DataValue)` - The value of the node, which may hold a bad status.
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    fn read_value(&self, node_id: NodeId, max_age: f64) -> Result<DataValue, StatusCode> {
+        let mut results = self.read(&[node_id.into()], TimestampsToReturn::Both, max_age)?;
+        if results.len() != 1 {
+            error!("read_value() expected 1 result but got {}", results.len());
+            Err(StatusCode::BadUnexpectedError)
+        } else {
+            Ok(results.remove(0))
+        }
+    }
+
+    /// Reads several attributes of a single node in one [`ReadRequest`], returning the values
+    /// keyed by attribute. An attribute that the server fails to read is still present in
+    /// the map, holding a [`DataValue`] with the bad status, so the whole call does not fail
+    /// because one attribute is unavailable.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The node to read attributes from.
+    /// * `attributes` - The attributes to read.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HashMap<AttributeId, DataValue>)` - The value of each requested attribute.
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    /// [`ReadRequest`]: ./struct.ReadRequest.html
+    /// [`DataValue`]: ./struct.DataValue.html
+    ///
+    fn read_node(
+        &self,
+        node_id: NodeId,
+        attributes: &[AttributeId],
+    ) -> Result<HashMap<AttributeId, DataValue>, StatusCode> {
+        let nodes_to_read = attributes
+            .iter()
+            .map(|attribute_id| ReadValueId {
+                node_id: node_id.clone(),
+                attribute_id: *attribute_id as u32,
+                index_range: UAString::null(),
+                data_encoding: QualifiedName::null(),
+            })
+            .collect::<Vec<_>>();
+        let results = self.read(&nodes_to_read, TimestampsToReturn::Both, 0.0)?;
+        if results.len() != attributes.len() {
+            error!(
+                "read_node() expected {} results but got {}",
+                attributes.len(),
+                results.len()
+            );
+            Err(StatusCode::BadUnexpectedError)
+        } else {
+            Ok(attributes.iter().copied().zip(results).collect())
+        }
+    }
+
+    /// Reads the DataType, ValueRank and ArrayDimensions attributes of a variable in one request.
+    /// This is enough information for a generic client to know what shape of value the node
+    /// holds and how to present or edit it.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The variable to read the metadata of.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(VariableMetadata)` - The type metadata of the variable. The array dimensions are `None`
+    ///   if the server does not supply them.
+    /// * `Err(StatusCode)` - Status code reason for failure, including the status of the DataType
+    ///   or ValueRank attribute if either could not be read.
+    ///
+    fn read_variable_metadata(&self, node_id: NodeId) -> Result<VariableMetadata, StatusCode> {
+        let mut results = self.read_node(
+            node_id,
+            &[
+                AttributeId::DataType,
+                AttributeId::ValueRank,
+                AttributeId::ArrayDimensions,
+            ],
+        )?;
+        let mut take_value = |attribute_id| {
+            let value = results.remove(&attribute_id).unwrap_or_default();
+            if value.status().is_bad() {
+                Err(value.status())
+            } else {
+                Ok(value.value)
+            }
+        };
+        let data_type = match take_value(AttributeId::DataType)? {
+            Some(Variant::NodeId(data_type)) => *data_type,
+            _ => return Err(StatusCode::BadTypeMismatch),
+        };
+        let value_rank = match take_value(AttributeId::ValueRank)? {
+            Some(Variant::Int32(value_rank)) => value_rank,
+            _ => return Err(StatusCode::BadTypeMismatch),
+        };
+        let array_dimensions = take_value(AttributeId::ArrayDimensions)
+            .ok()
+            .flatten()
+            .and_then(|v| <Vec<u32>>::try_from(&v).ok());
+        Ok(VariableMetadata {
+            data_type,
+            value_rank,
+            array_dimensions,
+        })
+    }
+
+    /// Tests if a node exists by reading its NodeClass attribute, which every node has. This lets
+    /// a client check a node is there before operating on it, and tells a node that does not
+    /// exist apart from a read that failed for some other reason.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The node to test.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(bool)` - `true` if the node exists, `false` if the server reports `BadNodeIdUnknown`.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. the read timed out.
+    ///
+    fn node_exists(&self, node_id: NodeId) -> Result<bool, StatusCode> {
+        let value = self
+            .read_node(node_id, &[AttributeId::NodeClass])?
+            .remove(&AttributeId::NodeClass)
+            .unwrap_or_default();
+        match value.status() {
+            StatusCode::BadNodeIdUnknown => Ok(false),
+            status if status.is_bad() => Err(status),
+            _ => Ok(true),
+        }
+    }
+
+    /// Tests if a node is writable by reading its AccessLevel attribute and checking the
+    /// `CurrentWrite` bit. This lets a client check a write is possible before attempting it,
+    /// e.g. to show a read only value as such, rather than to have the write fail with
+    /// `BadNotWritable`. Note the server may still deny the write to a particular user, which is
+    /// described by the UserAccessLevel attribute.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The node to test.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(bool)` - `true` if the node's value can be written.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadAttributeIdInvalid` if the
+    ///   node is not a variable.
+    ///
+    fn is_writable(&self, node_id: NodeId) -> Result<bool, StatusCode> {
+        let value = self
+            .read_node(node_id, &[AttributeId::AccessLevel])?
+            .remove(&AttributeId::AccessLevel)
+            .unwrap_or_default();
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        match value.value {
+            Some(Variant::Byte(access_level)) => {
+                Ok(AccessLevelType::from_bits_truncate(access_level)
+                    .contains(AccessLevelType::CurrentWrite))
+            }
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Tests if the history of a node can be read by reading its Historizing attribute and
+    /// checking the `HistoryRead` bit of its AccessLevel attribute, both in one request. This
+    /// lets a client skip history reads of nodes that don't support them rather than have them
+    /// fail with `BadHistoryOperationUnsupported`. A node whose history is no longer being
+    /// collected is reported as not supporting history, even if old history can still be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The node to test.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(bool)` - `true` if the server is collecting the history of the node and it may be
+    ///   read.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadAttributeIdInvalid` if the
+    ///   node is not a variable.
+    ///
+    fn supports_history(&self, node_id: NodeId) -> Result<bool, StatusCode> {
+        let mut values = self.read_node(
+            node_id,
+            &[AttributeId::Historizing, AttributeId::AccessLevel],
+        )?;
+        let mut value = |attribute_id| {
+            let value = values.remove(&attribute_id).unwrap_or_default();
+            if value.status().is_bad() {
+                Err(value.status())
+            } else {
+                Ok(value.value)
+            }
+        };
+        let historizing = match value(AttributeId::Historizing)? {
+            Some(Variant::Boolean(historizing)) => historizing,
+            _ => return Err(StatusCode::BadTypeMismatch),
+        };
+        let history_read = match value(AttributeId::AccessLevel)? {
+            Some(Variant::Byte(access_level)) => AccessLevelType::from_bits_truncate(access_level)
+                .contains(AccessLevelType::HistoryRead),
+            _ => return Err(StatusCode::BadTypeMismatch),
+        };
+        Ok(historizing && history_read)
+    }
+
+    /// Measures the round trip time to the server by reading its current time, the smallest
+    /// meaningful request. This can be used as a latency probe for the health of the link, e.g.
+    /// before starting a heavy operation. The time includes encoding, the network in both
+    /// directions and the server processing the read.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Duration)` - The time from sending the request to receiving the response.
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    fn ping(&self) -> Result<Duration, StatusCode> {
+        let node_id: NodeId = VariableId::Server_ServerStatus_CurrentTime.into();
+        let start = Instant::now();
+        let _ = self.read(&[node_id.into()], TimestampsToReturn::Neither, 0.0)?;
+        Ok(start.elapsed())
+    }
+
+    /// Reads the server's current time from its `Server_ServerStatus_CurrentTime` variable, e.g. to
+    /// use the server as a time source or to estimate the skew between the client and server
+    /// clocks. The time is returned even if it looks stale, since only the caller knows how much
+    /// latency it can tolerate.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(DateTime)` - The server's current time.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadTypeMismatch` if the server
+    ///   returned something other than a `DateTime`.
+    ///
+    fn server_time(&self) -> Result<DateTime, StatusCode> {
+        let node_id: NodeId = VariableId::Server_ServerStatus_CurrentTime.into();
+        let value = self.read_value(node_id, 0.0)?;
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        match value.value {
+            Some(Variant::DateTime(current_time)) => Ok(*current_time),
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Reads the server's current time like [`AttributeService::server_time`], compensating for
+    /// the latency of the read, e.g. for a client that must align itself with the server clock
+    /// to within a fraction of a second. The server reads its clock at some point between the
+    /// request being sent and the response arriving, so the time is advanced by half the round
+    /// trip, and half the round trip is how far out the estimate may be either way.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((DateTime, chrono::Duration))` - The estimate of the server's current time as the
+    ///   response arrived, and its uncertainty.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadTypeMismatch` if the server
+    ///   returned something other than a `DateTime`.
+    ///
+    fn synchronized_server_time(&self) -> Result<(DateTime, chrono::Duration), StatusCode> {
+        let start = Instant::now();
+        let server_time = self.server_time()?;
+        let uncertainty = chrono::Duration::from_std(start.elapsed() / 2)
+            .map_err(|_| StatusCode::BadUnexpectedError)?;
+        Ok((server_time + uncertainty, uncertainty))
+    }
+
+    /// Reads the locales the server supports from its `Server_ServerCapabilities_LocaleIdArray`
+    /// variable, e.g. to choose preferred locales that the server can actually honour.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` - The locale ids, e.g. "en-US". The list may be empty if the server
+    ///   does not declare its locales.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadTypeMismatch` if the server
+    ///   returned something other than an array of locale ids.
+    ///
+    fn supported_locales(&self) -> Result<Vec<String>, StatusCode> {
+        let node_id: NodeId = VariableId::Server_ServerCapabilities_LocaleIdArray.into();
+        let value = self.read_value(node_id, 0.0)?;
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        match value.value {
+            Some(Variant::Array(array)) => array
+                .values
+                .iter()
+                .map(|v| match v {
+                    Variant::String(locale_id) => Ok(locale_id.as_ref().to_string()),
+                    _ => Err(StatusCode::BadTypeMismatch),
+                })
+                .collect(),
+            None | Some(Variant::Empty) => Ok(Vec::new()),
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Reads the server's build information from its `Server_ServerStatus_BuildInfo` variable,
+    /// e.g. to log the server's product name and software version or to work around known
+    /// problems with particular versions.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BuildInfo)` - The server's build information.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadTypeMismatch` if the server
+    ///   returned something other than a `BuildInfo`.
+    ///
+    fn read_build_info(&self) -> Result<BuildInfo, StatusCode> {
+        let node_id: NodeId = VariableId::Server_ServerStatus_BuildInfo.into();
+        let value = self.read_value(node_id, 0.0)?;
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        match value.value {
+            Some(Variant::ExtensionObject(extension_object)) => {
+                match extension_object.object_id() {
+                    Ok(ObjectId::BuildInfo_Encoding_DefaultBinary) => extension_object
+                        .decode_inner::<BuildInfo>(&self.decoding_options())
+                        .map_err(|_| StatusCode::BadDecodingError),
+                    _ => Err(StatusCode::BadTypeMismatch),
+                }
+            }
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Finds out which services the server appears to support, so a client can avoid calls that
+    /// will always fail, e.g. history reads of a server that has no history. Each capability is
+    /// inferred from a node that a server supporting the service would normally have, and all of
+    /// them are read in one request. A node that cannot be read counts as the service not being
+    /// supported.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ServiceCapabilities)` - The services the server appears to support.
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    fn server_service_capabilities(&self) -> Result<ServiceCapabilities, StatusCode> {
+        let value_id = |node_id: NodeId, attribute_id: AttributeId| ReadValueId {
+            node_id,
+            attribute_id: attribute_id as u32,
+            index_range: UAString::null(),
+            data_encoding: QualifiedName::null(),
+        };
+        let nodes_to_read = [
+            value_id(MethodId::Server_GetMonitoredItems.into(), AttributeId::NodeClass),
+            value_id(
+                VariableId::HistoryServerCapabilities_AccessHistoryDataCapability.into(),
+                AttributeId::Value,
+            ),
+            value_id(ObjectId::Server.into(), AttributeId::EventNotifier),
+            value_id(
+                VariableId::Server_ServerDiagnostics_ServerDiagnosticsSummary_CurrentSubscriptionCount
+                    .into(),
+                AttributeId::Value,
+            ),
+        ];
+        let results = self.read(&nodes_to_read, TimestampsToReturn::Neither, 0.0)?;
+        if results.len() != nodes_to_read.len() {
+            error!(
+                "server_service_capabilities() expected {} results but got {}",
+                nodes_to_read.len(),
+                results.len()
+            );
+            return Err(StatusCode::BadUnexpectedError);
+        }
+        let values = results
+            .into_iter()
+            .map(|value| {
+                if value.status().is_bad() {
+                    None
+                } else {
+                    value.value
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(ServiceCapabilities {
+            supports_method_call: values[0].is_some(),
+            supports_historical_data: matches!(values[1], Some(Variant::Boolean(true))),
+            supports_events: match values[2] {
+                Some(Variant::Byte(event_notifier)) => {
+                    EventNotifierType::from_bits_truncate(event_notifier)
+                        .contains(EventNotifierType::SubscribeToEvents)
+                }
+                _ => false,
+            },
+            supports_subscriptions: values[3].is_some(),
+        })
+    }
 }
 
 /// Method Service set
@@ -601,6 +1236,33 @@ pub trait MethodService: Service {
             Err(StatusCode::BadUnexpectedError)
         }
     }
+
+    /// Calls GetMonitoredItems like [`MethodService::call_get_monitored_items`], pairing up the
+    /// results. This can be used to check that the monitored items of a subscription on the
+    /// server match those the client knows of, e.g. after transferring the subscription.
+    ///
+    /// # Arguments
+    ///
+    /// * `subscription_id` - Server allocated identifier for the subscription to return monitored items for.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(u32, u32)>)` - A list of (server handle, client handle), one per monitored item
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    fn get_monitored_items(&self, subscription_id: u32) -> Result<Vec<(u32, u32)>, StatusCode> {
+        let (server_handles, client_handles) = self.call_get_monitored_items(subscription_id)?;
+        if server_handles.len() == client_handles.len() {
+            Ok(server_handles.into_iter().zip(client_handles).collect())
+        } else {
+            error!(
+                "GetMonitoredItems returned {} server handles but {} client handles",
+                server_handles.len(),
+                client_handles.len()
+            );
+            Err(StatusCode::BadUnexpectedError)
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////
@@ -620,7 +1282,9 @@ pub trait MonitoredItemService: Service {
     /// # Returns
     ///
     /// * `Ok(Vec<MonitoredItemCreateResult>)` - A list of [`MonitoredItemCreateResult`] corresponding to the items to create.
-    ///    The size and order of the list matches the size and order of the `items_to_create` request parameter.
+    ///   The size and order of the list matches the size and order of the `items_to_create` request parameter.
+    ///   If the items are created in batches and a batch fails after earlier ones succeeded, the
+    ///   failure is the status code of each item that was not created.
     /// * `Err(StatusCode)` - Status code reason for failure
     ///
     /// [`CreateMonitoredItemsRequest`]: ./struct.CreateMonitoredItemsRequest.html
@@ -701,7 +1365,7 @@ pub trait MonitoredItemService: Service {
     ///
     /// # Returns
     ///
-    /// * `Ok((Option<Vec<StatusCode>>, Option<Vec<StatusCode>>))` - Individual result for each item added / removed for the SetTriggering call.
+    /// * `Ok(SetTriggeringResult)` - Individual result for each item added / removed for the SetTriggering call.
     /// * `Err(StatusCode)` - Status code reason for failure.
     ///
     /// [`SetTriggeringRequest`]: ./struct.SetTriggeringRequest.html
@@ -712,10 +1376,14 @@ pub trait MonitoredItemService: Service {
         triggering_item_id: u32,
         links_to_add: &[u32],
         links_to_remove: &[u32],
-    ) -> Result<(Option<Vec<StatusCode>>, Option<Vec<StatusCode>>), StatusCode>;
+    ) -> Result<SetTriggeringResult, StatusCode>;
 
     /// Deletes monitored items from a subscription by sending a [`DeleteMonitoredItemsRequest`] to the server.
     ///
+    /// Items that are deleted, or that the server says are already gone with `BadMonitoredItemIdInvalid`,
+    /// are removed from the client side subscription along with any triggering links to them. Items that
+    /// fail for any other reason are kept.
+    ///
     /// See OPC UA Part 4 - Services 5.12.6 for complete description of the service and error responses.
     ///
     /// # Arguments
@@ -810,7 +1478,8 @@ pub trait SubscriptionService: Service {
     ///
     /// # Returns
     ///
-    /// * `Ok(())` - Success
+    /// * `Ok(ModifiedSubscription)` - Success, with the values the server revised the requested
+    ///   parameters to. The client's copy of the subscription is updated with these.
     /// * `Err(StatusCode)` - Request failed, status code is the reason for failure
     ///
     /// [`ModifySubscriptionRequest`]: ./struct.ModifySubscriptionRequest.html
@@ -823,7 +1492,7 @@ pub trait SubscriptionService: Service {
         max_keep_alive_count: u32,
         max_notifications_per_publish: u32,
         priority: u8,
-    ) -> Result<(), StatusCode>;
+    ) -> Result<ModifiedSubscription, StatusCode>;
 
     /// Changes the publishing mode of subscriptions by sending a [`SetPublishingModeRequest`] to the server.
     ///
@@ -859,11 +1528,14 @@ pub trait SubscriptionService: Service {
     /// * `send_initial_values` - A boolean parameter with the following values - `true` the first
     ///   publish response shall contain the current values of all monitored items in the subscription,
     ///   `false`, the first publish response shall contain only the value changes since the last
-    ///   publish response was sent.
+    ///   publish response was sent. When `true`, the current values are routed to the subscription's
+    ///   callback like any other change, with `MonitoredItem::is_initial_value()` set.
     ///
     /// # Returns
     ///
-    /// * `Ok(Vec<TransferResult>)` - The [`TransferResult`] for each transfer subscription.
+    /// * `Ok(Vec<TransferResult>)` - The [`TransferResult`] for each transfer subscription, in the
+    ///   same order as `subscription_ids`. A subscription whose result has a bad status code was
+    ///   not transferred and must be created again.
     /// * `Err(StatusCode)` - Status code reason for failure
     ///
     /// [`TransferSubscriptionsRequest`]: ./struct.TransferSubscriptionsRequest.html
@@ -9,7 +9,7 @@
 //! and events.
 use std::{
     cmp,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     result::Result,
     str::FromStr,
     sync::{mpsc::SyncSender, Arc},
@@ -23,14 +23,27 @@ use tokio::{
 
 use crate::{
     client::{
-        callbacks::{OnConnectionStatusChange, OnSessionClosed, OnSubscriptionNotification},
+        callbacks::{
+            DataChangeCallback, EventCallback, OnConnectionStatusChange, OnSessionClosed,
+            OnSubscriptionNotification,
+        },
         client::IdentityToken,
         comms::tcp_transport::TcpTransport,
+        message_queue::{MessageQueueMetrics, ResponseFuture, UnmatchedResponsePolicy},
+        monitored_item_builder::{
+            decode_event_fields, DataChangeMonitoredItemBuilder, EventMonitoredItemBuilder,
+        },
         process_service_result, process_unexpected_response,
+        rate_limiter::RateLimitPolicy,
         session::{
+            diagnostics::SessionDiagnostics,
             services::*,
             session_debug, session_error,
-            session_state::{ConnectionState, SessionState},
+            session_state::{
+                ConnectionState, PartialResultsPolicy, PreActivationPolicy, PublishFuture,
+                ReconnectPolicy, RequestError, RetryPolicy, SecureChannelRetryPolicy, SessionState,
+                SessionStateConfig, SubscriptionSummary,
+            },
             session_trace, session_warn,
         },
         session_retry_policy::{Answer, SessionRetryPolicy},
@@ -51,9 +64,18 @@ use crate::{
     },
     deregister_runtime_component, register_runtime_component,
     sync::*,
-    types::{node_ids::ObjectId, status_code::StatusCode, *},
+    types::{
+        node_ids::{ObjectId, VariableId},
+        service_types::Argument,
+        status_code::StatusCode,
+        *,
+    },
 };
 
+/// The number of monitored items created in one call when the server does not say how many it
+/// allows.
+const DEFAULT_MAX_MONITORED_ITEMS_PER_CALL: usize = 100;
+
 /// Information about the server endpoint, security policy, security mode and user identity that the session will
 /// will use to establish a connection.
 #[derive(Debug)]
@@ -89,6 +111,21 @@ pub enum SessionCommand {
     Stop,
 }
 
+/// What to do with the subscriptions of a previous session when reconnecting has to create a
+/// new session, i.e. the subscriptions are orphaned on the server.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OrphanAction {
+    /// Transfer the subscriptions to the new session, recreating any that cannot be transferred
+    Transfer,
+    /// Delete the subscriptions from the server
+    Delete,
+    /// Leave the subscriptions on the server to expire
+    Ignore,
+}
+
+/// A function that decides what to do with orphaned subscriptions, given their ids.
+pub type OrphanedSubscriptionsHandler = dyn Fn(&[u32]) -> OrphanAction + Send + Sync;
+
 /// A session of the client. The session is associated with an endpoint and maintains a state
 /// when it is active. The `Session` struct provides functions for all the supported
 /// request types in the API.
@@ -122,6 +159,30 @@ pub struct Session {
     single_threaded_executor: bool,
     /// Tokio runtime
     runtime: Arc<Mutex<tokio::runtime::Runtime>>,
+    /// Cache of the EnumStrings of enumeration data types, keyed by data type.
+    enum_strings: RwLock<HashMap<NodeId, Vec<String>>>,
+    /// Cache of server variables that don't change during a session, e.g. server capabilities.
+    /// A `None` value means the server does not provide the variable.
+    server_values: RwLock<HashMap<NodeId, Option<Variant>>>,
+    /// Cache of the node ids of ServerCapabilities properties found by browse name, for those
+    /// with no well known node id. A `None` value means the server does not have the property.
+    server_capabilities: RwLock<HashMap<String, Option<NodeId>>>,
+    /// Fail to create a subscription that would exceed the server's MaxSubscriptionsPerSession
+    /// instead of sending the request.
+    enforce_max_subscriptions_per_session: bool,
+    /// Reject monitored items requesting a sampling interval below the server's
+    /// MinSupportedSampleRate instead of just warning about them.
+    reject_unsupported_sampling_intervals: bool,
+    /// Reject subscriptions requesting a publishing interval below the server's
+    /// MinPublishingInterval instead of just warning about them.
+    reject_unsupported_publishing_intervals: bool,
+    /// Cache of the arguments of methods read with `read_method_arguments`, keyed by method id
+    method_arguments: RwLock<HashMap<NodeId, MethodArguments>>,
+    /// Check the inputs of a call against the method's arguments, if they have been read,
+    /// before sending it
+    validate_method_arguments: bool,
+    /// Decides what to do with subscriptions that are orphaned by reconnecting with a new session.
+    on_orphaned_subscriptions: Option<Box<OrphanedSubscriptionsHandler>>,
 }
 
 impl Drop for Session {
@@ -155,8 +216,8 @@ impl Session {
         ignore_clock_skew: bool,
         single_threaded_executor: bool,
     ) -> Session
-        where
-            T: Into<UAString>,
+    where
+        T: Into<UAString>,
     {
         let session_name = session_name.into();
 
@@ -199,22 +260,32 @@ impl Session {
             ignore_clock_skew,
             single_threaded_executor,
             runtime: Arc::new(Mutex::new(runtime)),
+            enum_strings: RwLock::new(HashMap::new()),
+            server_values: RwLock::new(HashMap::new()),
+            server_capabilities: RwLock::new(HashMap::new()),
+            enforce_max_subscriptions_per_session: false,
+            reject_unsupported_sampling_intervals: false,
+            reject_unsupported_publishing_intervals: false,
+            method_arguments: RwLock::new(HashMap::new()),
+            validate_method_arguments: false,
+            on_orphaned_subscriptions: None,
         }
     }
 
-    fn reset(&mut self) {
+    fn reset(&self) {
         // Clear the existing secure channel state
         {
             let mut secure_channel = trace_write_lock!(self.secure_channel);
             secure_channel.clear_security_token();
         }
 
-        // Create a new session state
-        self.session_state = Arc::new(RwLock::new(SessionState::new(
-            self.ignore_clock_skew,
-            self.secure_channel.clone(),
-            self.subscription_state.clone(),
-        )));
+        // Keep the session state, which the transport shares and which holds the settings of the
+        // session and the ids that the session is activated again with. Only what belonged to
+        // the old connection is cleared.
+        {
+            let mut session_state = trace_write_lock!(self.session_state);
+            session_state.reset_connection();
+        }
 
         // Keep the existing transport, we should never drop a tokio runtime from a sync function
     }
@@ -254,8 +325,8 @@ impl Session {
     /// * `session_closed_callback` - the session closed callback
     ///
     pub fn set_session_closed_callback<CB>(&mut self, session_closed_callback: CB)
-        where
-            CB: OnSessionClosed + Send + Sync + 'static,
+    where
+        CB: OnSessionClosed + Send + Sync + 'static,
     {
         let mut session_state = trace_write_lock!(self.session_state);
         session_state.set_session_closed_callback(session_closed_callback);
@@ -269,443 +340,1787 @@ impl Session {
     /// * `connection_status_callback` - the connection status callback.
     ///
     pub fn set_connection_status_callback<CB>(&mut self, connection_status_callback: CB)
-        where
-            CB: OnConnectionStatusChange + Send + Sync + 'static,
+    where
+        CB: OnConnectionStatusChange + Send + Sync + 'static,
     {
         let mut session_state = trace_write_lock!(self.session_state);
         session_state.set_connection_status_callback(connection_status_callback);
     }
 
-    /// Reconnects to the server and tries to activate the existing session. If there
-    /// is a failure, it will be communicated by the status code in the result. You should not
-    /// call this if there is a session retry policy associated with the session.
+    /// Sets a function that is called with every request before it is sent. If the function
+    /// returns an error the request is not sent and the error is returned to the caller. This
+    /// allows an application to enforce a policy on requests in one place, e.g. to reject writes.
     ///
-    /// Reconnecting will attempt to transfer or recreate subscriptions that were on the old
-    /// session before it terminated.
+    /// # Arguments
     ///
-    /// # Returns
+    /// * `request_authorizer` - the function that authorizes requests.
     ///
-    /// * `Ok(())` - reconnection has happened and the session is activated
-    /// * `Err(StatusCode)` - reason for failure
+    pub fn set_request_authorizer<F>(&mut self, request_authorizer: F)
+    where
+        F: Fn(&SupportedMessage) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_request_authorizer(request_authorizer);
+    }
+
+    /// Sets a function that decides whether to trust the certificate of the server, e.g. by
+    /// pinning its thumbprint, consulting a trust list or asking the user. It is called before a
+    /// signed or encrypted secure channel is opened, and if it returns an error, opening the
+    /// channel fails with `BadCertificateUntrusted` before anything is sent. By default the
+    /// certificate is trusted as before.
     ///
-    pub fn reconnect_and_activate(&mut self) -> Result<(), StatusCode> {
-        // Do nothing if already connected / activated
-        if self.is_connected() {
-            session_error!(
-                self,
-                "Reconnect is going to do nothing because already connected"
-            );
-            Err(StatusCode::BadUnexpectedError)
-        } else {
-            // Reset the session state
-            self.reset();
+    /// # Arguments
+    ///
+    /// * `certificate_verifier` - the function that verifies the server's certificate.
+    ///
+    pub fn set_certificate_verifier<F>(&mut self, certificate_verifier: F)
+    where
+        F: Fn(&X509) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_certificate_verifier(certificate_verifier);
+    }
 
-            // Connect to server (again)
-            self.connect_no_retry()?;
+    /// Sets the session to be read only. A read only session rejects services that would modify
+    /// the server, such as writes, method calls, history updates and node management, with
+    /// `BadUserAccessDenied` without sending them.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_only` - true to reject services that modify the server
+    ///
+    pub fn set_read_only(&mut self, read_only: bool) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_read_only(read_only);
+    }
 
-            // Attempt to reactivate the existing session
-            match self.activate_session() {
-                Err(status_code) => {
-                    // Activation didn't work, so create a new session
-                    info!("Session activation failed on reconnect, error = {}, so creating a new session", status_code);
-                    {
-                        let mut session_state = trace_write_lock!(self.session_state);
-                        session_state.reset();
-                    }
+    /// Tests if the session is read only. See [`Session::set_read_only`].
+    pub fn read_only(&self) -> bool {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.read_only()
+    }
 
-                    session_debug!(self, "create_session");
-                    self.create_session()?;
-                    session_debug!(self, "activate_session");
-                    self.activate_session()?;
-                    session_debug!(self, "reconnect should be complete");
-                }
-                Ok(_) => {
-                    info!("Activation succeeded");
-                }
-            }
-            session_debug!(self, "transfer_subscriptions_from_old_session");
-            self.transfer_subscriptions_from_old_session()?;
-            Ok(())
-        }
+    /// Sets the longest array that may be written, e.g. to match a limit of the server. A write
+    /// with a longer array value fails with `BadOutOfRange` without being sent, rather than
+    /// being sent only for the server to reject it. By default there is no limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_write_array_length` - the most elements an array value may have, or `None` for no
+    ///   limit.
+    ///
+    pub fn set_max_write_array_length(&mut self, max_write_array_length: Option<usize>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_max_write_array_length(max_write_array_length);
     }
 
-    /// This code attempts to take the existing subscriptions created by a previous session and
-    /// either transfer them to this session, or construct them from scratch.
-    fn transfer_subscriptions_from_old_session(&mut self) -> Result<(), StatusCode> {
-        let subscription_state = self.subscription_state.clone();
+    /// Returns the longest array that may be written. See
+    /// [`Session::set_max_write_array_length`].
+    pub fn max_write_array_length(&self) -> Option<usize> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.max_write_array_length()
+    }
 
-        let subscription_ids = {
-            let subscription_state = trace_read_lock!(subscription_state);
-            subscription_state.subscription_ids()
-        };
+    /// Sets the most monitored items that may be created across all subscriptions of the
+    /// session, e.g. so a proxy keeps within a budget of its own whatever the server allows.
+    /// Creating monitored items that would take the total over the limit fails with
+    /// `BadTooManyMonitoredItems` without sending the request. Deleted monitored items, and those
+    /// of deleted subscriptions, no longer count. By default there is no limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_total_monitored_items` - the most monitored items, or `None` for no limit.
+    ///
+    pub fn set_max_total_monitored_items(&mut self, max_total_monitored_items: Option<usize>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_max_total_monitored_items(max_total_monitored_items);
+    }
 
-        // Start by getting the subscription ids
-        if let Some(subscription_ids) = subscription_ids {
-            // Try to use TransferSubscriptions to move subscriptions_ids over. If this
-            // works then there is nothing else to do.
-            let mut subscription_ids_to_recreate =
-                subscription_ids.iter().copied().collect::<HashSet<u32>>();
-            if let Ok(transfer_results) = self.transfer_subscriptions(&subscription_ids, true) {
-                session_debug!(self, "transfer_results = {:?}", transfer_results);
-                transfer_results.iter().enumerate().for_each(|(i, r)| {
-                    if r.status_code.is_good() {
-                        // Subscription was transferred so it does not need to be recreated
-                        subscription_ids_to_recreate.remove(&subscription_ids[i]);
-                    }
-                });
-            }
+    /// Returns the most monitored items that may be created across all subscriptions. See
+    /// [`Session::set_max_total_monitored_items`].
+    pub fn max_total_monitored_items(&self) -> Option<usize> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.max_total_monitored_items()
+    }
 
-            // But if it didn't work, then some or all subscriptions have to be remade.
-            if !subscription_ids_to_recreate.is_empty() {
-                session_warn!(self, "Some or all of the existing subscriptions could not be transferred and must be created manually");
-            }
+    /// Returns the number of monitored items created across all subscriptions. See
+    /// [`Session::set_max_total_monitored_items`].
+    pub fn total_monitored_items(&self) -> usize {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.total_monitored_items()
+    }
 
-            // Now create any subscriptions that could not be transferred
-            subscription_ids_to_recreate
-                .iter()
-                .for_each(|subscription_id| {
-                    info!("Recreating subscription {}", subscription_id);
-                    // Remove the subscription data, create it again from scratch
-                    let deleted_subscription = {
-                        let mut subscription_state = trace_write_lock!(subscription_state);
-                        subscription_state.delete_subscription(*subscription_id)
-                    };
+    /// Sets the session to open a new secure channel when the server answers a request with
+    /// `BadSecureChannelTokenUnknown`, e.g. because a token renewal was missed and the token
+    /// expired, and then send the request once more. The response to the retry is returned,
+    /// whatever it is. This is off by default so that a channel that has really failed is not
+    /// hidden behind a retry.
+    ///
+    /// # Arguments
+    ///
+    /// * `reissue_channel_on_token_unknown` - true to reissue the channel and retry the request
+    ///
+    pub fn set_reissue_channel_on_token_unknown(&mut self, reissue_channel_on_token_unknown: bool) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_reissue_channel_on_token_unknown(reissue_channel_on_token_unknown);
+    }
 
-                    if let Some(subscription) = deleted_subscription {
-                        // Attempt to replicate the subscription (subscription id will be new)
-                        if let Ok(subscription_id) = self.create_subscription_inner(
-                            subscription.publishing_interval(),
-                            subscription.lifetime_count(),
-                            subscription.max_keep_alive_count(),
-                            subscription.max_notifications_per_publish(),
-                            subscription.priority(),
-                            subscription.publishing_enabled(),
-                            subscription.notification_callback(),
-                        ) {
-                            info!("New subscription created with id {}", subscription_id);
+    /// Tests if the secure channel is reissued when the server doesn't know its token. See
+    /// [`Session::set_reissue_channel_on_token_unknown`].
+    pub fn reissue_channel_on_token_unknown(&self) -> bool {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.reissue_channel_on_token_unknown()
+    }
 
-                            // For each monitored item
-                            let items_to_create = subscription
-                                .monitored_items()
-                                .iter()
-                                .map(|(_, item)| MonitoredItemCreateRequest {
-                                    item_to_monitor: item.item_to_monitor().clone(),
-                                    monitoring_mode: item.monitoring_mode(),
-                                    requested_parameters: MonitoringParameters {
-                                        client_handle: item.client_handle(),
-                                        sampling_interval: item.sampling_interval(),
-                                        filter: ExtensionObject::null(),
-                                        queue_size: item.queue_size() as u32,
-                                        discard_oldest: true,
-                                    },
-                                })
-                                .collect::<Vec<MonitoredItemCreateRequest>>();
-                            let _ = self.create_monitored_items(
-                                subscription_id,
-                                TimestampsToReturn::Both,
-                                &items_to_create,
-                            );
+    /// Sets how long in milliseconds to wait for the response to a request before it fails with
+    /// `BadTimeout`. Unless a timeout hint is set, the timeout is also passed to the server as the
+    /// timeout hint of each request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_timeout` - the timeout in milliseconds.
+    ///
+    pub fn set_request_timeout(&mut self, request_timeout: u32) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_request_timeout(request_timeout);
+    }
 
-                            // Recreate any triggers for the monitored item. This code assumes monitored item
-                            // ids are the same value as they were in the previous subscription.
-                            subscription.monitored_items().iter().for_each(|(_, item)| {
-                                let triggered_items = item.triggered_items();
-                                if !triggered_items.is_empty() {
-                                    let links_to_add =
-                                        triggered_items.iter().copied().collect::<Vec<u32>>();
-                                    let _ = self.set_triggering(
-                                        subscription_id,
-                                        item.id(),
-                                        links_to_add.as_slice(),
-                                        &[],
-                                    );
-                                }
-                            });
-                        } else {
-                            session_warn!(
-                                self,
-                                "Could not create a subscription from the existing subscription {}",
-                                subscription_id
-                            );
-                        }
-                    } else {
-                        panic!(
-                            "Subscription {}, doesn't exist although it should",
-                            subscription_id
-                        );
-                    }
-                });
-        }
-        Ok(())
+    /// Returns how long in milliseconds to wait for the response to a request. See
+    /// [`Session::set_request_timeout`].
+    pub fn request_timeout(&self) -> u32 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.request_timeout()
     }
 
-    /// Connects to the server using the retry policy to repeat connecting until such time as it
-    /// succeeds or the policy says to give up. If there is a failure, it will be
-    /// communicated by the status code in the result.
-    pub fn connect(&self) -> Result<(), StatusCode> {
-        loop {
-            match self.connect_no_retry() {
-                Ok(_) => {
-                    info!("Connect was successful");
-                    let mut session_retry_policy = trace_lock!(self.session_retry_policy);
-                    session_retry_policy.reset_retry_count();
-                    return Ok(());
-                }
-                Err(status_code) => {
-                    self.disconnect();
-                    let mut session_retry_policy = trace_lock!(self.session_retry_policy);
-                    session_retry_policy.increment_retry_count();
-                    session_warn!(
-                        self,
-                        "Connect was unsuccessful, error = {}, retries = {}",
-                        status_code,
-                        session_retry_policy.retry_count()
-                    );
+    /// Sets the timeout hint in milliseconds passed to the server with each request, i.e. how
+    /// long the server may spend on a request before it gives up, separately from how long the
+    /// client waits for the response. The request timeout should be at least the timeout hint
+    /// plus a margin for the network, so the client doesn't give up on a response that the server
+    /// is still allowed to send. By default the timeout hint is the request timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout_hint` - the timeout hint in milliseconds, or `None` for the request timeout.
+    ///
+    pub fn set_timeout_hint(&mut self, timeout_hint: Option<u32>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_timeout_hint(timeout_hint);
+    }
 
-                    match session_retry_policy.should_retry_connect(DateTime::now()) {
-                        Answer::GiveUp => {
-                            session_error!(self, "Session has given up trying to connect to the server after {} retries", session_retry_policy.retry_count());
-                            return Err(StatusCode::BadNotConnected);
-                        }
-                        Answer::Retry => {
-                            info!("Retrying to connect to server...");
-                            session_retry_policy.set_last_attempt(DateTime::now());
-                        }
-                        Answer::WaitFor(sleep_for) => {
-                            // Sleep for the instructed interval before looping around and trying
-                            // once more.
-                            thread::sleep(Duration::from_millis(sleep_for as u64));
-                        }
-                    }
-                }
-            }
-        }
+    /// Returns the timeout hint in milliseconds passed to the server with each request. See
+    /// [`Session::set_timeout_hint`].
+    pub fn timeout_hint(&self) -> u32 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.timeout_hint()
     }
 
-    /// Connects to the server using the configured session arguments. No attempt is made to retry
-    /// the connection if the attempt fails. If there is a failure, it will be communicated by the
-    /// status code in the result.
+    /// Synchronously sends a request with its own timeout in place of the request timeout, e.g.
+    /// for a history read over a long period or a call to a slow method. The timeout is also
+    /// passed to the server as the timeout hint of the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to send.
+    /// * `timeout_ms` - How long in milliseconds to wait for the response.
     ///
     /// # Returns
     ///
-    /// * `Ok(())` - connection has happened
-    /// * `Err(StatusCode)` - reason for failure
+    /// * `Ok(SupportedMessage)` - The response to the request.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadTimeout` if there was no
+    ///   response within the timeout.
     ///
-    pub fn connect_no_retry(&self) -> Result<(), StatusCode> {
-        let endpoint_url = self.session_info.endpoint.endpoint_url.clone();
-        info!("Connect");
-        let security_policy =
-            SecurityPolicy::from_str(self.session_info.endpoint.security_policy_uri.as_ref())
-                .unwrap();
-        if security_policy == SecurityPolicy::Unknown {
-            session_error!(
-                self,
-                "connect, security policy \"{}\" is unknown",
-                self.session_info.endpoint.security_policy_uri.as_ref()
-            );
-            Err(StatusCode::BadSecurityPolicyRejected)
-        } else {
-            let (cert, key) = {
-                let certificate_store = trace_write_lock!(self.certificate_store);
-                certificate_store.read_own_cert_and_pkey_optional()
-            };
+    pub fn send_request_with_timeout<T>(
+        &self,
+        request: T,
+        timeout_ms: u32,
+    ) -> Result<SupportedMessage, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        let request = self.reopen_idle_for_request(request.into())?;
+        let mut session_state = self.lock_for_request(&request)?;
+        session_state
+            .send_request_with_timeout(request, timeout_ms)
+            .map_err(StatusCode::from)
+    }
 
-            {
-                let mut secure_channel = trace_write_lock!(self.secure_channel);
-                secure_channel.set_private_key(key);
-                secure_channel.set_cert(cert);
-                secure_channel.set_security_policy(security_policy);
-                secure_channel.set_security_mode(self.session_info.endpoint.security_mode);
-                let _ = secure_channel.set_remote_cert_from_byte_string(
-                    &self.session_info.endpoint.server_certificate,
-                );
-                info!("Security policy = {:?}", security_policy);
-                info!(
-                    "Security mode = {:?}",
-                    self.session_info.endpoint.security_mode
-                );
-            }
+    /// Asynchronously sends a request, returning a future that resolves to the response. Unlike
+    /// `send_request()` this doesn't block a thread while waiting, so the response can be awaited
+    /// from a tokio runtime. The future doesn't time out by itself, see [`ResponseFuture`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to send, e.g. with a header from `make_request_header()`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ResponseFuture)` - A future for the response to the request.
+    /// * `Err(StatusCode)` - Status code reason the request could not be sent.
+    ///
+    pub fn async_send_request_future<T>(&self, request: T) -> Result<ResponseFuture, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        let request = self.reopen_idle_for_request(request.into())?;
+        let mut session_state = self.lock_for_request(&request)?;
+        session_state.async_send_request_future(request)
+    }
 
-            // Transport's tokio runtime is made here, not in transport
-            self.transport.connect(endpoint_url.as_ref())?;
-            self.open_secure_channel()?;
-            self.on_connection_status_change(true);
-            Ok(())
-        }
+    /// Sends a publish request, returning a future that resolves to the response once the session
+    /// has processed it, e.g. to await the notifications of the session's subscriptions from a
+    /// tokio runtime. This is in addition to the publish requests that the session sends by itself
+    /// to keep its subscriptions going. The future doesn't time out by itself, see
+    /// [`PublishFuture`].
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PublishFuture)` - A future for the response to the publish request.
+    /// * `Err(StatusCode)` - Status code reason the request could not be sent, e.g.
+    ///   `BadTooManyPublishRequests` if as many as the server allows are already outstanding.
+    ///
+    pub fn async_publish_future(&self) -> Result<PublishFuture, StatusCode> {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.async_publish_future()
     }
 
-    pub(crate) fn session_state(&self) -> Arc<RwLock<SessionState>> {
-        self.session_state.clone()
+    /// Sets the buffer sizes and request timeout of the session. The buffer sizes are advertised
+    /// to the server when connecting, so changes to them apply from the next connection. See
+    /// [`SessionStateConfig`] for the settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - the buffer sizes and request timeout.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The configuration was applied.
+    /// * `Err(StatusCode)` - `BadConfigurationError` if a size or the timeout is zero, or the
+    ///   max message size is less than the send buffer size.
+    ///
+    pub fn set_session_state_config(
+        &mut self,
+        config: SessionStateConfig,
+    ) -> Result<(), StatusCode> {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_config(config)
     }
 
-    /// Disconnect from the server. Disconnect is an explicit command to drop the socket and throw
-    /// away all state information. If you disconnect you cannot reconnect to your existing session
-    /// or retrieve any existing subscriptions.
-    pub fn disconnect(&self) {
-        if self.is_connected() {
-            let _ = self.close_session_and_delete_subscriptions();
-            let _ = self.close_secure_channel();
+    /// Returns the buffer sizes and request timeout of the session.
+    pub fn session_state_config(&self) -> SessionStateConfig {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.config()
+    }
 
-            {
-                let session_state = trace_read_lock!(self.session_state);
-                session_state.quit();
-            }
+    /// Sets how long in milliseconds to wait for the responses to the requests that establish a
+    /// connection, i.e. opening the secure channel, creating the session and activating it. This
+    /// is separate from the request timeout, e.g. to allow a slow handshake or to fail quickly on
+    /// an unreachable server. The default is the same as the default request timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `connect_timeout` - the timeout in milliseconds.
+    ///
+    pub fn set_connect_timeout(&mut self, connect_timeout: u32) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_connect_timeout(connect_timeout);
+    }
 
-            self.transport.wait_for_disconnect();
-            self.on_connection_status_change(false);
-        }
+    /// Returns how long in milliseconds to wait for the responses to the requests that establish
+    /// a connection. See [`Session::set_connect_timeout`].
+    pub fn connect_timeout(&self) -> u32 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.connect_timeout()
     }
 
-    /// Test if the session is in a connected state
+    /// Sets the diagnostics the server is asked to return with the response to each request, e.g.
+    /// `DiagnosticBits::SERVICE_LEVEL_SYMBOLIC_ID | DiagnosticBits::OPERATIONAL_LEVEL_SYMBOLIC_ID`.
+    /// The diagnostics are in the `service_diagnostics` of the response header and the
+    /// `diagnostic_infos` of the response, and help to find out why a server rejects a service
+    /// call. The default is to ask for none, as the server may do extra work to provide them.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// * `true` - Session is connected
-    /// * `false` - Session is not connected
+    /// * `return_diagnostics` - the diagnostics to ask for.
     ///
-    pub fn is_connected(&self) -> bool {
-        self.transport.is_connected()
+    pub fn set_return_diagnostics(&mut self, return_diagnostics: DiagnosticBits) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_return_diagnostics(return_diagnostics);
     }
 
-    /// Internal constant for the sleep interval used during polling
-    const POLL_SLEEP_INTERVAL: u64 = 10;
+    /// Returns the diagnostics the server is asked to return with the response to each request.
+    pub fn return_diagnostics(&self) -> DiagnosticBits {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.return_diagnostics()
+    }
 
-    /// Synchronously runs a polling loop over the supplied session. Running a session performs
-    /// periodic actions such as receiving messages, processing subscriptions, and recovering from
-    /// connection errors. The run function will return if the session is disconnected and
-    /// cannot be reestablished.
+    /// Sets the audit entry id passed to the server with each request. A server that audits
+    /// client actions records it with them, so they can be tied back to an operator or workflow.
+    /// The default is to pass none.
     ///
     /// # Arguments
     ///
-    /// * `session` - the session to run ynchronously
+    /// * `audit_entry_id` - the audit entry id, or `None` for none.
     ///
-    pub fn run(session: Arc<RwLock<Session>>) {
-        let (_tx, rx) = oneshot::channel();
-        Self::run_loop(session, Self::POLL_SLEEP_INTERVAL, rx);
+    pub fn set_audit_entry_id(&mut self, audit_entry_id: Option<String>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_audit_entry_id(audit_entry_id);
     }
 
-    /// Runs the session asynchronously on a new thread. The function returns immediately
-    /// and gives a caller a `Sender` that can be used to send a message to the session
-    /// to cause it to terminate. Do not drop this sender (i.e. make sure to bind it to a variable with
-    /// sufficient lifetime) or the session will terminate as soon as you do.
+    /// Returns the audit entry id passed to the server with each request. See
+    /// [`Session::set_audit_entry_id`].
+    pub fn audit_entry_id(&self) -> Option<String> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.audit_entry_id()
+    }
+
+    /// Makes a request header with an audit entry id for a single operation, instead of the audit
+    /// entry id of the session, for a request to send with [`Service::send_request`].
     ///
-    /// Running a session performs periodic actions such as receiving messages, processing subscriptions,
-    /// and recovering from.  connection errors. The session will terminate by itself if it is disconnected
-    /// and cannot be reestablished. It will terminate if the sender is dropped or if sent a ClientCommand
-    /// to terminate.  caller to this function can monitor the status of the session through state
-    /// calls to know when this happens.
+    /// # Arguments
+    ///
+    /// * `audit_entry_id` - the audit entry id of the operation.
     ///
+    pub fn make_request_header_with_audit(&self, audit_entry_id: &str) -> RequestHeader {
+        self.reopen_idle();
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.make_request_header_with_audit(audit_entry_id)
+    }
+
+    /// Sets how long in milliseconds the session may go without a response from the server
+    /// before it reads the server state, `Server_ServerStatus_State`, as a keep-alive. This
+    /// keeps the session from looking idle to a server, e.g. when the client only receives
+    /// publish responses, and notices a stalled connection before the next real request. If the
+    /// read fails or times out, [`Session::is_connection_lost`] returns true until a later
+    /// keep-alive succeeds. No keep-alive is sent while other responses arrive within the
+    /// interval. The default of 0 turns keep-alives off.
     ///
     /// # Arguments
     ///
-    /// * `session` - the session to run asynchronously
+    /// * `interval_ms` - the keep-alive interval in milliseconds, or 0 for none.
     ///
-    /// # Returns
+    pub fn set_keep_alive_interval(&mut self, interval_ms: u32) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_keep_alive_interval(interval_ms);
+    }
+
+    /// Returns the keep-alive interval in milliseconds. See [`Session::set_keep_alive_interval`].
+    pub fn keep_alive_interval(&self) -> u32 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.keep_alive_interval()
+    }
+
+    /// Tests if the last keep-alive read of the server state failed. See
+    /// [`Session::set_keep_alive_interval`].
+    pub fn is_connection_lost(&self) -> bool {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.is_connection_lost()
+    }
+
+    /// Sets how long the session may go without a request from the client before it is closed,
+    /// along with its secure channel, to save the resources it holds on the server. The next
+    /// request the client makes connects, creates and activates a session again first, so a
+    /// client that only talks to the server now and then needn't manage the connection itself.
+    /// Keep-alives and publish requests don't count as requests from the client, and a session
+    /// with subscriptions is never closed. The session is checked for being idle by
+    /// [`Session::poll`], so it must be running. The default of `None` never closes it.
     ///
-    /// * `oneshot::Sender<ClientCommand>` - A sender that allows the caller to send a message to the
-    ///                        run loop to cause it to stop. Note that dropping the sender, i.e. not binding it to
-    ///                        a variable will also cause the loop to stop.
+    /// # Arguments
     ///
-    pub fn run_async(session: Arc<RwLock<Session>>) -> oneshot::Sender<SessionCommand> {
-        let (tx, rx) = oneshot::channel();
-        thread::spawn(move || Self::run_loop(session, Self::POLL_SLEEP_INTERVAL, rx));
-        tx
+    /// * `auto_close_idle_after` - how long the session may be idle, or `None` for ever.
+    ///
+    pub fn set_auto_close_idle_after(
+        &mut self,
+        auto_close_idle_after: Option<std::time::Duration>,
+    ) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_auto_close_idle_after(auto_close_idle_after);
     }
 
-    /// The asynchronous main session loop. This is the function that processes responses and
-    /// keeps the session alive. Note that while the client normally calls `run()` or `run_loop()`
-    /// to invoke this, there may be situations where the client wishes to directly use this
-    /// function, for example if the client has its own Tokio runtime and prefers to spawn the task
-    /// with that.
-    pub async fn session_task(
-        session: Arc<RwLock<Session>>,
-        sleep_interval: u64,
-        rx: oneshot::Receiver<SessionCommand>,
-    ) {
-        tokio::select! {
-            _ = async {
-                let mut timer = interval(Duration::from_millis(sleep_interval));
-                loop {
-                    // Poll the session.
-                    let poll_result = {
-                        let mut session = session.write();
-                        session.poll().await
-                    };
-                    match poll_result {
-                        Ok(did_something) => {
-                            // If the session did nothing, then sleep for a moment to save some CPU
-                            if !did_something {
-                                timer.tick().await;
-                            }
-                        }
-                        Err(_) => {
-                            // Break the loop if connection goes down
-                            info!("Run session connection to server broke, so terminating");
-                            break;
-                        }
-                    }
-                }
-            } => {}
-            message = rx => {
-                if let Ok(message) = message {
-                    // Only message is a Quit command so no point even testing what it is.
-                    info!("Run session was terminated by a message {:?}", message);
+    /// Returns how long the session may be idle before it is closed. See
+    /// [`Session::set_auto_close_idle_after`].
+    pub fn auto_close_idle_after(&self) -> Option<std::time::Duration> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.auto_close_idle_after()
+    }
+
+    /// Returns how long it has been since the client last sent a request, or since the session
+    /// was activated. See [`Session::set_auto_close_idle_after`].
+    pub fn idle_time(&self) -> std::time::Duration {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.idle_time()
+    }
+
+    /// Tests if the session was closed for being idle and has not been opened again since. See
+    /// [`Session::set_auto_close_idle_after`].
+    pub fn is_closed_idle(&self) -> bool {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.is_closed_idle()
+    }
+
+    /// Closes the session and secure channel because the session has been idle, leaving it to
+    /// the next request to open them again.
+    fn close_idle(&self) {
+        info!("Session has been idle, so it is being closed");
+        {
+            let mut session_state = trace_write_lock!(self.session_state);
+            session_state.set_closed_idle(true);
+        }
+        self.disconnect();
+    }
+
+    /// Opens a session that was closed for being idle again, before a request is made on it.
+    /// Returns true if the session was closed and has been opened again.
+    fn reopen_idle(&self) -> bool {
+        {
+            let mut session_state = trace_write_lock!(self.session_state);
+            if !session_state.is_closed_idle() {
+                return false;
+            }
+            session_state.set_closed_idle(false);
+        }
+        info!("Session was closed for being idle, so it is being opened again");
+        self.reset();
+        let result = self
+            .connect()
+            .and_then(|_| self.create_session())
+            .and_then(|_| self.activate_session());
+        if let Err(status_code) = result {
+            session_error!(self, "Session could not be opened again, {}", status_code);
+        }
+        true
+    }
+
+    /// Opens a session that was closed for being idle again before sending a request that was
+    /// made beforehand. The request still carries the authentication token of the session that
+    /// was closed, so it is given the token of the new one, which fails with
+    /// `BadRequestTypeInvalid` if the message is not a request.
+    fn reopen_idle_for_request(
+        &self,
+        mut request: SupportedMessage,
+    ) -> Result<SupportedMessage, StatusCode> {
+        if self.reopen_idle() {
+            let session_state = trace_read_lock!(self.session_state);
+            match request.request_header_mut() {
+                Some(request_header) => {
+                    request_header.authentication_token = session_state.authentication_token();
                 }
-                else {
-                    warn!("Run session was terminated, presumably by caller dropping oneshot sender. Don't do that unless you meant to.");
+                None => {
+                    session_error!(self, "{} is not a request", request.type_name());
+                    return Err(StatusCode::BadRequestTypeInvalid);
                 }
             }
         }
+        Ok(request)
     }
 
-    /// The main running loop for a session. This is used by `run()` and `run_async()` to run
-    /// continuously until a signal is received to terminate.
+    /// Locks the session state to send the request once the rate limit allows it. While it
+    /// doesn't, the session state is unlocked for the wait so other threads can use the session.
+    fn lock_for_request(
+        &self,
+        request: &SupportedMessage,
+    ) -> Result<RwLockWriteGuard<'_, SessionState>, StatusCode> {
+        loop {
+            let wait = {
+                let mut session_state = trace_write_lock!(self.session_state);
+                match session_state.try_acquire_rate_limit(request)? {
+                    Some(wait) => wait,
+                    None => return Ok(session_state),
+                }
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    /// Sets the lifetime in milliseconds to ask for the security token of the secure channel. A
+    /// longer lifetime means fewer renewals, but a server may enforce a maximum and grant less
+    /// than asked for. The token is renewed according to the lifetime actually granted. The
+    /// default is 60000, and a change applies the next time the secure channel is opened or
+    /// renewed.
     ///
     /// # Arguments
     ///
-    /// * `session`   - The session
-    /// * `sleep_interval` - An internal polling timer in ms
-    /// * `rx`        - A receiver that the task uses to receive a quit command directly from the caller.
+    /// * `requested_secure_channel_lifetime` - the lifetime in milliseconds.
     ///
-    pub fn run_loop(
-        session: Arc<RwLock<Session>>,
-        sleep_interval: u64,
-        rx: oneshot::Receiver<SessionCommand>,
+    pub fn set_requested_secure_channel_lifetime(
+        &mut self,
+        requested_secure_channel_lifetime: u32,
     ) {
-        let task = {
-            let session = session.clone();
-            async move {
-                Self::session_task(session, sleep_interval, rx).await;
-            }
-        };
-        // Spawn the task on the alloted runtime
-        let runtime = {
-            let session = trace_read_lock!(session);
-            session.runtime.clone()
-        };
-        let runtime = trace_lock!(runtime);
-        runtime.block_on(task);
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_requested_secure_channel_lifetime(requested_secure_channel_lifetime);
     }
 
-    /// Polls on the session which basically dispatches any pending
-    /// async responses, attempts to reconnect if the client is disconnected from the client and
-    /// sleeps a little bit if nothing needed to be done.
+    /// Returns the lifetime in milliseconds asked for the security token of the secure channel.
+    /// See [`Session::set_requested_secure_channel_lifetime`].
+    pub fn requested_secure_channel_lifetime(&self) -> u32 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.requested_secure_channel_lifetime()
+    }
+
+    /// Sets how opening a new secure channel is retried if it fails for what may be a transient
+    /// reason, e.g. so a client that starts before its server waits for the server to come up
+    /// rather than failing straight away. By default it is not retried. This is separate from
+    /// the session retry policy, which reconnects a session that has been lost.
     ///
     /// # Arguments
     ///
-    /// * `sleep_for` - the period of time in milliseconds that poll should sleep for if it performed
-    ///                 no action.
+    /// * `policy` - the retry policy.
     ///
-    /// # Returns
+    pub fn set_secure_channel_retry_policy(&mut self, policy: SecureChannelRetryPolicy) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_secure_channel_retry_policy(policy);
+    }
+
+    /// Sets the security policies and modes to try in turn when connecting, most preferred first,
+    /// e.g. for a client that connects to servers which don't all offer the same security. Each
+    /// is tried on a new connection and whichever the server accepts is used for the secure
+    /// channel. By default only the policy and mode of the endpoint are tried.
     ///
-    /// * `true` - if an action was performed during the poll
-    /// * `false` - if no action was performed during the poll and the poll slept
+    /// # Arguments
     ///
-    pub async fn poll(&mut self) -> Result<bool, ()> {
-        let did_something = if self.is_connected() {
-            let mut session_state = trace_write_lock!(self.session_state);
-            session_state.handle_publish_responses()
-        } else {
+    /// * `security_policy_preferences` - the policies and modes in order of preference.
+    ///
+    pub fn set_security_policy_preferences(
+        &mut self,
+        security_policy_preferences: Vec<(SecurityPolicy, MessageSecurityMode)>,
+    ) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_security_policy_preferences(security_policy_preferences);
+    }
+
+    /// Returns the security policies and modes tried when opening a new secure channel. See
+    /// [`Session::set_security_policy_preferences`].
+    pub fn security_policy_preferences(&self) -> Vec<(SecurityPolicy, MessageSecurityMode)> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.security_policy_preferences().to_vec()
+    }
+
+    /// Sets the session id that the server must return when the session is created, e.g. when
+    /// reconnecting to resume a session that is known by that id. If the server returns any
+    /// other id, creating the session fails with `BadSessionIdInvalid` rather than silently
+    /// carrying on with a different session. By default whatever id the server returns is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `expected_session_id` - the expected session id, or `None` to accept any.
+    ///
+    pub fn set_expected_session_id(&mut self, expected_session_id: Option<NodeId>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_expected_session_id(expected_session_id);
+    }
+
+    /// Sets the user identity that the session is activated with, in place of the one it was
+    /// made with. A user name and password is encrypted with the server's certificate and nonce
+    /// according to the security policy of the endpoint's user token policy, and an X509
+    /// certificate is sent with a signature made with its private key. The identity is used from
+    /// the next time the session is activated, so calling `activate_session` again changes the
+    /// user of a session that is already activated. If the server won't accept the identity,
+    /// activation fails with `BadIdentityTokenRejected`, `BadIdentityTokenInvalid` or
+    /// `BadUserAccessDenied`.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_identity_token` - the user identity to activate the session with.
+    ///
+    pub fn set_user_identity_token(&mut self, user_identity_token: IdentityToken) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_user_identity_token(user_identity_token);
+    }
+
+    /// Returns the session id that the server must return when the session is created. See
+    /// [`Session::set_expected_session_id`].
+    pub fn expected_session_id(&self) -> Option<NodeId> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.expected_session_id().cloned()
+    }
+
+    /// Returns how opening a new secure channel is retried. See
+    /// [`Session::set_secure_channel_retry_policy`].
+    pub fn secure_channel_retry_policy(&self) -> SecureChannelRetryPolicy {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.secure_channel_retry_policy().clone()
+    }
+
+    /// Exports the symmetric keys of the session's secure channel so that a tool such as
+    /// Wireshark can decrypt captured traffic. Returns `None` if the channel has no keys, e.g.
+    /// because its security policy is `None`. Keys change when the channel's token is renewed.
+    ///
+    /// **WARNING** This is for debugging in a lab only. Anyone holding the keys can read and forge
+    /// the session's messages. It requires the `unsafe-key-export` feature, which must never be
+    /// enabled in production.
+    #[cfg(feature = "unsafe-key-export")]
+    pub fn export_session_keys(
+        &self,
+    ) -> Option<crate::core::comms::secure_channel::SessionKeyMaterial> {
+        let secure_channel = trace_read_lock!(self.secure_channel);
+        secure_channel.export_keys()
+    }
+
+    /// Sets how a request that times out is sent again. Only requests that merely read from the
+    /// server, e.g. read and browse, are ever retried. If every retry times out, the request
+    /// fails with the error of the last one. By default requests are not retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry_policy` - the retry policy.
+    ///
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_retry_policy(retry_policy);
+    }
+
+    /// Returns how a request that times out is sent again. See [`Session::set_retry_policy`].
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.retry_policy()
+    }
+
+    fn make_connect_request_header(&self) -> RequestHeader {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.make_connect_request_header()
+    }
+
+    fn send_connect_request<T>(&self, request: T) -> Result<SupportedMessage, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state
+            .send_connect_request(request)
+            .map_err(StatusCode::from)
+    }
+
+    /// Returns a snapshot of the traffic of the session so far, i.e. the requests sent, responses
+    /// received, bytes transferred and timeouts. Compare two snapshots with
+    /// [`SessionDiagnostics::diff`] to find the rates in between.
+    pub fn diagnostics(&self) -> SessionDiagnostics {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.diagnostics()
+    }
+
+    /// Returns the counts of the requests and responses of the session so far, the requests
+    /// waiting for a response now and the average time taken for a response to arrive over the
+    /// most recent responses, e.g. to graph the health of a session over time.
+    pub fn metrics(&self) -> MessageQueueMetrics {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.metrics()
+    }
+
+    /// Returns a summary of each subscription the session has created and not yet deleted, with
+    /// the publishing interval and counts revised by the server and the client handles of its
+    /// monitored items.
+    pub fn subscriptions(&self) -> Vec<SubscriptionSummary> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.subscriptions()
+    }
+
+    /// Sets the timeout in milliseconds for publish requests. A server holds on to a publish
+    /// request until a subscription has something to report, so publish requests need a longer
+    /// timeout than other requests. By default the timeout is the longest keep-alive interval of
+    /// the subscriptions, i.e. the publishing interval times the keep-alive count, plus the
+    /// request timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `publish_timeout` - the timeout in milliseconds, or `None` for the default.
+    ///
+    pub fn set_publish_timeout(&mut self, publish_timeout: Option<u32>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_publish_timeout(publish_timeout);
+    }
+
+    /// Returns the timeout in milliseconds for publish requests. See
+    /// [`Session::set_publish_timeout`].
+    pub fn publish_timeout(&self) -> u32 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.publish_timeout()
+    }
+
+    /// Sets the session to dry run requests. In a dry run, a request has the same checks as usual,
+    /// e.g. whether the session is read only, and is encoded to find any encoding errors, but it is
+    /// logged instead of being sent. The reply to every request that passes is a placeholder of
+    /// the response it expects, with a `Good` result for every operation, so service helpers work
+    /// as they would against a server that accepts everything. Requests that only a server can
+    /// answer, e.g. to create a session, fail with `BadServiceUnsupported`. This is meant for
+    /// validating requests, e.g. in tests, without a server.
+    ///
+    /// # Arguments
+    ///
+    /// * `dry_run` - true to validate requests without sending them
+    ///
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_dry_run(dry_run);
+    }
+
+    /// Tests if the session dry runs requests. See [`Session::set_dry_run`].
+    pub fn dry_run(&self) -> bool {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.dry_run()
+    }
+
+    /// Limits the rate at which the session sends requests so that a busy client does not
+    /// overwhelm a shared server. The limit is a token bucket that holds up to a second of
+    /// requests. Publish requests, the session's own keep-alive and secure channel requests are
+    /// exempt. What happens to a request over the limit is set by
+    /// [`Session::set_rate_limit_policy`].
+    ///
+    /// # Arguments
+    ///
+    /// * `max_requests_per_second` - the most requests per second, or `None` for no limit.
+    ///
+    pub fn set_max_requests_per_second(&mut self, max_requests_per_second: Option<f64>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_max_requests_per_second(max_requests_per_second);
+    }
+
+    /// Returns the most requests per second the session sends, if there is a limit. See
+    /// [`Session::set_max_requests_per_second`].
+    pub fn max_requests_per_second(&self) -> Option<f64> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.max_requests_per_second()
+    }
+
+    /// Sets whether a request over the rate limit waits until it is allowed, the default, or
+    /// fails with `BadResourceUnavailable`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate_limit_policy` - what happens to a request over the rate limit.
+    ///
+    pub fn set_rate_limit_policy(&mut self, rate_limit_policy: RateLimitPolicy) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_rate_limit_policy(rate_limit_policy);
+    }
+
+    /// Returns what happens to a request over the rate limit. See
+    /// [`Session::set_rate_limit_policy`].
+    pub fn rate_limit_policy(&self) -> RateLimitPolicy {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.rate_limit_policy()
+    }
+
+    /// Sets what happens when the server answers a read or write with a different number of
+    /// results than there were items to read or write. The default is to fail the call with
+    /// `BadUnexpectedError`, but the results can instead be padded with `BadInternalError`, or
+    /// truncated, to cope with a non-conforming server.
+    ///
+    /// # Arguments
+    ///
+    /// * `partial_results_policy` - what happens to a response with the wrong number of results.
+    ///
+    pub fn set_partial_results_policy(&mut self, partial_results_policy: PartialResultsPolicy) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_partial_results_policy(partial_results_policy);
+    }
+
+    /// Returns what happens to a read or write response with the wrong number of results. See
+    /// [`Session::set_partial_results_policy`].
+    pub fn partial_results_policy(&self) -> PartialResultsPolicy {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.partial_results_policy()
+    }
+
+    /// Sets which timestamp of a value is treated as its time when both are returned, e.g. by
+    /// reading with `TimestampsToReturn::Both`. Pass [`Session::preferred_timestamp`] to
+    /// [`DataValue::effective_timestamp`] so all code uses the same one. The default is
+    /// `SourceElseServer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `preferred_timestamp` - the timestamp to prefer.
+    ///
+    pub fn set_preferred_timestamp(&mut self, preferred_timestamp: TimestampPreference) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_preferred_timestamp(preferred_timestamp);
+    }
+
+    /// Returns which timestamp of a value is treated as its time. See
+    /// [`Session::set_preferred_timestamp`].
+    pub fn preferred_timestamp(&self) -> TimestampPreference {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.preferred_timestamp()
+    }
+
+    /// Sets other urls of the server to try, in order, when the endpoint url can't be connected
+    /// to, e.g. the urls of a redundant server. Whichever url last connected is tried first next
+    /// time. With a session retry policy, each retry goes through the urls again.
+    ///
+    /// # Arguments
+    ///
+    /// * `alternate_endpoint_urls` - the urls, each of which must be a valid opc.tcp url.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - the urls are set
+    /// * `Err(StatusCode)` - `BadTcpEndpointUrlInvalid` if a url is not valid
+    ///
+    pub fn set_alternate_endpoint_urls(
+        &mut self,
+        alternate_endpoint_urls: Vec<UAString>,
+    ) -> Result<(), StatusCode> {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_alternate_endpoint_urls(alternate_endpoint_urls)
+    }
+
+    /// Returns the other urls of the server to try. See [`Session::set_alternate_endpoint_urls`].
+    pub fn alternate_endpoint_urls(&self) -> Vec<UAString> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.alternate_endpoint_urls().to_vec()
+    }
+
+    /// Returns the url that the session last connected to, if it has connected.
+    pub fn last_endpoint_url(&self) -> Option<UAString> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.last_endpoint_url().cloned()
+    }
+
+    /// Sets a function that is called whenever an operation of the session state fails, e.g.
+    /// sending a request or renewing the secure channel, with the name of the operation and the
+    /// error. The handler only observes errors, the operation still returns its error as before,
+    /// so it is a single place to log or raise alerts for failures.
+    ///
+    /// # Arguments
+    ///
+    /// * `on_error` - the function that is told of errors.
+    ///
+    pub fn set_error_handler<F>(&mut self, on_error: F)
+    where
+        F: Fn(&str, StatusCode) + Send + Sync + 'static,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_error_handler(on_error);
+    }
+
+    /// Sets a function that is called with the subscription id whenever a subscription sends a
+    /// keep-alive, i.e. a publish response with no notifications. A server sends these when a
+    /// subscription has had nothing to report for its keep-alive count of publishing intervals,
+    /// so they confirm that a quiet subscription is still alive.
+    ///
+    /// # Arguments
+    ///
+    /// * `on_keep_alive` - the function that is told of keep-alives.
+    ///
+    pub fn set_keep_alive_handler<F>(&mut self, on_keep_alive: F)
+    where
+        F: Fn(u32) + Send + Sync + 'static,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_keep_alive_handler(on_keep_alive);
+    }
+
+    /// Sets a channel that the client handle and value of each monitored item notification is
+    /// sent to, as well as the subscription's callback, e.g. to hand notifications to a consumer
+    /// thread. The channel is bounded and notifications are never waited on, so a notification
+    /// that doesn't fit because the consumer has fallen behind is dropped with a warning.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - the sending end of the channel, or `None` to stop sending notifications.
+    ///
+    pub fn set_notification_sender(&mut self, sender: Option<SyncSender<(u32, DataValue)>>) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_notification_sender(sender);
+    }
+
+    /// Sets a function that is called with the time the security token has left when it is
+    /// about to expire and has not been renewed. The token is normally renewed once 75% of its
+    /// lifetime has elapsed, so this gives warning that renewal is not happening, e.g. because
+    /// the timer driving it is starved, before requests fail with `BadSecureChannelTokenUnknown`.
+    /// The token is checked whenever it would be renewed.
+    ///
+    /// # Arguments
+    ///
+    /// * `on_token_near_expiry` - the function that is told the remaining lifetime of the token.
+    ///
+    pub fn set_token_near_expiry_handler<F>(&mut self, on_token_near_expiry: F)
+    where
+        F: Fn(std::time::Duration) + Send + Sync + 'static,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_token_near_expiry_handler(on_token_near_expiry);
+    }
+
+    /// Sets the fraction of the security token lifetime left below which the token near expiry
+    /// handler is called. The default is 0.1, i.e. when less than 10% of the lifetime is left.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_near_expiry_fraction` - a fraction between 0 and 1.
+    ///
+    pub fn set_token_near_expiry_fraction(&mut self, token_near_expiry_fraction: f64) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_token_near_expiry_fraction(token_near_expiry_fraction);
+    }
+
+    /// Returns the fraction of the security token lifetime left below which the token near
+    /// expiry handler is called.
+    pub fn token_near_expiry_fraction(&self) -> f64 {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.token_near_expiry_fraction()
+    }
+
+    /// Sets the most subscription acknowledgements, across all subscriptions, that may be pending
+    /// at once. Acknowledgements are normally sent with the next publish request, but if that
+    /// stalls they would pile up without limit. Past the limit the oldest are dropped, with a
+    /// warning, and the server keeps those notifications for republishing until the subscription
+    /// expires. The default is 10000.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_subscription_acknowledgements` - the most pending acknowledgements, at least 1.
+    ///
+    pub fn set_max_subscription_acknowledgements(
+        &mut self,
+        max_subscription_acknowledgements: usize,
+    ) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_max_subscription_acknowledgements(max_subscription_acknowledgements);
+    }
+
+    /// Returns the most subscription acknowledgements that may be pending at once. See
+    /// [`Session::set_max_subscription_acknowledgements`].
+    pub fn max_subscription_acknowledgements(&self) -> usize {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.max_subscription_acknowledgements()
+    }
+
+    /// Sets a function that is called with each subscription acknowledgement dropped because too
+    /// many are pending. See [`Session::set_max_subscription_acknowledgements`].
+    ///
+    /// # Arguments
+    ///
+    /// * `on_ack_overflow` - the function that is told of dropped acknowledgements.
+    ///
+    pub fn set_ack_overflow_handler<F>(&mut self, on_ack_overflow: F)
+    where
+        F: Fn(&SubscriptionAcknowledgement) + Send + Sync + 'static,
+    {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_ack_overflow_handler(on_ack_overflow);
+    }
+
+    /// Sets what happens to a response that doesn't match any request in flight, for example a
+    /// response from a slow server that arrives after its request has timed out. The default is
+    /// to log such responses as errors and drop them.
+    ///
+    /// # Arguments
+    ///
+    /// * `unmatched_response_policy` - the policy for unmatched responses.
+    ///
+    pub fn set_unmatched_response_policy(
+        &mut self,
+        unmatched_response_policy: UnmatchedResponsePolicy,
+    ) {
+        let session_state = trace_read_lock!(self.session_state);
+        let mut message_queue = trace_write_lock!(session_state.message_queue);
+        message_queue.set_unmatched_response_policy(unmatched_response_policy);
+    }
+
+    /// Sets a function that decides what happens to the subscriptions of the previous session
+    /// when reconnecting fails to reactivate it and a new session has to be created. The function
+    /// is called with the ids of the prior subscriptions. When no function is set, the
+    /// subscriptions are transferred to the new session.
+    ///
+    /// # Arguments
+    ///
+    /// * `on_orphaned_subscriptions` - the function that decides what to do.
+    ///
+    pub fn set_orphaned_subscriptions_handler<F>(&mut self, on_orphaned_subscriptions: F)
+    where
+        F: Fn(&[u32]) -> OrphanAction + Send + Sync + 'static,
+    {
+        self.on_orphaned_subscriptions = Some(Box::new(on_orphaned_subscriptions));
+    }
+
+    /// Reconnects to the server and tries to activate the existing session. If there
+    /// is a failure, it will be communicated by the status code in the result. You should not
+    /// call this if there is a session retry policy associated with the session.
+    ///
+    /// Reconnecting will attempt to transfer or recreate subscriptions that were on the old
+    /// session before it terminated, unless a handler set with `set_orphaned_subscriptions_handler`
+    /// decides otherwise.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - reconnection has happened and the session is activated
+    /// * `Err(StatusCode)` - reason for failure
+    ///
+    pub fn reconnect_and_activate(&mut self) -> Result<(), StatusCode> {
+        // Do nothing if already connected / activated
+        if self.is_connected() {
+            session_error!(
+                self,
+                "Reconnect is going to do nothing because already connected"
+            );
+            Err(StatusCode::BadUnexpectedError)
+        } else {
+            // Reset the session state
+            self.reset();
+
+            // Connect to server (again)
+            info!("Reconnect is opening a new secure channel");
+            self.connect_no_retry()?;
+
+            // Attempt to reactivate the existing session
+            info!("Reconnect is activating the existing session");
+            let orphan_action = match self.activate_session() {
+                Err(status_code) => {
+                    // Activation didn't work, so create a new session
+                    info!("Session activation failed on reconnect, error = {}, so creating a new session", status_code);
+                    {
+                        let mut session_state = trace_write_lock!(self.session_state);
+                        session_state.reset();
+                    }
+
+                    info!("Reconnect is creating a new session");
+                    self.create_session()?;
+                    info!("Reconnect is activating the new session");
+                    self.activate_session()?;
+                    session_debug!(self, "reconnect should be complete");
+                    self.orphan_action()
+                }
+                Ok(_) => {
+                    info!("Activation succeeded");
+                    OrphanAction::Transfer
+                }
+            };
+            match orphan_action {
+                OrphanAction::Transfer => {
+                    session_debug!(self, "transfer_subscriptions_from_old_session");
+                    self.transfer_subscriptions_from_old_session()?;
+                }
+                OrphanAction::Delete => {
+                    session_debug!(self, "delete_subscriptions_from_old_session");
+                    self.delete_subscriptions_from_old_session();
+                }
+                OrphanAction::Ignore => {
+                    session_debug!(self, "Ignoring the subscriptions of the old session");
+                    let mut subscription_state = trace_write_lock!(self.subscription_state);
+                    if let Some(subscription_ids) = subscription_state.subscription_ids() {
+                        subscription_ids.iter().for_each(|subscription_id| {
+                            let _ = subscription_state.delete_subscription(*subscription_id);
+                        });
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Recovers from a secure channel or session that has died by reconnecting to the server,
+    /// retrying according to the reconnect policy. Each attempt opens a new secure channel and
+    /// activates the existing session over it, or creates and activates a new session if the
+    /// server no longer has the old one, in which case the session id and authentication token
+    /// are replaced. The subscriptions of the old session are then transferred, or dealt with as
+    /// a handler set with `set_orphaned_subscriptions_handler` decides. Monitored item handles
+    /// carry on from where they were so they stay unique.
+    ///
+    /// Unlike `reconnect_and_activate`, this may be called while the transport is still
+    /// connected, e.g. when the session has been lost but the connection hasn't, and the
+    /// connection is dropped first without closing the session.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - reconnection has happened and the session is activated
+    /// * `Err(StatusCode)` - the reason the last attempt failed
+    ///
+    pub fn reconnect(&mut self) -> Result<(), StatusCode> {
+        let reconnect_policy = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.reconnect_policy()
+        };
+        if self.is_connected() {
+            info!("Reconnect is dropping the existing connection");
+            self.drop_connection();
+        }
+        let max_attempts = cmp::max(reconnect_policy.max_attempts, 1);
+        let mut attempt = 1;
+        loop {
+            info!("Reconnect attempt {} of {}", attempt, max_attempts);
+            match self.reconnect_and_activate() {
+                Ok(_) => {
+                    info!("Reconnect attempt {} succeeded", attempt);
+                    return Ok(());
+                }
+                Err(status_code) => {
+                    // Leave nothing half connected for the next attempt
+                    self.drop_connection();
+                    if attempt >= max_attempts {
+                        session_error!(
+                            self,
+                            "Reconnect has given up after {} attempts, {}",
+                            attempt,
+                            status_code
+                        );
+                        return Err(status_code);
+                    }
+                    attempt += 1;
+                    let backoff = reconnect_policy.backoff(attempt);
+                    session_warn!(
+                        self,
+                        "Reconnect attempt failed with {}, trying again in {} ms",
+                        status_code,
+                        backoff
+                    );
+                    thread::sleep(Duration::from_millis(backoff as u64));
+                }
+            }
+        }
+    }
+
+    /// Sets how [`Session::reconnect`] retries reconnecting, i.e. how many attempts it makes and
+    /// how long it waits between them. The default is 3 attempts, waiting 1000 ms and then
+    /// 2000 ms.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - the reconnect policy.
+    ///
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_reconnect_policy(policy);
+    }
+
+    /// Sets what happens to publish responses that a misbehaving server sends before the session
+    /// is activated. They are never delivered to the subscription callbacks until then, and by
+    /// default they are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - the pre-activation policy.
+    ///
+    pub fn set_pre_activation_policy(&mut self, policy: PreActivationPolicy) {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.set_pre_activation_policy(policy);
+    }
+
+    /// Returns what happens to publish responses received before the session is activated. See
+    /// [`Session::set_pre_activation_policy`].
+    pub fn pre_activation_policy(&self) -> PreActivationPolicy {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.pre_activation_policy()
+    }
+
+    /// Returns how reconnecting is retried. See [`Session::set_reconnect_policy`].
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.reconnect_policy()
+    }
+
+    /// Drops the connection to the server without closing the session or deleting its
+    /// subscriptions, so the session can be activated again over a new connection.
+    fn drop_connection(&self) {
+        if self.is_connected() {
+            let _ = self.close_secure_channel();
+            {
+                let session_state = trace_read_lock!(self.session_state);
+                session_state.quit();
+            }
+            self.transport.wait_for_disconnect();
+            self.on_connection_status_change(false);
+        }
+    }
+
+    /// Asks the orphaned subscriptions handler, if there is one, what to do with the subscriptions
+    /// of the previous session.
+    fn orphan_action(&self) -> OrphanAction {
+        let subscription_ids = {
+            let subscription_state = trace_read_lock!(self.subscription_state);
+            subscription_state.subscription_ids()
+        };
+        match (&self.on_orphaned_subscriptions, subscription_ids) {
+            (Some(on_orphaned_subscriptions), Some(subscription_ids)) => {
+                on_orphaned_subscriptions(&subscription_ids)
+            }
+            _ => OrphanAction::Transfer,
+        }
+    }
+
+    /// Deletes the subscriptions created by a previous session from the server. A session can
+    /// only delete its own subscriptions, so they are transferred to this session first.
+    /// Subscriptions that cannot be transferred are left to expire on the server.
+    fn delete_subscriptions_from_old_session(&mut self) {
+        let subscription_ids = {
+            let subscription_state = trace_read_lock!(self.subscription_state);
+            subscription_state.subscription_ids()
+        };
+        if let Some(subscription_ids) = subscription_ids {
+            let transferred_ids = match self.transfer_subscriptions(&subscription_ids, false) {
+                Ok(transfer_results) => subscription_ids
+                    .iter()
+                    .zip(transfer_results.iter())
+                    .filter(|(_, r)| r.status_code.is_good())
+                    .map(|(subscription_id, _)| *subscription_id)
+                    .collect::<Vec<u32>>(),
+                Err(status_code) => {
+                    session_warn!(
+                        self,
+                        "Subscriptions could not be transferred to be deleted, {}",
+                        status_code
+                    );
+                    Vec::new()
+                }
+            };
+            if !transferred_ids.is_empty() {
+                if let Err(status_code) = self.delete_subscriptions(&transferred_ids) {
+                    session_warn!(
+                        self,
+                        "Subscriptions of the old session could not be deleted, {}",
+                        status_code
+                    );
+                }
+            }
+            // Nothing is kept of the old subscriptions whether the delete worked or not
+            let mut subscription_state = trace_write_lock!(self.subscription_state);
+            subscription_ids.iter().for_each(|subscription_id| {
+                let _ = subscription_state.delete_subscription(*subscription_id);
+            });
+        }
+    }
+
+    /// This code attempts to take the existing subscriptions created by a previous session and
+    /// either transfer them to this session, or construct them from scratch.
+    fn transfer_subscriptions_from_old_session(&mut self) -> Result<(), StatusCode> {
+        let subscription_state = self.subscription_state.clone();
+
+        let subscription_ids = {
+            let subscription_state = trace_read_lock!(subscription_state);
+            subscription_state.subscription_ids()
+        };
+
+        // Start by getting the subscription ids
+        if let Some(subscription_ids) = subscription_ids {
+            // Try to use TransferSubscriptions to move subscriptions_ids over. If this
+            // works then there is nothing else to do.
+            let mut subscription_ids_to_recreate =
+                subscription_ids.iter().copied().collect::<HashSet<u32>>();
+            if let Ok(transfer_results) = self.transfer_subscriptions(&subscription_ids, true) {
+                session_debug!(self, "transfer_results = {:?}", transfer_results);
+                transfer_results.iter().enumerate().for_each(|(i, r)| {
+                    if r.status_code.is_good() {
+                        // Subscription was transferred so it does not need to be recreated
+                        subscription_ids_to_recreate.remove(&subscription_ids[i]);
+                    }
+                });
+            }
+
+            // But if it didn't work, then some or all subscriptions have to be remade.
+            if !subscription_ids_to_recreate.is_empty() {
+                session_warn!(self, "Some or all of the existing subscriptions could not be transferred and must be created manually");
+            }
+
+            // Now create any subscriptions that could not be transferred
+            subscription_ids_to_recreate
+                .iter()
+                .for_each(|subscription_id| {
+                    info!("Recreating subscription {}", subscription_id);
+                    // Remove the subscription data, create it again from scratch
+                    let deleted_subscription = {
+                        let mut subscription_state = trace_write_lock!(subscription_state);
+                        subscription_state.delete_subscription(*subscription_id)
+                    };
+
+                    if let Some(subscription) = deleted_subscription {
+                        // Attempt to replicate the subscription (subscription id will be new)
+                        if let Ok(subscription_id) = self.create_subscription_inner(
+                            subscription.publishing_interval(),
+                            subscription.lifetime_count(),
+                            subscription.max_keep_alive_count(),
+                            subscription.max_notifications_per_publish(),
+                            subscription.priority(),
+                            subscription.publishing_enabled(),
+                            subscription.notification_callback(),
+                        ) {
+                            info!("New subscription created with id {}", subscription_id);
+
+                            // For each monitored item
+                            let items_to_create = subscription
+                                .monitored_items()
+                                .iter()
+                                .map(|(_, item)| MonitoredItemCreateRequest {
+                                    item_to_monitor: item.item_to_monitor().clone(),
+                                    monitoring_mode: item.monitoring_mode(),
+                                    requested_parameters: MonitoringParameters {
+                                        client_handle: item.client_handle(),
+                                        sampling_interval: item.sampling_interval(),
+                                        filter: ExtensionObject::null(),
+                                        queue_size: item.queue_size() as u32,
+                                        discard_oldest: true,
+                                    },
+                                })
+                                .collect::<Vec<MonitoredItemCreateRequest>>();
+                            let _ = self.create_monitored_items(
+                                subscription_id,
+                                TimestampsToReturn::Both,
+                                &items_to_create,
+                            );
+
+                            // Recreate any triggers for the monitored item. This code assumes monitored item
+                            // ids are the same value as they were in the previous subscription.
+                            subscription.monitored_items().iter().for_each(|(_, item)| {
+                                let triggered_items = item.triggered_items();
+                                if !triggered_items.is_empty() {
+                                    let links_to_add =
+                                        triggered_items.iter().copied().collect::<Vec<u32>>();
+                                    let _ = self.set_triggering(
+                                        subscription_id,
+                                        item.id(),
+                                        links_to_add.as_slice(),
+                                        &[],
+                                    );
+                                }
+                            });
+                        } else {
+                            session_warn!(
+                                self,
+                                "Could not create a subscription from the existing subscription {}",
+                                subscription_id
+                            );
+                        }
+                    } else {
+                        panic!(
+                            "Subscription {}, doesn't exist although it should",
+                            subscription_id
+                        );
+                    }
+                });
+        }
+        Ok(())
+    }
+
+    /// Connects to the server using the retry policy to repeat connecting until such time as it
+    /// succeeds or the policy says to give up. If there is a failure, it will be
+    /// communicated by the status code in the result.
+    pub fn connect(&self) -> Result<(), StatusCode> {
+        loop {
+            match self.connect_no_retry() {
+                Ok(_) => {
+                    info!("Connect was successful");
+                    let mut session_retry_policy = trace_lock!(self.session_retry_policy);
+                    session_retry_policy.reset_retry_count();
+                    return Ok(());
+                }
+                Err(status_code) => {
+                    self.disconnect();
+                    let mut session_retry_policy = trace_lock!(self.session_retry_policy);
+                    session_retry_policy.increment_retry_count();
+                    session_warn!(
+                        self,
+                        "Connect was unsuccessful, error = {}, retries = {}",
+                        status_code,
+                        session_retry_policy.retry_count()
+                    );
+
+                    match session_retry_policy.should_retry_connect(DateTime::now()) {
+                        Answer::GiveUp => {
+                            session_error!(self, "Session has given up trying to connect to the server after {} retries", session_retry_policy.retry_count());
+                            return Err(StatusCode::BadNotConnected);
+                        }
+                        Answer::Retry => {
+                            info!("Retrying to connect to server...");
+                            session_retry_policy.set_last_attempt(DateTime::now());
+                        }
+                        Answer::WaitFor(sleep_for) => {
+                            // Sleep for the instructed interval before looping around and trying
+                            // once more.
+                            thread::sleep(Duration::from_millis(sleep_for as u64));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connects to the server using the configured session arguments. No attempt is made to retry
+    /// the connection if the attempt fails. If there is a failure, it will be communicated by the
+    /// status code in the result.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - connection has happened
+    /// * `Err(StatusCode)` - reason for failure
+    ///
+    pub fn connect_no_retry(&self) -> Result<(), StatusCode> {
+        let endpoint_urls = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.endpoint_urls(&self.session_info.endpoint.endpoint_url)
+        };
+        info!("Connect");
+        let security_policy =
+            SecurityPolicy::from_str(self.session_info.endpoint.security_policy_uri.as_ref())
+                .unwrap();
+        if security_policy == SecurityPolicy::Unknown {
+            session_error!(
+                self,
+                "connect, security policy \"{}\" is unknown",
+                self.session_info.endpoint.security_policy_uri.as_ref()
+            );
+            Err(StatusCode::BadSecurityPolicyRejected)
+        } else {
+            let (cert, key) = {
+                let certificate_store = trace_write_lock!(self.certificate_store);
+                certificate_store.read_own_cert_and_pkey_optional()
+            };
+
+            {
+                let mut secure_channel = trace_write_lock!(self.secure_channel);
+                secure_channel.set_private_key(key);
+                secure_channel.set_cert(cert);
+                secure_channel.set_security_policy(security_policy);
+                secure_channel.set_security_mode(self.session_info.endpoint.security_mode);
+                let _ = secure_channel.set_remote_cert_from_byte_string(
+                    &self.session_info.endpoint.server_certificate,
+                );
+                info!("Security policy = {:?}", security_policy);
+                info!(
+                    "Security mode = {:?}",
+                    self.session_info.endpoint.security_mode
+                );
+            }
+
+            // Transport's tokio runtime is made here, not in transport
+            self.connect_secure_channel(&endpoint_urls)?;
+            self.on_connection_status_change(true);
+            Ok(())
+        }
+    }
+
+    /// Connects the transport and opens a secure channel on it. With security policy preferences,
+    /// each is tried in turn on a new connection, since a server closes the connection after
+    /// rejecting the security of a secure channel, leaving the secure channel set to the policy
+    /// and mode that the server accepted.
+    fn connect_secure_channel(&self, endpoint_urls: &[UAString]) -> Result<(), StatusCode> {
+        let security_policy_preferences = self.security_policy_preferences();
+        if security_policy_preferences.is_empty() {
+            self.connect_transport(endpoint_urls)?;
+            return self.open_secure_channel();
+        }
+        let mut result = Err(StatusCode::BadSecurityPolicyRejected);
+        for (security_policy, security_mode) in security_policy_preferences {
+            {
+                let mut secure_channel = trace_write_lock!(self.secure_channel);
+                secure_channel.set_security_policy(security_policy);
+                secure_channel.set_security_mode(security_mode);
+            }
+            self.connect_transport(endpoint_urls)?;
+            result = self.open_secure_channel();
+            match result {
+                Err(StatusCode::BadSecurityPolicyRejected)
+                | Err(StatusCode::BadSecurityModeRejected)
+                | Err(StatusCode::BadSecurityChecksFailed) => {
+                    session_warn!(
+                        self,
+                        "Opening the secure channel with {:?} / {:?} failed with {}, trying the next preference",
+                        security_policy,
+                        security_mode,
+                        result.unwrap_err()
+                    );
+                    {
+                        let session_state = trace_read_lock!(self.session_state);
+                        session_state.quit();
+                    }
+                    self.transport.wait_for_disconnect();
+                }
+                _ => break,
+            }
+        }
+        result
+    }
+
+    /// Connects the transport to the first of the urls that accepts the connection, remembering
+    /// which one it was. The error from the last url is returned if none of them do.
+    fn connect_transport(&self, endpoint_urls: &[UAString]) -> Result<(), StatusCode> {
+        let mut result = Err(StatusCode::BadTcpEndpointUrlInvalid);
+        for endpoint_url in endpoint_urls {
+            result = self.transport.connect(endpoint_url.as_ref());
+            match result {
+                Ok(_) => {
+                    let mut session_state = trace_write_lock!(self.session_state);
+                    session_state.set_last_endpoint_url(endpoint_url.clone());
+                    break;
+                }
+                Err(status_code) => {
+                    session_warn!(
+                        self,
+                        "Cannot connect to {}, error = {}",
+                        endpoint_url,
+                        status_code
+                    );
+                    // Let the failed connection finish before trying the next url
+                    while self.transport.is_connected() {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// The url of the server that the session is connected to, which may be an alternate url if
+    /// the endpoint url was unreachable.
+    fn endpoint_url(&self) -> UAString {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state
+            .last_endpoint_url()
+            .cloned()
+            .unwrap_or_else(|| self.session_info.endpoint.endpoint_url.clone())
+    }
+
+    pub(crate) fn session_state(&self) -> Arc<RwLock<SessionState>> {
+        self.session_state.clone()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn secure_channel(&self) -> Arc<RwLock<SecureChannel>> {
+        self.secure_channel.clone()
+    }
+
+    /// Disconnect from the server. Disconnect is an explicit command to drop the socket and throw
+    /// away all state information. If you disconnect you cannot reconnect to your existing session
+    /// or retrieve any existing subscriptions.
+    pub fn disconnect(&self) {
+        if self.is_connected() {
+            let _ = self.close_session(true);
+
+            {
+                let session_state = trace_read_lock!(self.session_state);
+                session_state.quit();
+            }
+
+            self.transport.wait_for_disconnect();
+            self.on_connection_status_change(false);
+        }
+    }
+
+    /// Restores a session from its persisted session id, authentication token and the security
+    /// token of its secure channel, avoiding the handshake of creating and activating a new
+    /// session. A read is sent to confirm the server still considers the session valid.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The session id returned when the session was created.
+    /// * `authentication_token` - The authentication token returned when the session was created.
+    /// * `channel_token` - The security token of the secure channel the session was using. The
+    ///   session can only be restored on the secure channel that issued this token.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The session was restored.
+    /// * `Err(StatusCode)` - `BadSessionIdInvalid` if the server does not recognize the session,
+    ///   `BadSecureChannelIdInvalid` if the token is for another secure channel,
+    ///   `BadNotConnected` if the transport is not connected, or another reason for failure.
+    ///
+    pub fn restore(
+        &self,
+        session_id: NodeId,
+        authentication_token: NodeId,
+        channel_token: ChannelSecurityToken,
+    ) -> Result<(), StatusCode> {
+        if !self.is_connected() {
+            return Err(StatusCode::BadNotConnected);
+        }
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.restore(session_id, authentication_token, channel_token)
+    }
+
+    /// Asks the server to resend a notification message that was lost, e.g. because there is a
+    /// gap in the sequence numbers of the notifications received for a subscription.
+    ///
+    /// See OPC UA Part 4 - Services 5.13.6 for complete description of the service and error responses.
+    ///
+    /// # Arguments
+    ///
+    /// * `subscription_id` - The id of the subscription the message belongs to.
+    /// * `retransmit_sequence_number` - The sequence number of the message to resend.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(NotificationMessage)` - The message that was resent.
+    /// * `Err(StatusCode)` - `BadMessageNotAvailable` if the server no longer has the message, in
+    ///   which case it cannot be recovered, or another reason for failure.
+    ///
+    pub fn republish(
+        &self,
+        subscription_id: u32,
+        retransmit_sequence_number: u32,
+    ) -> Result<NotificationMessage, StatusCode> {
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.republish(subscription_id, retransmit_sequence_number)
+    }
+
+    /// Test if the session is in a connected state
+    ///
+    /// # Returns
+    ///
+    /// * `true` - Session is connected
+    /// * `false` - Session is not connected
+    ///
+    pub fn is_connected(&self) -> bool {
+        self.transport.is_connected()
+    }
+
+    /// Test if the session has been activated over the current connection.
+    ///
+    /// # Returns
+    ///
+    /// * `true` - Session is activated
+    /// * `false` - Session is not activated, e.g. it is still connecting or has been reset
+    ///
+    pub fn is_activated(&self) -> bool {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.is_activated()
+    }
+
+    /// Internal constant for the sleep interval used during polling
+    const POLL_SLEEP_INTERVAL: u64 = 10;
+
+    /// Synchronously runs a polling loop over the supplied session. Running a session performs
+    /// periodic actions such as receiving messages, processing subscriptions, and recovering from
+    /// connection errors. The run function will return if the session is disconnected and
+    /// cannot be reestablished.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - the session to run ynchronously
+    ///
+    pub fn run(session: Arc<RwLock<Session>>) {
+        let (_tx, rx) = oneshot::channel();
+        Self::run_loop(session, Self::POLL_SLEEP_INTERVAL, rx);
+    }
+
+    /// Runs the session asynchronously on a new thread. The function returns immediately
+    /// and gives a caller a `Sender` that can be used to send a message to the session
+    /// to cause it to terminate. Do not drop this sender (i.e. make sure to bind it to a variable with
+    /// sufficient lifetime) or the session will terminate as soon as you do.
+    ///
+    /// Running a session performs periodic actions such as receiving messages, processing subscriptions,
+    /// and recovering from.  connection errors. The session will terminate by itself if it is disconnected
+    /// and cannot be reestablished. It will terminate if the sender is dropped or if sent a ClientCommand
+    /// to terminate.  caller to this function can monitor the status of the session through state
+    /// calls to know when this happens.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - the session to run asynchronously
+    ///
+    /// # Returns
+    ///
+    /// * `oneshot::Sender<ClientCommand>` - A sender that allows the caller to send a message to the
+    ///                        run loop to cause it to stop. Note that dropping the sender, i.e. not binding it to
+    ///                        a variable will also cause the loop to stop.
+    ///
+    pub fn run_async(session: Arc<RwLock<Session>>) -> oneshot::Sender<SessionCommand> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || Self::run_loop(session, Self::POLL_SLEEP_INTERVAL, rx));
+        tx
+    }
+
+    /// The asynchronous main session loop. This is the function that processes responses and
+    /// keeps the session alive. Note that while the client normally calls `run()` or `run_loop()`
+    /// to invoke this, there may be situations where the client wishes to directly use this
+    /// function, for example if the client has its own Tokio runtime and prefers to spawn the task
+    /// with that.
+    pub async fn session_task(
+        session: Arc<RwLock<Session>>,
+        sleep_interval: u64,
+        rx: oneshot::Receiver<SessionCommand>,
+    ) {
+        tokio::select! {
+            _ = async {
+                let mut timer = interval(Duration::from_millis(sleep_interval));
+                loop {
+                    // Poll the session.
+                    let poll_result = {
+                        let mut session = session.write();
+                        session.poll().await
+                    };
+                    match poll_result {
+                        Ok(did_something) => {
+                            // If the session did nothing, then sleep for a moment to save some CPU
+                            if !did_something {
+                                timer.tick().await;
+                            }
+                        }
+                        Err(_) => {
+                            // Break the loop if connection goes down
+                            info!("Run session connection to server broke, so terminating");
+                            break;
+                        }
+                    }
+                }
+            } => {}
+            message = rx => {
+                if let Ok(message) = message {
+                    // Only message is a Quit command so no point even testing what it is.
+                    info!("Run session was terminated by a message {:?}", message);
+                }
+                else {
+                    warn!("Run session was terminated, presumably by caller dropping oneshot sender. Don't do that unless you meant to.");
+                }
+            }
+        }
+    }
+
+    /// The main running loop for a session. This is used by `run()` and `run_async()` to run
+    /// continuously until a signal is received to terminate.
+    ///
+    /// # Arguments
+    ///
+    /// * `session`   - The session
+    /// * `sleep_interval` - An internal polling timer in ms
+    /// * `rx`        - A receiver that the task uses to receive a quit command directly from the caller.
+    ///
+    pub fn run_loop(
+        session: Arc<RwLock<Session>>,
+        sleep_interval: u64,
+        rx: oneshot::Receiver<SessionCommand>,
+    ) {
+        let task = {
+            let session = session.clone();
+            async move {
+                Self::session_task(session, sleep_interval, rx).await;
+            }
+        };
+        // Spawn the task on the alloted runtime
+        let runtime = {
+            let session = trace_read_lock!(session);
+            session.runtime.clone()
+        };
+        let runtime = trace_lock!(runtime);
+        runtime.block_on(task);
+    }
+
+    /// Polls on the session which basically dispatches any pending
+    /// async responses, attempts to reconnect if the client is disconnected from the client and
+    /// sleeps a little bit if nothing needed to be done.
+    ///
+    /// # Arguments
+    ///
+    /// * `sleep_for` - the period of time in milliseconds that poll should sleep for if it performed
+    ///                 no action.
+    ///
+    /// # Returns
+    ///
+    /// * `true` - if an action was performed during the poll
+    /// * `false` - if no action was performed during the poll and the poll slept
+    ///
+    pub async fn poll(&mut self) -> Result<bool, ()> {
+        let idle_close_due = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.idle_close_due()
+        };
+        let closed_idle = self.is_closed_idle();
+        let did_something = if idle_close_due && self.is_connected() {
+            self.close_idle();
+            true
+        } else if self.is_connected() {
+            let mut session_state = trace_write_lock!(self.session_state);
+            session_state.handle_publish_responses()
+        } else if closed_idle {
+            // The session is opened again by the next request, not by reconnecting
+            false
+        } else {
             let should_retry_connect = {
                 let session_retry_policy = trace_lock!(self.session_retry_policy);
                 session_retry_policy.should_retry_connect(DateTime::now())
@@ -743,52 +2158,224 @@ impl Session {
                     }
                     true
                 }
-                Answer::WaitFor(_) => {
-                    // Note we could sleep for the interval in the WaitFor(), but the poll() sleeps
-                    // anyway so it probably makes no odds.
-                    false
+                Answer::WaitFor(_) => {
+                    // Note we could sleep for the interval in the WaitFor(), but the poll() sleeps
+                    // anyway so it probably makes no odds.
+                    false
+                }
+            }
+        };
+        Ok(did_something)
+    }
+
+    /// Start a task that will periodically "ping" the server to keep the session alive. The ping rate
+    /// will be 3/4 the session timeout rate.
+    ///
+    /// NOTE: This code assumes that the session_timeout period never changes, e.g. if you
+    /// connected to a server, negotiate a timeout period and then for whatever reason need to
+    /// reconnect to that same server, you will receive the same timeout. If you get a different
+    /// timeout then this code will not care and will continue to ping at the original rate.
+    fn spawn_session_activity_task(&self, session_timeout: f64) {
+        session_debug!(self, "spawn_session_activity_task({})", session_timeout);
+
+        let connection_state = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.connection_state()
+        };
+
+        let session_state = self.session_state.clone();
+
+        // Session activity will happen every 3/4 of the timeout period
+        const MIN_SESSION_ACTIVITY_MS: u64 = 1000;
+        let session_activity = cmp::max((session_timeout as u64 / 4) * 3, MIN_SESSION_ACTIVITY_MS);
+        session_debug!(
+            self,
+            "session timeout is {}, activity timer is {}",
+            session_timeout,
+            session_activity
+        );
+
+        let id = format!("session-activity-thread-{:?}", thread::current().id());
+        let runtime = trace_lock!(self.runtime);
+        runtime.spawn(async move {
+            register_runtime_component!(&id);
+            // The timer runs at a higher frequency timer loop to terminate as soon after the session
+            // state has terminated. Each time it runs it will test if the interval has elapsed or not.
+            let session_activity_interval = Duration::from_millis(session_activity);
+            let mut timer = interval(Duration::from_millis(MIN_SESSION_ACTIVITY_MS));
+            let mut last_timeout = Instant::now();
+
+            loop {
+                timer.tick().await;
+
+                if connection_state.is_finished() {
+                    info!("Session activity timer is terminating");
+                    break;
+                }
+
+                // Get the time now
+                let now = Instant::now();
+
+                // Calculate to interval since last check
+                let interval = now - last_timeout;
+                if interval > session_activity_interval {
+                    match connection_state.state() {
+                        ConnectionState::Processing => {
+                            info!("Session activity keep-alive request");
+                            let mut session_state = trace_write_lock!(session_state);
+                            let request_header = session_state.make_request_header();
+                            let request = ReadRequest {
+                                request_header,
+                                max_age: 1f64,
+                                timestamps_to_return: TimestampsToReturn::Server,
+                                nodes_to_read: Some(vec![]),
+                            };
+                            // The response to this is ignored
+                            let _ = session_state.async_send_keep_alive_request(request);
+                        }
+                        connection_state => {
+                            info!("Session activity keep-alive is doing nothing - connection state = {:?}", connection_state);
+                        }
+                    };
+                    last_timeout = now;
+                }
+            }
+
+            info!("Session activity timer task is finished");
+            deregister_runtime_component!(&id);
+        });
+    }
+
+    /// Start a thread that reads the server state when no response has come from the server within
+    /// the keep-alive interval. The interval is looked up each time so it may be changed while
+    /// connected, and nothing is sent while it is 0.
+    fn spawn_keep_alive_task(&self) {
+        session_debug!(self, "spawn_keep_alive_task");
+
+        let connection_state = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.connection_state()
+        };
+        let session_state = self.session_state.clone();
+
+        // How often a keep-alive is checked for, which also bounds how long the thread takes to
+        // stop
+        const KEEP_ALIVE_CHECK_MS: u64 = 1000;
+
+        let id = format!("keep-alive-thread-{:?}", thread::current().id());
+        thread::spawn(move || {
+            register_runtime_component!(&id);
+            loop {
+                let keep_alive_interval = {
+                    let session_state = trace_read_lock!(session_state);
+                    session_state.keep_alive_interval() as u64
+                };
+                let check_interval = if keep_alive_interval > 0 {
+                    cmp::min(keep_alive_interval, KEEP_ALIVE_CHECK_MS)
+                } else {
+                    KEEP_ALIVE_CHECK_MS
+                };
+                thread::sleep(Duration::from_millis(check_interval));
+
+                if connection_state.is_finished() {
+                    info!("Keep-alive thread is terminating");
+                    break;
+                }
+                if connection_state.state() != ConnectionState::Processing {
+                    continue;
+                }
+
+                let mut session_state = trace_write_lock!(session_state);
+                if session_state.keep_alive_due() {
+                    // A failure is logged and flagged by the session state
+                    let _ = session_state.keep_alive();
+                }
+            }
+            info!("Keep-alive thread is finished");
+            deregister_runtime_component!(&id);
+        });
+    }
+
+    /// Starts a thread that renews the security token of the secure channel once 75% of its
+    /// lifetime has elapsed. Otherwise the token is only renewed when a request is sent, so a
+    /// session that sends nothing for a while, e.g. one that only receives notifications, may be
+    /// left with an expired token. The thread stops when the session disconnects, so call this
+    /// again after a reconnect.
+    ///
+    /// # Returns
+    ///
+    /// * `JoinHandle<()>` - The handle of the thread.
+    ///
+    pub fn start_token_renewal(&self) -> thread::JoinHandle<()> {
+        session_debug!(self, "start_token_renewal");
+
+        let connection_state = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.connection_state()
+        };
+        let session_state = self.session_state.clone();
+        let secure_channel = self.secure_channel.clone();
+
+        // How often the token is checked, which also bounds how long the thread takes to stop
+        const TOKEN_RENEWAL_CHECK_MS: u64 = 1000;
+
+        let id = format!("token-renewal-thread-{:?}", thread::current().id());
+        thread::spawn(move || {
+            register_runtime_component!(&id);
+            loop {
+                thread::sleep(Duration::from_millis(TOKEN_RENEWAL_CHECK_MS));
+
+                if connection_state.is_finished() {
+                    info!("Token renewal thread is terminating");
+                    break;
+                }
+                if connection_state.state() != ConnectionState::Processing {
+                    continue;
+                }
+
+                let should_renew_security_token = {
+                    let secure_channel = trace_read_lock!(secure_channel);
+                    secure_channel.should_renew_security_token()
+                };
+                if should_renew_security_token {
+                    info!("Token renewal thread is renewing the security token");
+                    let mut session_state = trace_write_lock!(session_state);
+                    if let Err(status_code) = session_state.ensure_secure_channel_token() {
+                        error!("Security token could not be renewed, {}", status_code);
+                    }
                 }
             }
-        };
-        Ok(did_something)
+            info!("Token renewal thread is finished");
+            deregister_runtime_component!(&id);
+        })
     }
 
-    /// Start a task that will periodically "ping" the server to keep the session alive. The ping rate
-    /// will be 3/4 the session timeout rate.
-    ///
-    /// NOTE: This code assumes that the session_timeout period never changes, e.g. if you
-    /// connected to a server, negotiate a timeout period and then for whatever reason need to
-    /// reconnect to that same server, you will receive the same timeout. If you get a different
-    /// timeout then this code will not care and will continue to ping at the original rate.
-    fn spawn_session_activity_task(&self, session_timeout: f64) {
-        session_debug!(self, "spawn_session_activity_task({})", session_timeout);
+    /// Start a task that will periodically send a publish request to keep the subscriptions alive.
+    /// The request rate will be 3/4 of the shortest (revised publishing interval * the revised keep
+    /// alive count) of all subscriptions that belong to a single session.
+    fn spawn_subscription_activity_task(&self) {
+        session_debug!(self, "spawn_subscription_activity_task",);
 
         let connection_state = {
             let session_state = trace_read_lock!(self.session_state);
             session_state.connection_state()
         };
 
+        const MIN_SUBSCRIPTION_ACTIVITY_MS: u64 = 1000;
         let session_state = self.session_state.clone();
+        let subscription_state = self.subscription_state.clone();
 
-        // Session activity will happen every 3/4 of the timeout period
-        const MIN_SESSION_ACTIVITY_MS: u64 = 1000;
-        let session_activity = cmp::max((session_timeout as u64 / 4) * 3, MIN_SESSION_ACTIVITY_MS);
-        session_debug!(
-            self,
-            "session timeout is {}, activity timer is {}",
-            session_timeout,
-            session_activity
-        );
-
-        let id = format!("session-activity-thread-{:?}", thread::current().id());
+        let id = format!("subscription-activity-thread-{:?}", thread::current().id());
         let runtime = trace_lock!(self.runtime);
         runtime.spawn(async move {
             register_runtime_component!(&id);
+
             // The timer runs at a higher frequency timer loop to terminate as soon after the session
             // state has terminated. Each time it runs it will test if the interval has elapsed or not.
-            let session_activity_interval = Duration::from_millis(session_activity);
-            let mut timer = interval(Duration::from_millis(MIN_SESSION_ACTIVITY_MS));
-            let mut last_timeout = Instant::now();
+            let mut timer = interval(Duration::from_millis(MIN_SUBSCRIPTION_ACTIVITY_MS));
+
+            let mut last_timeout: Instant;
+            let mut subscription_activity_interval: Duration;
 
             loop {
                 timer.tick().await;
@@ -798,227 +2385,1126 @@ impl Session {
                     break;
                 }
 
-                // Get the time now
-                let now = Instant::now();
+                if let (Some(keep_alive_timeout), last_publish_request) = {
+                    let subscription_state = trace_read_lock!(subscription_state);
+                    (
+                        subscription_state.keep_alive_timeout(),
+                        subscription_state.last_publish_request(),
+                    )
+                } {
+                    subscription_activity_interval =
+                        Duration::from_millis((keep_alive_timeout / 4) * 3);
+                    last_timeout = last_publish_request;
 
-                // Calculate to interval since last check
-                let interval = now - last_timeout;
-                if interval > session_activity_interval {
-                    match connection_state.state() {
-                        ConnectionState::Processing => {
-                            info!("Session activity keep-alive request");
-                            let mut session_state = trace_write_lock!(session_state);
-                            let request_header = session_state.make_request_header();
-                            let request = ReadRequest {
-                                request_header,
-                                max_age: 1f64,
-                                timestamps_to_return: TimestampsToReturn::Server,
-                                nodes_to_read: Some(vec![]),
-                            };
-                            // The response to this is ignored
-                            let _ = session_state.async_send_request(request, None);
-                        }
-                        connection_state => {
-                            info!("Session activity keep-alive is doing nothing - connection state = {:?}", connection_state);
-                        }
-                    };
-                    last_timeout = now;
+                    // Get the time now
+                    let now = Instant::now();
+
+                    // Calculate to interval since last check
+                    let interval = now - last_timeout;
+                    if interval > subscription_activity_interval {
+                        let mut session_state = trace_write_lock!(session_state);
+                        let _ = session_state.async_publish();
+                    }
+                }
+            }
+
+            info!("Subscription activity timer task is finished");
+            deregister_runtime_component!(&id);
+        });
+    }
+
+    /// This is the internal handler for create subscription that receives the callback wrapped up and reference counted.
+    fn create_subscription_inner(
+        &self,
+        publishing_interval: f64,
+        lifetime_count: u32,
+        max_keep_alive_count: u32,
+        max_notifications_per_publish: u32,
+        priority: u8,
+        publishing_enabled: bool,
+        callback: Arc<Mutex<dyn OnSubscriptionNotification + Send + Sync + 'static>>,
+    ) -> Result<u32, StatusCode> {
+        if self.enforce_max_subscriptions_per_session {
+            if let Some(max_subscriptions) = self.max_subscriptions_per_session() {
+                let subscription_count = self.subscription_count();
+                if max_subscriptions > 0 && subscription_count >= max_subscriptions as usize {
+                    session_error!(
+                        self,
+                        "create_subscription, session has {} subscriptions which is the server's MaxSubscriptionsPerSession",
+                        subscription_count
+                    );
+                    return Err(StatusCode::BadTooManySubscriptions);
+                }
+            }
+        }
+        self.check_publishing_interval(publishing_interval)?;
+        let request = CreateSubscriptionRequest {
+            request_header: self.make_request_header(),
+            requested_publishing_interval: publishing_interval,
+            requested_lifetime_count: lifetime_count,
+            requested_max_keep_alive_count: max_keep_alive_count,
+            max_notifications_per_publish,
+            publishing_enabled,
+            priority,
+        };
+        let response = self.send_request(request)?;
+        if let SupportedMessage::CreateSubscriptionResponse(response) = response {
+            process_service_result(&response.response_header)?;
+            let subscription = Subscription::new(
+                response.subscription_id,
+                response.revised_publishing_interval,
+                response.revised_lifetime_count,
+                response.revised_max_keep_alive_count,
+                max_notifications_per_publish,
+                publishing_enabled,
+                priority,
+                callback,
+            );
+
+            // Track the new subscription and send an async publish request for it
+            {
+                let mut session_state = trace_write_lock!(self.session_state);
+                session_state.add_subscription(subscription);
+                let _ = session_state.async_publish();
+            }
+
+            session_debug!(
+                self,
+                "create_subscription, created a subscription with id {}",
+                response.subscription_id
+            );
+            Ok(response.subscription_id)
+        } else {
+            session_error!(self, "create_subscription failed {:?}", response);
+            Err(process_unexpected_response(response))
+        }
+    }
+
+    /// Deletes all subscriptions by sending a [`DeleteSubscriptionsRequest`] to the server with
+    /// ids for all subscriptions.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(u32, StatusCode)>)` - List of (id, status code) result for delete action on each id, `Good` or `BadSubscriptionIdInvalid`
+    /// * `Err(StatusCode)` - Status code reason for failure
+    ///
+    /// [`DeleteSubscriptionsRequest`]: ./struct.DeleteSubscriptionsRequest.html
+    ///
+    pub fn delete_all_subscriptions(&self) -> Result<Vec<(u32, StatusCode)>, StatusCode> {
+        let subscription_ids = {
+            let subscription_state = trace_read_lock!(self.subscription_state);
+            subscription_state.subscription_ids()
+        };
+        if let Some(ref subscription_ids) = subscription_ids {
+            let status_codes = self.delete_subscriptions(subscription_ids.as_slice())?;
+            // Return a list of (id, status_code) for each subscription
+            Ok(subscription_ids
+                .iter()
+                .zip(status_codes)
+                .map(|(id, status_code)| (*id, status_code))
+                .collect())
+        } else {
+            // No subscriptions
+            session_trace!(
+                self,
+                "delete_all_subscriptions, called when there are no subscriptions"
+            );
+            Err(StatusCode::BadNothingToDo)
+        }
+    }
+
+    /// Closes the session and deletes all subscriptions
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - if the session was closed
+    /// * `Err(StatusCode)` - Status code reason for failure
+    ///
+    /// [`CloseSessionRequest`]: ./struct.CloseSessionRequest.html
+    ///
+    pub fn close_session_and_delete_subscriptions(&self) -> Result<(), StatusCode> {
+        if !self.is_connected() {
+            return Err(StatusCode::BadNotConnected);
+        }
+        // for some operations like enumerating endpoints, there is no session equivalent
+        // on the server and it's a local helper object, only. In that case: nothing to do.
+        if trace_read_lock!(self.session_state).session_id().identifier == Identifier::Numeric(0) {
+            return Ok(());
+        }
+        let request = CloseSessionRequest {
+            delete_subscriptions: true,
+            request_header: self.make_request_header(),
+        };
+        let response = self.send_request(request)?;
+        if let SupportedMessage::CloseSessionResponse(_) = response {
+            let mut subscription_state = trace_write_lock!(self.subscription_state);
+            if let Some(subscription_ids) = subscription_state.subscription_ids() {
+                for subscription_id in subscription_ids {
+                    subscription_state.delete_subscription(subscription_id);
                 }
             }
+            Ok(())
+        } else {
+            session_error!(self, "close_session failed {:?}", response);
+            Err(process_unexpected_response(response))
+        }
+    }
+
+    /// Closes the session in an orderly way and then the secure channel, leaving the session with
+    /// no session id or authentication token. The secure channel is closed even if the server
+    /// fails to close the session. Unlike [`Session::disconnect`], the connection is left open.
+    ///
+    /// # Arguments
+    ///
+    /// * `delete_subscriptions` - whether the server deletes the session's subscriptions.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - if the session and secure channel were closed
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. why the session wasn't closed
+    ///
+    pub fn close_session(&self, delete_subscriptions: bool) -> Result<(), StatusCode> {
+        if !self.is_connected() {
+            return Err(StatusCode::BadNotConnected);
+        }
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.close_session(delete_subscriptions)
+    }
+
+    /// Subscribes to changes in the values of the nodes. A subscription is created with a
+    /// monitored item for each node, and the function is called with the node id and new value
+    /// of each change as notifications arrive, in the order they are received.
+    ///
+    /// The subscription is deleted again if its monitored items cannot be created. A node that
+    /// the server rejects, e.g. because it does not exist, is logged and otherwise ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `nodes` - The nodes whose values to watch.
+    /// * `publishing_interval` - The publishing interval of the subscription in milliseconds.
+    /// * `sampling_interval` - The sampling interval of the monitored items in milliseconds.
+    /// * `on_change` - The function that is called with each change of value.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u32)` - identifier for new subscription
+    /// * `Err(StatusCode)` - Status code reason for failure
+    ///
+    pub fn subscribe_values<CB>(
+        &self,
+        nodes: &[NodeId],
+        publishing_interval: f64,
+        sampling_interval: f64,
+        on_change: CB,
+    ) -> Result<u32, StatusCode>
+    where
+        CB: Fn(NodeId, DataValue) + Send + Sync + 'static,
+    {
+        if nodes.is_empty() {
+            session_error!(self, "subscribe_values, called with no nodes");
+            return Err(StatusCode::BadNothingToDo);
+        }
+        let callback = DataChangeCallback::new(move |changed_monitored_items| {
+            changed_monitored_items.iter().for_each(|item| {
+                let node_id = &item.item_to_monitor().node_id;
+                item.values()
+                    .iter()
+                    .for_each(|value| on_change(node_id.clone(), value.clone()));
+            });
+        });
+        // A lifetime of 3 keep alive intervals is the least the spec allows
+        let subscription_id =
+            self.create_subscription(publishing_interval, 30, 10, 0, 0, true, callback)?;
+
+        let items_to_create = nodes
+            .iter()
+            .map(|node_id| {
+                DataChangeMonitoredItemBuilder::new(node_id.clone())
+                    .sampling_interval(sampling_interval)
+                    .build()
+            })
+            .collect::<Vec<MonitoredItemCreateRequest>>();
+        match self.create_monitored_items(
+            subscription_id,
+            TimestampsToReturn::Both,
+            &items_to_create,
+        ) {
+            Ok(results) => {
+                nodes
+                    .iter()
+                    .zip(results.iter())
+                    .filter(|(_, result)| result.status_code.is_bad())
+                    .for_each(|(node_id, result)| {
+                        session_warn!(
+                            self,
+                            "subscribe_values, cannot monitor node {}, {}",
+                            node_id,
+                            result.status_code
+                        );
+                    });
+                Ok(subscription_id)
+            }
+            Err(status_code) => {
+                let _ = self.delete_subscription(subscription_id);
+                Err(status_code)
+            }
+        }
+    }
 
-            info!("Session activity timer task is finished");
-            deregister_runtime_component!(&id);
+    /// Subscribes to the events of an event source, e.g. the `Server` object. A subscription is
+    /// created with a monitored item that selects the fields of each event with the select
+    /// clauses, and the function is called with the name and value of each field of each event
+    /// as notifications arrive. Events whose fields do not match the select clauses are logged
+    /// and otherwise ignored.
+    ///
+    /// The subscription is deleted again if its monitored item cannot be created.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_source` - The node whose events to watch.
+    /// * `select_clauses` - The fields to select from each event. See
+    ///   [`EventMonitoredItemBuilder`].
+    /// * `where_clause` - The filter which events must pass to be reported, or `None` for all.
+    /// * `publishing_interval` - The publishing interval of the subscription in milliseconds.
+    /// * `on_event` - The function that is called with the fields of each event.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u32)` - identifier for new subscription
+    /// * `Err(StatusCode)` - Status code reason for failure
+    ///
+    /// [`EventMonitoredItemBuilder`]: ../struct.EventMonitoredItemBuilder.html
+    pub fn subscribe_events<T, CB>(
+        &self,
+        event_source: T,
+        select_clauses: Vec<SimpleAttributeOperand>,
+        where_clause: Option<ContentFilter>,
+        publishing_interval: f64,
+        on_event: CB,
+    ) -> Result<u32, StatusCode>
+    where
+        T: Into<NodeId>,
+        CB: Fn(Vec<(String, Variant)>) + Send + Sync + 'static,
+    {
+        if select_clauses.is_empty() {
+            session_error!(self, "subscribe_events, called with no select clauses");
+            return Err(StatusCode::BadNothingToDo);
+        }
+        let mut builder =
+            EventMonitoredItemBuilder::new(event_source).select_clauses(select_clauses);
+        if let Some(where_clause) = where_clause {
+            builder = builder.where_clause(where_clause);
+        }
+        let selected = builder.selected().to_vec();
+        let callback = EventCallback::new(move |events| {
+            events.events.iter().flatten().for_each(|event| {
+                if let Ok(fields) = decode_event_fields(&selected, event) {
+                    on_event(fields);
+                }
+            });
         });
+        let subscription_id =
+            self.create_subscription(publishing_interval, 30, 10, 0, 0, true, callback)?;
+
+        match self.create_monitored_items(
+            subscription_id,
+            TimestampsToReturn::Neither,
+            &[builder.build()],
+        ) {
+            Ok(results) if results.iter().all(|result| result.status_code.is_good()) => {
+                Ok(subscription_id)
+            }
+            result => {
+                let status_code = match result {
+                    Ok(results) => results
+                        .first()
+                        .map(|result| result.status_code)
+                        .unwrap_or(StatusCode::BadUnexpectedError),
+                    Err(status_code) => status_code,
+                };
+                session_warn!(
+                    self,
+                    "subscribe_events, cannot monitor events, {}",
+                    status_code
+                );
+                let _ = self.delete_subscription(subscription_id);
+                Err(status_code)
+            }
+        }
     }
 
-    /// Start a task that will periodically send a publish request to keep the subscriptions alive.
-    /// The request rate will be 3/4 of the shortest (revised publishing interval * the revised keep
-    /// alive count) of all subscriptions that belong to a single session.
-    fn spawn_subscription_activity_task(&self) {
-        session_debug!(self, "spawn_subscription_activity_task",);
+    /// Deletes a subscription, e.g. one created by [`Session::subscribe_values`], along with its
+    /// monitored items. Its callback is deregistered even if the server cannot be told, so it is
+    /// never called again, and any notifications for it that are still in flight are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `subscription_id` - subscription identifier returned from `subscribe_values` or
+    ///   `create_subscription`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - if the subscription was deleted
+    /// * `Err(StatusCode)` - Status code reason for failure
+    ///
+    pub fn unsubscribe(&self, subscription_id: u32) -> Result<(), StatusCode> {
+        let result = self.delete_subscription(subscription_id);
+        {
+            let mut session_state = trace_write_lock!(self.session_state);
+            let _ = session_state.remove_subscription(subscription_id);
+        }
+        match result {
+            Ok(status_code) if status_code.is_bad() => Err(status_code),
+            Ok(_) => Ok(()),
+            Err(status_code) => Err(status_code),
+        }
+    }
 
-        let connection_state = {
-            let session_state = trace_read_lock!(self.session_state);
-            session_state.connection_state()
+    /// Warns about any preferred locale that the server does not declare support for. The server
+    /// falls back to a locale of its choosing for those, so localized text may not be in the
+    /// language the client asked for.
+    fn check_preferred_locales(&self) {
+        match self.supported_locales() {
+            Ok(supported_locales) if !supported_locales.is_empty() => {
+                let is_supported = |locale: &str| {
+                    let language = locale.split('-').next().unwrap_or(locale);
+                    supported_locales
+                        .iter()
+                        .any(|l| l.eq_ignore_ascii_case(locale) || l.eq_ignore_ascii_case(language))
+                };
+                self.session_info
+                    .preferred_locales
+                    .iter()
+                    .filter(|l| !is_supported(l))
+                    .for_each(|l| {
+                        session_warn!(
+                            self,
+                            "Preferred locale {} is not supported by the server, supported locales are {:?}",
+                            l,
+                            supported_locales
+                        );
+                    });
+            }
+            Ok(_) => {}
+            Err(err) => {
+                session_debug!(self, "Cannot read the server's supported locales, {}", err);
+            }
+        }
+    }
+
+    /// Returns the names of an enumeration data type, read from its `EnumStrings` property. The
+    /// value of an enumeration is the index of its name in the list. Names are cached after the
+    /// first successful read so subsequent calls for the same data type do not go to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_type` - The node id of the enumeration data type.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` - The names of the enumeration, in value order.
+    /// * `Err(StatusCode)` - Status code reason for failure, e.g. `BadNoMatch` if the data type has
+    ///   no `EnumStrings` property.
+    ///
+    pub fn enum_strings(&self, data_type: &NodeId) -> Result<Vec<String>, StatusCode> {
+        {
+            let enum_strings = trace_read_lock!(self.enum_strings);
+            if let Some(names) = enum_strings.get(data_type) {
+                return Ok(names.clone());
+            }
+        }
+        let property = self.find_property(data_type, "EnumStrings")?;
+        let value = self
+            .read(&[property.into()], TimestampsToReturn::Neither, 0.0)?
+            .pop()
+            .ok_or(StatusCode::BadUnexpectedError)?;
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        let names = match value.value {
+            Some(Variant::Array(array)) => array
+                .values
+                .iter()
+                .map(|v| match v {
+                    Variant::LocalizedText(text) => Ok(text.text.as_ref().to_string()),
+                    _ => Err(StatusCode::BadTypeMismatch),
+                })
+                .collect::<Result<Vec<String>, StatusCode>>()?,
+            _ => return Err(StatusCode::BadTypeMismatch),
         };
+        let mut enum_strings = trace_write_lock!(self.enum_strings);
+        enum_strings.insert(data_type.clone(), names.clone());
+        Ok(names)
+    }
 
-        const MIN_SUBSCRIPTION_ACTIVITY_MS: u64 = 1000;
-        let session_state = self.session_state.clone();
-        let subscription_state = self.subscription_state.clone();
+    /// Returns the name of an enumeration value, e.g. to display a state as text rather than as
+    /// an integer. See `enum_strings()` for how the names are obtained.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_type` - The node id of the enumeration data type.
+    /// * `value` - The enumeration value.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(String)` - The name of the value.
+    /// * `None` - The names could not be read, or the value is out of their range.
+    ///
+    pub fn enum_name(&self, data_type: NodeId, value: i64) -> Option<String> {
+        match self.enum_strings(&data_type) {
+            Ok(names) => usize::try_from(value)
+                .ok()
+                .and_then(|i| names.get(i).cloned()),
+            Err(status_code) => {
+                session_debug!(
+                    self,
+                    "enum_name() could not get the names of {}, {}",
+                    data_type,
+                    status_code
+                );
+                None
+            }
+        }
+    }
 
-        let id = format!("subscription-activity-thread-{:?}", thread::current().id());
-        let runtime = trace_lock!(self.runtime);
-        runtime.spawn(async move {
-            register_runtime_component!(&id);
+    /// Returns the server's MinSupportedSampleRate, the fastest sampling interval in milliseconds
+    /// that the server supports for monitored items. The value is read once and cached.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(f64)` - The minimum supported sample rate in milliseconds.
+    /// * `None` - The server does not provide the value or it could not be read.
+    ///
+    pub fn min_supported_sample_rate(&self) -> Option<f64> {
+        self.cached_server_value(VariableId::Server_ServerCapabilities_MinSupportedSampleRate)
+            .ok()
+            .flatten()
+            .and_then(|v| v.as_f64().ok())
+    }
 
-            // The timer runs at a higher frequency timer loop to terminate as soon after the session
-            // state has terminated. Each time it runs it will test if the interval has elapsed or not.
-            let mut timer = interval(Duration::from_millis(MIN_SUBSCRIPTION_ACTIVITY_MS));
+    /// Returns the server's MaxMonitoredItemsPerCall, the most monitored items that may be
+    /// created, modified or deleted in one call. The value is read once and cached.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(u32)` - The maximum number of monitored items per call, or 0 for no limit.
+    /// * `None` - The server does not provide the value or it could not be read.
+    ///
+    pub fn max_monitored_items_per_call(&self) -> Option<u32> {
+        match self.cached_server_value(
+            VariableId::Server_ServerCapabilities_OperationLimits_MaxMonitoredItemsPerCall,
+        ) {
+            Ok(Some(Variant::UInt32(v))) => Some(v),
+            _ => None,
+        }
+    }
 
-            let mut last_timeout: Instant;
-            let mut subscription_activity_interval: Duration;
+    /// Returns the URIs of the profiles the server supports, read from its ServerProfileArray.
+    /// A client can use these to decide if the server supports a facet it needs, e.g. historical
+    /// access or method calls, before trying to use it. The value is read once and cached.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` - The profile URIs. This is empty if the server does not provide them.
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    pub fn server_profiles(&self) -> Result<Vec<String>, StatusCode> {
+        match self.cached_server_value(VariableId::Server_ServerCapabilities_ServerProfileArray)? {
+            Some(Variant::Array(array)) => array
+                .values
+                .iter()
+                .map(|v| match v {
+                    Variant::String(uri) => Ok(uri.as_ref().to_string()),
+                    _ => Err(StatusCode::BadTypeMismatch),
+                })
+                .collect(),
+            Some(Variant::Empty) | None => Ok(Vec::new()),
+            Some(_) => Err(StatusCode::BadTypeMismatch),
+        }
+    }
 
-            loop {
-                timer.tick().await;
+    /// Sets whether creating monitored items with a sampling interval below the server's
+    /// MinSupportedSampleRate fails with `BadOutOfRange`. When not set, which is the default,
+    /// a warning is logged and the server revises the interval up.
+    ///
+    /// # Arguments
+    ///
+    /// * `reject` - true to reject intervals below the minimum supported sample rate.
+    ///
+    pub fn set_reject_unsupported_sampling_intervals(&mut self, reject: bool) {
+        self.reject_unsupported_sampling_intervals = reject;
+    }
 
-                if connection_state.is_finished() {
-                    info!("Session activity timer is terminating");
-                    break;
-                }
+    /// Returns the server's MaxSubscriptionsPerSession, the most subscriptions a session may
+    /// have. The value is read once and cached.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(u32)` - The maximum number of subscriptions per session, or 0 for no limit.
+    /// * `None` - The server does not provide the value or it could not be read.
+    ///
+    pub fn max_subscriptions_per_session(&self) -> Option<u32> {
+        match self.cached_server_capability("MaxSubscriptionsPerSession") {
+            Ok(Some(Variant::UInt32(v))) => Some(v),
+            _ => None,
+        }
+    }
 
-                if let (Some(keep_alive_timeout), last_publish_request) = {
-                    let subscription_state = trace_read_lock!(subscription_state);
-                    (
-                        subscription_state.keep_alive_timeout(),
-                        subscription_state.last_publish_request(),
-                    )
-                } {
-                    subscription_activity_interval =
-                        Duration::from_millis((keep_alive_timeout / 4) * 3);
-                    last_timeout = last_publish_request;
+    /// Sets whether creating a subscription that would exceed the server's
+    /// MaxSubscriptionsPerSession fails with `BadTooManySubscriptions` without being sent. When
+    /// not set, which is the default, the limit is not read and the server enforces it.
+    ///
+    /// # Arguments
+    ///
+    /// * `enforce` - true to check the limit before creating a subscription.
+    ///
+    pub fn set_enforce_max_subscriptions_per_session(&mut self, enforce: bool) {
+        self.enforce_max_subscriptions_per_session = enforce;
+    }
 
-                    // Get the time now
-                    let now = Instant::now();
+    /// Returns the server's MinPublishingInterval, the fastest publishing interval in
+    /// milliseconds that the server supports for subscriptions. A subscription asking for a
+    /// faster interval is revised up to it. Not every server provides the value. It is read once
+    /// and cached.
+    ///
+    /// The value is the `MinPublishingInterval` property of the server's `ServerCapabilities`
+    /// object. The property isn't in the OPC UA 1.04 node set that the node ids of this crate are
+    /// generated from, so there is no well known node id for it and it is found by its browse
+    /// name.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(f64)` - The minimum publishing interval in milliseconds.
+    /// * `None` - The server does not provide the value or it could not be read.
+    ///
+    pub fn min_publishing_interval(&self) -> Option<f64> {
+        self.cached_server_capability("MinPublishingInterval")
+            .ok()
+            .flatten()
+            .and_then(|v| v.as_f64().ok())
+    }
 
-                    // Calculate to interval since last check
-                    let interval = now - last_timeout;
-                    if interval > subscription_activity_interval {
-                        let mut session_state = trace_write_lock!(session_state);
-                        let _ = session_state.async_publish();
-                    }
-                }
-            }
+    /// Sets whether creating a subscription with a publishing interval below the server's
+    /// MinPublishingInterval fails with `BadOutOfRange` without being sent. When not set, which
+    /// is the default, a warning is logged and the server revises the interval up.
+    ///
+    /// # Arguments
+    ///
+    /// * `reject` - true to reject intervals below the minimum publishing interval.
+    ///
+    pub fn set_reject_unsupported_publishing_intervals(&mut self, reject: bool) {
+        self.reject_unsupported_publishing_intervals = reject;
+    }
 
-            info!("Subscription activity timer task is finished");
-            deregister_runtime_component!(&id);
-        });
+    /// Returns the number of subscriptions the session has.
+    pub fn subscription_count(&self) -> usize {
+        let subscription_state = trace_read_lock!(self.subscription_state);
+        subscription_state.subscription_count()
     }
 
-    /// This is the internal handler for create subscription that receives the callback wrapped up and reference counted.
-    fn create_subscription_inner(
+    /// Creates a batch of monitored items in a single request, recording them in the
+    /// subscription state.
+    fn create_monitored_items_batch(
         &self,
-        publishing_interval: f64,
-        lifetime_count: u32,
-        max_keep_alive_count: u32,
-        max_notifications_per_publish: u32,
-        priority: u8,
-        publishing_enabled: bool,
-        callback: Arc<Mutex<dyn OnSubscriptionNotification + Send + Sync + 'static>>,
-    ) -> Result<u32, StatusCode> {
-        let request = CreateSubscriptionRequest {
+        subscription_id: u32,
+        timestamps_to_return: TimestampsToReturn,
+        items_to_create: &[MonitoredItemCreateRequest],
+    ) -> Result<Vec<MonitoredItemCreateResult>, StatusCode> {
+        let request = CreateMonitoredItemsRequest {
             request_header: self.make_request_header(),
-            requested_publishing_interval: publishing_interval,
-            requested_lifetime_count: lifetime_count,
-            requested_max_keep_alive_count: max_keep_alive_count,
-            max_notifications_per_publish,
-            publishing_enabled,
-            priority,
+            subscription_id,
+            timestamps_to_return,
+            items_to_create: Some(items_to_create.to_vec()),
         };
         let response = self.send_request(request)?;
-        if let SupportedMessage::CreateSubscriptionResponse(response) = response {
+        if let SupportedMessage::CreateMonitoredItemsResponse(response) = response {
             process_service_result(&response.response_header)?;
-            let subscription = Subscription::new(
-                response.subscription_id,
-                response.revised_publishing_interval,
-                response.revised_lifetime_count,
-                response.revised_max_keep_alive_count,
-                max_notifications_per_publish,
-                publishing_enabled,
-                priority,
-                callback,
-            );
+            if let Some(ref results) = response.results {
+                session_debug!(
+                    self,
+                    "create_monitored_items, {} items created",
+                    items_to_create.len()
+                );
+                // Set the items in our internal state
+                let items_to_create = items_to_create
+                    .iter()
+                    .zip(results)
+                    .map(|(i, r)| subscription::CreateMonitoredItem {
+                        id: r.monitored_item_id,
+                        client_handle: i.requested_parameters.client_handle,
+                        discard_oldest: i.requested_parameters.discard_oldest,
+                        item_to_monitor: i.item_to_monitor.clone(),
+                        monitoring_mode: i.monitoring_mode,
+                        queue_size: r.revised_queue_size,
+                        sampling_interval: r.revised_sampling_interval,
+                    })
+                    .collect::<Vec<subscription::CreateMonitoredItem>>();
+                {
+                    let mut subscription_state = trace_write_lock!(self.subscription_state);
+                    subscription_state.insert_monitored_items(subscription_id, &items_to_create);
+                }
+            } else {
+                session_error!(
+                    self,
+                    "create_monitored_items, success but the response has no results"
+                );
+                return Err(StatusCode::BadUnexpectedError);
+            }
+            Ok(response.results.unwrap())
+        } else {
+            session_error!(self, "create_monitored_items failed {:?}", response);
+            Err(process_unexpected_response(response))
+        }
+    }
+
+    /// Checks the requested sampling intervals against the server's MinSupportedSampleRate. An
+    /// interval of 0 (fastest practical) or below (use the publishing interval) is not checked.
+    fn check_sampling_intervals(
+        &self,
+        items_to_create: &[MonitoredItemCreateRequest],
+    ) -> Result<(), StatusCode> {
+        let min_supported_sample_rate = if let Some(v) = self.min_supported_sample_rate() {
+            v
+        } else {
+            return Ok(());
+        };
+        let mut unsupported = false;
+        items_to_create
+            .iter()
+            .filter(|i| {
+                let sampling_interval = i.requested_parameters.sampling_interval;
+                sampling_interval > 0.0 && sampling_interval < min_supported_sample_rate
+            })
+            .for_each(|i| {
+                unsupported = true;
+                session_warn!(
+                    self,
+                    "Monitored item for {} requests sampling interval {}ms which is below the server's MinSupportedSampleRate of {}ms",
+                    i.item_to_monitor.node_id,
+                    i.requested_parameters.sampling_interval,
+                    min_supported_sample_rate
+                );
+            });
+        if unsupported && self.reject_unsupported_sampling_intervals {
+            Err(StatusCode::BadOutOfRange)
+        } else {
+            Ok(())
+        }
+    }
 
-            // Add the new subscription to the subscription state
+    /// Checks the requested publishing interval against the server's MinPublishingInterval. An
+    /// interval below the minimum is logged, and rejected if the session is set to.
+    fn check_publishing_interval(&self, publishing_interval: f64) -> Result<(), StatusCode> {
+        match self.min_publishing_interval() {
+            Some(min_publishing_interval)
+                if publishing_interval > 0.0 && publishing_interval < min_publishing_interval =>
             {
-                let mut subscription_state = trace_write_lock!(self.subscription_state);
-                subscription_state.add_subscription(subscription);
+                session_warn!(
+                    self,
+                    "Subscription requests publishing interval {}ms which is below the server's MinPublishingInterval of {}ms",
+                    publishing_interval,
+                    min_publishing_interval
+                );
+                if self.reject_unsupported_publishing_intervals {
+                    Err(StatusCode::BadOutOfRange)
+                } else {
+                    Ok(())
+                }
             }
+            _ => Ok(()),
+        }
+    }
 
-            // Send an async publish request for this new subscription
-            {
-                let mut session_state = trace_write_lock!(self.session_state);
-                let _ = session_state.async_publish();
+    /// Reads the value of a server variable that does not change during the session, such as a
+    /// server capability, caching it so it is only read from the server once.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(Variant))` - The value of the variable.
+    /// * `Ok(None)` - The server does not provide the variable.
+    /// * `Err(StatusCode)` - The read failed, in which case nothing is cached.
+    ///
+    fn cached_server_value<T>(&self, node_id: T) -> Result<Option<Variant>, StatusCode>
+    where
+        T: Into<NodeId>,
+    {
+        let node_id: NodeId = node_id.into();
+        {
+            let server_values = trace_read_lock!(self.server_values);
+            if let Some(value) = server_values.get(&node_id) {
+                return Ok(value.clone());
             }
-
+        }
+        let value = self
+            .read(&[node_id.clone().into()], TimestampsToReturn::Neither, 0.0)?
+            .pop()
+            .ok_or(StatusCode::BadUnexpectedError)?;
+        let value = if value.status().is_bad() {
             session_debug!(
                 self,
-                "create_subscription, created a subscription with id {}",
-                response.subscription_id
+                "Server variable {} could not be read, {}",
+                node_id,
+                value.status()
             );
-            Ok(response.subscription_id)
+            None
         } else {
-            session_error!(self, "create_subscription failed {:?}", response);
-            Err(process_unexpected_response(response))
+            value.value
+        };
+        let mut server_values = trace_write_lock!(self.server_values);
+        server_values.insert(node_id, value.clone());
+        Ok(value)
+    }
+
+    /// Reads the value of a property of the server's ServerCapabilities by its browse name, for
+    /// capabilities added by later versions of OPC UA that have no well known node id. The
+    /// property is found and read once and cached, as for [`Session::cached_server_value`].
+    fn cached_server_capability(&self, browse_name: &str) -> Result<Option<Variant>, StatusCode> {
+        let node_id = {
+            let server_capabilities = trace_read_lock!(self.server_capabilities);
+            server_capabilities.get(browse_name).cloned()
+        };
+        let node_id = match node_id {
+            Some(node_id) => node_id,
+            None => {
+                let server_capabilities: NodeId = ObjectId::Server_ServerCapabilities.into();
+                let node_id = match self.find_property(&server_capabilities, browse_name) {
+                    Ok(node_id) => Some(node_id),
+                    Err(StatusCode::BadNoMatch) | Err(StatusCode::BadNodeIdUnknown) => {
+                        session_debug!(self, "Server does not have the capability {}", browse_name);
+                        None
+                    }
+                    Err(status_code) => return Err(status_code),
+                };
+                let mut server_capabilities = trace_write_lock!(self.server_capabilities);
+                server_capabilities.insert(browse_name.to_string(), node_id.clone());
+                node_id
+            }
+        };
+        match node_id {
+            Some(node_id) => self.cached_server_value(node_id),
+            None => Ok(None),
         }
     }
 
-    /// Deletes all subscriptions by sending a [`DeleteSubscriptionsRequest`] to the server with
-    /// ids for all subscriptions.
+    /// Reads the scaling and display information of an analog item, i.e. its `EURange`,
+    /// `EngineeringUnits` and `InstrumentRange` properties, e.g. to scale a bar graph and label
+    /// it with the units. The properties are found and read with one request each for all three.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// * `Ok(Vec<(u32, StatusCode)>)` - List of (id, status code) result for delete action on each id, `Good` or `BadSubscriptionIdInvalid`
-    /// * `Err(StatusCode)` - Status code reason for failure
+    /// * `node_id` - The node id of the analog item.
     ///
-    /// [`DeleteSubscriptionsRequest`]: ./struct.DeleteSubscriptionsRequest.html
+    /// # Returns
     ///
-    pub fn delete_all_subscriptions(&self) -> Result<Vec<(u32, StatusCode)>, StatusCode> {
-        let subscription_ids = {
-            let subscription_state = trace_read_lock!(self.subscription_state);
-            subscription_state.subscription_ids()
-        };
-        if let Some(ref subscription_ids) = subscription_ids {
-            let status_codes = self.delete_subscriptions(subscription_ids.as_slice())?;
-            // Return a list of (id, status_code) for each subscription
-            Ok(subscription_ids
-                .iter()
-                .zip(status_codes)
-                .map(|(id, status_code)| (*id, status_code))
-                .collect())
+    /// * `Ok(AnalogMetadata)` - The properties. Any the node doesn't have, or that cannot be read,
+    ///   are `None`.
+    /// * `Err(StatusCode)` - Status code reason for failure.
+    ///
+    pub fn read_analog_metadata(&self, node_id: NodeId) -> Result<AnalogMetadata, StatusCode> {
+        const PROPERTIES: [&str; 3] = ["EURange", "EngineeringUnits", "InstrumentRange"];
+        let browse_paths = PROPERTIES
+            .iter()
+            .map(|browse_name| Self::property_path(&node_id, browse_name))
+            .collect::<Vec<_>>();
+        let results = self.translate_browse_paths_to_node_ids(&browse_paths)?;
+        if results.len() != PROPERTIES.len() {
+            return Err(StatusCode::BadUnexpectedError);
+        }
+        // Only the properties that exist are read
+        let properties = results
+            .into_iter()
+            .map(|result| {
+                if result.status_code.is_bad() {
+                    None
+                } else {
+                    result
+                        .targets
+                        .and_then(|targets| targets.into_iter().next())
+                        .map(|target| target.target_id.node_id)
+                }
+            })
+            .collect::<Vec<_>>();
+        let nodes_to_read = properties
+            .iter()
+            .flatten()
+            .map(|node_id| node_id.clone().into())
+            .collect::<Vec<ReadValueId>>();
+        let mut values = if nodes_to_read.is_empty() {
+            Vec::new()
         } else {
-            // No subscriptions
-            session_trace!(
-                self,
-                "delete_all_subscriptions, called when there are no subscriptions"
-            );
-            Err(StatusCode::BadNothingToDo)
+            let values = self.read(&nodes_to_read, TimestampsToReturn::Neither, 0.0)?;
+            if values.len() != nodes_to_read.len() {
+                return Err(StatusCode::BadUnexpectedError);
+            }
+            values
         }
+        .into_iter();
+        // Line the values back up with the properties
+        let mut values = properties
+            .iter()
+            .map(|property| property.as_ref().and_then(|_| values.next()));
+        let mut next_value = || values.next().flatten();
+        let decoding_options = self.decoding_options();
+        Ok(AnalogMetadata {
+            eu_range: Self::decode_property(
+                next_value(),
+                ObjectId::Range_Encoding_DefaultBinary,
+                &decoding_options,
+            ),
+            engineering_units: Self::decode_property(
+                next_value(),
+                ObjectId::EUInformation_Encoding_DefaultBinary,
+                &decoding_options,
+            ),
+            instrument_range: Self::decode_property(
+                next_value(),
+                ObjectId::Range_Encoding_DefaultBinary,
+                &decoding_options,
+            ),
+        })
     }
 
-    /// Closes the session and deletes all subscriptions
+    /// Reads the input and output arguments of a method from its `InputArguments` and
+    /// `OutputArguments` properties, e.g. to build a form for calling the method or to check
+    /// the inputs before calling it. The properties are found and read with one request each.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// * `Ok(())` - if the session was closed
-    /// * `Err(StatusCode)` - Status code reason for failure
+    /// * `method_id` - The node id of the method.
     ///
-    /// [`CloseSessionRequest`]: ./struct.CloseSessionRequest.html
+    /// # Returns
     ///
-    pub fn close_session_and_delete_subscriptions(&self) -> Result<(), StatusCode> {
-        if !self.is_connected() {
-            return Err(StatusCode::BadNotConnected);
+    /// * `Ok(MethodArguments)` - The arguments. A list is empty if the method doesn't have the
+    ///   property.
+    /// * `Err(StatusCode)` - Status code reason for failure. `BadTypeMismatch` if a property
+    ///   doesn't hold an array of `Argument`.
+    ///
+    pub fn read_method_arguments(&self, method_id: NodeId) -> Result<MethodArguments, StatusCode> {
+        const PROPERTIES: [&str; 2] = ["InputArguments", "OutputArguments"];
+        let browse_paths = PROPERTIES
+            .iter()
+            .map(|browse_name| Self::property_path(&method_id, browse_name))
+            .collect::<Vec<_>>();
+        let results = self.translate_browse_paths_to_node_ids(&browse_paths)?;
+        if results.len() != PROPERTIES.len() {
+            return Err(StatusCode::BadUnexpectedError);
         }
-        // for some operations like enumerating endpoints, there is no session equivalent
-        // on the server and it's a local helper object, only. In that case: nothing to do.
-        if trace_read_lock!(self.session_state).session_id().identifier == Identifier::Numeric(0) {
-            return Ok(());
+        // Only the properties that exist are read
+        let properties = results
+            .into_iter()
+            .map(|result| {
+                if result.status_code.is_bad() {
+                    None
+                } else {
+                    result
+                        .targets
+                        .and_then(|targets| targets.into_iter().next())
+                        .map(|target| target.target_id.node_id)
+                }
+            })
+            .collect::<Vec<_>>();
+        let nodes_to_read = properties
+            .iter()
+            .flatten()
+            .map(|node_id| node_id.clone().into())
+            .collect::<Vec<ReadValueId>>();
+        let mut values = if nodes_to_read.is_empty() {
+            Vec::new()
+        } else {
+            let values = self.read(&nodes_to_read, TimestampsToReturn::Neither, 0.0)?;
+            if values.len() != nodes_to_read.len() {
+                return Err(StatusCode::BadUnexpectedError);
+            }
+            values
         }
-        let request = CloseSessionRequest {
-            delete_subscriptions: true,
-            request_header: self.make_request_header(),
+        .into_iter();
+        let decoding_options = self.decoding_options();
+        let mut arguments = properties.iter().map(|property| match property {
+            Some(_) => values
+                .next()
+                .map(|value| Self::decode_arguments(value, &decoding_options))
+                .unwrap_or(Err(StatusCode::BadUnexpectedError)),
+            None => Ok(Vec::new()),
+        });
+        let input_arguments = arguments.next().unwrap_or_else(|| Ok(Vec::new()))?;
+        let output_arguments = arguments.next().unwrap_or_else(|| Ok(Vec::new()))?;
+        let method_arguments = MethodArguments {
+            input_arguments,
+            output_arguments,
         };
-        let response = self.send_request(request)?;
-        if let SupportedMessage::CloseSessionResponse(_) = response {
-            let mut subscription_state = trace_write_lock!(self.subscription_state);
-            if let Some(subscription_ids) = subscription_state.subscription_ids() {
-                for subscription_id in subscription_ids {
-                    subscription_state.delete_subscription(subscription_id);
+        // Kept to check the inputs of calls to the method
+        let mut cache = trace_write_lock!(self.method_arguments);
+        cache.insert(method_id, method_arguments.clone());
+        Ok(method_arguments)
+    }
+
+    /// Sets whether the inputs of a call are checked against the input arguments of the method
+    /// before the call is sent, failing with `BadInvalidArgument` if they differ in number, data
+    /// type or value rank. Only methods whose arguments have been read with
+    /// [`Session::read_method_arguments`] are checked, so calls don't cost an extra read. The
+    /// default is not to check. See [`MethodArguments::validate_inputs`] for what is checked.
+    ///
+    /// # Arguments
+    ///
+    /// * `validate` - true to check the inputs of calls to methods whose arguments are known.
+    ///
+    pub fn set_validate_method_arguments(&mut self, validate: bool) {
+        self.validate_method_arguments = validate;
+    }
+
+    /// Decodes the value of an `InputArguments` or `OutputArguments` property, which is an array
+    /// of `Argument` structures. A null value is taken as no arguments.
+    fn decode_arguments(
+        value: DataValue,
+        decoding_options: &DecodingOptions,
+    ) -> Result<Vec<Argument>, StatusCode> {
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        let values = match value.value {
+            None | Some(Variant::Empty) => return Ok(Vec::new()),
+            Some(Variant::Array(array)) => array.values,
+            Some(Variant::ExtensionObject(extension_object)) => {
+                vec![Variant::ExtensionObject(extension_object)]
+            }
+            Some(_) => return Err(StatusCode::BadTypeMismatch),
+        };
+        values
+            .into_iter()
+            .map(|value| match value {
+                Variant::ExtensionObject(extension_object)
+                    if extension_object.object_id().ok()
+                        == Some(ObjectId::Argument_Encoding_DefaultBinary) =>
+                {
+                    extension_object.decode_inner::<Argument>(decoding_options)
                 }
+                _ => Err(StatusCode::BadTypeMismatch),
+            })
+            .collect()
+    }
+
+    /// Reads the diagnostics that the server keeps for this session, i.e. its count of each
+    /// service the session has called, e.g. to reconcile the server's view of the session's
+    /// activity with the client's own [`Session::diagnostics`]. The server's diagnostics object
+    /// for a session has the session id as its node id, and the summary is read from its
+    /// `SessionDiagnostics` variable. A server that doesn't expose session diagnostics answers
+    /// with `BadNoMatch` or `BadNodeIdUnknown`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ServerSessionDiagnostics)` - The server's diagnostics for the session.
+    /// * `Err(StatusCode)` - Status code reason for failure. `BadSessionIdInvalid` if the session
+    ///   has not been created.
+    ///
+    pub fn read_server_session_diagnostics(&self) -> Result<ServerSessionDiagnostics, StatusCode> {
+        let session_id = trace_read_lock!(self.session_state).session_id();
+        if session_id.is_null() {
+            session_error!(
+                self,
+                "read_server_session_diagnostics, the session has no id yet"
+            );
+            return Err(StatusCode::BadSessionIdInvalid);
+        }
+        let browse_path = BrowsePath {
+            starting_node: session_id,
+            relative_path: RelativePath {
+                elements: Some(vec![RelativePathElement {
+                    reference_type_id: ReferenceTypeId::HasComponent.into(),
+                    is_inverse: false,
+                    include_subtypes: true,
+                    target_name: QualifiedName::new(0, "SessionDiagnostics"),
+                }]),
+            },
+        };
+        let result = self
+            .translate_browse_paths_to_node_ids(&[browse_path])?
+            .pop()
+            .ok_or(StatusCode::BadUnexpectedError)?;
+        if result.status_code.is_bad() {
+            return Err(result.status_code);
+        }
+        let node_id = result
+            .targets
+            .and_then(|targets| targets.into_iter().next())
+            .map(|target| target.target_id.node_id)
+            .ok_or(StatusCode::BadNoMatch)?;
+        let value = self
+            .read(&[node_id.into()], TimestampsToReturn::Neither, 0.0)?
+            .pop()
+            .ok_or(StatusCode::BadUnexpectedError)?;
+        if value.status().is_bad() {
+            return Err(value.status());
+        }
+        match value.value {
+            Some(Variant::ExtensionObject(extension_object))
+                if extension_object.object_id().ok()
+                    == Some(ObjectId::SessionDiagnosticsDataType_Encoding_DefaultBinary) =>
+            {
+                extension_object
+                    .decode_inner::<SessionDiagnosticsDataType>(&self.decoding_options())
+                    .map(ServerSessionDiagnostics::from)
             }
-            Ok(())
+            _ => {
+                session_error!(
+                    self,
+                    "read_server_session_diagnostics, the value is not a SessionDiagnosticsDataType"
+                );
+                Err(StatusCode::BadTypeMismatch)
+            }
+        }
+    }
+
+    /// Decodes the structure in the value of a property, or returns `None` if the value is
+    /// missing, bad or holds some other type.
+    fn decode_property<T>(
+        value: Option<DataValue>,
+        object_id: ObjectId,
+        decoding_options: &DecodingOptions,
+    ) -> Option<T>
+    where
+        T: BinaryEncoder<T>,
+    {
+        let value = value.filter(|value| !value.status().is_bad())?;
+        match value.value {
+            Some(Variant::ExtensionObject(extension_object))
+                if extension_object.object_id().ok() == Some(object_id) =>
+            {
+                extension_object.decode_inner::<T>(decoding_options).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Makes the browse path from a node to one of its properties.
+    fn property_path(node_id: &NodeId, browse_name: &str) -> BrowsePath {
+        BrowsePath {
+            starting_node: node_id.clone(),
+            relative_path: RelativePath {
+                elements: Some(vec![RelativePathElement {
+                    reference_type_id: ReferenceTypeId::HasProperty.into(),
+                    is_inverse: false,
+                    include_subtypes: true,
+                    target_name: QualifiedName::new(0, browse_name),
+                }]),
+            },
+        }
+    }
+
+    /// Finds the node id of a property of a node, by the browse name of the property.
+    fn find_property(&self, node_id: &NodeId, browse_name: &str) -> Result<NodeId, StatusCode> {
+        let browse_path = Self::property_path(node_id, browse_name);
+        let result = self
+            .translate_browse_paths_to_node_ids(&[browse_path])?
+            .pop()
+            .ok_or(StatusCode::BadUnexpectedError)?;
+        if result.status_code.is_bad() {
+            Err(result.status_code)
         } else {
-            session_error!(self, "close_session failed {:?}", response);
-            Err(process_unexpected_response(response))
+            result
+                .targets
+                .and_then(|targets| targets.into_iter().next())
+                .map(|target| target.target_id.node_id)
+                .ok_or(StatusCode::BadNoMatch)
         }
     }
 
@@ -1052,6 +3538,37 @@ impl Session {
         subscription_state.subscription_exists(subscription_id)
     }
 
+    /// Returns the user identity to activate the session with, which is the one set on the
+    /// session state if there is one, or else the one the session was made with.
+    fn identity_token(&self) -> IdentityToken {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state
+            .user_identity_token()
+            .cloned()
+            .unwrap_or_else(|| self.session_info.user_identity_token.clone())
+    }
+
+    /// Logs that the server would not activate the session with the user identity, if that is
+    /// why activation failed, since the status code alone doesn't say which identity was used.
+    fn log_identity_token_error(&self, status_code: StatusCode) {
+        if let StatusCode::BadIdentityTokenRejected
+        | StatusCode::BadIdentityTokenInvalid
+        | StatusCode::BadUserAccessDenied = status_code
+        {
+            let user_token_type = match self.identity_token() {
+                IdentityToken::Anonymous => UserTokenType::Anonymous,
+                IdentityToken::UserName(_, _) => UserTokenType::UserName,
+                IdentityToken::X509(_, _) => UserTokenType::Certificate,
+            };
+            session_error!(
+                self,
+                "activate_session, the server did not accept the {:?} user identity, {}",
+                user_token_type,
+                status_code
+            );
+        }
+    }
+
     // Creates a user identity token according to the endpoint, policy that the client is currently connected to the
     // server with.
     fn user_identity_token(
@@ -1059,7 +3576,7 @@ impl Session {
         server_cert: &Option<X509>,
         server_nonce: &[u8],
     ) -> Result<(ExtensionObject, SignatureData), StatusCode> {
-        let user_identity_token = &self.session_info.user_identity_token;
+        let user_identity_token = &self.identity_token();
         let user_token_type = match user_identity_token {
             IdentityToken::Anonymous => UserTokenType::Anonymous,
             IdentityToken::UserName(_, _) => UserTokenType::UserName,
@@ -1111,6 +3628,7 @@ impl Session {
                             let identity_token = self.make_user_name_identity_token(
                                 &secure_channel,
                                 policy,
+                                server_nonce,
                                 user,
                                 pass,
                             )?;
@@ -1175,32 +3693,84 @@ impl Session {
     }
 
     /// Create a filled in UserNameIdentityToken by using the endpoint's token policy, the current
-    /// secure channel information and the user name and password.
+    /// secure channel information, the server's nonce and the user name and password.
     fn make_user_name_identity_token(
         &self,
         secure_channel: &SecureChannel,
         user_token_policy: &UserTokenPolicy,
+        server_nonce: &[u8],
         user: &str,
         pass: &str,
     ) -> Result<UserNameIdentityToken, StatusCode> {
         let channel_security_policy = secure_channel.security_policy();
-        let nonce = secure_channel.remote_nonce();
         let cert = secure_channel.remote_cert();
         make_user_name_identity_token(
             channel_security_policy,
             user_token_policy,
-            nonce,
+            server_nonce,
             &cert,
             user,
             pass,
         )
     }
+
+    /// Blocks until every request sent so far, e.g. with `async_send_request`, has been written to
+    /// the transport. This does not wait for responses, so it is a point after which the queued
+    /// requests are known to be on the wire. Fails with `BadTimeout` if that takes longer than
+    /// the request timeout.
+    pub fn flush(&self) -> Result<(), StatusCode> {
+        let session_state = trace_read_lock!(self.session_state);
+        session_state.flush()
+    }
+
+    /// Synchronously sends a request like [`Service::send_request`], but an error says which
+    /// request failed, i.e. its request handle and type, so it can be matched up with the log.
+    /// A service fault in response to the request is also returned as an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - the request to send.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(SupportedMessage)` - the response to the request
+    /// * `Err(RequestError)` - the status code of the failure with the request handle and type
+    pub fn send_request_detailed<T>(&self, request: T) -> Result<SupportedMessage, RequestError>
+    where
+        T: Into<SupportedMessage>,
+    {
+        let request = request.into();
+        let request_handle = request.request_handle();
+        let request_type = request.type_name();
+        let request_error = |status_code| RequestError {
+            status_code,
+            request_handle,
+            request_type,
+        };
+        let request = self
+            .reopen_idle_for_request(request)
+            .map_err(request_error)?;
+        let response = {
+            let mut session_state = self.lock_for_request(&request).map_err(request_error)?;
+            session_state.send_request(request)?
+        };
+        if let SupportedMessage::ServiceFault(_) = response {
+            Err(RequestError {
+                status_code: process_unexpected_response(response),
+                request_handle,
+                request_type,
+            })
+        } else {
+            Ok(response)
+        }
+    }
 }
 
 impl Service for Session {
     /// Construct a request header for the session. All requests after create session are expected
     /// to supply an authentication token.
     fn make_request_header(&self) -> RequestHeader {
+        self.reopen_idle();
         let mut session_state = trace_write_lock!(self.session_state);
         session_state.make_request_header()
     }
@@ -1210,8 +3780,11 @@ impl Service for Session {
     where
         T: Into<SupportedMessage>,
     {
-        let mut session_state = trace_write_lock!(self.session_state);
-        session_state.send_request(request)
+        let request = self.reopen_idle_for_request(request.into())?;
+        let mut session_state = self.lock_for_request(&request)?;
+        session_state
+            .send_request(request)
+            .map_err(StatusCode::from)
     }
 
     // Asynchronously sends a request. The return value is the request handle of the request
@@ -1223,9 +3796,16 @@ impl Service for Session {
     where
         T: Into<SupportedMessage>,
     {
-        let mut session_state = trace_write_lock!(self.session_state);
+        let request = self.reopen_idle_for_request(request.into())?;
+        let mut session_state = self.lock_for_request(&request)?;
         session_state.async_send_request(request, sender)
     }
+
+    /// Returns the decoding options of the secure channel
+    fn decoding_options(&self) -> DecodingOptions {
+        let secure_channel = trace_read_lock!(self.secure_channel);
+        secure_channel.decoding_options()
+    }
 }
 
 impl DiscoveryService for Session {
@@ -1255,7 +3835,7 @@ impl DiscoveryService for Session {
 
     fn get_endpoints(&self) -> Result<Vec<EndpointDescription>, StatusCode> {
         session_debug!(self, "get_endpoints");
-        let endpoint_url = self.session_info.endpoint.endpoint_url.clone();
+        let endpoint_url = self.endpoint_url();
 
         let request = GetEndpointsRequest {
             request_header: self.make_request_header(),
@@ -1318,7 +3898,7 @@ impl SecureChannelService for Session {
 impl SessionService for Session {
     fn create_session(&self) -> Result<NodeId, StatusCode> {
         // Get some state stuff
-        let endpoint_url = self.session_info.endpoint.endpoint_url.clone();
+        let endpoint_url = self.endpoint_url();
 
         let client_nonce = {
             let secure_channel = trace_read_lock!(self.secure_channel);
@@ -1347,7 +3927,7 @@ impl SessionService for Session {
         };
 
         let request = CreateSessionRequest {
-            request_header: self.make_request_header(),
+            request_header: self.make_connect_request_header(),
             client_description: self.application_description.clone(),
             server_uri,
             endpoint_url,
@@ -1360,14 +3940,16 @@ impl SessionService for Session {
 
         session_debug!(self, "CreateSessionRequest = {:?}", request);
 
-        let response = self.send_request(request)?;
+        let response = self.send_connect_request(request)?;
         if let SupportedMessage::CreateSessionResponse(response) = response {
             process_service_result(&response.response_header)?;
 
             let session_id = {
                 let mut session_state = trace_write_lock!(self.session_state);
+                session_state.check_created_session_id(&response.session_id)?;
                 session_state.set_session_id(response.session_id.clone());
                 session_state.set_authentication_token(response.authentication_token.clone());
+                session_state.set_server_nonce(response.server_nonce.clone());
                 {
                     let mut secure_channel = trace_write_lock!(self.secure_channel);
                     let _ =
@@ -1394,9 +3976,8 @@ impl SessionService for Session {
                     crypto::X509::from_byte_string(&response.server_certificate)
                 {
                     // Validate server certificate against hostname and application_uri
-                    let hostname =
-                        hostname_from_url(self.session_info.endpoint.endpoint_url.as_ref())
-                            .map_err(|_| StatusCode::BadUnexpectedError)?;
+                    let hostname = hostname_from_url(self.endpoint_url().as_ref())
+                        .map_err(|_| StatusCode::BadUnexpectedError)?;
                     let application_uri =
                         self.session_info.endpoint.server.application_uri.as_ref();
 
@@ -1433,6 +4014,7 @@ impl SessionService for Session {
                 );
                 self.spawn_session_activity_task(response.revised_session_timeout);
                 self.spawn_subscription_activity_task();
+                self.spawn_keep_alive_task();
 
                 // TODO Verify signature using server's public key (from endpoint) comparing with data made from client certificate and nonce.
                 // crypto::verify_signature_data(verification_key, security_policy, server_certificate, client_certificate, client_nonce);
@@ -1444,9 +4026,15 @@ impl SessionService for Session {
     }
 
     fn activate_session(&self) -> Result<(), StatusCode> {
+        // The nonce is the session's, from the server's last response to creating or activating
+        // it, not the secure channel's, which changes whenever the channel is renewed
+        let server_nonce = {
+            let session_state = trace_read_lock!(self.session_state);
+            session_state.server_nonce().clone()
+        };
         let (user_identity_token, user_token_signature) = {
             let secure_channel = trace_read_lock!(self.secure_channel);
-            self.user_identity_token(&secure_channel.remote_cert(), secure_channel.remote_nonce())?
+            self.user_identity_token(&secure_channel.remote_cert(), server_nonce.as_ref())?
         };
 
         let locale_ids = if self.session_info.preferred_locales.is_empty() {
@@ -1467,7 +4055,6 @@ impl SessionService for Session {
             _ => {
                 let secure_channel = trace_read_lock!(self.secure_channel);
                 let server_cert = secure_channel.remote_cert();
-                let server_nonce = secure_channel.remote_nonce();
 
                 let (_, client_pkey) = {
                     let certificate_store = trace_write_lock!(self.certificate_store);
@@ -1484,7 +4071,7 @@ impl SessionService for Session {
                         "Cannot sign server certificate because server cert is null"
                     );
                     return Err(StatusCode::BadUnexpectedError);
-                } else if server_nonce.is_empty() {
+                } else if server_nonce.as_ref().is_empty() {
                     session_error!(
                         self,
                         "Cannot sign server certificate because server nonce is empty"
@@ -1497,7 +4084,6 @@ impl SessionService for Session {
                     .as_ref()
                     .unwrap()
                     .as_byte_string();
-                let server_nonce = ByteString::from(secure_channel.remote_nonce());
                 let signing_key = client_pkey.as_ref().unwrap();
                 crypto::create_signature_data(
                     signing_key,
@@ -1511,7 +4097,7 @@ impl SessionService for Session {
         let client_software_certificates = None;
 
         let request = ActivateSessionRequest {
-            request_header: self.make_request_header(),
+            request_header: self.make_connect_request_header(),
             client_signature,
             client_software_certificates,
             locale_ids,
@@ -1521,13 +4107,34 @@ impl SessionService for Session {
 
         // trace!("ActivateSessionRequest = {:#?}", request);
 
-        let response = self.send_request(request)?;
+        let response = self.send_connect_request(request).map_err(|status_code| {
+            if status_code == StatusCode::BadTimeout {
+                session_error!(
+                    self,
+                    "activate_session, no response from the server within the connect timeout"
+                );
+                StatusCode::BadSessionNotActivated
+            } else {
+                status_code
+            }
+        })?;
         if let SupportedMessage::ActivateSessionResponse(response) = response {
             // trace!("ActivateSessionResponse = {:#?}", response);
-            process_service_result(&response.response_header)?;
+            process_service_result(&response.response_header)
+                .inspect_err(|status_code| self.log_identity_token_error(*status_code))?;
+            {
+                let mut session_state = trace_write_lock!(self.session_state);
+                session_state.set_activated(true);
+                session_state.set_server_nonce(response.server_nonce.clone());
+            }
+            if !self.session_info.preferred_locales.is_empty() {
+                self.check_preferred_locales();
+            }
             Ok(())
         } else {
-            Err(process_unexpected_response(response))
+            let status_code = process_unexpected_response(response);
+            self.log_identity_token_error(status_code);
+            Err(status_code)
         }
     }
 
@@ -1579,7 +4186,7 @@ impl SubscriptionService for Session {
         max_keep_alive_count: u32,
         max_notifications_per_publish: u32,
         priority: u8,
-    ) -> Result<(), StatusCode> {
+    ) -> Result<ModifiedSubscription, StatusCode> {
         if subscription_id == 0 {
             session_error!(self, "modify_subscription, subscription id must be non-zero, or the subscription is considered invalid");
             Err(StatusCode::BadInvalidArgument)
@@ -1609,7 +4216,11 @@ impl SubscriptionService for Session {
                     priority,
                 );
                 session_debug!(self, "modify_subscription success for {}", subscription_id);
-                Ok(())
+                Ok(ModifiedSubscription {
+                    revised_publishing_interval: response.revised_publishing_interval,
+                    revised_lifetime_count: response.revised_lifetime_count,
+                    revised_max_keep_alive_count: response.revised_max_keep_alive_count,
+                })
             } else {
                 session_error!(self, "modify_subscription failed {:?}", response);
                 Err(process_unexpected_response(response))
@@ -1663,29 +4274,8 @@ impl SubscriptionService for Session {
         subscription_ids: &[u32],
         send_initial_values: bool,
     ) -> Result<Vec<TransferResult>, StatusCode> {
-        if subscription_ids.is_empty() {
-            // No subscriptions
-            session_error!(
-                self,
-                "set_publishing_mode, no subscription ids were provided"
-            );
-            Err(StatusCode::BadNothingToDo)
-        } else {
-            let request = TransferSubscriptionsRequest {
-                request_header: self.make_request_header(),
-                subscription_ids: Some(subscription_ids.to_vec()),
-                send_initial_values,
-            };
-            let response = self.send_request(request)?;
-            if let SupportedMessage::TransferSubscriptionsResponse(response) = response {
-                process_service_result(&response.response_header)?;
-                session_debug!(self, "transfer_subscriptions success");
-                Ok(response.results.unwrap())
-            } else {
-                session_error!(self, "transfer_subscriptions failed {:?}", response);
-                Err(process_unexpected_response(response))
-            }
-        }
+        let mut session_state = trace_write_lock!(self.session_state);
+        session_state.transfer_subscriptions(subscription_ids, send_initial_values)
     }
 
     fn delete_subscription(&self, subscription_id: u32) -> Result<StatusCode, StatusCode> {
@@ -1724,9 +4314,9 @@ impl SubscriptionService for Session {
                 process_service_result(&response.response_header)?;
                 {
                     // Clear out deleted subscriptions, assuming the delete worked
-                    let mut subscription_state = trace_write_lock!(self.subscription_state);
+                    let mut session_state = trace_write_lock!(self.session_state);
                     subscription_ids.iter().for_each(|id| {
-                        let _ = subscription_state.delete_subscription(*id);
+                        let _ = session_state.remove_subscription(*id);
                     });
                 }
                 session_debug!(self, "delete_subscriptions success");
@@ -1855,64 +4445,70 @@ impl MonitoredItemService for Session {
             );
             Err(StatusCode::BadNothingToDo)
         } else {
+            self.check_sampling_intervals(items_to_create)?;
+            {
+                let session_state = trace_read_lock!(self.session_state);
+                session_state.check_total_monitored_items(items_to_create.len())?;
+            }
+
             // Assign each item a unique client handle
             let mut items_to_create = items_to_create.to_vec();
             {
-                let mut session_state = trace_write_lock!(self.session_state);
-                items_to_create.iter_mut().for_each(|i| {
-                    //if user doesn't specify a valid client_handle
-                    if i.requested_parameters.client_handle == 0 {
-                        i.requested_parameters.client_handle =
-                            session_state.next_monitored_item_handle();
-                    }
-                });
+                //if user doesn't specify a valid client_handle
+                let mut unassigned = items_to_create
+                    .iter_mut()
+                    .filter(|i| i.requested_parameters.client_handle == 0)
+                    .collect::<Vec<_>>();
+                let client_handles = {
+                    let mut session_state = trace_write_lock!(self.session_state);
+                    session_state.next_monitored_item_handles(unassigned.len())
+                };
+                unassigned
+                    .iter_mut()
+                    .zip(client_handles)
+                    .for_each(|(i, client_handle)| {
+                        i.requested_parameters.client_handle = client_handle;
+                    });
             }
 
-            let request = CreateMonitoredItemsRequest {
-                request_header: self.make_request_header(),
-                subscription_id,
-                timestamps_to_return,
-                items_to_create: Some(items_to_create.clone()),
+            // Servers limit how many items may be created in one call, so large numbers of
+            // items are created in batches. If the limit is unknown a conservative one is assumed.
+            let batch_size = match self.max_monitored_items_per_call() {
+                Some(0) => items_to_create.len(),
+                Some(max_monitored_items_per_call) => max_monitored_items_per_call as usize,
+                None => DEFAULT_MAX_MONITORED_ITEMS_PER_CALL,
             };
-            let response = self.send_request(request)?;
-            if let SupportedMessage::CreateMonitoredItemsResponse(response) = response {
-                process_service_result(&response.response_header)?;
-                if let Some(ref results) = response.results {
-                    session_debug!(
-                        self,
-                        "create_monitored_items, {} items created",
-                        items_to_create.len()
-                    );
-                    // Set the items in our internal state
-                    let items_to_create = items_to_create
-                        .iter()
-                        .zip(results)
-                        .map(|(i, r)| subscription::CreateMonitoredItem {
-                            id: r.monitored_item_id,
-                            client_handle: i.requested_parameters.client_handle,
-                            discard_oldest: i.requested_parameters.discard_oldest,
-                            item_to_monitor: i.item_to_monitor.clone(),
-                            monitoring_mode: i.monitoring_mode,
-                            queue_size: r.revised_queue_size,
-                            sampling_interval: r.revised_sampling_interval,
-                        })
-                        .collect::<Vec<subscription::CreateMonitoredItem>>();
-                    {
-                        let mut subscription_state = trace_write_lock!(self.subscription_state);
-                        subscription_state
-                            .insert_monitored_items(subscription_id, &items_to_create);
+            let mut results = Vec::with_capacity(items_to_create.len());
+            for batch in items_to_create.chunks(batch_size) {
+                match self.create_monitored_items_batch(
+                    subscription_id,
+                    timestamps_to_return,
+                    batch,
+                ) {
+                    Ok(batch_results) => results.extend(batch_results),
+                    // Nothing has been created yet
+                    Err(status_code) if results.is_empty() => return Err(status_code),
+                    Err(status_code) => {
+                        // The items of earlier batches exist on the server, so their results are
+                        // returned, with the error as the result of every item not created
+                        session_error!(
+                            self,
+                            "create_monitored_items, a batch failed after {} items were created, {}",
+                            results.len(),
+                            status_code
+                        );
+                        results.resize_with(items_to_create.len(), || MonitoredItemCreateResult {
+                            status_code,
+                            monitored_item_id: 0,
+                            revised_sampling_interval: 0f64,
+                            revised_queue_size: 0,
+                            filter_result: ExtensionObject::null(),
+                        });
+                        break;
                     }
-                } else {
-                    session_debug!(
-                        self,
-                        "create_monitored_items, success but no monitored items were created"
-                    );
                 }
-                Ok(response.results.unwrap())
-            } else {
-                session_error!(self, "create_monitored_items failed {:?}", response);
-                Err(process_unexpected_response(response))
             }
+            Ok(results)
         }
     }
 
@@ -2019,7 +4615,7 @@ impl MonitoredItemService for Session {
         triggering_item_id: u32,
         links_to_add: &[u32],
         links_to_remove: &[u32],
-    ) -> Result<(Option<Vec<StatusCode>>, Option<Vec<StatusCode>>), StatusCode> {
+    ) -> Result<SetTriggeringResult, StatusCode> {
         if links_to_add.is_empty() && links_to_remove.is_empty() {
             session_error!(self, "set_triggering, called with nothing to add or remove");
             Err(StatusCode::BadNothingToDo)
@@ -2045,15 +4641,27 @@ impl MonitoredItemService for Session {
             };
             let response = self.send_request(request)?;
             if let SupportedMessage::SetTriggeringResponse(response) = response {
-                // Update client side state
+                let result = SetTriggeringResult {
+                    add_results: response.add_results.unwrap_or_default(),
+                    remove_results: response.remove_results.unwrap_or_default(),
+                };
+                // Update client side state with the links the server accepted
+                let succeeded = |links: &[u32], results: &[StatusCode]| -> Vec<u32> {
+                    links
+                        .iter()
+                        .zip(results.iter())
+                        .filter(|(_, r)| r.is_good())
+                        .map(|(l, _)| *l)
+                        .collect()
+                };
                 let mut subscription_state = trace_write_lock!(self.subscription_state);
                 subscription_state.set_triggering(
                     subscription_id,
                     triggering_item_id,
-                    links_to_add,
-                    links_to_remove,
+                    &succeeded(links_to_add, &result.add_results),
+                    &succeeded(links_to_remove, &result.remove_results),
                 );
-                Ok((response.add_results, response.remove_results))
+                Ok(result)
             } else {
                 session_error!(self, "set_triggering failed {:?}", response);
                 Err(process_unexpected_response(response))
@@ -2097,12 +4705,24 @@ impl MonitoredItemService for Session {
             let response = self.send_request(request)?;
             if let SupportedMessage::DeleteMonitoredItemsResponse(response) = response {
                 process_service_result(&response.response_header)?;
-                if response.results.is_some() {
+                let results = response.results.unwrap_or_default();
+                // An item the server doesn't know about is gone either way, so it is forgotten
+                // too. Any other failure leaves the item in place.
+                let deleted_items = items_to_delete
+                    .iter()
+                    .zip(results.iter())
+                    .filter(|(_, status_code)| {
+                        status_code.is_good()
+                            || **status_code == StatusCode::BadMonitoredItemIdInvalid
+                    })
+                    .map(|(monitored_item_id, _)| *monitored_item_id)
+                    .collect::<Vec<u32>>();
+                {
                     let mut subscription_state = trace_write_lock!(self.subscription_state);
-                    subscription_state.delete_monitored_items(subscription_id, items_to_delete);
+                    subscription_state.delete_monitored_items(subscription_id, &deleted_items);
                 }
                 session_debug!(self, "delete_monitored_items, success");
-                Ok(response.results.unwrap())
+                Ok(results)
             } else {
                 session_error!(self, "delete_monitored_items failed {:?}", response);
                 Err(process_unexpected_response(response))
@@ -2253,7 +4873,21 @@ impl MethodService for Session {
         T: Into<CallMethodRequest>,
     {
         session_debug!(self, "call()");
-        let methods_to_call = Some(vec![method.into()]);
+        let method = method.into();
+        if self.validate_method_arguments {
+            let method_arguments = trace_read_lock!(self.method_arguments);
+            if let Some(method_arguments) = method_arguments.get(&method.method_id) {
+                let inputs = method.input_arguments.as_deref().unwrap_or(&[]);
+                method_arguments.validate_inputs(inputs).inspect_err(|_| {
+                    session_error!(
+                        self,
+                        "call(), inputs don't match the arguments of method {}",
+                        method.method_id
+                    );
+                })?;
+            }
+        }
+        let methods_to_call = Some(vec![method]);
         let request = CallRequest {
             request_header: self.make_request_header(),
             methods_to_call,
@@ -2312,7 +4946,16 @@ impl AttributeService for Session {
                 } else {
                     Vec::new()
                 };
-                Ok(results)
+                let missing_result = DataValue {
+                    status: Some(StatusCode::BadInternalError),
+                    ..Default::default()
+                };
+                self.partial_results_policy().apply(
+                    "read()",
+                    nodes_to_read.len(),
+                    results,
+                    missing_result,
+                )
             } else {
                 session_error!(self, "read() value failed");
                 Err(process_unexpected_response(response))
@@ -2375,7 +5018,12 @@ impl AttributeService for Session {
             if let SupportedMessage::WriteResponse(response) = response {
                 session_debug!(self, "write(), success");
                 process_service_result(&response.response_header)?;
-                Ok(response.results.unwrap_or_default())
+                self.partial_results_policy().apply(
+                    "write()",
+                    nodes_to_write.len(),
+                    response.results.unwrap_or_default(),
+                    StatusCode::BadInternalError,
+                )
             } else {
                 session_error!(self, "write() failed {:?}", response);
                 Err(process_unexpected_response(response))
@@ -2398,7 +5046,7 @@ impl AttributeService for Session {
             // Turn the enums into ExtensionObjects
             let history_update_details = history_update_details
                 .iter()
-                .map(|action|ExtensionObject::from(action))
+                .map(|action| ExtensionObject::from(action))
                 .collect::<Vec<ExtensionObject>>();
 
             let request = HistoryUpdateRequest {
@@ -3,31 +3,47 @@
 // Copyright (C) 2017-2022 Adam Lock
 
 use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
     sync::{
         atomic::{AtomicU32, Ordering},
-        mpsc::{self, Receiver, SyncSender},
+        mpsc::{SyncSender, TrySendError},
         Arc,
     },
-    u32,
+    task::{Context, Poll},
+    thread, u32,
 };
 
+use tokio::sync::oneshot;
+
 use chrono::Duration;
 use tokio::time::Instant;
 
 use crate::{
     client::{
         callbacks::{OnConnectionStatusChange, OnSessionClosed},
-        message_queue::MessageQueue,
-        process_unexpected_response,
-        session::{session_debug, session_trace},
+        client::IdentityToken,
+        message_queue::{MessageQueue, MessageQueueMetrics, ResponseFuture, ResponseSender},
+        process_service_result, process_unexpected_response,
+        rate_limiter::{RateLimitPolicy, RateLimiter},
+        session::{
+            diagnostics::{SessionCounters, SessionDiagnostics},
+            session_debug, session_error, session_trace, session_warn,
+        },
+        subscription::Subscription,
         subscription_state::SubscriptionState,
     },
     core::{
-        comms::secure_channel::SecureChannel, handle::Handle, supported_message::SupportedMessage,
+        comms::{secure_channel::SecureChannel, url::is_valid_opc_ua_url},
+        handle::Handle,
+        supported_message::SupportedMessage,
     },
-    crypto::SecurityPolicy,
+    crypto::{SecurityPolicy, X509},
     sync::*,
-    types::{status_code::StatusCode, *},
+    types::{node_ids::VariableId, status_code::StatusCode, *},
 };
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -88,12 +104,328 @@ impl ConnectionStateMgr {
     }
 }
 
+/// A function that authorizes an outgoing request, returning an error to veto it.
+pub type RequestAuthorizer = dyn Fn(&SupportedMessage) -> Result<(), StatusCode> + Send + Sync;
+
+/// A function that decides whether to trust the certificate of a server, returning an error if
+/// it should not be trusted.
+pub type CertificateVerifier = dyn Fn(&X509) -> Result<(), StatusCode> + Send + Sync;
+
+/// A function that is told the name of an operation and the error it failed with.
+pub type ErrorHandler = dyn Fn(&str, StatusCode) + Send + Sync;
+
+/// A function that is told the id of a subscription that sent a keep-alive.
+pub type KeepAliveHandler = dyn Fn(u32) + Send + Sync;
+
+/// A function that is told of an acknowledgement that was dropped because too many were pending.
+pub type AckOverflowHandler = dyn Fn(&SubscriptionAcknowledgement) + Send + Sync;
+
+/// A function that is told how long the security token has left when it is close to expiring.
+pub type TokenNearExpiryHandler = dyn Fn(std::time::Duration) + Send + Sync;
+
+/// The error from sending a request, with the handle and type of the request that failed so it
+/// can be matched up with the log.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RequestError {
+    /// What the request failed with
+    pub status_code: StatusCode,
+    /// The request handle of the request
+    pub request_handle: u32,
+    /// The type of the request, e.g. "ReadRequest"
+    pub request_type: &'static str,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} failed, {}",
+            self.request_type, self.request_handle, self.status_code
+        )
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestError> for StatusCode {
+    fn from(error: RequestError) -> StatusCode {
+        error.status_code
+    }
+}
+
+/// The sending half of a `PublishFuture`
+type PublishOutcomeSender = oneshot::Sender<Result<PublishResponse, StatusCode>>;
+
+/// A future that resolves to the response to a publish request, returned by
+/// `Session::async_publish_future()`. It resolves once the session has processed the response,
+/// so its notifications have already been delivered and its acknowledgements queued, or to the
+/// service result if the server answered with a fault.
+///
+/// The future resolves to `BadConnectionClosed` if the connection closes before the response
+/// arrives, to `BadSessionNotActivated` if the response arrives before the session is activated
+/// and is dropped, and straight away to `BadNothingToDo` in a dry run, which sends no publish
+/// request.
+/// Like `ResponseFuture` it does not time out by itself.
+pub struct PublishFuture {
+    receiver: oneshot::Receiver<Result<PublishResponse, StatusCode>>,
+}
+
+impl Future for PublishFuture {
+    type Output = Result<PublishResponse, StatusCode>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(StatusCode::BadConnectionClosed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// How opening a new secure channel is retried when it fails for what may be a transient reason,
+/// e.g. because the server is still starting up. Each retry waits twice as long as the one before
+/// it, starting from `backoff_ms`. Renewing an existing channel is never retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecureChannelRetryPolicy {
+    /// The number of times to retry, 0 for none
+    pub max_retries: u32,
+    /// The time in milliseconds to wait before the first retry
+    pub backoff_ms: u32,
+    /// The failures that are retried
+    pub retry_on: Vec<StatusCode>,
+}
+
+impl Default for SecureChannelRetryPolicy {
+    fn default() -> Self {
+        SecureChannelRetryPolicy {
+            max_retries: 0,
+            backoff_ms: 500,
+            retry_on: vec![StatusCode::BadServerNotConnected, StatusCode::BadTimeout],
+        }
+    }
+}
+
+impl SecureChannelRetryPolicy {
+    /// The time in milliseconds to wait before the retry, numbered from 1
+    pub fn backoff(&self, retry: u32) -> u32 {
+        exponential_backoff(self.backoff_ms, retry)
+    }
+
+    /// Tests if the failure should be retried after the number of retries so far
+    fn should_retry(&self, retries: u32, status_code: StatusCode) -> bool {
+        retries < self.max_retries && self.retry_on.contains(&status_code)
+    }
+}
+
+/// How a request that times out is sent again. Only requests that merely read from the server,
+/// i.e. get endpoints, find servers, browse, translate browse paths and read, are retried.
+/// Requests that change the server, e.g. creating a subscription or adding nodes, are never
+/// retried. Each retry has a new request handle and waits twice as long as the one before it,
+/// starting from `backoff_ms`. The default is not to retry.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct RetryPolicy {
+    /// The number of times to retry, 0 for none
+    pub max_retries: u32,
+    /// The time in milliseconds to wait before the first retry
+    pub backoff_ms: u32,
+}
+
+impl RetryPolicy {
+    /// The time in milliseconds to wait before the retry, numbered from 1
+    pub fn backoff(&self, retry: u32) -> u32 {
+        exponential_backoff(self.backoff_ms, retry)
+    }
+}
+
+/// How `Session::reconnect()` retries reconnecting to the server. Each attempt after the first
+/// waits twice as long as the one before it, starting from `backoff_ms`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// The number of attempts to reconnect before giving up, at least 1
+    pub max_attempts: u32,
+    /// The time in milliseconds to wait before the second attempt
+    pub backoff_ms: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 3,
+            backoff_ms: 1000,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The time in milliseconds to wait before the attempt, numbered from 2
+    pub fn backoff(&self, attempt: u32) -> u32 {
+        exponential_backoff(self.backoff_ms, attempt.saturating_sub(1))
+    }
+}
+
+/// A snapshot of a subscription that the session has created, with the values that the server
+/// revised its parameters to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionSummary {
+    /// The id that the server assigned to the subscription
+    pub subscription_id: u32,
+    /// The publishing interval in milliseconds
+    pub revised_publishing_interval: f64,
+    /// The number of publishing intervals without a notification before a keep-alive is sent
+    pub revised_max_keep_alive_count: u32,
+    /// The number of publishing intervals without a publish request before the subscription expires
+    pub revised_lifetime_count: u32,
+    /// The client handles of the subscription's monitored items, in ascending order
+    pub monitored_item_handles: Vec<u32>,
+}
+
+impl From<&Subscription> for SubscriptionSummary {
+    fn from(subscription: &Subscription) -> Self {
+        let mut monitored_item_handles = subscription
+            .monitored_items()
+            .values()
+            .map(|item| item.client_handle())
+            .collect::<Vec<_>>();
+        monitored_item_handles.sort_unstable();
+        SubscriptionSummary {
+            subscription_id: subscription.subscription_id(),
+            revised_publishing_interval: subscription.publishing_interval(),
+            revised_max_keep_alive_count: subscription.max_keep_alive_count(),
+            revised_lifetime_count: subscription.lifetime_count(),
+            monitored_item_handles,
+        }
+    }
+}
+
+/// What a session does with a publish response that arrives before the session has been
+/// activated. A well behaved server never sends one, so it is never delivered to the
+/// subscription callbacks while the session isn't active.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum PreActivationPolicy {
+    /// Drop the response with a debug log. This is the default.
+    #[default]
+    Drop,
+    /// Hold on to the response and process it once the session is activated. Only the most recent
+    /// responses are held, older ones are dropped when the buffer is full.
+    Buffer,
+}
+
+/// What a session does with the response to a read or write that has a different number of
+/// results than there were items in the request. This is a protocol violation by the server and
+/// the results can't be matched up to the items with certainty.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum PartialResultsPolicy {
+    /// Fail the call with `BadUnexpectedError`. This is the default.
+    #[default]
+    Strict,
+    /// Assume the results are in order and were cut short, padding any missing results with
+    /// `BadInternalError` and dropping any extra ones.
+    Lenient,
+}
+
+impl PartialResultsPolicy {
+    /// Checks there are as many results as items requested, applying the policy if there are not.
+    /// The missing result is what a result that was not returned is padded with.
+    pub(crate) fn apply<T>(
+        &self,
+        operation: &str,
+        items: usize,
+        mut results: Vec<T>,
+        missing_result: T,
+    ) -> Result<Vec<T>, StatusCode>
+    where
+        T: Clone,
+    {
+        if results.len() == items {
+            Ok(results)
+        } else if *self == PartialResultsPolicy::Strict {
+            error!(
+                "{} expected {} results but got {}",
+                operation,
+                items,
+                results.len()
+            );
+            Err(StatusCode::BadUnexpectedError)
+        } else {
+            warn!(
+                "{} expected {} results but got {}, padding or truncating them",
+                operation,
+                items,
+                results.len()
+            );
+            results.resize(items, missing_result);
+            Ok(results)
+        }
+    }
+}
+
+/// The buffer sizes and request timeout of a session, which may be tuned for the application,
+/// e.g. a larger receive buffer for a client with many monitored items. The buffer sizes are
+/// those advertised to the server in the HELLO so changes apply from the next connection.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SessionStateConfig {
+    /// Size in bytes of the send buffer, i.e. the largest chunk that will be sent
+    pub send_buffer_size: usize,
+    /// Size in bytes of the receive buffer, i.e. the largest chunk that may be received
+    pub receive_buffer_size: usize,
+    /// Size in bytes of the largest message, which spans one or more chunks
+    pub max_message_size: usize,
+    /// Timeout in milliseconds to wait for the response to a request
+    pub request_timeout: u32,
+}
+
+impl Default for SessionStateConfig {
+    fn default() -> Self {
+        SessionStateConfig {
+            send_buffer_size: SessionState::SEND_BUFFER_SIZE,
+            receive_buffer_size: SessionState::RECEIVE_BUFFER_SIZE,
+            max_message_size: SessionState::MAX_BUFFER_SIZE,
+            request_timeout: SessionState::DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl SessionStateConfig {
+    /// Tests the configuration is usable, i.e. that nothing is zero and that a message can hold
+    /// at least a whole chunk.
+    pub fn validate(&self) -> Result<(), StatusCode> {
+        if self.send_buffer_size == 0
+            || self.receive_buffer_size == 0
+            || self.max_message_size == 0
+            || self.request_timeout == 0
+        {
+            error!("Session state config {:?} has a zero size or timeout", self);
+            Err(StatusCode::BadConfigurationError)
+        } else if self.max_message_size < self.send_buffer_size {
+            error!(
+                "Session state config max message size {} is less than the send buffer size {}",
+                self.max_message_size, self.send_buffer_size
+            );
+            Err(StatusCode::BadConfigurationError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Doubles the backoff for each retry after the first, numbered from 1
+fn exponential_backoff(backoff_ms: u32, retry: u32) -> u32 {
+    let exponent = retry.saturating_sub(1).min(31);
+    backoff_ms.saturating_mul(1 << exponent)
+}
+
 lazy_static! {
     static ref NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(1);
 }
 
 /// Session's state indicates connection status, negotiated times and sizes,
 /// and security tokens.
+///
+/// The secure channel is held through an `Arc` and the spec permits several sessions over one
+/// channel, so more than one session state may be constructed with the same channel. Renewal of
+/// the channel's security token is coordinated through the channel itself, so only the first
+/// session to notice the token is due for renewal sends the renew request and the others carry
+/// on with the existing token in the meantime.
 pub(crate) struct SessionState {
     /// A unique identifier for the session, this is NOT the session id assigned after a session is created
     id: u32,
@@ -108,32 +440,139 @@ pub(crate) struct SessionState {
     /// The request timeout is how long the session will wait from sending a request expecting a response
     /// if no response is received the client will terminate.
     request_timeout: u32,
+    /// The timeout hint passed to the server with each request, i.e. how long the server may
+    /// spend on it. `None` passes the request timeout
+    timeout_hint: Option<u32>,
+    /// The connect timeout is how long the session will wait for the responses to open secure
+    /// channel, create session and activate session while connecting.
+    connect_timeout: u32,
+    /// The lifetime in milliseconds asked for the security token of the secure channel. The
+    /// server may grant a different lifetime and that is what renewal is timed from.
+    requested_secure_channel_lifetime: u32,
+    /// How a failure to open a new secure channel is retried
+    secure_channel_retry_policy: SecureChannelRetryPolicy,
+    /// The security policies and modes to try in turn when opening a new secure channel. Empty
+    /// means only the secure channel's own policy and mode are tried.
+    security_policy_preferences: Vec<(SecurityPolicy, MessageSecurityMode)>,
+    /// How a request that times out is retried
+    retry_policy: RetryPolicy,
+    /// How reconnecting to the server is retried
+    reconnect_policy: ReconnectPolicy,
+    /// The diagnostics the server is asked to return with the response to each request
+    return_diagnostics: DiagnosticBits,
+    /// Passed to an auditing server with each request to tie the actions to an operator or
+    /// workflow
+    audit_entry_id: Option<String>,
+    /// How long in milliseconds without a response before the server state is read to check the
+    /// connection is alive. 0 means never
+    keep_alive_interval: u32,
+    /// Set when a keep-alive read of the server state fails and cleared when one succeeds
+    connection_lost: bool,
+    /// How long the session may go without a request from the client before it is closed, if it
+    /// is closed when idle
+    auto_close_idle_after: Option<std::time::Duration>,
+    /// When the client last sent a request of its own, i.e. not a keep-alive or publish request
+    last_activity: Instant,
+    /// Set when the session was closed for being idle, until it is opened again
+    closed_idle: bool,
+    /// Set once the session is activated and cleared when the connection is reset
+    activated: bool,
+    /// What happens to publish responses that arrive before the session is activated
+    pre_activation_policy: PreActivationPolicy,
+    /// Publish responses held until the session is activated
+    pre_activation_responses: VecDeque<SupportedMessage>,
+    /// Timeout in milliseconds for publish requests, derived from the subscriptions if not set
+    publish_timeout: Option<u32>,
     /// Size of the send buffer
     send_buffer_size: usize,
-    /// Size of the
+    /// Size of the receive buffer
     receive_buffer_size: usize,
     /// Maximum message size
     max_message_size: usize,
-    /// Maximum chunk size
+    /// Maximum number of chunks in a message received from the server, advertised in the HELLO.
+    /// 0 means no limit
     max_chunk_count: usize,
     /// The session's id assigned after a connection and used for diagnostic info
     session_id: NodeId,
     /// The session authentication token, used for session activation
     authentication_token: NodeId,
+    /// The nonce the server last gave the session when creating or activating it. The next
+    /// activation signs it and encrypts a user's password with it
+    server_nonce: ByteString,
+    /// The session id the server must return when the session is created, e.g. when resuming a
+    /// known session. `None` adopts whatever session id the server returns
+    expected_session_id: Option<NodeId>,
+    /// The user identity to activate the session with, in place of the one it was made with
+    user_identity_token: Option<IdentityToken>,
     /// The next handle to assign to a request
     request_handle: Handle,
     /// Next monitored item client side handle
     monitored_item_handle: Handle,
     /// Subscription acknowledgements pending for send
     subscription_acknowledgements: Vec<SubscriptionAcknowledgement>,
+    /// Handles of the publish requests that have been sent and are waiting for a response, with
+    /// the sender of the future for those sent by `async_publish_future()`
+    outstanding_publish_requests: HashMap<u32, Option<PublishOutcomeSender>>,
+    /// The number of publish requests that may be outstanding at once. This is reduced when the
+    /// server says there are too many and recovers slowly as publish requests succeed.
+    max_outstanding_publish_requests: usize,
+    /// Successful publish responses since the outstanding publish limit last changed
+    publish_successes: usize,
     /// Subscription state
     subscription_state: Arc<RwLock<SubscriptionState>>,
     /// Connection closed callback
     session_closed_callback: Option<Box<dyn OnSessionClosed + Send + Sync + 'static>>,
     /// Connection status callback
     connection_status_callback: Option<Box<dyn OnConnectionStatusChange + Send + Sync + 'static>>,
+    /// Consulted before each request is sent, returning an error to stop the request being sent
+    request_authorizer: Option<Box<RequestAuthorizer>>,
+    /// Consulted before a secure channel is opened to a server, returning an error if the
+    /// server's certificate is not trusted
+    certificate_verifier: Option<Box<CertificateVerifier>>,
+    /// Rejects requests that would modify the server before they are sent
+    read_only: bool,
+    /// The longest array that may be written, if the server has a limit
+    max_write_array_length: Option<usize>,
+    /// The most monitored items that may be created across all subscriptions, if there is a limit
+    max_total_monitored_items: Option<usize>,
+    /// Validates and encodes requests without sending them, answering each with a placeholder
+    dry_run: bool,
+    /// Opens a new secure channel and retries a request once if the server doesn't know the
+    /// token it was sent with
+    reissue_channel_on_token_unknown: bool,
+    /// Limits the rate of requests, if set
+    rate_limiter: Option<RateLimiter>,
+    /// What happens to a request that exceeds the rate limit
+    rate_limit_policy: RateLimitPolicy,
+    /// The rate limit has already been taken for the next request sent, by
+    /// `try_acquire_rate_limit()`
+    rate_limit_acquired: bool,
+    /// What happens to a read or write response with the wrong number of results
+    partial_results_policy: PartialResultsPolicy,
+    /// Called with the name of the operation and the status whenever an operation fails
+    on_error: Option<Box<ErrorHandler>>,
+    /// Called with the subscription id when a publish response is a keep-alive
+    on_keep_alive: Option<Box<KeepAliveHandler>>,
+    /// Sent the client handle and value of each monitored item notification
+    notification_sender: Option<SyncSender<(u32, DataValue)>>,
+    /// The most subscription acknowledgements that may be pending before the oldest are dropped
+    max_subscription_acknowledgements: usize,
+    /// Called with each acknowledgement dropped because too many are pending
+    on_ack_overflow: Option<Box<AckOverflowHandler>>,
+    /// Called with the remaining lifetime of the security token when it is close to expiring
+    on_token_near_expiry: Option<Box<TokenNearExpiryHandler>>,
+    /// The fraction of the token lifetime left below which the token counts as close to expiring
+    token_near_expiry_fraction: f64,
+    /// Which timestamp of a value the application treats as its time
+    preferred_timestamp: TimestampPreference,
+    /// Other urls of the server to try if the endpoint url can't be connected to
+    alternate_endpoint_urls: Vec<UAString>,
+    /// The url that the session last connected to
+    last_endpoint_url: Option<UAString>,
     /// Message queue.
     pub(crate) message_queue: Arc<RwLock<MessageQueue>>,
+    /// Counts the traffic of the session, shared with the transport
+    pub(crate) counters: Arc<SessionCounters>,
 }
 
 impl OnSessionClosed for SessionState {
@@ -156,16 +595,35 @@ impl SessionState {
     const FIRST_MONITORED_ITEM_HANDLE: u32 = 1000;
 
     const DEFAULT_REQUEST_TIMEOUT: u32 = 10 * 1000;
+    /// The default lifetime in milliseconds asked for the security token of the secure channel
+    const DEFAULT_REQUESTED_SECURE_CHANNEL_LIFETIME: u32 = 60 * 1000;
+    /// How often in milliseconds a flush checks if the queued requests have been written
+    const FLUSH_POLL_INTERVAL: u64 = 5;
     const SEND_BUFFER_SIZE: usize = 65535;
     const RECEIVE_BUFFER_SIZE: usize = 65535;
     const MAX_BUFFER_SIZE: usize = 65535;
 
+    /// The most publish requests that will be outstanding at once
+    const MAX_OUTSTANDING_PUBLISH_REQUESTS: usize = 10;
+    /// The most publish responses held until the session is activated
+    const MAX_PRE_ACTIVATION_RESPONSES: usize = 100;
+    /// The default for the most subscription acknowledgements that may be pending
+    const DEFAULT_MAX_SUBSCRIPTION_ACKNOWLEDGEMENTS: usize = 10000;
+    /// The default fraction of the token lifetime left below which the token is close to expiring
+    const DEFAULT_TOKEN_NEAR_EXPIRY_FRACTION: f64 = 0.1;
+    /// Number of successful publish responses before a reduced outstanding publish limit is
+    /// raised by one
+    const PUBLISH_SUCCESSES_TO_RECOVER: usize = 10;
+
     pub fn new(
         ignore_clock_skew: bool,
         secure_channel: Arc<RwLock<SecureChannel>>,
         subscription_state: Arc<RwLock<SubscriptionState>>,
     ) -> SessionState {
         let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        let max_chunk_count = trace_read_lock!(secure_channel)
+            .decoding_options()
+            .max_chunk_count;
         SessionState {
             id,
             client_offset: Duration::zero(),
@@ -173,19 +631,65 @@ impl SessionState {
             secure_channel,
             connection_state: ConnectionStateMgr::new(),
             request_timeout: Self::DEFAULT_REQUEST_TIMEOUT,
+            timeout_hint: None,
+            connect_timeout: Self::DEFAULT_REQUEST_TIMEOUT,
+            requested_secure_channel_lifetime: Self::DEFAULT_REQUESTED_SECURE_CHANNEL_LIFETIME,
+            return_diagnostics: DiagnosticBits::empty(),
+            audit_entry_id: None,
+            keep_alive_interval: 0,
+            connection_lost: false,
+            auto_close_idle_after: None,
+            last_activity: Instant::now(),
+            closed_idle: false,
+            activated: false,
+            pre_activation_policy: PreActivationPolicy::default(),
+            pre_activation_responses: VecDeque::new(),
+            secure_channel_retry_policy: SecureChannelRetryPolicy::default(),
+            security_policy_preferences: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            publish_timeout: None,
             send_buffer_size: Self::SEND_BUFFER_SIZE,
             receive_buffer_size: Self::RECEIVE_BUFFER_SIZE,
             max_message_size: Self::MAX_BUFFER_SIZE,
-            max_chunk_count: constants::MAX_CHUNK_COUNT,
+            max_chunk_count,
             request_handle: Handle::new(Self::FIRST_REQUEST_HANDLE),
             session_id: NodeId::null(),
+            expected_session_id: None,
+            user_identity_token: None,
             authentication_token: NodeId::null(),
+            server_nonce: ByteString::null(),
             monitored_item_handle: Handle::new(Self::FIRST_MONITORED_ITEM_HANDLE),
             subscription_acknowledgements: Vec::new(),
+            outstanding_publish_requests: HashMap::new(),
+            max_outstanding_publish_requests: Self::MAX_OUTSTANDING_PUBLISH_REQUESTS,
+            publish_successes: 0,
             subscription_state,
             session_closed_callback: None,
             connection_status_callback: None,
+            request_authorizer: None,
+            certificate_verifier: None,
+            read_only: false,
+            max_write_array_length: None,
+            max_total_monitored_items: None,
+            dry_run: false,
+            reissue_channel_on_token_unknown: false,
+            rate_limiter: None,
+            rate_limit_policy: RateLimitPolicy::default(),
+            rate_limit_acquired: false,
+            partial_results_policy: PartialResultsPolicy::default(),
+            on_error: None,
+            on_keep_alive: None,
+            notification_sender: None,
+            max_subscription_acknowledgements: Self::DEFAULT_MAX_SUBSCRIPTION_ACKNOWLEDGEMENTS,
+            on_ack_overflow: None,
+            on_token_near_expiry: None,
+            token_near_expiry_fraction: Self::DEFAULT_TOKEN_NEAR_EXPIRY_FRACTION,
+            preferred_timestamp: TimestampPreference::default(),
+            alternate_endpoint_urls: Vec::new(),
+            last_endpoint_url: None,
             message_queue: Arc::new(RwLock::new(MessageQueue::new())),
+            counters: Arc::new(SessionCounters::default()),
         }
     }
 
@@ -206,10 +710,67 @@ impl SessionState {
         self.session_id.clone()
     }
 
+    pub(crate) fn authentication_token(&self) -> NodeId {
+        self.authentication_token.clone()
+    }
+
+    pub fn set_expected_session_id(&mut self, expected_session_id: Option<NodeId>) {
+        self.expected_session_id = expected_session_id;
+    }
+
+    pub fn expected_session_id(&self) -> Option<&NodeId> {
+        self.expected_session_id.as_ref()
+    }
+
+    pub fn set_user_identity_token(&mut self, user_identity_token: IdentityToken) {
+        self.user_identity_token = Some(user_identity_token);
+    }
+
+    pub fn user_identity_token(&self) -> Option<&IdentityToken> {
+        self.user_identity_token.as_ref()
+    }
+
+    /// Checks the id of a session that the server has created is the one expected, if any.
+    /// Returns `BadSessionIdInvalid` if it is not, so the session is not adopted.
+    pub(crate) fn check_created_session_id(&self, session_id: &NodeId) -> Result<(), StatusCode> {
+        if let Some(ref expected_session_id) = self.expected_session_id {
+            if expected_session_id != session_id {
+                session_error!(
+                    self,
+                    "Server created session {} but session {} was expected",
+                    session_id,
+                    expected_session_id
+                );
+                return Err(StatusCode::BadSessionIdInvalid);
+            }
+        }
+        Ok(())
+    }
+
     pub fn receive_buffer_size(&self) -> usize {
         self.receive_buffer_size
     }
 
+    /// Sets the buffer sizes and request timeout, failing with `BadConfigurationError` and
+    /// leaving them unchanged if the configuration is not valid.
+    pub fn set_config(&mut self, config: SessionStateConfig) -> Result<(), StatusCode> {
+        config.validate()?;
+        self.send_buffer_size = config.send_buffer_size;
+        self.receive_buffer_size = config.receive_buffer_size;
+        self.max_message_size = config.max_message_size;
+        self.request_timeout = config.request_timeout;
+        Ok(())
+    }
+
+    pub fn config(&self) -> SessionStateConfig {
+        SessionStateConfig {
+            send_buffer_size: self.send_buffer_size,
+            receive_buffer_size: self.receive_buffer_size,
+            max_message_size: self.max_message_size,
+            request_timeout: self.request_timeout,
+        }
+    }
+
     pub fn max_message_size(&self) -> usize {
         self.max_message_size
     }
@@ -218,222 +779,1943 @@ impl SessionState {
         self.max_chunk_count
     }
 
+    pub fn set_request_timeout(&mut self, request_timeout: u32) {
+        self.request_timeout = request_timeout;
+    }
+
     pub fn request_timeout(&self) -> u32 {
         self.request_timeout
     }
 
-    pub fn send_buffer_size(&self) -> usize {
-        self.send_buffer_size
+    pub fn set_timeout_hint(&mut self, timeout_hint: Option<u32>) {
+        self.timeout_hint = timeout_hint;
     }
 
-    pub fn add_subscription_acknowledgement(
-        &mut self,
-        subscription_acknowledgement: SubscriptionAcknowledgement,
-    ) {
-        self.subscription_acknowledgements
-            .push(subscription_acknowledgement);
+    /// Returns the timeout hint passed to the server with each request, which is the request
+    /// timeout unless it is set.
+    pub fn timeout_hint(&self) -> u32 {
+        self.timeout_hint.unwrap_or(self.request_timeout)
     }
 
-    pub fn set_authentication_token(&mut self, authentication_token: NodeId) {
-        self.authentication_token = authentication_token;
+    pub fn set_connect_timeout(&mut self, connect_timeout: u32) {
+        self.connect_timeout = connect_timeout;
     }
 
-    pub fn set_session_closed_callback<CB>(&mut self, session_closed_callback: CB)
-    where
-        CB: OnSessionClosed + Send + Sync + 'static,
-    {
-        self.session_closed_callback = Some(Box::new(session_closed_callback));
+    pub fn connect_timeout(&self) -> u32 {
+        self.connect_timeout
     }
 
-    pub fn set_connection_status_callback<CB>(&mut self, connection_status_callback: CB)
-    where
-        CB: OnConnectionStatusChange + Send + Sync + 'static,
-    {
-        self.connection_status_callback = Some(Box::new(connection_status_callback));
+    pub fn set_return_diagnostics(&mut self, return_diagnostics: DiagnosticBits) {
+        self.return_diagnostics = return_diagnostics;
     }
 
-    pub(crate) fn on_connection_status_change(&mut self, connected: bool) {
-        if let Some(ref mut connection_status) = self.connection_status_callback {
-            connection_status.on_connection_status_change(connected);
-        }
+    pub fn return_diagnostics(&self) -> DiagnosticBits {
+        self.return_diagnostics
     }
 
-    pub(crate) fn connection_state(&self) -> ConnectionStateMgr {
-        self.connection_state.clone()
+    pub fn set_audit_entry_id(&mut self, audit_entry_id: Option<String>) {
+        self.audit_entry_id = audit_entry_id;
     }
 
-    /// Construct a request header for the session. All requests after create session are expected
-    /// to supply an authentication token.
-    pub fn make_request_header(&mut self) -> RequestHeader {
-        RequestHeader {
-            authentication_token: self.authentication_token.clone(),
-            timestamp: DateTime::now_with_offset(self.client_offset),
-            request_handle: self.request_handle.next(),
-            return_diagnostics: DiagnosticBits::empty(),
-            timeout_hint: self.request_timeout,
-            ..Default::default()
-        }
+    pub fn audit_entry_id(&self) -> Option<String> {
+        self.audit_entry_id.clone()
     }
 
-    /// Sends a publish request containing acknowledgements for previous notifications.
-    pub fn async_publish(&mut self) -> Result<u32, StatusCode> {
-        let subscription_acknowledgements = if self.subscription_acknowledgements.is_empty() {
-            None
-        } else {
-            let subscription_acknowledgements: Vec<SubscriptionAcknowledgement> =
-                self.subscription_acknowledgements.drain(..).collect();
-            // Debug sequence nrs
-            if log_enabled!(log::Level::Debug) {
-                let sequence_nrs: Vec<u32> = subscription_acknowledgements
-                    .iter()
-                    .map(|ack| ack.sequence_number)
-                    .collect();
-                debug!(
-                    "async_publish is acknowledging subscription acknowledgements with sequence nrs {:?}",
-                    sequence_nrs
-                );
-            }
-            Some(subscription_acknowledgements)
-        };
-        let request = PublishRequest {
-            request_header: self.make_request_header(),
-            subscription_acknowledgements,
-        };
-        let request_handle = self.async_send_request(request, None)?;
+    pub fn set_keep_alive_interval(&mut self, keep_alive_interval: u32) {
+        self.keep_alive_interval = keep_alive_interval;
+    }
 
-        {
-            let mut subscription_state = trace_write_lock!(self.subscription_state);
-            subscription_state.set_last_publish_request(Instant::now());
-        }
+    pub fn keep_alive_interval(&self) -> u32 {
+        self.keep_alive_interval
+    }
 
-        debug!("async_publish, request sent with handle {}", request_handle);
-        Ok(request_handle)
+    pub fn is_connection_lost(&self) -> bool {
+        self.connection_lost
     }
 
-    /// Synchronously sends a request. The return value is the response to the request
-    pub(crate) fn send_request<T>(&mut self, request: T) -> Result<SupportedMessage, StatusCode>
-    where
-        T: Into<SupportedMessage>,
-    {
-        // A channel is created to receive the response
-        let (sender, receiver) = mpsc::sync_channel(1);
-        // Send the request
-        let request_handle = self.async_send_request(request, Some(sender))?;
-        // Wait for the response
-        let request_timeout = self.request_timeout();
-        self.wait_for_sync_response(request_handle, request_timeout, receiver)
+    pub fn set_auto_close_idle_after(
+        &mut self,
+        auto_close_idle_after: Option<std::time::Duration>,
+    ) {
+        self.auto_close_idle_after = auto_close_idle_after;
     }
 
-    pub(crate) fn reset(&mut self) {
-        // Clear tokens, ids etc.
-        self.session_id = NodeId::null();
-        self.authentication_token = NodeId::null();
-        self.request_handle.reset();
-        self.monitored_item_handle.reset();
+    pub fn auto_close_idle_after(&self) -> Option<std::time::Duration> {
+        self.auto_close_idle_after
+    }
 
-        // Clear the message queue
-        {
-            let mut message_queue = trace_write_lock!(self.message_queue);
-            message_queue.clear();
-        };
+    /// Returns how long it has been since the client last sent a request of its own, or since
+    /// the session was activated. Keep-alives and publish requests do not count.
+    pub fn idle_time(&self) -> std::time::Duration {
+        self.last_activity.elapsed()
     }
 
-    /// Asynchronously sends a request. The return value is the request handle of the request
-    pub(crate) fn async_send_request<T>(
-        &mut self,
-        request: T,
-        sender: Option<SyncSender<SupportedMessage>>,
-    ) -> Result<u32, StatusCode>
-    where
-        T: Into<SupportedMessage>,
-    {
-        let request = request.into();
-        match request {
-            SupportedMessage::OpenSecureChannelRequest(_)
-            | SupportedMessage::CloseSecureChannelRequest(_) => {}
-            _ => {
-                // Make sure secure channel token hasn't expired
-                let _ = self.ensure_secure_channel_token();
+    /// Tests if an activated session has been idle for long enough to be closed. A session with
+    /// subscriptions is never idle, since closing it would end them.
+    pub(crate) fn idle_close_due(&self) -> bool {
+        match self.auto_close_idle_after {
+            Some(auto_close_idle_after) => {
+                self.activated
+                    && self.subscription_ids().is_empty()
+                    && self.idle_time() >= auto_close_idle_after
             }
+            None => false,
         }
+    }
 
-        // TODO should error here if not connected
+    pub(crate) fn set_closed_idle(&mut self, closed_idle: bool) {
+        self.closed_idle = closed_idle;
+    }
 
-        // Enqueue the request
-        let request_handle = request.request_handle();
-        self.add_request(request, sender);
+    pub fn is_closed_idle(&self) -> bool {
+        self.closed_idle
+    }
 
-        Ok(request_handle)
+    pub fn set_requested_secure_channel_lifetime(
+        &mut self,
+        requested_secure_channel_lifetime: u32,
+    ) {
+        self.requested_secure_channel_lifetime = requested_secure_channel_lifetime;
     }
 
-    pub(crate) fn quit(&self) {
-        let message_queue = trace_read_lock!(self.message_queue);
-        message_queue.quit();
+    pub fn requested_secure_channel_lifetime(&self) -> u32 {
+        self.requested_secure_channel_lifetime
     }
 
-    /// Wait for a response with a matching request handle. If request handle is 0 then no match
-    /// is performed and in fact the function is expected to receive no messages except asynchronous
-    /// and housekeeping events from the server. A 0 handle will cause the wait to process at most
-    /// one async message before returning.
-    fn wait_for_sync_response(
-        &mut self,
-        request_handle: u32,
-        request_timeout: u32,
-        receiver: Receiver<SupportedMessage>,
-    ) -> Result<SupportedMessage, StatusCode> {
-        if request_handle == 0 {
-            panic!("Request handle must be non zero");
-        }
-        // Receive messages until the one expected comes back. Publish responses will be consumed
-        // silently.
-        let request_timeout = std::time::Duration::from_millis(request_timeout as u64);
-        receiver.recv_timeout(request_timeout).map_err(|_| {
-            info!("Timeout waiting for response from server");
-            self.request_has_timed_out(request_handle);
-            StatusCode::BadTimeout
-        })
+    pub fn set_secure_channel_retry_policy(&mut self, policy: SecureChannelRetryPolicy) {
+        self.secure_channel_retry_policy = policy;
     }
 
-    fn request_has_timed_out(&self, request_handle: u32) {
-        let mut message_queue = trace_write_lock!(self.message_queue);
-        message_queue.request_has_timed_out(request_handle)
+    pub fn secure_channel_retry_policy(&self) -> &SecureChannelRetryPolicy {
+        &self.secure_channel_retry_policy
     }
 
-    fn add_request(
+    pub fn set_security_policy_preferences(
         &mut self,
-        request: SupportedMessage,
-        sender: Option<SyncSender<SupportedMessage>>,
+        security_policy_preferences: Vec<(SecurityPolicy, MessageSecurityMode)>,
     ) {
-        let mut message_queue = trace_write_lock!(self.message_queue);
-        message_queue.add_request(request, sender)
+        self.security_policy_preferences = security_policy_preferences;
     }
 
-    /// Checks if secure channel token needs to be renewed and renews it
-    fn ensure_secure_channel_token(&mut self) -> Result<(), StatusCode> {
-        let should_renew_security_token = {
-            let secure_channel = trace_read_lock!(self.secure_channel);
-            secure_channel.should_renew_security_token()
-        };
-        if should_renew_security_token {
-            self.issue_or_renew_secure_channel(SecurityTokenRequestType::Renew)
-        } else {
-            Ok(())
-        }
+    pub fn security_policy_preferences(&self) -> &[(SecurityPolicy, MessageSecurityMode)] {
+        &self.security_policy_preferences
     }
 
-    pub(crate) fn issue_or_renew_secure_channel(
-        &mut self,
-        request_type: SecurityTokenRequestType,
-    ) -> Result<(), StatusCode> {
-        trace!("issue_or_renew_secure_channel({:?})", request_type);
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
 
-        const REQUESTED_LIFETIME: u32 = 60000; // TODO
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
 
-        let (security_mode, security_policy, client_nonce) = {
-            let mut secure_channel = trace_write_lock!(self.secure_channel);
-            let client_nonce = secure_channel.security_policy().random_nonce();
-            secure_channel.set_local_nonce(client_nonce.as_ref());
-            (
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = policy;
+    }
+
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        self.reconnect_policy
+    }
+
+    pub fn set_pre_activation_policy(&mut self, policy: PreActivationPolicy) {
+        self.pre_activation_policy = policy;
+        if policy == PreActivationPolicy::Drop {
+            self.pre_activation_responses.clear();
+        }
+    }
+
+    pub fn pre_activation_policy(&self) -> PreActivationPolicy {
+        self.pre_activation_policy
+    }
+
+    pub fn is_activated(&self) -> bool {
+        self.activated
+    }
+
+    /// Marks the session as activated, or not. On activation any publish responses held until
+    /// then are processed.
+    pub(crate) fn set_activated(&mut self, activated: bool) {
+        self.activated = activated;
+        if activated {
+            // The session is only idle from when it is activated
+            self.last_activity = Instant::now();
+            while let Some(response) = self.pre_activation_responses.pop_front() {
+                self.handle_async_response(response);
+            }
+        }
+    }
+
+    /// Returns a snapshot of the traffic of the session so far
+    pub fn diagnostics(&self) -> SessionDiagnostics {
+        self.counters.snapshot()
+    }
+
+    /// Returns the counts and response latency of the requests through the message queue
+    pub fn metrics(&self) -> MessageQueueMetrics {
+        let message_queue = trace_read_lock!(self.message_queue);
+        message_queue.metrics()
+    }
+
+    /// Sets the timeout for publish requests, or `None` to derive it from the subscriptions.
+    pub fn set_publish_timeout(&mut self, publish_timeout: Option<u32>) {
+        self.publish_timeout = publish_timeout;
+    }
+
+    /// Returns the timeout for publish requests. A server holds a publish request until a
+    /// subscription has a notification or keep-alive to send, so unless it is set, the timeout is
+    /// the longest keep-alive interval of the subscriptions, i.e. the publishing interval times
+    /// the keep-alive count, plus the request timeout.
+    pub fn publish_timeout(&self) -> u32 {
+        self.publish_timeout.unwrap_or_else(|| {
+            let max_keep_alive_interval = {
+                let subscription_state = trace_read_lock!(self.subscription_state);
+                subscription_state.max_keep_alive_interval().unwrap_or(0)
+            };
+            let publish_timeout =
+                max_keep_alive_interval.saturating_add(self.request_timeout as u64);
+            cmp::min(publish_timeout, u32::MAX as u64) as u32
+        })
+    }
+
+    pub fn send_buffer_size(&self) -> usize {
+        self.send_buffer_size
+    }
+
+    pub fn add_subscription_acknowledgement(
+        &mut self,
+        subscription_acknowledgement: SubscriptionAcknowledgement,
+    ) {
+        self.subscription_acknowledgements
+            .push(subscription_acknowledgement);
+        // Drop the oldest acknowledgements if they are piling up, e.g. because publish requests
+        // aren't being sent. The server will hold on to the notifications so they can still be
+        // republished.
+        while self.subscription_acknowledgements.len() > self.max_subscription_acknowledgements {
+            let dropped = self.subscription_acknowledgements.remove(0);
+            session_warn!(
+                self,
+                "Too many pending acknowledgements, dropping acknowledgement of sequence nr {} for subscription {}",
+                dropped.sequence_number,
+                dropped.subscription_id
+            );
+            if let Some(ref on_ack_overflow) = self.on_ack_overflow {
+                on_ack_overflow(&dropped);
+            }
+        }
+    }
+
+    /// Sets the most subscription acknowledgements, across all subscriptions, that may be
+    /// pending before the oldest are dropped. The value is at least 1.
+    pub fn set_max_subscription_acknowledgements(
+        &mut self,
+        max_subscription_acknowledgements: usize,
+    ) {
+        self.max_subscription_acknowledgements = cmp::max(1, max_subscription_acknowledgements);
+    }
+
+    pub fn max_subscription_acknowledgements(&self) -> usize {
+        self.max_subscription_acknowledgements
+    }
+
+    pub fn set_ack_overflow_handler<F>(&mut self, on_ack_overflow: F)
+    where
+        F: Fn(&SubscriptionAcknowledgement) + Send + Sync + 'static,
+    {
+        self.on_ack_overflow = Some(Box::new(on_ack_overflow));
+    }
+
+    pub fn set_token_near_expiry_handler<F>(&mut self, on_token_near_expiry: F)
+    where
+        F: Fn(std::time::Duration) + Send + Sync + 'static,
+    {
+        self.on_token_near_expiry = Some(Box::new(on_token_near_expiry));
+    }
+
+    pub fn set_token_near_expiry_fraction(&mut self, token_near_expiry_fraction: f64) {
+        self.token_near_expiry_fraction = token_near_expiry_fraction.clamp(0.0, 1.0);
+    }
+
+    pub fn token_near_expiry_fraction(&self) -> f64 {
+        self.token_near_expiry_fraction
+    }
+
+    /// Tracks a subscription that the server has created.
+    pub(crate) fn add_subscription(&mut self, subscription: Subscription) {
+        let mut subscription_state = trace_write_lock!(self.subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+
+    /// Stops tracking a subscription that has been deleted, dropping any acknowledgements pending
+    /// for it. Returns the subscription if it was being tracked.
+    pub(crate) fn remove_subscription(&mut self, subscription_id: u32) -> Option<Subscription> {
+        self.remove_subscription_acknowledgements(subscription_id);
+        let mut subscription_state = trace_write_lock!(self.subscription_state);
+        subscription_state.delete_subscription(subscription_id)
+    }
+
+    /// The ids of the subscriptions the session is tracking, in ascending order.
+    pub fn subscription_ids(&self) -> Vec<u32> {
+        let subscription_state = trace_read_lock!(self.subscription_state);
+        let mut subscription_ids = subscription_state.subscription_ids().unwrap_or_default();
+        subscription_ids.sort_unstable();
+        subscription_ids
+    }
+
+    /// A summary of each subscription the session is tracking, in ascending order of id.
+    pub fn subscriptions(&self) -> Vec<SubscriptionSummary> {
+        let subscription_ids = self.subscription_ids();
+        let subscription_state = trace_read_lock!(self.subscription_state);
+        subscription_ids
+            .into_iter()
+            .filter_map(|id| subscription_state.get(id).map(SubscriptionSummary::from))
+            .collect()
+    }
+
+    /// Drops the acknowledgements pending for a subscription that has been deleted.
+    pub(crate) fn remove_subscription_acknowledgements(&mut self, subscription_id: u32) {
+        self.subscription_acknowledgements
+            .retain(|ack| ack.subscription_id != subscription_id);
+    }
+
+    pub fn set_authentication_token(&mut self, authentication_token: NodeId) {
+        self.authentication_token = authentication_token;
+    }
+
+    pub fn set_server_nonce(&mut self, server_nonce: ByteString) {
+        self.server_nonce = server_nonce;
+    }
+
+    pub fn server_nonce(&self) -> &ByteString {
+        &self.server_nonce
+    }
+
+    pub fn set_session_closed_callback<CB>(&mut self, session_closed_callback: CB)
+    where
+        CB: OnSessionClosed + Send + Sync + 'static,
+    {
+        self.session_closed_callback = Some(Box::new(session_closed_callback));
+    }
+
+    pub fn set_connection_status_callback<CB>(&mut self, connection_status_callback: CB)
+    where
+        CB: OnConnectionStatusChange + Send + Sync + 'static,
+    {
+        self.connection_status_callback = Some(Box::new(connection_status_callback));
+    }
+
+    pub fn set_request_authorizer<F>(&mut self, request_authorizer: F)
+    where
+        F: Fn(&SupportedMessage) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        self.request_authorizer = Some(Box::new(request_authorizer));
+    }
+
+    pub fn set_certificate_verifier<F>(&mut self, certificate_verifier: F)
+    where
+        F: Fn(&X509) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        self.certificate_verifier = Some(Box::new(certificate_verifier));
+    }
+
+    /// Asks the certificate verifier, if there is one, whether to trust the certificate of the
+    /// server that a secure channel is being opened to. Only a channel that is signed or
+    /// encrypted relies on the certificate, so there is nothing to verify otherwise.
+    fn verify_server_certificate(
+        &self,
+        security_policy: SecurityPolicy,
+        security_mode: MessageSecurityMode,
+    ) -> Result<(), StatusCode> {
+        let certificate_verifier = match self.certificate_verifier {
+            Some(ref certificate_verifier) => certificate_verifier,
+            None => return Ok(()),
+        };
+        if security_policy == SecurityPolicy::None || security_mode == MessageSecurityMode::None {
+            return Ok(());
+        }
+        let server_cert = {
+            let secure_channel = trace_read_lock!(self.secure_channel);
+            secure_channel.remote_cert()
+        };
+        let server_cert = match server_cert {
+            Some(server_cert) => server_cert,
+            None => {
+                session_error!(self, "The server has no certificate to verify");
+                return Err(StatusCode::BadCertificateUntrusted);
+            }
+        };
+        certificate_verifier(&server_cert).map_err(|status_code| {
+            session_error!(
+                self,
+                "The certificate of the server, {}, is not trusted, {}",
+                server_cert.subject_name(),
+                status_code
+            );
+            StatusCode::BadCertificateUntrusted
+        })
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_max_write_array_length(&mut self, max_write_array_length: Option<usize>) {
+        self.max_write_array_length = max_write_array_length;
+    }
+
+    pub fn max_write_array_length(&self) -> Option<usize> {
+        self.max_write_array_length
+    }
+
+    pub fn set_max_total_monitored_items(&mut self, max_total_monitored_items: Option<usize>) {
+        self.max_total_monitored_items = max_total_monitored_items;
+    }
+
+    pub fn max_total_monitored_items(&self) -> Option<usize> {
+        self.max_total_monitored_items
+    }
+
+    /// The number of monitored items created across all subscriptions. Items are counted as they
+    /// are created and stop being counted when they or their subscription are deleted.
+    pub fn total_monitored_items(&self) -> usize {
+        let subscription_state = trace_read_lock!(self.subscription_state);
+        subscription_state.monitored_item_count()
+    }
+
+    /// Checks that creating more monitored items would not take the total across all
+    /// subscriptions over the maximum, if there is one.
+    pub(crate) fn check_total_monitored_items(&self, count: usize) -> Result<(), StatusCode> {
+        let max_total_monitored_items = match self.max_total_monitored_items {
+            Some(max_total_monitored_items) => max_total_monitored_items,
+            None => return Ok(()),
+        };
+        let total_monitored_items = self.total_monitored_items();
+        if total_monitored_items + count > max_total_monitored_items {
+            session_error!(
+                self,
+                "Creating {} monitored items is rejected because there are already {} of a maximum of {}",
+                count,
+                total_monitored_items,
+                max_total_monitored_items
+            );
+            Err(StatusCode::BadTooManyMonitoredItems)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that no value in a write request is an array longer than the maximum write array
+    /// length, if there is one, so an oversized array is rejected without sending it.
+    fn check_write_array_lengths(&self, request: &SupportedMessage) -> Result<(), StatusCode> {
+        let max_write_array_length = match self.max_write_array_length {
+            Some(max_write_array_length) => max_write_array_length,
+            None => return Ok(()),
+        };
+        let nodes_to_write = match request {
+            SupportedMessage::WriteRequest(request) => &request.nodes_to_write,
+            _ => return Ok(()),
+        };
+        let oversized = nodes_to_write
+            .iter()
+            .flatten()
+            .find(|node| match node.value.value {
+                Some(Variant::Array(ref array)) => array.values.len() > max_write_array_length,
+                _ => false,
+            });
+        if let Some(node) = oversized {
+            session_error!(
+                self,
+                "Write of {} is rejected because its array is longer than the maximum of {}",
+                node.node_id,
+                max_write_array_length
+            );
+            Err(StatusCode::BadOutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn set_reissue_channel_on_token_unknown(&mut self, reissue_channel_on_token_unknown: bool) {
+        self.reissue_channel_on_token_unknown = reissue_channel_on_token_unknown;
+    }
+
+    pub fn reissue_channel_on_token_unknown(&self) -> bool {
+        self.reissue_channel_on_token_unknown
+    }
+
+    /// Sets the most requests per second the session sends, or `None` for no limit. A rate that
+    /// is not a positive number is treated as no limit.
+    pub fn set_max_requests_per_second(&mut self, max_requests_per_second: Option<f64>) {
+        self.rate_limiter = match max_requests_per_second {
+            Some(rate) if rate.is_finite() && rate > 0.0 => Some(RateLimiter::new(rate)),
+            Some(rate) => {
+                session_error!(self, "Ignoring invalid max requests per second {}", rate);
+                None
+            }
+            None => None,
+        };
+    }
+
+    pub fn max_requests_per_second(&self) -> Option<f64> {
+        self.rate_limiter
+            .as_ref()
+            .map(|rate_limiter| rate_limiter.requests_per_second())
+    }
+
+    pub fn set_rate_limit_policy(&mut self, rate_limit_policy: RateLimitPolicy) {
+        self.rate_limit_policy = rate_limit_policy;
+    }
+
+    pub fn rate_limit_policy(&self) -> RateLimitPolicy {
+        self.rate_limit_policy
+    }
+
+    pub fn set_partial_results_policy(&mut self, partial_results_policy: PartialResultsPolicy) {
+        self.partial_results_policy = partial_results_policy;
+    }
+
+    pub fn partial_results_policy(&self) -> PartialResultsPolicy {
+        self.partial_results_policy
+    }
+
+    pub fn set_preferred_timestamp(&mut self, preferred_timestamp: TimestampPreference) {
+        self.preferred_timestamp = preferred_timestamp;
+    }
+
+    pub fn preferred_timestamp(&self) -> TimestampPreference {
+        self.preferred_timestamp
+    }
+
+    /// Sets other urls of the server to try when the endpoint url can't be connected to, e.g. the
+    /// urls of a redundant server. Fails with `BadTcpEndpointUrlInvalid`, leaving the urls
+    /// unchanged, if any url is not a valid opc.tcp url.
+    pub fn set_alternate_endpoint_urls(
+        &mut self,
+        alternate_endpoint_urls: Vec<UAString>,
+    ) -> Result<(), StatusCode> {
+        if let Some(url) = alternate_endpoint_urls
+            .iter()
+            .find(|url| !is_valid_opc_ua_url(url.as_ref()))
+        {
+            session_error!(self, "Alternate endpoint url {} is not valid", url);
+            Err(StatusCode::BadTcpEndpointUrlInvalid)
+        } else {
+            self.alternate_endpoint_urls = alternate_endpoint_urls;
+            Ok(())
+        }
+    }
+
+    pub fn alternate_endpoint_urls(&self) -> &[UAString] {
+        &self.alternate_endpoint_urls
+    }
+
+    pub(crate) fn set_last_endpoint_url(&mut self, endpoint_url: UAString) {
+        self.last_endpoint_url = Some(endpoint_url);
+    }
+
+    pub fn last_endpoint_url(&self) -> Option<&UAString> {
+        self.last_endpoint_url.as_ref()
+    }
+
+    /// Returns the urls to try connecting to, in order. The url that last worked comes first,
+    /// then the endpoint url and then the alternates.
+    pub fn endpoint_urls(&self, endpoint_url: &UAString) -> Vec<UAString> {
+        let mut endpoint_urls = Vec::with_capacity(self.alternate_endpoint_urls.len() + 2);
+        self.last_endpoint_url
+            .iter()
+            .chain(std::iter::once(endpoint_url))
+            .chain(self.alternate_endpoint_urls.iter())
+            .for_each(|url| {
+                if !endpoint_urls.contains(url) {
+                    endpoint_urls.push(url.clone());
+                }
+            });
+        endpoint_urls
+    }
+
+    pub fn set_error_handler<F>(&mut self, on_error: F)
+    where
+        F: Fn(&str, StatusCode) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(on_error));
+    }
+
+    pub fn set_keep_alive_handler<F>(&mut self, on_keep_alive: F)
+    where
+        F: Fn(u32) + Send + Sync + 'static,
+    {
+        self.on_keep_alive = Some(Box::new(on_keep_alive));
+    }
+
+    pub fn set_notification_sender(&mut self, sender: Option<SyncSender<(u32, DataValue)>>) {
+        self.notification_sender = sender;
+    }
+
+    /// Sends the client handle and value of each monitored item in the data change notifications
+    /// to the notification sender, if there is one. The receive thread never waits on the
+    /// channel, so a notification that doesn't fit is dropped, and the sender is dropped once
+    /// the receiver has gone.
+    fn send_notifications(&mut self, data_change_notifications: &[DataChangeNotification]) {
+        let sender = match self.notification_sender {
+            Some(ref sender) => sender.clone(),
+            None => return,
+        };
+        let monitored_items = data_change_notifications
+            .iter()
+            .filter_map(|notification| notification.monitored_items.as_ref())
+            .flatten();
+        for monitored_item in monitored_items {
+            match sender.try_send((monitored_item.client_handle, monitored_item.value.clone())) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    session_warn!(
+                        self,
+                        "Notification channel is full, dropping the notification for client handle {}",
+                        monitored_item.client_handle
+                    );
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    session_warn!(
+                        self,
+                        "Notification channel has been disconnected, no more notifications will be sent to it"
+                    );
+                    self.notification_sender = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tells the error handler, if there is one, that the operation failed. The result is
+    /// returned unchanged.
+    fn report_error<T, E>(&self, operation: &str, result: Result<T, E>) -> Result<T, E>
+    where
+        E: Copy + Into<StatusCode>,
+    {
+        if let (Some(ref on_error), Err(error)) = (&self.on_error, &result) {
+            on_error(operation, (*error).into());
+        }
+        result
+    }
+
+    /// Tests if the request counts against the rate limit. Requests that keep the session and
+    /// secure channel alive are exempt so a busy client can't starve them.
+    fn is_rate_limited_request(request: &SupportedMessage) -> bool {
+        !matches!(
+            request,
+            SupportedMessage::PublishRequest(_)
+                | SupportedMessage::OpenSecureChannelRequest(_)
+                | SupportedMessage::CloseSecureChannelRequest(_)
+                | SupportedMessage::CloseSessionRequest(_)
+        )
+    }
+
+    /// Tests if the request can be sent again without changing the outcome, i.e. it only reads
+    /// from the server, so it may be retried automatically after a timeout.
+    fn is_idempotent_request(request: &SupportedMessage) -> bool {
+        matches!(
+            request,
+            SupportedMessage::GetEndpointsRequest(_)
+                | SupportedMessage::FindServersRequest(_)
+                | SupportedMessage::BrowseRequest(_)
+                | SupportedMessage::TranslateBrowsePathsToNodeIdsRequest(_)
+                | SupportedMessage::ReadRequest(_)
+        )
+    }
+
+    /// Waits until the rate limit allows another request, or fails with `BadResourceUnavailable`
+    /// if the policy is to reject requests over the limit.
+    fn acquire_rate_limit(&mut self, request: &SupportedMessage) -> Result<(), StatusCode> {
+        while let Some(wait) = self.take_rate_limit(request)? {
+            thread::sleep(wait);
+        }
+        Ok(())
+    }
+
+    /// Takes the rate limit for the request without waiting, so the caller can wait with the
+    /// session state unlocked. Returns how long to wait before trying again if the limit has been
+    /// reached, or fails with `BadResourceUnavailable` if the policy is to reject requests over
+    /// the limit. Once taken, the next request sent doesn't take the rate limit again, so it must
+    /// be sent before the session state is unlocked.
+    pub(crate) fn try_acquire_rate_limit(
+        &mut self,
+        request: &SupportedMessage,
+    ) -> Result<Option<std::time::Duration>, StatusCode> {
+        if !Self::is_rate_limited_request(request) {
+            return Ok(None);
+        }
+        let wait = self.take_rate_limit(request)?;
+        self.rate_limit_acquired = wait.is_none();
+        Ok(wait)
+    }
+
+    /// Takes a token from the rate limiter for the request, returning how long to wait for one
+    /// if there are none
+    fn take_rate_limit(
+        &mut self,
+        request: &SupportedMessage,
+    ) -> Result<Option<std::time::Duration>, StatusCode> {
+        let result = match self.rate_limiter {
+            Some(ref mut rate_limiter) => rate_limiter.try_acquire(std::time::Instant::now()),
+            None => Ok(()),
+        };
+        match result {
+            Ok(_) => Ok(None),
+            Err(_) if self.rate_limit_policy == RateLimitPolicy::Reject => {
+                session_debug!(
+                    self,
+                    "Request {} {} is rejected because it exceeds the rate limit",
+                    request.type_name(),
+                    request.request_handle()
+                );
+                Err(StatusCode::BadResourceUnavailable)
+            }
+            Err(wait) => {
+                session_trace!(self, "Rate limit reached, waiting {:?}", wait);
+                Ok(Some(wait))
+            }
+        }
+    }
+
+    /// Tests if the request would modify the server's state, e.g. by writing a value, calling a
+    /// method or changing the address space.
+    fn is_mutating_request(request: &SupportedMessage) -> bool {
+        matches!(
+            request,
+            SupportedMessage::WriteRequest(_)
+                | SupportedMessage::CallRequest(_)
+                | SupportedMessage::HistoryUpdateRequest(_)
+                | SupportedMessage::AddNodesRequest(_)
+                | SupportedMessage::AddReferencesRequest(_)
+                | SupportedMessage::DeleteNodesRequest(_)
+                | SupportedMessage::DeleteReferencesRequest(_)
+        )
+    }
+
+    pub(crate) fn on_connection_status_change(&mut self, connected: bool) {
+        if let Some(ref mut connection_status) = self.connection_status_callback {
+            connection_status.on_connection_status_change(connected);
+        }
+    }
+
+    pub(crate) fn connection_state(&self) -> ConnectionStateMgr {
+        self.connection_state.clone()
+    }
+
+    /// Construct a request header for the session. All requests after create session are expected
+    /// to supply an authentication token.
+    pub fn make_request_header(&mut self) -> RequestHeader {
+        RequestHeader {
+            authentication_token: self.authentication_token.clone(),
+            timestamp: DateTime::now_with_offset(self.client_offset),
+            request_handle: self.request_handle.next(),
+            return_diagnostics: self.return_diagnostics,
+            audit_entry_id: self
+                .audit_entry_id
+                .as_ref()
+                .map(UAString::from)
+                .unwrap_or_default(),
+            timeout_hint: self.timeout_hint(),
+            ..Default::default()
+        }
+    }
+
+    /// Construct a request header for the session with an audit entry id for just this request,
+    /// instead of the session's audit entry id.
+    pub fn make_request_header_with_audit(&mut self, audit_entry_id: &str) -> RequestHeader {
+        RequestHeader {
+            audit_entry_id: audit_entry_id.into(),
+            ..self.make_request_header()
+        }
+    }
+
+    /// Construct a request header for a request made while connecting, i.e. open secure channel,
+    /// create session or activate session. The timeout hint is the connect timeout.
+    pub(crate) fn make_connect_request_header(&mut self) -> RequestHeader {
+        RequestHeader {
+            timeout_hint: self.connect_timeout,
+            ..self.make_request_header()
+        }
+    }
+
+    /// Returns the number of publish requests waiting for a response
+    #[cfg(test)]
+    pub fn outstanding_publish_requests(&self) -> usize {
+        self.outstanding_publish_requests.len()
+    }
+
+    /// Returns the number of publish requests that may currently be outstanding
+    #[cfg(test)]
+    pub fn max_outstanding_publish_requests(&self) -> usize {
+        self.max_outstanding_publish_requests
+    }
+
+    /// Sends a publish request containing acknowledgements for previous notifications. No request
+    /// is sent and `BadTooManyPublishRequests` is returned if the limit of outstanding publish
+    /// requests has been reached. That is the session backing off rather than a failure, so it
+    /// isn't passed to the error handler.
+    pub fn async_publish(&mut self) -> Result<u32, StatusCode> {
+        self.async_publish_with_outcome(None)
+    }
+
+    /// Sends a publish request like `async_publish()`, returning a future that resolves to the
+    /// response once it has been processed.
+    pub fn async_publish_future(&mut self) -> Result<PublishFuture, StatusCode> {
+        let (sender, receiver) = oneshot::channel();
+        self.async_publish_with_outcome(Some(sender))?;
+        Ok(PublishFuture { receiver })
+    }
+
+    fn async_publish_with_outcome(
+        &mut self,
+        outcome: Option<PublishOutcomeSender>,
+    ) -> Result<u32, StatusCode> {
+        if self.outstanding_publish_requests.len() >= self.max_outstanding_publish_requests {
+            debug!(
+                "async_publish is not sending a request because {} are already outstanding",
+                self.outstanding_publish_requests.len()
+            );
+            return Err(StatusCode::BadTooManyPublishRequests);
+        }
+        let result = self.async_publish_inner(outcome);
+        self.report_error("async_publish", result)
+    }
+
+    fn async_publish_inner(
+        &mut self,
+        outcome: Option<PublishOutcomeSender>,
+    ) -> Result<u32, StatusCode> {
+        let subscription_acknowledgements = if self.subscription_acknowledgements.is_empty() {
+            None
+        } else {
+            let subscription_acknowledgements: Vec<SubscriptionAcknowledgement> =
+                self.subscription_acknowledgements.drain(..).collect();
+            // Debug sequence nrs
+            if log_enabled!(log::Level::Debug) {
+                let sequence_nrs: Vec<u32> = subscription_acknowledgements
+                    .iter()
+                    .map(|ack| ack.sequence_number)
+                    .collect();
+                debug!(
+                    "async_publish is acknowledging subscription acknowledgements with sequence nrs {:?}",
+                    sequence_nrs
+                );
+            }
+            Some(subscription_acknowledgements)
+        };
+        // The server may hold the request for as long as the keep-alive interval, so it is given
+        // a longer timeout than other requests
+        let mut request_header = self.make_request_header();
+        request_header.timeout_hint = self.publish_timeout();
+        let request = PublishRequest {
+            request_header,
+            subscription_acknowledgements,
+        };
+        let request_handle = self.async_send_request_inner(request.into(), None, false)?;
+        // A dry run request gets no response to account for it
+        if self.dry_run {
+            if let Some(outcome) = outcome {
+                let _ = outcome.send(Err(StatusCode::BadNothingToDo));
+            }
+        } else {
+            self.outstanding_publish_requests
+                .insert(request_handle, outcome);
+        }
+
+        {
+            let mut subscription_state = trace_write_lock!(self.subscription_state);
+            subscription_state.set_last_publish_request(Instant::now());
+        }
+
+        debug!("async_publish, request sent with handle {}", request_handle);
+        Ok(request_handle)
+    }
+
+    /// Synchronously sends a request. The return value is the response to the request. An error
+    /// carries the handle and type of the request, e.g. to tell which request timed out.
+    pub(crate) fn send_request<T>(&mut self, request: T) -> Result<SupportedMessage, RequestError>
+    where
+        T: Into<SupportedMessage>,
+    {
+        self.last_activity = Instant::now();
+        let request_timeout = self.request_timeout();
+        let result = self.send_request_inner(request.into(), request_timeout, true);
+        self.report_error("send_request", result)
+    }
+
+    /// Synchronously sends a request, waiting for up to the supplied timeout rather than the
+    /// request timeout for the response, e.g. for a history read over a long period. The timeout
+    /// is also passed to the server as the timeout hint of the request.
+    pub fn send_request_with_timeout<T>(
+        &mut self,
+        request: T,
+        timeout_ms: u32,
+    ) -> Result<SupportedMessage, RequestError>
+    where
+        T: Into<SupportedMessage>,
+    {
+        self.last_activity = Instant::now();
+        let mut request = request.into();
+        let result = match request.request_header_mut() {
+            Some(request_header) => {
+                request_header.timeout_hint = timeout_ms;
+                self.send_request_inner(request, timeout_ms, true)
+            }
+            None => Err(RequestError {
+                status_code: StatusCode::BadRequestTypeInvalid,
+                request_handle: request.request_handle(),
+                request_type: request.type_name(),
+            }),
+        };
+        self.report_error("send_request_with_timeout", result)
+    }
+
+    /// Synchronously sends a request made while connecting, waiting for up to the connect timeout
+    /// rather than the request timeout for the response.
+    pub(crate) fn send_connect_request<T>(
+        &mut self,
+        request: T,
+    ) -> Result<SupportedMessage, RequestError>
+    where
+        T: Into<SupportedMessage>,
+    {
+        let connect_timeout = self.connect_timeout();
+        let result = self.send_request_inner(request.into(), connect_timeout, true);
+        self.report_error("send_connect_request", result)
+    }
+
+    fn send_request_inner(
+        &mut self,
+        mut request: SupportedMessage,
+        request_timeout: u32,
+        rate_limited: bool,
+    ) -> Result<SupportedMessage, RequestError> {
+        if !self.reissue_channel_on_token_unknown
+            || matches!(request, SupportedMessage::OpenSecureChannelRequest(_))
+        {
+            return self.send_request_with_retries(request, request_timeout, rate_limited);
+        }
+        let result = self.send_request_with_retries(request.clone(), request_timeout, rate_limited);
+        let token_unknown = match result {
+            Ok(SupportedMessage::ServiceFault(ref response)) => {
+                response.response_header.service_result == StatusCode::BadSecureChannelTokenUnknown
+            }
+            Err(ref error) => error.status_code == StatusCode::BadSecureChannelTokenUnknown,
+            _ => false,
+        };
+        if !token_unknown {
+            return result;
+        }
+        // The token probably expired before it was renewed, so a new channel is opened and the
+        // request is tried once more
+        session_warn!(
+            self,
+            "Server does not know the secure channel token, opening a new secure channel"
+        );
+        let reissued = self.issue_or_renew_secure_channel(SecurityTokenRequestType::Issue);
+        if let Err(status_code) = reissued {
+            session_error!(
+                self,
+                "Secure channel could not be reissued, {}",
+                status_code
+            );
+            return result;
+        }
+        self.renew_request_header(&mut request);
+        self.send_request_with_retries(request, request_timeout, rate_limited)
+    }
+
+    /// Gives a request that is sent again a new request handle and timestamp, as it is a new
+    /// request as far as the server is concerned.
+    fn renew_request_header(&mut self, request: &mut SupportedMessage) {
+        let request_header = self.make_request_header();
+        if let Some(header) = request.request_header_mut() {
+            header.request_handle = request_header.request_handle;
+            header.timestamp = request_header.timestamp;
+        }
+    }
+
+    fn send_request_with_retries(
+        &mut self,
+        mut request: SupportedMessage,
+        request_timeout: u32,
+        rate_limited: bool,
+    ) -> Result<SupportedMessage, RequestError> {
+        if self.retry_policy.max_retries == 0 || !Self::is_idempotent_request(&request) {
+            return self.send_request_once(request, request_timeout, rate_limited);
+        }
+        let mut retries = 0;
+        loop {
+            let result = self.send_request_once(request.clone(), request_timeout, rate_limited);
+            match result {
+                Err(error)
+                    if error.status_code == StatusCode::BadTimeout
+                        && retries < self.retry_policy.max_retries =>
+                {
+                    retries += 1;
+                    let backoff = self.retry_policy.backoff(retries);
+                    session_warn!(
+                        self,
+                        "{}, retry {} of {} in {} ms",
+                        error,
+                        retries,
+                        self.retry_policy.max_retries,
+                        backoff
+                    );
+                    thread::sleep(std::time::Duration::from_millis(backoff as u64));
+                    self.renew_request_header(&mut request);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send_request_once(
+        &mut self,
+        request: SupportedMessage,
+        request_timeout: u32,
+        rate_limited: bool,
+    ) -> Result<SupportedMessage, RequestError> {
+        let request_handle = request.request_handle();
+        let request_type = request.type_name();
+        let request_error = |status_code| RequestError {
+            status_code,
+            request_handle,
+            request_type,
+        };
+        // Send the request
+        let response = self
+            .async_send_request_future_inner(request, rate_limited)
+            .map_err(request_error)?;
+        // Wait for the response
+        self.wait_for_sync_response(response, request_timeout)
+            .map_err(request_error)
+    }
+
+    /// Clears the ids of the session so a new one can be created. The request and monitored item
+    /// handles carry on from where they were, so the client handles of monitored items that are
+    /// recreated on the new session stay unique.
+    pub(crate) fn reset(&mut self) {
+        // Clear tokens, ids etc.
+        self.session_id = NodeId::null();
+        self.authentication_token = NodeId::null();
+        self.server_nonce = ByteString::null();
+        self.reset_connection();
+    }
+
+    /// Forgets the requests of a connection that has gone, along with whether it was lost and
+    /// the responses held until the session was activated on it. The ids of the session are kept
+    /// so it can be activated again over a new connection.
+    pub(crate) fn reset_connection(&mut self) {
+        self.outstanding_publish_requests.clear();
+        self.activated = false;
+        self.connection_lost = false;
+        self.pre_activation_responses.clear();
+
+        // Clear the message queue
+        {
+            let mut message_queue = trace_write_lock!(self.message_queue);
+            message_queue.clear();
+        };
+    }
+
+    /// Restores a session from its persisted ids and the security token of its secure channel,
+    /// so a client can carry on with a session without creating and activating a new one. The
+    /// secure channel and session must still be open on the server, which is confirmed by
+    /// reading the server state. If the server no longer recognizes the session, the restored
+    /// ids are cleared and `BadSessionIdInvalid` is returned.
+    ///
+    /// A session can only be restored on the secure channel that issued the token. A token of
+    /// any other channel is rejected with `BadSecureChannelIdInvalid`, leaving the channel and
+    /// session as they were.
+    pub fn restore(
+        &mut self,
+        session_id: NodeId,
+        authentication_token: NodeId,
+        channel_token: ChannelSecurityToken,
+    ) -> Result<(), StatusCode> {
+        {
+            let mut secure_channel = trace_write_lock!(self.secure_channel);
+            if channel_token.channel_id != secure_channel.secure_channel_id() {
+                session_error!(
+                    self,
+                    "Session could not be restored, the token is for secure channel {} but the channel is {}",
+                    channel_token.channel_id,
+                    secure_channel.secure_channel_id()
+                );
+                return self.report_error("restore", Err(StatusCode::BadSecureChannelIdInvalid));
+            }
+            secure_channel.set_client_offset(self.client_offset);
+            secure_channel.restore_security_token(channel_token);
+        }
+        self.session_id = session_id;
+        self.authentication_token = authentication_token;
+
+        let server_state: NodeId = VariableId::Server_ServerStatus_State.into();
+        let request = ReadRequest {
+            request_header: self.make_request_header(),
+            max_age: 0f64,
+            timestamps_to_return: TimestampsToReturn::Neither,
+            nodes_to_read: Some(vec![server_state.into()]),
+        };
+        let request_timeout = self.request_timeout();
+        let restore_error = |status_code| match status_code {
+            StatusCode::BadSessionIdInvalid
+            | StatusCode::BadSessionClosed
+            | StatusCode::BadSessionNotActivated
+            | StatusCode::BadIdentityTokenInvalid => StatusCode::BadSessionIdInvalid,
+            status_code => status_code,
+        };
+        let result = match self.send_request_inner(request.into(), request_timeout, true) {
+            Ok(SupportedMessage::ReadResponse(response)) => {
+                let service_result = response.response_header.service_result;
+                if service_result.is_bad() {
+                    Err(restore_error(service_result))
+                } else {
+                    // The server has accepted the session even if it won't give the value, e.g.
+                    // because the user may not read it, so the session is still restored
+                    let status = response
+                        .results
+                        .as_ref()
+                        .and_then(|results| results.first())
+                        .map(|result| result.status())
+                        .unwrap_or(StatusCode::BadUnexpectedError);
+                    if status.is_bad() {
+                        session_warn!(
+                            self,
+                            "Reading the server state to restore the session failed with {}",
+                            status
+                        );
+                    }
+                    Ok(())
+                }
+            }
+            Ok(response) => Err(restore_error(process_unexpected_response(response))),
+            Err(error) => Err(error.status_code),
+        };
+        if let Err(status_code) = result {
+            session_error!(self, "Session could not be restored, {}", status_code);
+            self.session_id = NodeId::null();
+            self.authentication_token = NodeId::null();
+        } else {
+            self.set_activated(true);
+        }
+        self.report_error("restore", result)
+    }
+
+    /// Tests if a keep-alive is due, i.e. keep-alives are enabled and nothing has been received
+    /// from the server within the keep-alive interval. Any other response shows the connection is
+    /// alive, so a keep-alive is not needed while there is other traffic.
+    pub(crate) fn keep_alive_due(&self) -> bool {
+        if self.keep_alive_interval == 0 {
+            return false;
+        }
+        let last_response = {
+            let message_queue = trace_read_lock!(self.message_queue);
+            message_queue.last_response()
+        };
+        let keep_alive_interval = std::time::Duration::from_millis(self.keep_alive_interval as u64);
+        last_response
+            .map(|last_response| last_response.elapsed() >= keep_alive_interval)
+            .unwrap_or(true)
+    }
+
+    /// Reads the state of the server as a keep-alive, to show the connection still works. If the
+    /// read fails or times out, the connection is flagged as lost until a later keep-alive
+    /// succeeds.
+    pub fn keep_alive(&mut self) -> Result<(), StatusCode> {
+        let server_state: NodeId = VariableId::Server_ServerStatus_State.into();
+        let request = ReadRequest {
+            request_header: self.make_request_header(),
+            max_age: 0f64,
+            timestamps_to_return: TimestampsToReturn::Neither,
+            nodes_to_read: Some(vec![server_state.into()]),
+        };
+        let request_timeout = self.request_timeout();
+        // Exempt from the rate limit so a busy client can't starve the keep-alive and lose the
+        // session
+        let result = match self.send_request_inner(request.into(), request_timeout, false) {
+            Ok(SupportedMessage::ReadResponse(response)) => {
+                process_service_result(&response.response_header)
+            }
+            Ok(response) => Err(process_unexpected_response(response)),
+            Err(error) => Err(error.status_code),
+        };
+        match result {
+            Ok(_) => {
+                if self.connection_lost {
+                    session_debug!(self, "Keep-alive succeeded, the connection is back");
+                }
+                self.connection_lost = false;
+            }
+            Err(status_code) => {
+                session_warn!(
+                    self,
+                    "Keep-alive failed, the connection is lost, {}",
+                    status_code
+                );
+                self.connection_lost = true;
+            }
+        }
+        self.report_error("keep_alive", result)
+    }
+
+    /// Transfers subscriptions, e.g. those of a session that was lost, to this session by
+    /// sending a `TransferSubscriptionsRequest`, so they don't have to be created again along with
+    /// all of their monitored items.
+    ///
+    /// Each subscription has its own result and the caller must check them, since any that
+    /// failed to transfer still have to be created again. If `send_initial_values` is true, the
+    /// server sends the current value of every monitored item in the transferred subscriptions
+    /// and they are reported as initial values.
+    pub fn transfer_subscriptions(
+        &mut self,
+        subscription_ids: &[u32],
+        send_initial_values: bool,
+    ) -> Result<Vec<TransferResult>, StatusCode> {
+        if subscription_ids.is_empty() {
+            session_error!(
+                self,
+                "transfer_subscriptions, no subscription ids were provided"
+            );
+            return Err(StatusCode::BadNothingToDo);
+        }
+        let request = TransferSubscriptionsRequest {
+            request_header: self.make_request_header(),
+            subscription_ids: Some(subscription_ids.to_vec()),
+            send_initial_values,
+        };
+        let request_timeout = self.request_timeout();
+        let result = match self.send_request_inner(request.into(), request_timeout, true) {
+            Ok(SupportedMessage::TransferSubscriptionsResponse(response)) => {
+                process_service_result(&response.response_header)
+                    .map(|_| response.results.unwrap_or_default())
+                    .and_then(|results| {
+                        if results.len() == subscription_ids.len() {
+                            Ok(results)
+                        } else {
+                            session_error!(
+                                self,
+                                "transfer_subscriptions, expected {} results but got {}",
+                                subscription_ids.len(),
+                                results.len()
+                            );
+                            Err(StatusCode::BadUnexpectedError)
+                        }
+                    })
+            }
+            Ok(response) => Err(process_unexpected_response(response)),
+            Err(error) => Err(error.status_code),
+        };
+        if let Ok(ref results) = result {
+            let mut subscription_state = trace_write_lock!(self.subscription_state);
+            subscription_ids
+                .iter()
+                .zip(results.iter())
+                .for_each(|(subscription_id, result)| {
+                    if result.status_code.is_good() {
+                        // The server will send the current value of every monitored item in the
+                        // transferred subscription, so mark them to be reported as initial values
+                        if send_initial_values {
+                            subscription_state.expect_initial_values(*subscription_id);
+                        }
+                    } else {
+                        session_warn!(
+                            self,
+                            "Subscription {} could not be transferred, {}",
+                            subscription_id,
+                            result.status_code
+                        );
+                    }
+                });
+        }
+        self.report_error("transfer_subscriptions", result)
+    }
+
+    /// Asks the server to resend a notification message of a subscription, e.g. to fill a gap
+    /// in the sequence numbers of the publish responses, by sending a `RepublishRequest`.
+    ///
+    /// The server only keeps messages that haven't been acknowledged and may discard those too,
+    /// in which case this returns `BadMessageNotAvailable` and the caller should give up on that
+    /// sequence number.
+    pub fn republish(
+        &mut self,
+        subscription_id: u32,
+        retransmit_sequence_number: u32,
+    ) -> Result<NotificationMessage, StatusCode> {
+        let request = RepublishRequest {
+            request_header: self.make_request_header(),
+            subscription_id,
+            retransmit_sequence_number,
+        };
+        let request_timeout = self.request_timeout();
+        let result = match self.send_request_inner(request.into(), request_timeout, true) {
+            Ok(SupportedMessage::RepublishResponse(response)) => {
+                process_service_result(&response.response_header)
+                    .map(|_| response.notification_message)
+            }
+            Ok(response) => Err(process_unexpected_response(response)),
+            Err(error) => Err(error.status_code),
+        };
+        match result {
+            Err(StatusCode::BadMessageNotAvailable) => {
+                session_warn!(
+                    self,
+                    "Notification message {} of subscription {} is no longer available",
+                    retransmit_sequence_number,
+                    subscription_id
+                );
+            }
+            Err(status_code) => {
+                session_error!(
+                    self,
+                    "Notification message {} of subscription {} could not be republished, {}",
+                    retransmit_sequence_number,
+                    subscription_id,
+                    status_code
+                );
+            }
+            Ok(_) => {}
+        }
+        self.report_error("republish", result)
+    }
+
+    /// Asynchronously sends a request. The return value is the request handle of the request
+    pub(crate) fn async_send_request<T>(
+        &mut self,
+        request: T,
+        sender: Option<SyncSender<SupportedMessage>>,
+    ) -> Result<u32, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        self.last_activity = Instant::now();
+        let result =
+            self.async_send_request_inner(request.into(), sender.map(ResponseSender::from), true);
+        self.report_error("async_send_request", result)
+    }
+
+    /// Asynchronously sends a request. The return value is a future that resolves to the response
+    /// to the request.
+    pub fn async_send_request_future<T>(&mut self, request: T) -> Result<ResponseFuture, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        self.last_activity = Instant::now();
+        let result = self.async_send_request_future_inner(request.into(), true);
+        self.report_error("async_send_request_future", result)
+    }
+
+    fn async_send_request_future_inner(
+        &mut self,
+        request: SupportedMessage,
+        rate_limited: bool,
+    ) -> Result<ResponseFuture, StatusCode> {
+        let (sender, receiver) = oneshot::channel();
+        let request_handle = self.async_send_request_inner(
+            request,
+            Some(ResponseSender::Future(sender)),
+            rate_limited,
+        )?;
+        Ok(ResponseFuture::new(
+            request_handle,
+            receiver,
+            self.message_queue.clone(),
+        ))
+    }
+
+    /// Sends a request that keeps the session alive without counting it against the rate limit.
+    pub(crate) fn async_send_keep_alive_request<T>(&mut self, request: T) -> Result<u32, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        let result = self.async_send_request_inner(request.into(), None, false);
+        self.report_error("async_send_request", result)
+    }
+
+    fn async_send_request_inner(
+        &mut self,
+        request: SupportedMessage,
+        sender: Option<ResponseSender>,
+        rate_limited: bool,
+    ) -> Result<u32, StatusCode> {
+        let rate_limit_acquired = std::mem::take(&mut self.rate_limit_acquired);
+        if self.read_only && Self::is_mutating_request(&request) {
+            session_debug!(
+                self,
+                "Request {} {} is rejected because the session is read only",
+                request.type_name(),
+                request.request_handle()
+            );
+            return Err(StatusCode::BadUserAccessDenied);
+        }
+        if let Some(ref request_authorizer) = self.request_authorizer {
+            if let Err(status_code) = request_authorizer(&request) {
+                session_debug!(
+                    self,
+                    "Request {} {} was not authorized, {}",
+                    request.type_name(),
+                    request.request_handle(),
+                    status_code
+                );
+                return Err(status_code);
+            }
+        }
+        self.check_write_array_lengths(&request)?;
+
+        if self.dry_run {
+            return self.dry_run_request(request, sender);
+        }
+
+        if rate_limited && !rate_limit_acquired && Self::is_rate_limited_request(&request) {
+            self.acquire_rate_limit(&request)?;
+        }
+
+        match request {
+            SupportedMessage::OpenSecureChannelRequest(_)
+            | SupportedMessage::CloseSecureChannelRequest(_) => {}
+            _ => {
+                // Make sure secure channel token hasn't expired
+                let _ = self.ensure_secure_channel_token();
+            }
+        }
+
+        // TODO should error here if not connected
+
+        // Enqueue the request
+        let request_handle = request.request_handle();
+        session_debug!(
+            self,
+            "Sending request {} {}",
+            request.type_name(),
+            request_handle
+        );
+        self.add_request(request, sender)?;
+        self.counters.request_sent();
+
+        Ok(request_handle)
+    }
+
+    /// Closes the session, then the secure channel, and clears the session id and authentication
+    /// token. The secure channel is closed even if closing the session fails, in which case the
+    /// reason the session wasn't closed is returned. If `delete_subscriptions` is set, the server
+    /// deletes the session's subscriptions and they are no longer tracked.
+    pub fn close_session(&mut self, delete_subscriptions: bool) -> Result<(), StatusCode> {
+        let result = if self.session_id.is_null() {
+            Ok(())
+        } else {
+            let request = CloseSessionRequest {
+                request_header: self.make_request_header(),
+                delete_subscriptions,
+            };
+            let request_timeout = self.request_timeout();
+            match self.send_request_inner(request.into(), request_timeout, true) {
+                Ok(SupportedMessage::CloseSessionResponse(response)) => {
+                    process_service_result(&response.response_header)
+                }
+                Ok(response) => Err(process_unexpected_response(response)),
+                Err(error) => Err(error.status_code),
+            }
+        };
+        if result.is_ok() && delete_subscriptions {
+            for subscription_id in self.subscription_ids() {
+                let _ = self.remove_subscription(subscription_id);
+            }
+        }
+
+        // There may not be a response to closing the secure channel so it is not waited for
+        let request = CloseSecureChannelRequest {
+            request_header: self.make_request_header(),
+        };
+        let channel_result = self.async_send_request(request, None).map(|_| ());
+
+        self.session_id = NodeId::null();
+        self.authentication_token = NodeId::null();
+        self.activated = false;
+        self.report_error("close_session", result.and(channel_result))
+    }
+
+    pub(crate) fn quit(&self) {
+        let message_queue = trace_read_lock!(self.message_queue);
+        message_queue.quit();
+    }
+
+    /// Encodes the request to find any encoding errors and logs it instead of sending it. The
+    /// sender, if there is one, is answered with a placeholder from
+    /// [`SessionState::dry_run_response`] in place of the real response.
+    fn dry_run_request(
+        &self,
+        request: SupportedMessage,
+        sender: Option<ResponseSender>,
+    ) -> Result<u32, StatusCode> {
+        let request_handle = request.request_handle();
+        let mut stream = Vec::with_capacity(request.byte_len());
+        if let Err(status_code) = request.encode(&mut stream) {
+            session_error!(
+                self,
+                "Request {} {} could not be encoded, {}",
+                request.type_name(),
+                request_handle,
+                status_code
+            );
+            return Err(status_code);
+        }
+        session_debug!(
+            self,
+            "Dry run, request {} {} of {} bytes is not sent",
+            request.type_name(),
+            request_handle,
+            stream.len()
+        );
+        session_trace!(self, "Dry run request {:?}", request);
+        if let Some(sender) = sender {
+            let _ = sender.send(Self::dry_run_response(&request));
+        }
+        Ok(request_handle)
+    }
+
+    /// Makes the placeholder response to a dry run request. It is the response the request
+    /// expects, with a `Good` result for every operation, and ids and revised values taken from
+    /// the request, e.g. a new subscription's id is the request handle and a new monitored item's
+    /// id is its client handle. Requests that need a server to answer them, e.g. to create a
+    /// session, get a service fault of `BadServiceUnsupported`.
+    fn dry_run_response(request: &SupportedMessage) -> SupportedMessage {
+        fn good_results<T, R>(items: &Option<Vec<T>>, result: impl Fn(&T) -> R) -> Option<Vec<R>> {
+            items
+                .as_ref()
+                .map(|items| items.iter().map(result).collect())
+        }
+        fn good_statuses<T>(items: &Option<Vec<T>>) -> Option<Vec<StatusCode>> {
+            good_results(items, |_| StatusCode::Good)
+        }
+        let response_header = ResponseHeader::new_good(request.request_header());
+        match request {
+            SupportedMessage::ReadRequest(request) => ReadResponse {
+                response_header,
+                results: good_results(&request.nodes_to_read, |_| DataValue::null()),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::WriteRequest(request) => WriteResponse {
+                response_header,
+                results: good_statuses(&request.nodes_to_write),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::HistoryReadRequest(request) => HistoryReadResponse {
+                response_header,
+                results: good_results(&request.nodes_to_read, |_| HistoryReadResult {
+                    status_code: StatusCode::Good,
+                    continuation_point: ByteString::null(),
+                    history_data: ExtensionObject::null(),
+                }),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::HistoryUpdateRequest(request) => HistoryUpdateResponse {
+                response_header,
+                results: good_results(&request.history_update_details, |_| HistoryUpdateResult {
+                    status_code: StatusCode::Good,
+                    operation_results: None,
+                    diagnostic_infos: None,
+                }),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::BrowseRequest(request) => BrowseResponse {
+                response_header,
+                results: good_results(&request.nodes_to_browse, |_| BrowseResult {
+                    status_code: StatusCode::Good,
+                    continuation_point: ByteString::null(),
+                    references: None,
+                }),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::BrowseNextRequest(request) => BrowseNextResponse {
+                response_header,
+                results: good_results(&request.continuation_points, |_| BrowseResult {
+                    status_code: StatusCode::Good,
+                    continuation_point: ByteString::null(),
+                    references: None,
+                }),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::TranslateBrowsePathsToNodeIdsRequest(request) => {
+                TranslateBrowsePathsToNodeIdsResponse {
+                    response_header,
+                    results: good_results(&request.browse_paths, |_| BrowsePathResult {
+                        status_code: StatusCode::Good,
+                        targets: None,
+                    }),
+                    diagnostic_infos: None,
+                }
+                .into()
+            }
+            SupportedMessage::RegisterNodesRequest(request) => RegisterNodesResponse {
+                response_header,
+                registered_node_ids: request.nodes_to_register.clone(),
+            }
+            .into(),
+            SupportedMessage::UnregisterNodesRequest(_) => {
+                UnregisterNodesResponse { response_header }.into()
+            }
+            SupportedMessage::CallRequest(request) => CallResponse {
+                response_header,
+                results: good_results(&request.methods_to_call, |_| CallMethodResult {
+                    status_code: StatusCode::Good,
+                    input_argument_results: None,
+                    input_argument_diagnostic_infos: None,
+                    output_arguments: None,
+                }),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::AddNodesRequest(request) => AddNodesResponse {
+                response_header,
+                results: good_results(&request.nodes_to_add, |item| AddNodesResult {
+                    status_code: StatusCode::Good,
+                    added_node_id: item.requested_new_node_id.node_id.clone(),
+                }),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::AddReferencesRequest(request) => AddReferencesResponse {
+                response_header,
+                results: good_statuses(&request.references_to_add),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::DeleteNodesRequest(request) => DeleteNodesResponse {
+                response_header,
+                results: good_statuses(&request.nodes_to_delete),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::DeleteReferencesRequest(request) => DeleteReferencesResponse {
+                response_header,
+                results: good_statuses(&request.references_to_delete),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::CreateSubscriptionRequest(request) => CreateSubscriptionResponse {
+                response_header,
+                subscription_id: request.request_header.request_handle,
+                revised_publishing_interval: request.requested_publishing_interval,
+                revised_lifetime_count: request.requested_lifetime_count,
+                revised_max_keep_alive_count: request.requested_max_keep_alive_count,
+            }
+            .into(),
+            SupportedMessage::ModifySubscriptionRequest(request) => ModifySubscriptionResponse {
+                response_header,
+                revised_publishing_interval: request.requested_publishing_interval,
+                revised_lifetime_count: request.requested_lifetime_count,
+                revised_max_keep_alive_count: request.requested_max_keep_alive_count,
+            }
+            .into(),
+            SupportedMessage::SetPublishingModeRequest(request) => SetPublishingModeResponse {
+                response_header,
+                results: good_statuses(&request.subscription_ids),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::DeleteSubscriptionsRequest(request) => DeleteSubscriptionsResponse {
+                response_header,
+                results: good_statuses(&request.subscription_ids),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::TransferSubscriptionsRequest(request) => {
+                TransferSubscriptionsResponse {
+                    response_header,
+                    results: good_results(&request.subscription_ids, |_| TransferResult {
+                        status_code: StatusCode::Good,
+                        available_sequence_numbers: None,
+                    }),
+                    diagnostic_infos: None,
+                }
+                .into()
+            }
+            SupportedMessage::RepublishRequest(request) => RepublishResponse {
+                response_header,
+                notification_message: NotificationMessage {
+                    sequence_number: request.retransmit_sequence_number,
+                    publish_time: DateTime::now(),
+                    notification_data: None,
+                },
+            }
+            .into(),
+            SupportedMessage::CreateMonitoredItemsRequest(request) => {
+                CreateMonitoredItemsResponse {
+                    response_header,
+                    results: good_results(&request.items_to_create, |item| {
+                        MonitoredItemCreateResult {
+                            status_code: StatusCode::Good,
+                            monitored_item_id: item.requested_parameters.client_handle,
+                            revised_sampling_interval: item.requested_parameters.sampling_interval,
+                            revised_queue_size: item.requested_parameters.queue_size,
+                            filter_result: ExtensionObject::null(),
+                        }
+                    }),
+                    diagnostic_infos: None,
+                }
+                .into()
+            }
+            SupportedMessage::ModifyMonitoredItemsRequest(request) => {
+                ModifyMonitoredItemsResponse {
+                    response_header,
+                    results: good_results(&request.items_to_modify, |item| {
+                        MonitoredItemModifyResult {
+                            status_code: StatusCode::Good,
+                            revised_sampling_interval: item.requested_parameters.sampling_interval,
+                            revised_queue_size: item.requested_parameters.queue_size,
+                            filter_result: ExtensionObject::null(),
+                        }
+                    }),
+                    diagnostic_infos: None,
+                }
+                .into()
+            }
+            SupportedMessage::SetMonitoringModeRequest(request) => SetMonitoringModeResponse {
+                response_header,
+                results: good_statuses(&request.monitored_item_ids),
+                diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::SetTriggeringRequest(request) => SetTriggeringResponse {
+                response_header,
+                add_results: good_statuses(&request.links_to_add),
+                add_diagnostic_infos: None,
+                remove_results: good_statuses(&request.links_to_remove),
+                remove_diagnostic_infos: None,
+            }
+            .into(),
+            SupportedMessage::DeleteMonitoredItemsRequest(request) => {
+                DeleteMonitoredItemsResponse {
+                    response_header,
+                    results: good_statuses(&request.monitored_item_ids),
+                    diagnostic_infos: None,
+                }
+                .into()
+            }
+            SupportedMessage::GetEndpointsRequest(_) => GetEndpointsResponse {
+                response_header,
+                endpoints: Some(Vec::new()),
+            }
+            .into(),
+            SupportedMessage::FindServersRequest(_) => FindServersResponse {
+                response_header,
+                servers: Some(Vec::new()),
+            }
+            .into(),
+            SupportedMessage::CancelRequest(_) => CancelResponse {
+                response_header,
+                cancel_count: 0,
+            }
+            .into(),
+            SupportedMessage::CloseSessionRequest(_) => {
+                CloseSessionResponse { response_header }.into()
+            }
+            _ => ServiceFault::new(request.request_header(), StatusCode::BadServiceUnsupported)
+                .into(),
+        }
+    }
+
+    /// Blocks until every request queued so far has been written to the transport. This does not
+    /// wait for responses. Fails with `BadTimeout` if the requests are not written within the
+    /// request timeout, or `BadConnectionClosed` if the connection closes first.
+    pub fn flush(&self) -> Result<(), StatusCode> {
+        let deadline = std::time::Instant::now()
+            + std::time::Duration::from_millis(self.request_timeout as u64);
+        loop {
+            let (unsent_requests, is_closed) = {
+                let message_queue = trace_read_lock!(self.message_queue);
+                (message_queue.unsent_requests(), message_queue.is_closed())
+            };
+            if unsent_requests == 0 {
+                return Ok(());
+            } else if is_closed {
+                session_error!(
+                    self,
+                    "Flush failed because the connection closed with {} requests unsent",
+                    unsent_requests
+                );
+                return Err(StatusCode::BadConnectionClosed);
+            } else if std::time::Instant::now() >= deadline {
+                session_error!(
+                    self,
+                    "Flush timed out with {} requests unsent",
+                    unsent_requests
+                );
+                return Err(StatusCode::BadTimeout);
+            }
+            thread::sleep(std::time::Duration::from_millis(Self::FLUSH_POLL_INTERVAL));
+        }
+    }
+
+    /// Waits for the response to the request, failing with `BadTimeout` if it doesn't arrive in
+    /// time. The request handle must not be 0.
+    fn wait_for_sync_response(
+        &mut self,
+        mut response: ResponseFuture,
+        request_timeout: u32,
+    ) -> Result<SupportedMessage, StatusCode> {
+        let request_handle = response.request_handle();
+        if request_handle == 0 {
+            panic!("Request handle must be non zero");
+        }
+        // Receive messages until the one expected comes back. Publish responses will be consumed
+        // silently.
+        let request_timeout = std::time::Duration::from_millis(request_timeout as u64);
+        response.wait(request_timeout).inspect_err(|status_code| {
+            if *status_code == StatusCode::BadTimeout {
+                info!("Timeout waiting for response from server");
+                self.request_has_timed_out(request_handle);
+            }
+        })
+    }
+
+    /// Completes every request in flight with the supplied status, so callers waiting for a
+    /// response, whether blocked in a synchronous call or awaiting a `ResponseFuture`, wake
+    /// straight away rather than when their request times out. The transport does this when the
+    /// connection closes. Publish requests in flight are forgotten as their responses will not
+    /// arrive either.
+    pub fn abort_all_requests(&mut self, status_code: StatusCode) {
+        let aborted = {
+            let mut message_queue = trace_write_lock!(self.message_queue);
+            message_queue.abort_all_requests(status_code)
+        };
+        if aborted > 0 {
+            session_debug!(
+                self,
+                "Aborted {} requests with status {}",
+                aborted,
+                status_code
+            );
+        }
+        self.outstanding_publish_requests.clear();
+    }
+
+    fn request_has_timed_out(&self, request_handle: u32) {
+        self.counters.timed_out();
+        let mut message_queue = trace_write_lock!(self.message_queue);
+        message_queue.request_has_timed_out(request_handle)
+    }
+
+    fn add_request(
+        &mut self,
+        request: SupportedMessage,
+        sender: Option<ResponseSender>,
+    ) -> Result<(), StatusCode> {
+        let mut message_queue = trace_write_lock!(self.message_queue);
+        message_queue.add_request(request, sender)
+    }
+
+    /// Checks if secure channel token needs to be renewed and renews it. If the secure channel
+    /// is shared with other sessions and one of them is already renewing the token, this session
+    /// leaves it to them and carries on using the current token, which is still valid.
+    pub(crate) fn ensure_secure_channel_token(&mut self) -> Result<(), StatusCode> {
+        self.check_token_near_expiry();
+        let should_renew_security_token = {
+            let mut secure_channel = trace_write_lock!(self.secure_channel);
+            secure_channel.should_renew_security_token() && secure_channel.begin_token_renewal()
+        };
+        if should_renew_security_token {
+            let result = self.issue_or_renew_secure_channel(SecurityTokenRequestType::Renew);
+            let mut secure_channel = trace_write_lock!(self.secure_channel);
+            secure_channel.end_token_renewal();
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Tells the token near expiry handler, if there is one, how long the security token has
+    /// left if that is less than the configured fraction of its lifetime. The token should have
+    /// been renewed well before this, so it suggests whatever renews the token is not keeping up.
+    fn check_token_near_expiry(&self) {
+        if let Some(ref on_token_near_expiry) = self.on_token_near_expiry {
+            let (token_id, token_lifetime, token_created_at) = {
+                let secure_channel = trace_read_lock!(self.secure_channel);
+                (
+                    secure_channel.token_id(),
+                    secure_channel.token_lifetime(),
+                    secure_channel.token_created_at(),
+                )
+            };
+            if token_id == 0 {
+                return;
+            }
+            let elapsed = (DateTime::now() - token_created_at)
+                .num_milliseconds()
+                .max(0) as u64;
+            let remaining = (token_lifetime as u64).saturating_sub(elapsed);
+            if (remaining as f64) < token_lifetime as f64 * self.token_near_expiry_fraction {
+                session_warn!(
+                    self,
+                    "Security token {} expires in {} ms and has not been renewed",
+                    token_id,
+                    remaining
+                );
+                on_token_near_expiry(std::time::Duration::from_millis(remaining));
+            }
+        }
+    }
+
+    pub(crate) fn issue_or_renew_secure_channel(
+        &mut self,
+        request_type: SecurityTokenRequestType,
+    ) -> Result<(), StatusCode> {
+        let mut result = self.issue_or_renew_secure_channel_inner(request_type);
+        if request_type == SecurityTokenRequestType::Issue {
+            let mut retries = 0;
+            while let Err(status_code) = result {
+                if !self
+                    .secure_channel_retry_policy
+                    .should_retry(retries, status_code)
+                {
+                    break;
+                }
+                retries += 1;
+                let backoff = self.secure_channel_retry_policy.backoff(retries);
+                session_warn!(
+                    self,
+                    "Opening the secure channel failed with {}, retry {} of {} in {} ms",
+                    status_code,
+                    retries,
+                    self.secure_channel_retry_policy.max_retries,
+                    backoff
+                );
+                thread::sleep(std::time::Duration::from_millis(backoff as u64));
+                result = self.issue_or_renew_secure_channel_inner(request_type);
+            }
+        }
+        self.report_error("issue_or_renew_secure_channel", result)
+    }
+
+    fn issue_or_renew_secure_channel_inner(
+        &mut self,
+        request_type: SecurityTokenRequestType,
+    ) -> Result<(), StatusCode> {
+        trace!("issue_or_renew_secure_channel({:?})", request_type);
+
+        let (security_mode, security_policy, client_nonce) = {
+            let mut secure_channel = trace_write_lock!(self.secure_channel);
+            let client_nonce = secure_channel.security_policy().random_nonce();
+            secure_channel.set_local_nonce(client_nonce.as_ref());
+            (
                 secure_channel.security_mode(),
                 secure_channel.security_policy(),
                 client_nonce,
@@ -444,19 +2726,42 @@ impl SessionState {
         info!("security_mode = {:?}", security_mode);
         info!("security_policy = {:?}", security_policy);
 
-        let requested_lifetime = REQUESTED_LIFETIME;
+        // The server is only trusted with a new channel, renewing one it was already trusted with
+        // doesn't ask again
+        if request_type == SecurityTokenRequestType::Issue {
+            self.verify_server_certificate(security_policy, security_mode)?;
+        }
+
+        let requested_lifetime = self.requested_secure_channel_lifetime;
+        // Issuing a channel is part of connecting, renewing it is a steady state request
+        let (request_header, request_timeout) = match request_type {
+            SecurityTokenRequestType::Issue => {
+                (self.make_connect_request_header(), self.connect_timeout())
+            }
+            SecurityTokenRequestType::Renew => (self.make_request_header(), self.request_timeout()),
+        };
         let request = OpenSecureChannelRequest {
-            request_header: self.make_request_header(),
+            request_header,
             client_protocol_version: 0,
             request_type,
             security_mode,
             client_nonce,
             requested_lifetime,
         };
-        let response = self.send_request(request)?;
+        let response = self.send_request_inner(request.into(), request_timeout, true)?;
         if let SupportedMessage::OpenSecureChannelResponse(response) = response {
             // Extract the security token from the response.
             let mut security_token = response.security_token.clone();
+            // The token is renewed according to the lifetime the server granted, which may be
+            // shorter or longer than asked for
+            if security_token.revised_lifetime != requested_lifetime {
+                session_debug!(
+                    self,
+                    "Secure channel lifetime of {} ms was asked for, the server granted {} ms",
+                    requested_lifetime,
+                    security_token.revised_lifetime
+                );
+            }
 
             // When ignoring clock skew, we calculate the time offset between the client and the
             // server and use that offset to compensate for the difference in time when setting
@@ -475,14 +2780,17 @@ impl SessionState {
             debug!("Setting transport's security token");
             {
                 let mut secure_channel = trace_write_lock!(self.secure_channel);
-                secure_channel.set_client_offset(self.client_offset);
-                secure_channel.set_security_token(security_token);
-
-                if security_policy != SecurityPolicy::None
+                let derive_keys = security_policy != SecurityPolicy::None
                     && (security_mode == MessageSecurityMode::Sign
-                        || security_mode == MessageSecurityMode::SignAndEncrypt)
-                {
+                        || security_mode == MessageSecurityMode::SignAndEncrypt);
+                // The server nonce must be as long as the policy requires, otherwise the channel
+                // is rejected before the new token is used, so it is never secured by weak keys
+                if derive_keys {
                     secure_channel.set_remote_nonce_from_byte_string(&response.server_nonce)?;
+                }
+                secure_channel.set_client_offset(self.client_offset);
+                secure_channel.set_security_token(security_token);
+                if derive_keys {
                     secure_channel.derive_keys();
                 }
             }
@@ -503,12 +2811,53 @@ impl SessionState {
         } else {
             session_debug!(self, "Processing {} async messages", responses.len());
             for response in responses {
-                self.handle_async_response(response);
+                if self.activated || !matches!(response, SupportedMessage::PublishResponse(_)) {
+                    self.handle_async_response(response);
+                } else {
+                    self.handle_pre_activation_response(response);
+                }
             }
             true
         }
     }
 
+    /// Buffers or drops a publish response that arrived before the session was activated,
+    /// according to the pre-activation policy.
+    fn handle_pre_activation_response(&mut self, response: SupportedMessage) {
+        match self.pre_activation_policy {
+            PreActivationPolicy::Drop => {
+                session_debug!(
+                    self,
+                    "Dropping a publish response received before the session was activated"
+                );
+                self.drop_pre_activation_response(&response);
+            }
+            PreActivationPolicy::Buffer => {
+                if self.pre_activation_responses.len() == Self::MAX_PRE_ACTIVATION_RESPONSES {
+                    session_debug!(
+                        self,
+                        "Too many publish responses received before the session was activated, dropping the oldest"
+                    );
+                    if let Some(dropped) = self.pre_activation_responses.pop_front() {
+                        self.drop_pre_activation_response(&dropped);
+                    }
+                }
+                self.pre_activation_responses.push_back(response);
+            }
+        }
+    }
+
+    /// Forgets the publish request of a response that is dropped without being processed, so it
+    /// no longer counts as outstanding
+    fn drop_pre_activation_response(&mut self, response: &SupportedMessage) {
+        if let Some(Some(outcome)) = self
+            .outstanding_publish_requests
+            .remove(&response.request_handle())
+        {
+            let _ = outcome.send(Err(StatusCode::BadSessionNotActivated));
+        }
+    }
+
     /// This is the handler for asynchronous responses which are currently assumed to be publish
     /// responses. It maintains the acknowledgements to be sent and sends the data change
     /// notifications to the client for processing.
@@ -517,20 +2866,42 @@ impl SessionState {
         match response {
             SupportedMessage::PublishResponse(response) => {
                 session_debug!(self, "PublishResponse");
+                let outcome = self.on_publish_success(response.response_header.request_handle);
 
                 // Update subscriptions based on response
                 // Queue acknowledgements for next request
                 let notification_message = response.notification_message.clone();
                 let subscription_id = response.subscription_id;
 
-                // Queue an acknowledgement for this request (if it has data)
-                if let Some(ref notification_data) = notification_message.notification_data {
-                    if !notification_data.is_empty() {
-                        self.add_subscription_acknowledgement(SubscriptionAcknowledgement {
-                            subscription_id,
-                            sequence_number: notification_message.sequence_number,
-                        });
+                // Queue an acknowledgement for this request (if it has data). A response that
+                // was in flight when its subscription was deleted is ignored.
+                let subscription_exists = {
+                    let subscription_state = trace_read_lock!(self.subscription_state);
+                    subscription_state.subscription_exists(subscription_id)
+                };
+                let is_keep_alive = notification_message
+                    .notification_data
+                    .as_ref()
+                    .map(|notification_data| notification_data.is_empty())
+                    .unwrap_or(true);
+                if is_keep_alive {
+                    // A keep-alive carries the sequence number of the next notification rather
+                    // than one of its own, so there is nothing to acknowledge and nothing lost
+                    session_debug!(
+                        self,
+                        "Keep-alive received for subscription {}",
+                        subscription_id
+                    );
+                    if subscription_exists {
+                        if let Some(ref on_keep_alive) = self.on_keep_alive {
+                            on_keep_alive(subscription_id);
+                        }
                     }
+                } else if subscription_exists {
+                    self.add_subscription_acknowledgement(SubscriptionAcknowledgement {
+                        subscription_id,
+                        sequence_number: notification_message.sequence_number,
+                    });
                 }
 
                 let decoding_options = {
@@ -549,6 +2920,9 @@ impl SessionState {
                         events.len()
                     );
                     if !data_change_notifications.is_empty() {
+                        if subscription_exists {
+                            self.send_notifications(&data_change_notifications);
+                        }
                         let mut subscription_state = trace_write_lock!(self.subscription_state);
                         subscription_state
                             .on_data_change(subscription_id, &data_change_notifications);
@@ -559,6 +2933,10 @@ impl SessionState {
                     }
                 }
 
+                if let Some(outcome) = outcome {
+                    let _ = outcome.send(Ok(*response));
+                }
+
                 // Send another publish request
                 let _ = self.async_publish();
             }
@@ -570,15 +2948,32 @@ impl SessionState {
                     service_result
                 );
                 session_trace!(self, "ServiceFault {:?}", response);
+                // Only a fault for one of the publish requests affects how many are outstanding
+                let is_publish_fault = match self
+                    .outstanding_publish_requests
+                    .remove(&response.response_header.request_handle)
+                {
+                    Some(outcome) => {
+                        if let Some(outcome) = outcome {
+                            let _ = outcome.send(Err(service_result));
+                        }
+                        true
+                    }
+                    None => false,
+                };
 
                 match service_result {
-                    StatusCode::BadTimeout => {
+                    StatusCode::BadTimeout if is_publish_fault => {
                         debug!("Publish request timed out so sending another");
                         let _ = self.async_publish();
                     }
-                    StatusCode::BadTooManyPublishRequests => {
-                        // Turn off publish requests until server says otherwise
-                        debug!("Server tells us too many publish requests so waiting for a response before resuming");
+                    StatusCode::BadTooManyPublishRequests if is_publish_fault => {
+                        // Limit publish requests to those the server has accepted and wait for
+                        // responses to those before resuming
+                        self.max_outstanding_publish_requests =
+                            cmp::max(1, self.outstanding_publish_requests.len());
+                        self.publish_successes = 0;
+                        debug!("Server tells us too many publish requests so waiting for a response before resuming, outstanding publish requests limited to {}", self.max_outstanding_publish_requests);
                     }
                     StatusCode::BadSessionClosed
                     | StatusCode::BadSessionIdInvalid
@@ -595,8 +2990,31 @@ impl SessionState {
         }
     }
 
-    /// Returns the next monitored item handle
-    pub fn next_monitored_item_handle(&mut self) -> u32 {
-        self.monitored_item_handle.next()
+    /// Accounts for a successful response to the publish request with the handle, slowly raising
+    /// the outstanding publish limit if it was reduced. Returns the sender of the request's
+    /// future if it has one.
+    fn on_publish_success(&mut self, request_handle: u32) -> Option<PublishOutcomeSender> {
+        let outcome = self
+            .outstanding_publish_requests
+            .remove(&request_handle)
+            .flatten();
+        if self.max_outstanding_publish_requests < Self::MAX_OUTSTANDING_PUBLISH_REQUESTS {
+            self.publish_successes += 1;
+            if self.publish_successes >= Self::PUBLISH_SUCCESSES_TO_RECOVER {
+                self.publish_successes = 0;
+                self.max_outstanding_publish_requests += 1;
+                debug!(
+                    "Outstanding publish requests limit raised to {}",
+                    self.max_outstanding_publish_requests
+                );
+            }
+        }
+        outcome
+    }
+
+    /// Returns the next `count` monitored item handles in one go, e.g. to give a client handle to
+    /// each item of a `CreateMonitoredItems` request.
+    pub fn next_monitored_item_handles(&mut self, count: usize) -> Vec<u32> {
+        self.monitored_item_handle.next_n(count)
     }
 }
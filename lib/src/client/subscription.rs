@@ -65,6 +65,11 @@ pub struct MonitoredItem {
     values: Vec<DataValue>,
     /// Triggered items
     triggered_items: BTreeSet<u32>,
+    /// Set until the item's initial value is received, i.e. after the item is created or after
+    /// its subscription is transferred with initial values requested.
+    initial_value_pending: bool,
+    /// Set if the values received in the last data change notification were the initial value.
+    initial_value: bool,
 }
 
 impl MonitoredItem {
@@ -85,6 +90,8 @@ impl MonitoredItem {
             values: Vec::with_capacity(1),
             client_handle,
             triggered_items: BTreeSet::new(),
+            initial_value_pending: true,
+            initial_value: false,
         }
     }
 
@@ -135,6 +142,13 @@ impl MonitoredItem {
         self.discard_oldest
     }
 
+    /// Tests if the values received in the last data change notification are the item's initial
+    /// value rather than a change. The server sends the initial value when the item is created and
+    /// again when its subscription is transferred with `send_initial_values`.
+    pub fn is_initial_value(&self) -> bool {
+        self.initial_value
+    }
+
     pub(crate) fn set_id(&mut self, value: u32) {
         self.id = value;
     }
@@ -175,6 +189,10 @@ impl MonitoredItem {
     pub(crate) fn triggered_items(&self) -> &BTreeSet<u32> {
         &self.triggered_items
     }
+
+    pub(crate) fn expect_initial_value(&mut self) {
+        self.initial_value_pending = true;
+    }
 }
 
 pub struct Subscription {
@@ -316,13 +334,25 @@ impl Subscription {
         });
     }
 
+    /// Marks every monitored item as expecting its initial value, e.g. after the subscription is
+    /// transferred with initial values requested.
+    pub(crate) fn expect_initial_values(&mut self) {
+        self.monitored_items
+            .values_mut()
+            .for_each(|monitored_item| monitored_item.expect_initial_value());
+    }
+
     pub(crate) fn delete_monitored_items(&mut self, items_to_delete: &[u32]) {
         items_to_delete.iter().for_each(|id| {
             // Remove the monitored item and the client handle / id entry
             if let Some(monitored_item) = self.monitored_items.remove(id) {
                 let _ = self.client_handles.remove(&monitored_item.client_handle());
             }
-        })
+        });
+        // Deleting an item also deletes the links that trigger it
+        self.monitored_items
+            .values_mut()
+            .for_each(|monitored_item| monitored_item.set_triggering(&[], items_to_delete));
     }
 
     pub(crate) fn set_triggering(
@@ -361,6 +391,10 @@ impl Subscription {
                         *monitored_item_id.as_ref().unwrap()
                     };
                     let monitored_item = self.monitored_items.get_mut(&monitored_item_id).unwrap();
+                    if !monitored_item_ids.contains(&monitored_item_id) {
+                        monitored_item.initial_value = monitored_item.initial_value_pending;
+                        monitored_item.initial_value_pending = false;
+                    }
                     monitored_item.last_value = i.value.clone();
                     monitored_item.values.push(i.value.clone());
                     monitored_item_ids.insert(monitored_item_id);
@@ -37,6 +37,25 @@ impl SubscriptionState {
         }
     }
 
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// The number of monitored items created across all subscriptions. An item that the server
+    /// failed to create has the id 0, which is not counted.
+    pub fn monitored_item_count(&self) -> usize {
+        self.subscriptions
+            .values()
+            .map(|subscription| {
+                subscription
+                    .monitored_items()
+                    .keys()
+                    .filter(|monitored_item_id| **monitored_item_id != 0)
+                    .count()
+            })
+            .sum()
+    }
+
     pub fn subscription_exists(&self, subscription_id: u32) -> bool {
         self.subscriptions.contains_key(&subscription_id)
     }
@@ -104,6 +123,12 @@ impl SubscriptionState {
         }
     }
 
+    pub(crate) fn expect_initial_values(&mut self, subscription_id: u32) {
+        if let Some(ref mut subscription) = self.subscriptions.get_mut(&subscription_id) {
+            subscription.expect_initial_values();
+        }
+    }
+
     pub(crate) fn insert_monitored_items(
         &mut self,
         subscription_id: u32,
@@ -154,6 +179,15 @@ impl SubscriptionState {
         self.keep_alive_timeout
     }
 
+    /// The longest time in milliseconds any subscription may go without sending a notification
+    /// or keep-alive, i.e. its publishing interval times its keep-alive count.
+    pub(crate) fn max_keep_alive_interval(&self) -> Option<u64> {
+        self.subscriptions
+            .values()
+            .map(|v| (v.publishing_interval() * v.max_keep_alive_count() as f64).ceil() as u64)
+            .max()
+    }
+
     fn set_keep_alive_timeout(&mut self) {
         self.keep_alive_timeout = self
             .subscriptions
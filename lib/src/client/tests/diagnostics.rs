@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+use crate::client::session::diagnostics::{SessionCounters, SessionDiagnostics};
+
+#[test]
+fn diagnostics_delta() {
+    let earlier = SessionDiagnostics {
+        timestamp: Instant::now(),
+        requests_sent: 10,
+        responses_received: 8,
+        bytes_sent: 1000,
+        bytes_received: 4000,
+        timeouts: 1,
+    };
+    let later = SessionDiagnostics {
+        timestamp: earlier.timestamp + Duration::from_secs(2),
+        requests_sent: 30,
+        responses_received: 27,
+        bytes_sent: 3000,
+        bytes_received: 10000,
+        timeouts: 2,
+    };
+    let delta = later.diff(&earlier);
+    assert_eq!(delta.elapsed, Duration::from_secs(2));
+    assert_eq!(delta.requests_sent, 20);
+    assert_eq!(delta.responses_received, 19);
+    assert_eq!(delta.bytes_sent, 2000);
+    assert_eq!(delta.bytes_received, 6000);
+    assert_eq!(delta.timeouts, 1);
+    assert_eq!(delta.requests_per_second(), 10.0);
+    assert_eq!(delta.bytes_sent_per_second(), 1000.0);
+    assert_eq!(delta.bytes_received_per_second(), 3000.0);
+
+    // Snapshots the wrong way round do not underflow
+    let delta = earlier.diff(&later);
+    assert_eq!(delta.elapsed, Duration::ZERO);
+    assert_eq!(delta.requests_sent, 0);
+    assert_eq!(delta.requests_per_second(), 0.0);
+}
+
+#[test]
+fn counters_snapshot() {
+    let counters = SessionCounters::default();
+    let earlier = counters.snapshot();
+    counters.request_sent();
+    counters.request_sent();
+    counters.response_received();
+    counters.add_bytes_sent(100);
+    counters.add_bytes_received(250);
+    counters.timed_out();
+    let delta = counters.snapshot().diff(&earlier);
+    assert_eq!(delta.requests_sent, 2);
+    assert_eq!(delta.responses_received, 1);
+    assert_eq!(delta.bytes_sent, 100);
+    assert_eq!(delta.bytes_received, 250);
+    assert_eq!(delta.timeouts, 1);
+}
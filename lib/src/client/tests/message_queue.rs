@@ -0,0 +1,275 @@
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    client::message_queue::{
+        MessageQueue, MessageQueueMetrics, ResponseFuture, ResponseSender, UnmatchedResponsePolicy,
+    },
+    core::supported_message::SupportedMessage,
+    sync::*,
+    types::{status_code::StatusCode, *},
+};
+
+fn make_message_queue() -> MessageQueue {
+    let mut message_queue = MessageQueue::new();
+    // The receiver is dropped, the requests only need to be in flight
+    let _ = message_queue.make_request_channel();
+    message_queue
+}
+
+fn read_request(request_handle: u32) -> SupportedMessage {
+    ReadRequest {
+        request_header: RequestHeader {
+            request_handle,
+            ..Default::default()
+        },
+        max_age: 0f64,
+        timestamps_to_return: TimestampsToReturn::Both,
+        nodes_to_read: None,
+    }
+    .into()
+}
+
+fn service_fault(request_handle: u32) -> SupportedMessage {
+    ServiceFault {
+        response_header: ResponseHeader {
+            request_handle,
+            service_result: StatusCode::BadTimeout,
+            ..ResponseHeader::null()
+        },
+    }
+    .into()
+}
+
+#[test]
+fn unmatched_response_callback() {
+    let unmatched = Arc::new(Mutex::new(Vec::new()));
+    let mut message_queue = make_message_queue();
+    {
+        let unmatched = unmatched.clone();
+        message_queue.set_unmatched_response_policy(UnmatchedResponsePolicy::Callback(Arc::new(
+            move |response| unmatched.lock().push(response.request_handle()),
+        )));
+    }
+
+    // A matched response goes to the request's sender
+    let (tx, rx) = mpsc::sync_channel(1);
+    message_queue
+        .add_request(read_request(1), Some(tx.into()))
+        .unwrap();
+    assert!(message_queue.store_response(service_fault(1)).is_none());
+    assert_eq!(rx.try_recv().unwrap().request_handle(), 1);
+
+    // A late response to a request that timed out goes to the callback
+    let (tx, _rx) = mpsc::sync_channel(1);
+    message_queue
+        .add_request(read_request(2), Some(tx.into()))
+        .unwrap();
+    message_queue.request_has_timed_out(2);
+    let unmatched_response = message_queue.store_response(service_fault(2)).unwrap();
+    assert!(unmatched.lock().is_empty());
+    unmatched_response.dispatch();
+    assert_eq!(*unmatched.lock(), vec![2]);
+    assert!(message_queue.async_responses().is_empty());
+}
+
+#[test]
+fn unmatched_response_dropped() {
+    // Logged by default
+    let mut message_queue = make_message_queue();
+    assert!(message_queue.store_response(service_fault(5)).is_none());
+    assert!(message_queue.async_responses().is_empty());
+
+    message_queue.set_unmatched_response_policy(UnmatchedResponsePolicy::Drop);
+    assert!(message_queue.store_response(service_fault(6)).is_none());
+    assert!(message_queue.async_responses().is_empty());
+}
+
+fn add_request_future(
+    message_queue: &Arc<RwLock<MessageQueue>>,
+    request_handle: u32,
+) -> ResponseFuture {
+    let (tx, rx) = oneshot::channel();
+    trace_write_lock!(message_queue)
+        .add_request(
+            read_request(request_handle),
+            Some(ResponseSender::Future(tx)),
+        )
+        .unwrap();
+    ResponseFuture::new(request_handle, rx, message_queue.clone())
+}
+
+#[test]
+fn response_future() {
+    let message_queue = Arc::new(RwLock::new(make_message_queue()));
+
+    // The future resolves to the matching response
+    let response = add_request_future(&message_queue, 1);
+    assert_eq!(response.request_handle(), 1);
+    trace_write_lock!(message_queue).store_response(service_fault(1));
+    let response = futures::executor::block_on(response).unwrap();
+    assert_eq!(response.request_handle(), 1);
+
+    // Waiting blocks until the response arrives
+    let mut response = add_request_future(&message_queue, 2);
+    {
+        let message_queue = message_queue.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            trace_write_lock!(message_queue).store_response(service_fault(2));
+        });
+    }
+    let response = response.wait(Duration::from_secs(5)).unwrap();
+    assert_eq!(response.request_handle(), 2);
+
+    // Or until it times out
+    let mut response = add_request_future(&message_queue, 3);
+    assert_eq!(
+        response.wait(Duration::from_millis(10)).unwrap_err(),
+        StatusCode::BadTimeout
+    );
+
+    // A request that is abandoned without a response fails
+    let response = add_request_future(&message_queue, 4);
+    trace_write_lock!(message_queue).clear();
+    assert_eq!(
+        futures::executor::block_on(response).unwrap_err(),
+        StatusCode::BadConnectionClosed
+    );
+}
+
+#[test]
+fn dropped_response_future_abandons_request() {
+    let unmatched = Arc::new(Mutex::new(Vec::new()));
+    let message_queue = Arc::new(RwLock::new(make_message_queue()));
+    {
+        let unmatched = unmatched.clone();
+        // The callback runs once the queue is unlocked, so it can lock the queue itself
+        let message_queue_ref = Arc::downgrade(&message_queue);
+        trace_write_lock!(message_queue).set_unmatched_response_policy(
+            UnmatchedResponsePolicy::Callback(Arc::new(move |response| {
+                let message_queue = message_queue_ref.upgrade().unwrap();
+                assert!(message_queue.try_write().is_some());
+                unmatched.lock().push(response.request_handle())
+            })),
+        );
+    }
+    let response = add_request_future(&message_queue, 1);
+    drop(response);
+    let unmatched_response = trace_write_lock!(message_queue).store_response(service_fault(1));
+    unmatched_response.unwrap().dispatch();
+    assert_eq!(*unmatched.lock(), vec![1]);
+}
+
+#[test]
+fn abort_all_requests() {
+    let message_queue = Arc::new(RwLock::new(make_message_queue()));
+
+    // A synchronous caller, a future and a request that nothing waits for
+    let (tx, rx) = mpsc::sync_channel(1);
+    trace_write_lock!(message_queue)
+        .add_request(read_request(1), Some(tx.into()))
+        .unwrap();
+    let response = add_request_future(&message_queue, 2);
+    trace_write_lock!(message_queue)
+        .add_request(read_request(3), None)
+        .unwrap();
+
+    assert_eq!(
+        trace_write_lock!(message_queue).abort_all_requests(StatusCode::BadConnectionClosed),
+        3
+    );
+
+    // Both waiters are told the status straight away
+    let status_code = |response: SupportedMessage| match response {
+        SupportedMessage::ServiceFault(response) => response.response_header.service_result,
+        response => panic!("Expected a service fault, got {:?}", response),
+    };
+    let sync_response = rx.try_recv().unwrap();
+    assert_eq!(sync_response.request_handle(), 1);
+    assert_eq!(status_code(sync_response), StatusCode::BadConnectionClosed);
+    let response = futures::executor::block_on(response).unwrap();
+    assert_eq!(response.request_handle(), 2);
+    assert_eq!(status_code(response), StatusCode::BadConnectionClosed);
+
+    // Nothing is left in flight
+    assert_eq!(
+        trace_write_lock!(message_queue).abort_all_requests(StatusCode::BadConnectionClosed),
+        0
+    );
+    trace_write_lock!(message_queue).store_response(service_fault(3));
+    assert!(trace_write_lock!(message_queue)
+        .async_responses()
+        .is_empty());
+}
+
+#[test]
+fn metrics() {
+    let mut message_queue = make_message_queue();
+    assert_eq!(message_queue.metrics(), MessageQueueMetrics::default());
+
+    // A synchronous request that is answered
+    let (tx, _rx) = mpsc::sync_channel(1);
+    message_queue
+        .add_request(read_request(1), Some(tx.into()))
+        .unwrap();
+    // An asynchronous request that is answered
+    message_queue.add_request(read_request(2), None).unwrap();
+    // A request that times out
+    let (tx, _rx) = mpsc::sync_channel(1);
+    message_queue
+        .add_request(read_request(3), Some(tx.into()))
+        .unwrap();
+    // And one still waiting
+    message_queue.add_request(read_request(4), None).unwrap();
+
+    thread::sleep(Duration::from_millis(5));
+    message_queue.store_response(service_fault(1));
+    message_queue.store_response(service_fault(2));
+    message_queue.request_has_timed_out(3);
+    assert_eq!(message_queue.async_responses().len(), 1);
+
+    let metrics = message_queue.metrics();
+    assert_eq!(metrics.requests_sent, 4);
+    assert_eq!(metrics.responses_received, 2);
+    assert_eq!(metrics.timeouts, 1);
+    assert_eq!(metrics.async_messages_consumed, 1);
+    assert_eq!(metrics.pending_requests, 1);
+    assert!(metrics.average_response_latency.unwrap() >= Duration::from_millis(5));
+
+    // A response to nothing isn't counted
+    message_queue.store_response(service_fault(3));
+    assert_eq!(message_queue.metrics().responses_received, 2);
+}
+
+#[test]
+fn duplicate_request_handle() {
+    let mut message_queue = make_message_queue();
+    let (tx, rx) = mpsc::sync_channel(1);
+    message_queue
+        .add_request(read_request(1), Some(tx.into()))
+        .unwrap();
+
+    // A second request with the handle of one in flight is rejected
+    let (tx, duplicate_rx) = mpsc::sync_channel(1);
+    assert_eq!(
+        message_queue
+            .add_request(read_request(1), Some(tx.into()))
+            .unwrap_err(),
+        StatusCode::BadRequestHeaderInvalid
+    );
+    assert_eq!(message_queue.metrics().requests_sent, 1);
+
+    // So the response goes to the first request only
+    message_queue.store_response(service_fault(1));
+    assert_eq!(rx.try_recv().unwrap().request_handle(), 1);
+    assert!(duplicate_rx.try_recv().is_err());
+
+    // Once it is answered the handle may be used again
+    message_queue.add_request(read_request(1), None).unwrap();
+}
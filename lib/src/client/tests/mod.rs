@@ -1,14 +1,29 @@
-use std::{self, collections::BTreeMap, path::PathBuf};
+use std::{self, collections::BTreeMap, path::PathBuf, sync::Arc};
 
-use crate::core::config::Config;
-use crate::crypto::SecurityPolicy;
+use crate::core::{config::Config, supported_message::SupportedMessage};
+use crate::crypto::{verify_signature_data, CertificateStore, SecurityPolicy};
+use crate::sync::*;
+use crate::types::node_ids::VariableId;
 use crate::types::*;
 
 use crate::client::{
     builder::ClientBuilder,
+    client::Client,
     config::{ClientConfig, ClientEndpoint, ClientUserToken, ANONYMOUS_USER_TOKEN_ID},
+    session::{
+        services::{AttributeService, Service, SessionService},
+        session::Session,
+    },
 };
 
+mod diagnostics;
+mod message_queue;
+mod monitored_item_builder;
+mod rate_limiter;
+mod services;
+mod session_state;
+mod tcp_transport;
+
 fn make_test_file(filename: &str) -> PathBuf {
     let mut path = std::env::temp_dir();
     path.push(filename);
@@ -159,3 +174,451 @@ fn client_anonymous_user_tokens_id() {
     );
     assert!(!config.is_valid());
 }
+
+#[test]
+fn client_offered_security() {
+    let endpoint = |security_policy: SecurityPolicy, security_mode| {
+        EndpointDescription::from((
+            "opc.tcp://127.0.0.1:4855/",
+            security_policy.to_uri(),
+            security_mode,
+        ))
+    };
+    let endpoints = [
+        endpoint(
+            SecurityPolicy::Basic256Sha256,
+            MessageSecurityMode::SignAndEncrypt,
+        ),
+        endpoint(
+            SecurityPolicy::Aes128Sha256RsaOaep,
+            MessageSecurityMode::Sign,
+        ),
+    ];
+    assert_eq!(
+        Client::offered_security(&endpoints),
+        "Basic256Sha256 / SignAndEncrypt, Aes128Sha256RsaOaep / Sign"
+    );
+    // The client wants no security, which the server doesn't offer
+    assert!(Client::find_matching_endpoint(
+        &endpoints,
+        "opc.tcp://127.0.0.1:4855/",
+        SecurityPolicy::None,
+        MessageSecurityMode::None
+    )
+    .is_none());
+}
+
+#[test]
+fn dry_run_typed_service() {
+    let mut client = sample_builder()
+        .create_sample_keypair(false)
+        .pki_dir(make_test_file("dry_run_pki"))
+        .client()
+        .unwrap();
+    let session = client
+        .new_session_from_info(EndpointDescription::from((
+            "opc.tcp://127.0.0.1:4855/",
+            SecurityPolicy::None.to_uri(),
+            MessageSecurityMode::None,
+        )))
+        .unwrap();
+    let mut session = session.write();
+    session.set_dry_run(true);
+
+    // The helper gets the response it expects instead of treating the placeholder as an error
+    let node_id: NodeId = VariableId::Server_ServerStatus_State.into();
+    let results = session
+        .read(&[node_id.into()], TimestampsToReturn::Both, 0f64)
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].status.is_none());
+}
+
+#[test]
+fn activate_session_signs_session_nonce() {
+    let pki_dir = make_test_file("activate_session_pki");
+    let mut client = sample_builder().pki_dir(pki_dir.clone()).client().unwrap();
+    let session = client
+        .new_session_from_info(EndpointDescription::from((
+            "opc.tcp://127.0.0.1:4855/",
+            SecurityPolicy::Basic256Sha256.to_uri(),
+            MessageSecurityMode::SignAndEncrypt,
+            UserTokenPolicy::anonymous(),
+        )))
+        .unwrap();
+    let mut session = session.write();
+
+    // The client's own certificate stands in for the server's
+    let (own_cert, _) = CertificateStore::new(&pki_dir)
+        .read_own_cert_and_pkey()
+        .unwrap();
+    let channel_nonce = ByteString::from(vec![1u8; 32]);
+    let session_nonce = ByteString::from(vec![2u8; 32]);
+    {
+        let secure_channel = session.secure_channel();
+        let mut secure_channel = trace_write_lock!(secure_channel);
+        secure_channel.set_security_policy(SecurityPolicy::Basic256Sha256);
+        secure_channel.set_security_mode(MessageSecurityMode::SignAndEncrypt);
+        secure_channel
+            .set_remote_cert_from_byte_string(&own_cert.as_byte_string())
+            .unwrap();
+        // e.g. the channel has been renewed since the session was created
+        secure_channel
+            .set_remote_nonce_from_byte_string(&channel_nonce)
+            .unwrap();
+    }
+    {
+        let session_state = session.session_state();
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_server_nonce(session_nonce.clone());
+    }
+
+    // The request is captured instead of being sent
+    let activate_session_request = Arc::new(Mutex::new(None));
+    {
+        let activate_session_request = activate_session_request.clone();
+        session.set_request_authorizer(move |request| {
+            if let SupportedMessage::ActivateSessionRequest(request) = request {
+                *activate_session_request.lock() = Some(request.as_ref().clone());
+            }
+            Err(StatusCode::BadUserAccessDenied)
+        });
+    }
+    assert!(session.activate_session().is_err());
+
+    // The client signs the server's certificate with the session's nonce, not the channel's
+    let request = activate_session_request.lock().take().unwrap();
+    let verify = |nonce: &ByteString| {
+        verify_signature_data(
+            &request.client_signature,
+            SecurityPolicy::Basic256Sha256,
+            &own_cert,
+            &own_cert,
+            nonce.as_ref(),
+        )
+    };
+    assert_eq!(verify(&session_nonce), StatusCode::Good);
+    assert_ne!(verify(&channel_nonce), StatusCode::Good);
+}
+
+/// Serves a secure channel on each connection made to it, rejecting the security of the first
+/// by sending an error message and closing the connection, as a server does. A connection that
+/// is accepted is closed on the next message that arrives. Returns the port and the security
+/// mode that each connection asked for.
+fn spawn_rejecting_server() -> (u16, Arc<Mutex<Vec<MessageSecurityMode>>>) {
+    use std::io::{Read, Write};
+
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::core::comms::{
+        chunker::Chunker,
+        secure_channel::SecureChannel,
+        tcp_codec::{Message, TcpCodec},
+        tcp_types::{AcknowledgeMessage, ErrorMessage, MessageHeader, MessageType},
+    };
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let security_modes = Arc::new(Mutex::new(Vec::new()));
+    {
+        let security_modes = security_modes.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut codec = TcpCodec::new(DecodingOptions::default());
+                let mut buf = BytesMut::new();
+                let mut next_message = |stream: &mut std::net::TcpStream| loop {
+                    if let Some(message) = codec.decode(&mut buf).unwrap() {
+                        return Some(message);
+                    }
+                    let mut data = [0u8; 8192];
+                    match stream.read(&mut data) {
+                        Ok(0) | Err(_) => return None,
+                        Ok(n) => buf.extend_from_slice(&data[..n]),
+                    }
+                };
+                let write_message = |stream: &mut std::net::TcpStream, message| {
+                    let mut out = BytesMut::new();
+                    TcpCodec::new(DecodingOptions::default())
+                        .encode(message, &mut out)
+                        .unwrap();
+                    stream.write_all(&out).unwrap();
+                };
+
+                // Hello
+                assert!(matches!(next_message(&mut stream), Some(Message::Hello(_))));
+                let mut ack = AcknowledgeMessage {
+                    message_header: MessageHeader::new(MessageType::Acknowledge),
+                    protocol_version: 0,
+                    receive_buffer_size: 65535,
+                    send_buffer_size: 65535,
+                    max_message_size: 0,
+                    max_chunk_count: 0,
+                };
+                ack.message_header.message_size = ack.byte_len() as u32;
+                write_message(&mut stream, Message::Acknowledge(ack));
+
+                // Open secure channel
+                let chunk = match next_message(&mut stream) {
+                    Some(Message::Chunk(chunk)) => chunk,
+                    message => panic!("Expected a chunk, got {:?}", message),
+                };
+                let secure_channel = SecureChannel::new_no_certificate_store();
+                let request_id = chunk
+                    .chunk_info(&secure_channel)
+                    .unwrap()
+                    .sequence_header
+                    .request_id;
+                let request = match Chunker::decode(&[chunk], &secure_channel, None).unwrap() {
+                    SupportedMessage::OpenSecureChannelRequest(request) => request,
+                    request => panic!("Expected an open secure channel request, got {:?}", request),
+                };
+                let is_first = {
+                    let mut security_modes = security_modes.lock();
+                    security_modes.push(request.security_mode);
+                    security_modes.len() == 1
+                };
+                if is_first {
+                    write_message(
+                        &mut stream,
+                        Message::Error(ErrorMessage::from_status_code(
+                            StatusCode::BadSecurityPolicyRejected,
+                        )),
+                    );
+                    continue;
+                }
+                let response: SupportedMessage = OpenSecureChannelResponse {
+                    response_header: ResponseHeader::new_good(&request.request_header),
+                    server_protocol_version: 0,
+                    security_token: ChannelSecurityToken {
+                        channel_id: 1,
+                        token_id: 1,
+                        created_at: DateTime::now(),
+                        revised_lifetime: 60000,
+                    },
+                    server_nonce: ByteString::null(),
+                }
+                .into();
+                for chunk in
+                    Chunker::encode(1, request_id, 0, 0, &secure_channel, &response).unwrap()
+                {
+                    write_message(&mut stream, Message::Chunk(chunk));
+                }
+                let _ = next_message(&mut stream);
+            }
+        });
+    }
+    (port, security_modes)
+}
+
+#[test]
+fn security_policy_preferences() {
+    let (port, security_modes) = spawn_rejecting_server();
+    let mut client = sample_builder()
+        .create_sample_keypair(false)
+        .pki_dir(make_test_file("security_policy_preferences_pki"))
+        .client()
+        .unwrap();
+    let session = client
+        .new_session_from_info(EndpointDescription::from((
+            format!("opc.tcp://127.0.0.1:{}/", port).as_ref(),
+            SecurityPolicy::None.to_uri(),
+            MessageSecurityMode::None,
+        )))
+        .unwrap();
+    let mut session = session.write();
+    session.set_security_policy_preferences(vec![
+        (SecurityPolicy::None, MessageSecurityMode::Sign),
+        (SecurityPolicy::None, MessageSecurityMode::None),
+    ]);
+
+    // The server closes the connection on rejecting the first preference, so the second is
+    // tried on a new one
+    session.connect_no_retry().unwrap();
+    assert_eq!(
+        *security_modes.lock(),
+        vec![MessageSecurityMode::Sign, MessageSecurityMode::None]
+    );
+    {
+        let secure_channel = session.secure_channel();
+        let secure_channel = trace_read_lock!(secure_channel);
+        assert_eq!(secure_channel.security_mode(), MessageSecurityMode::None);
+    }
+    session.disconnect();
+}
+
+#[test]
+fn idle_session_is_reopened_for_service_requests() {
+    // Nothing listens on the port, so opening the session again fails and the requests are
+    // vetoed rather than left waiting for a response
+    let port = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let mut client = sample_builder()
+        .create_sample_keypair(false)
+        .session_retry_limit(0)
+        .pki_dir(make_test_file("reopen_idle_pki"))
+        .client()
+        .unwrap();
+    let session = client
+        .new_session_from_info(EndpointDescription::from((
+            format!("opc.tcp://127.0.0.1:{}/", port).as_ref(),
+            SecurityPolicy::None.to_uri(),
+            MessageSecurityMode::None,
+        )))
+        .unwrap();
+    let mut session = session.write();
+    session.set_request_authorizer(|_| Err(StatusCode::BadUserAccessDenied));
+
+    let read_request = |session: &Session| {
+        let request = ReadRequest {
+            request_header: session.make_request_header(),
+            max_age: 0f64,
+            timestamps_to_return: TimestampsToReturn::Both,
+            nodes_to_read: None,
+        };
+        // The session is closed for being idle after the request was made
+        let session_state = session.session_state();
+        trace_write_lock!(session_state).set_closed_idle(true);
+        request
+    };
+
+    // Each way of sending the request tries to open the session again first
+    assert!(session.send_request(read_request(&session)).is_err());
+    assert!(!session.is_closed_idle());
+    assert!(session
+        .async_send_request(read_request(&session), None)
+        .is_err());
+    assert!(!session.is_closed_idle());
+    assert!(session
+        .send_request_detailed(read_request(&session))
+        .is_err());
+    assert!(!session.is_closed_idle());
+}
+
+#[test]
+fn rate_limit_wait_unlocks_session_state() {
+    let mut client = sample_builder()
+        .create_sample_keypair(false)
+        .pki_dir(make_test_file("rate_limit_pki"))
+        .client()
+        .unwrap();
+    let session = client
+        .new_session_from_info(EndpointDescription::from((
+            "opc.tcp://127.0.0.1:4855/",
+            SecurityPolicy::None.to_uri(),
+            MessageSecurityMode::None,
+        )))
+        .unwrap();
+    {
+        let mut session = session.write();
+        session.set_dry_run(true);
+        session.set_max_requests_per_second(Some(1.0));
+    }
+    let read = |session: &Session| {
+        let node_id: NodeId = VariableId::Server_ServerStatus_State.into();
+        session.read(&[node_id.into()], TimestampsToReturn::Both, 0f64)
+    };
+
+    // The first read takes the only token, so the second waits for the next one
+    read(&session.read()).unwrap();
+    let started = std::time::Instant::now();
+    let reader = {
+        let session = session.clone();
+        std::thread::spawn(move || read(&session.read()))
+    };
+
+    // The session state isn't locked while the second read waits
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    {
+        let session_state = session.read().session_state();
+        assert!(session_state
+            .try_write_for(std::time::Duration::from_millis(200))
+            .is_some());
+    }
+    reader.join().unwrap().unwrap();
+    assert!(started.elapsed() >= std::time::Duration::from_millis(500));
+}
+
+#[test]
+fn min_publishing_interval() {
+    use crate::client::{
+        callbacks::DataChangeCallback, session::services::SubscriptionService,
+        tests::session_state::next_request,
+    };
+    use crate::types::node_ids::ObjectId;
+
+    let mut client = sample_builder()
+        .create_sample_keypair(false)
+        .pki_dir(make_test_file("min_publishing_interval_pki"))
+        .client()
+        .unwrap();
+    let session = client
+        .new_session_from_info(EndpointDescription::from((
+            "opc.tcp://127.0.0.1:4855/",
+            SecurityPolicy::None.to_uri(),
+            MessageSecurityMode::None,
+        )))
+        .unwrap();
+    let mut session = session.write();
+    let session_state = session.session_state();
+    let message_queue = trace_read_lock!(session_state).message_queue.clone();
+    let mut rx = trace_write_lock!(message_queue).make_request_channel();
+
+    // The server finds the property by its browse name and then reads it
+    let server = std::thread::spawn(move || {
+        let property: NodeId = NodeId::new(2, "MinPublishingInterval");
+        let request = match next_request(&mut rx) {
+            SupportedMessage::TranslateBrowsePathsToNodeIdsRequest(request) => request,
+            request => panic!(
+                "Expected a translate browse paths request, got {:?}",
+                request
+            ),
+        };
+        let browse_path = &request.browse_paths.as_ref().unwrap()[0];
+        let server_capabilities: NodeId = ObjectId::Server_ServerCapabilities.into();
+        assert_eq!(browse_path.starting_node, server_capabilities);
+        assert_eq!(
+            browse_path.relative_path.elements.as_ref().unwrap()[0].target_name,
+            QualifiedName::new(0, "MinPublishingInterval")
+        );
+        let response = TranslateBrowsePathsToNodeIdsResponse {
+            response_header: ResponseHeader::new_good(&request.request_header),
+            results: Some(vec![BrowsePathResult {
+                status_code: StatusCode::Good,
+                targets: Some(vec![BrowsePathTarget {
+                    target_id: property.clone().into(),
+                    remaining_path_index: u32::MAX,
+                }]),
+            }]),
+            diagnostic_infos: None,
+        };
+        trace_write_lock!(message_queue).store_response(response.into());
+
+        let request = match next_request(&mut rx) {
+            SupportedMessage::ReadRequest(request) => request,
+            request => panic!("Expected a read request, got {:?}", request),
+        };
+        assert_eq!(request.nodes_to_read.as_ref().unwrap()[0].node_id, property);
+        let response = ReadResponse {
+            response_header: ResponseHeader::new_good(&request.request_header),
+            results: Some(vec![DataValue::value_only(100f64)]),
+            diagnostic_infos: None,
+        };
+        trace_write_lock!(message_queue).store_response(response.into());
+    });
+    assert_eq!(session.min_publishing_interval(), Some(100f64));
+    server.join().unwrap();
+
+    // The value is cached, so a faster interval is rejected without sending anything
+    assert_eq!(session.min_publishing_interval(), Some(100f64));
+    session.set_reject_unsupported_publishing_intervals(true);
+    assert_eq!(
+        session
+            .create_subscription(10f64, 10, 30, 0, 0, true, DataChangeCallback::new(|_| {}))
+            .unwrap_err(),
+        StatusCode::BadOutOfRange
+    );
+}
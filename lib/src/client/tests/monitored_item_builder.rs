@@ -0,0 +1,167 @@
+use std::{convert::TryFrom, io::Cursor};
+
+use crate::{client::prelude::*, types::DecodingOptions};
+
+fn filter(request: &MonitoredItemCreateRequest) -> DataChangeFilter {
+    request
+        .requested_parameters
+        .filter
+        .decode_inner::<DataChangeFilter>(&DecodingOptions::test())
+        .unwrap()
+}
+
+#[test]
+fn data_change_trigger() {
+    // Status and value by default
+    let request = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1")).build();
+    assert_eq!(request.monitoring_mode, MonitoringMode::Reporting);
+    assert_eq!(filter(&request).trigger, DataChangeTrigger::StatusValue);
+
+    let request = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1"))
+        .trigger(DataChangeTrigger::StatusValueTimestamp)
+        .build();
+    assert_eq!(
+        filter(&request).trigger,
+        DataChangeTrigger::StatusValueTimestamp
+    );
+
+    assert_eq!(
+        DataChangeTrigger::try_from(0).unwrap(),
+        DataChangeTrigger::Status
+    );
+    assert_eq!(
+        DataChangeTrigger::try_from(3).unwrap_err(),
+        StatusCode::BadMonitoredItemFilterInvalid
+    );
+}
+
+#[test]
+fn discard_oldest() {
+    let request = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1"))
+        .queue_size(10)
+        .build();
+    assert_eq!(request.requested_parameters.queue_size, 10);
+    assert!(request.requested_parameters.discard_oldest);
+
+    let request = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1"))
+        .queue_size(10)
+        .discard_oldest(false)
+        .build();
+    assert!(!request.requested_parameters.discard_oldest);
+}
+
+#[test]
+fn exception_based_sampling_interval() {
+    // The default is the publishing interval
+    let request = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1")).build();
+    assert_eq!(request.requested_parameters.sampling_interval, -1.0);
+
+    // 0 is kept as it is, through encoding too
+    let request = DataChangeMonitoredItemBuilder::new(NodeId::new(2, "v1"))
+        .sampling_interval(0.0)
+        .build();
+    assert_eq!(request.requested_parameters.sampling_interval, 0.0);
+
+    let mut stream = Cursor::new(Vec::new());
+    request.encode(&mut stream).unwrap();
+    stream.set_position(0);
+    let decoded =
+        MonitoredItemCreateRequest::decode(&mut stream, &DecodingOptions::test()).unwrap();
+    assert_eq!(decoded.requested_parameters.sampling_interval, 0.0);
+    assert_eq!(decoded, request);
+}
+
+#[test]
+fn event_filter() {
+    let where_clause = ContentFilterBuilder::new()
+        .gt(
+            Operand::simple_attribute(
+                ObjectTypeId::BaseEventType,
+                "Severity",
+                AttributeId::Value,
+                UAString::null(),
+            ),
+            Operand::literal(500u16),
+        )
+        .build();
+    let builder = EventMonitoredItemBuilder::new(ObjectId::Server)
+        .select("Message")
+        .select("Severity")
+        .where_clause(where_clause.clone());
+    assert_eq!(builder.selected().len(), 2);
+    let request = builder.build();
+
+    // Events are reported through the event notifier and the session assigns the client handle
+    assert_eq!(request.item_to_monitor.node_id, ObjectId::Server.into());
+    assert_eq!(
+        request.item_to_monitor.attribute_id,
+        AttributeId::EventNotifier as u32
+    );
+    assert_eq!(request.requested_parameters.client_handle, 0);
+
+    let filter = request
+        .requested_parameters
+        .filter
+        .decode_inner::<EventFilter>(&DecodingOptions::test())
+        .unwrap();
+    let select_clauses = filter.select_clauses.unwrap();
+    assert_eq!(
+        select_clauses[1].browse_path,
+        Some(vec![QualifiedName::from("Severity")])
+    );
+    assert_eq!(
+        select_clauses[1].type_definition_id,
+        ObjectTypeId::BaseEventType.into()
+    );
+    assert_eq!(filter.where_clause, where_clause);
+
+    // All events by default
+    let filter = EventMonitoredItemBuilder::new(ObjectId::Server)
+        .select("Message")
+        .build()
+        .requested_parameters
+        .filter
+        .decode_inner::<EventFilter>(&DecodingOptions::test())
+        .unwrap();
+    assert_eq!(filter.where_clause.elements, None);
+}
+
+#[test]
+fn event_fields() {
+    let builder = EventMonitoredItemBuilder::new(ObjectId::Server)
+        .select("Message")
+        .select_clauses(vec![SimpleAttributeOperand {
+            type_definition_id: ObjectTypeId::BaseEventType.into(),
+            browse_path: Some(vec!["EnabledState".into(), "Id".into()]),
+            attribute_id: AttributeId::Value as u32,
+            index_range: UAString::null(),
+        }]);
+    let event = EventFieldList {
+        client_handle: 1,
+        event_fields: Some(vec![
+            Variant::from(LocalizedText::new("", "Alarm")),
+            Variant::from(true),
+        ]),
+    };
+    let fields = decode_event_fields(builder.selected(), &event).unwrap();
+    assert_eq!(
+        fields,
+        vec![
+            (
+                "Message".to_string(),
+                Variant::from(LocalizedText::new("", "Alarm"))
+            ),
+            ("EnabledState/Id".to_string(), Variant::from(true)),
+        ]
+    );
+
+    // The fields must match the select clauses
+    let event = EventFieldList {
+        client_handle: 1,
+        event_fields: Some(vec![Variant::from(true)]),
+    };
+    assert_eq!(
+        decode_event_fields(builder.selected(), &event).unwrap_err(),
+        StatusCode::BadUnexpectedError
+    );
+}
@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+use crate::client::rate_limiter::RateLimiter;
+
+#[test]
+fn rate_limiter_refills() {
+    let mut rate_limiter = RateLimiter::new(2.0);
+    let now = Instant::now();
+
+    // A full bucket allows a burst of a second of requests
+    assert!(rate_limiter.try_acquire(now).is_ok());
+    assert!(rate_limiter.try_acquire(now).is_ok());
+    let wait = rate_limiter.try_acquire(now).unwrap_err();
+    assert_eq!(wait, Duration::from_millis(500));
+
+    // Tokens are added over time
+    let now = now + Duration::from_millis(250);
+    let wait = rate_limiter.try_acquire(now).unwrap_err();
+    assert_eq!(wait, Duration::from_millis(250));
+    let now = now + Duration::from_millis(250);
+    assert!(rate_limiter.try_acquire(now).is_ok());
+    assert!(rate_limiter.try_acquire(now).is_err());
+
+    // The bucket never holds more than its capacity
+    let now = now + Duration::from_secs(10);
+    assert!(rate_limiter.try_acquire(now).is_ok());
+    assert!(rate_limiter.try_acquire(now).is_ok());
+    assert!(rate_limiter.try_acquire(now).is_err());
+}
+
+#[test]
+fn rate_limiter_below_one_per_second() {
+    let mut rate_limiter = RateLimiter::new(0.5);
+    let now = Instant::now();
+    assert!(rate_limiter.try_acquire(now).is_ok());
+    assert_eq!(
+        rate_limiter.try_acquire(now).unwrap_err(),
+        Duration::from_secs(2)
+    );
+    assert!(rate_limiter
+        .try_acquire(now + Duration::from_secs(2))
+        .is_ok());
+}
@@ -0,0 +1,604 @@
+use std::sync::mpsc::SyncSender;
+
+use crate::{
+    client::session::services::*,
+    core::supported_message::SupportedMessage,
+    sync::*,
+    types::{
+        node_ids::{MethodId, ObjectId, VariableId},
+        service_types::Argument,
+        status_code::StatusCode,
+        *,
+    },
+};
+
+type ReadHandler = dyn Fn(&[ReadValueId]) -> Result<Vec<DataValue>, StatusCode> + Send + Sync;
+
+/// A stand in for a session that answers reads from a handler and records what was asked for.
+pub struct MockSession {
+    read_handler: Box<ReadHandler>,
+    reads: Mutex<Vec<Vec<ReadValueId>>>,
+    max_age: Mutex<f64>,
+    call_result: Mutex<Option<CallMethodResult>>,
+    calls: Mutex<Vec<CallMethodRequest>>,
+}
+
+impl MockSession {
+    pub fn new<F>(read_handler: F) -> MockSession
+    where
+        F: Fn(&[ReadValueId]) -> Result<Vec<DataValue>, StatusCode> + Send + Sync + 'static,
+    {
+        MockSession {
+            read_handler: Box::new(read_handler),
+            reads: Mutex::new(Vec::new()),
+            max_age: Mutex::new(0.0),
+            call_result: Mutex::new(None),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates a mock session that answers every read with the supplied values, in order.
+    pub fn with_values(values: Vec<DataValue>) -> MockSession {
+        Self::new(move |_| Ok(values.clone()))
+    }
+
+    pub fn reads(&self) -> Vec<Vec<ReadValueId>> {
+        self.reads.lock().clone()
+    }
+
+    /// The max age of the last read
+    pub fn max_age(&self) -> f64 {
+        *self.max_age.lock()
+    }
+
+    /// Sets the result of method calls. Calls fail with `BadNotImplemented` if there is none.
+    pub fn set_call_result(&self, call_result: CallMethodResult) {
+        *self.call_result.lock() = Some(call_result);
+    }
+
+    pub fn calls(&self) -> Vec<CallMethodRequest> {
+        self.calls.lock().clone()
+    }
+}
+
+impl Service for MockSession {
+    fn make_request_header(&self) -> RequestHeader {
+        RequestHeader::dummy()
+    }
+
+    fn send_request<T>(&self, _request: T) -> Result<SupportedMessage, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        Err(StatusCode::BadNotImplemented)
+    }
+
+    fn async_send_request<T>(
+        &self,
+        _request: T,
+        _sender: Option<SyncSender<SupportedMessage>>,
+    ) -> Result<u32, StatusCode>
+    where
+        T: Into<SupportedMessage>,
+    {
+        Err(StatusCode::BadNotImplemented)
+    }
+}
+
+impl AttributeService for MockSession {
+    fn read(
+        &self,
+        nodes_to_read: &[ReadValueId],
+        _timestamps_to_return: TimestampsToReturn,
+        max_age: f64,
+    ) -> Result<Vec<DataValue>, StatusCode> {
+        *self.max_age.lock() = max_age;
+        self.reads.lock().push(nodes_to_read.to_vec());
+        (self.read_handler)(nodes_to_read)
+    }
+
+    fn history_read(
+        &self,
+        _history_read_details: HistoryReadAction,
+        _timestamps_to_return: TimestampsToReturn,
+        _release_continuation_points: bool,
+        _nodes_to_read: &[HistoryReadValueId],
+    ) -> Result<Vec<HistoryReadResult>, StatusCode> {
+        Err(StatusCode::BadNotImplemented)
+    }
+
+    fn write(&self, _nodes_to_write: &[WriteValue]) -> Result<Vec<StatusCode>, StatusCode> {
+        Err(StatusCode::BadNotImplemented)
+    }
+
+    fn history_update(
+        &self,
+        _history_update_details: &[HistoryUpdateAction],
+    ) -> Result<Vec<HistoryUpdateResult>, StatusCode> {
+        Err(StatusCode::BadNotImplemented)
+    }
+}
+
+impl MethodService for MockSession {
+    fn call<T>(&self, method: T) -> Result<CallMethodResult, StatusCode>
+    where
+        T: Into<CallMethodRequest>,
+    {
+        self.calls.lock().push(method.into());
+        self.call_result
+            .lock()
+            .clone()
+            .ok_or(StatusCode::BadNotImplemented)
+    }
+}
+
+fn bad_value(status: StatusCode) -> DataValue {
+    DataValue {
+        status: Some(status),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn read_node_keys_results_by_attribute() {
+    let data_type: NodeId = DataTypeId::Int32.into();
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(100i32),
+        DataValue::value_only(data_type.clone()),
+        bad_value(StatusCode::BadAttributeIdInvalid),
+    ]);
+    let node_id = NodeId::new(2, "v1");
+    let attributes = [
+        AttributeId::Value,
+        AttributeId::DataType,
+        AttributeId::Description,
+    ];
+    let results = session.read_node(node_id.clone(), &attributes).unwrap();
+
+    // One request with a read value id per attribute
+    let reads = session.reads();
+    assert_eq!(reads.len(), 1);
+    assert_eq!(reads[0].len(), 3);
+    reads[0].iter().zip(attributes.iter()).for_each(|(r, a)| {
+        assert_eq!(r.node_id, node_id);
+        assert_eq!(r.attribute_id, *a as u32);
+    });
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results[&AttributeId::Value].value,
+        Some(Variant::Int32(100))
+    );
+    assert_eq!(
+        results[&AttributeId::DataType].value,
+        Some(Variant::from(data_type))
+    );
+    // A bad attribute does not fail the call
+    assert_eq!(
+        results[&AttributeId::Description].status(),
+        StatusCode::BadAttributeIdInvalid
+    );
+}
+
+#[test]
+fn read_node_result_count_mismatch() {
+    let session = MockSession::with_values(vec![DataValue::value_only(1i32)]);
+    let result = session.read_node(
+        NodeId::new(2, "v1"),
+        &[AttributeId::Value, AttributeId::DataType],
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadUnexpectedError);
+}
+
+#[test]
+fn read_variable_metadata() {
+    let data_type: NodeId = DataTypeId::Double.into();
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(data_type.clone()),
+        DataValue::value_only(2i32),
+        DataValue::value_only(vec![3u32, 4u32]),
+    ]);
+    let metadata = session
+        .read_variable_metadata(NodeId::new(2, "v1"))
+        .unwrap();
+    assert_eq!(
+        metadata,
+        VariableMetadata {
+            data_type,
+            value_rank: 2,
+            array_dimensions: Some(vec![3, 4]),
+        }
+    );
+
+    // Scalars typically have no array dimensions
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(NodeId::from(&DataTypeId::Int32)),
+        DataValue::value_only(-1i32),
+        bad_value(StatusCode::BadAttributeIdInvalid),
+    ]);
+    let metadata = session
+        .read_variable_metadata(NodeId::new(2, "v1"))
+        .unwrap();
+    assert_eq!(metadata.value_rank, -1);
+    assert!(metadata.array_dimensions.is_none());
+
+    // Not a variable
+    let session = MockSession::with_values(vec![
+        bad_value(StatusCode::BadAttributeIdInvalid),
+        bad_value(StatusCode::BadAttributeIdInvalid),
+        bad_value(StatusCode::BadAttributeIdInvalid),
+    ]);
+    assert_eq!(
+        session
+            .read_variable_metadata(NodeId::new(2, "o1"))
+            .unwrap_err(),
+        StatusCode::BadAttributeIdInvalid
+    );
+}
+
+#[test]
+fn ping_reads_current_time() {
+    let session = MockSession::with_values(vec![DataValue::value_only(DateTime::now())]);
+    assert!(session.ping().is_ok());
+    let reads = session.reads();
+    assert_eq!(reads.len(), 1);
+    let current_time: NodeId = VariableId::Server_ServerStatus_CurrentTime.into();
+    assert_eq!(reads[0][0].node_id, current_time);
+
+    let session = MockSession::new(|_| Err(StatusCode::BadTimeout));
+    assert_eq!(session.ping().unwrap_err(), StatusCode::BadTimeout);
+}
+
+#[test]
+fn read_value_passes_max_age() {
+    let session = MockSession::with_values(vec![DataValue::value_only(5i32)]);
+    let value = session.read_value(NodeId::new(2, "v1"), 0.0).unwrap();
+    assert_eq!(value.value, Some(Variant::Int32(5)));
+    assert_eq!(session.max_age(), 0.0);
+
+    let _ = session.read_value(NodeId::new(2, "v1"), 60000.0).unwrap();
+    assert_eq!(session.max_age(), 60000.0);
+
+    let session = MockSession::with_values(vec![]);
+    assert_eq!(
+        session.read_value(NodeId::new(2, "v1"), 0.0).unwrap_err(),
+        StatusCode::BadUnexpectedError
+    );
+}
+
+#[test]
+fn server_time() {
+    let now = DateTime::now();
+    let session = MockSession::with_values(vec![DataValue::value_only(now)]);
+    assert_eq!(session.server_time().unwrap(), now);
+
+    let session = MockSession::with_values(vec![DataValue::value_only(100i32)]);
+    assert_eq!(
+        session.server_time().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+
+    let session = MockSession::with_values(vec![bad_value(StatusCode::BadNodeIdUnknown)]);
+    assert_eq!(
+        session.server_time().unwrap_err(),
+        StatusCode::BadNodeIdUnknown
+    );
+}
+
+#[test]
+fn synchronized_server_time() {
+    let now = DateTime::now();
+    let session = MockSession::with_values(vec![DataValue::value_only(now)]);
+    let (server_time, uncertainty) = session.synchronized_server_time().unwrap();
+    assert!(server_time >= now);
+    assert!(server_time - now <= uncertainty);
+    assert!(uncertainty < chrono::Duration::seconds(1));
+
+    let session = MockSession::with_values(vec![bad_value(StatusCode::BadNodeIdUnknown)]);
+    assert_eq!(
+        session.synchronized_server_time().unwrap_err(),
+        StatusCode::BadNodeIdUnknown
+    );
+}
+
+#[test]
+fn read_build_info() {
+    let build_info = BuildInfo {
+        product_uri: UAString::from("urn:product"),
+        manufacturer_name: UAString::from("Manufacturer"),
+        product_name: UAString::from("Product"),
+        software_version: UAString::from("1.2.3"),
+        build_number: UAString::from("456"),
+        build_date: DateTime::now(),
+    };
+    let value =
+        ExtensionObject::from_encodable(ObjectId::BuildInfo_Encoding_DefaultBinary, &build_info);
+    let session = MockSession::with_values(vec![DataValue::value_only(value)]);
+    assert_eq!(session.read_build_info().unwrap(), build_info);
+    let node_id: NodeId = VariableId::Server_ServerStatus_BuildInfo.into();
+    assert_eq!(session.reads()[0][0].node_id, node_id);
+
+    // Some other structure
+    let value = ExtensionObject::from_encodable(
+        ObjectId::DataChangeFilter_Encoding_DefaultBinary,
+        &DataChangeFilter {
+            trigger: DataChangeTrigger::Status,
+            deadband_type: 0,
+            deadband_value: 0.0,
+        },
+    );
+    let session = MockSession::with_values(vec![DataValue::value_only(value)]);
+    assert_eq!(
+        session.read_build_info().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+
+    let session = MockSession::with_values(vec![bad_value(StatusCode::BadNodeIdUnknown)]);
+    assert_eq!(
+        session.read_build_info().unwrap_err(),
+        StatusCode::BadNodeIdUnknown
+    );
+}
+
+#[test]
+fn supported_locales() {
+    let session = MockSession::with_values(vec![DataValue::value_only(vec![
+        UAString::from("en-US"),
+        UAString::from("de"),
+    ])]);
+    assert_eq!(
+        session.supported_locales().unwrap(),
+        vec!["en-US".to_string(), "de".to_string()]
+    );
+    let node_id: NodeId = VariableId::Server_ServerCapabilities_LocaleIdArray.into();
+    assert_eq!(session.reads()[0][0].node_id, node_id);
+
+    let session = MockSession::with_values(vec![DataValue::value_only(Variant::Empty)]);
+    assert!(session.supported_locales().unwrap().is_empty());
+
+    let session = MockSession::with_values(vec![DataValue::value_only(vec![1i32, 2i32])]);
+    assert_eq!(
+        session.supported_locales().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+}
+
+#[test]
+fn node_exists() {
+    let session = MockSession::with_values(vec![DataValue::value_only(NodeClass::Variable as i32)]);
+    assert!(session.node_exists(NodeId::new(2, "v1")).unwrap());
+    assert_eq!(
+        session.reads()[0][0].attribute_id,
+        AttributeId::NodeClass as u32
+    );
+
+    let session = MockSession::with_values(vec![bad_value(StatusCode::BadNodeIdUnknown)]);
+    assert!(!session.node_exists(NodeId::new(2, "v2")).unwrap());
+
+    // Other failures are errors, not a missing node
+    let session = MockSession::with_values(vec![bad_value(StatusCode::BadUserAccessDenied)]);
+    assert_eq!(
+        session.node_exists(NodeId::new(2, "v1")).unwrap_err(),
+        StatusCode::BadUserAccessDenied
+    );
+    let session = MockSession::new(|_| Err(StatusCode::BadTimeout));
+    assert_eq!(
+        session.node_exists(NodeId::new(2, "v1")).unwrap_err(),
+        StatusCode::BadTimeout
+    );
+}
+
+#[test]
+fn is_writable() {
+    let access_level = AccessLevelType::CurrentRead | AccessLevelType::CurrentWrite;
+    let session = MockSession::with_values(vec![DataValue::value_only(access_level.bits())]);
+    assert!(session.is_writable(NodeId::new(2, "v1")).unwrap());
+    assert_eq!(
+        session.reads()[0][0].attribute_id,
+        AttributeId::AccessLevel as u32
+    );
+
+    let access_level = AccessLevelType::CurrentRead | AccessLevelType::HistoryRead;
+    let session = MockSession::with_values(vec![DataValue::value_only(access_level.bits())]);
+    assert!(!session.is_writable(NodeId::new(2, "v1")).unwrap());
+
+    let session = MockSession::with_values(vec![bad_value(StatusCode::BadAttributeIdInvalid)]);
+    assert_eq!(
+        session.is_writable(NodeId::new(2, "o1")).unwrap_err(),
+        StatusCode::BadAttributeIdInvalid
+    );
+}
+
+#[test]
+fn supports_history() {
+    let access_level = AccessLevelType::CurrentRead | AccessLevelType::HistoryRead;
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(true),
+        DataValue::value_only(access_level.bits()),
+    ]);
+    assert!(session.supports_history(NodeId::new(2, "v1")).unwrap());
+    let attribute_ids = session.reads()[0]
+        .iter()
+        .map(|r| r.attribute_id)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        attribute_ids,
+        vec![
+            AttributeId::Historizing as u32,
+            AttributeId::AccessLevel as u32
+        ]
+    );
+
+    // History must be both collected and readable
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(false),
+        DataValue::value_only(access_level.bits()),
+    ]);
+    assert!(!session.supports_history(NodeId::new(2, "v1")).unwrap());
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(true),
+        DataValue::value_only(AccessLevelType::CurrentRead.bits()),
+    ]);
+    assert!(!session.supports_history(NodeId::new(2, "v1")).unwrap());
+
+    let session = MockSession::with_values(vec![
+        bad_value(StatusCode::BadAttributeIdInvalid),
+        bad_value(StatusCode::BadAttributeIdInvalid),
+    ]);
+    assert_eq!(
+        session.supports_history(NodeId::new(2, "o1")).unwrap_err(),
+        StatusCode::BadAttributeIdInvalid
+    );
+}
+
+#[test]
+fn server_service_capabilities() {
+    let session = MockSession::with_values(vec![
+        DataValue::value_only(NodeClass::Method as i32),
+        DataValue::value_only(true),
+        DataValue::value_only(EventNotifierType::SubscribeToEvents.bits()),
+        DataValue::value_only(2u32),
+    ]);
+    assert_eq!(
+        session.server_service_capabilities().unwrap(),
+        ServiceCapabilities {
+            supports_method_call: true,
+            supports_historical_data: true,
+            supports_events: true,
+            supports_subscriptions: true,
+        }
+    );
+    assert_eq!(session.reads().len(), 1);
+    assert_eq!(session.reads()[0].len(), 4);
+
+    // Nodes the server doesn't have mean it doesn't support the service
+    let session = MockSession::with_values(vec![
+        bad_value(StatusCode::BadNodeIdUnknown),
+        DataValue::value_only(false),
+        DataValue::value_only(0u8),
+        bad_value(StatusCode::BadNodeIdUnknown),
+    ]);
+    assert_eq!(
+        session.server_service_capabilities().unwrap(),
+        ServiceCapabilities::default()
+    );
+
+    let session = MockSession::new(|_| Err(StatusCode::BadTimeout));
+    assert_eq!(
+        session.server_service_capabilities().unwrap_err(),
+        StatusCode::BadTimeout
+    );
+}
+
+fn call_result(output_arguments: Vec<Variant>) -> CallMethodResult {
+    CallMethodResult {
+        status_code: StatusCode::Good,
+        input_argument_results: None,
+        input_argument_diagnostic_infos: None,
+        output_arguments: Some(output_arguments),
+    }
+}
+
+#[test]
+fn get_monitored_items() {
+    let session = MockSession::with_values(vec![]);
+    session.set_call_result(call_result(vec![
+        Variant::from(vec![1u32, 2u32]),
+        Variant::from(vec![100u32, 200u32]),
+    ]));
+    assert_eq!(
+        session.get_monitored_items(5).unwrap(),
+        vec![(1, 100), (2, 200)]
+    );
+    let calls = session.calls();
+    assert_eq!(calls.len(), 1);
+    let method_id: NodeId = MethodId::Server_GetMonitoredItems.into();
+    assert_eq!(calls[0].method_id, method_id);
+    assert_eq!(calls[0].input_arguments, Some(vec![Variant::UInt32(5)]));
+
+    // The handles must pair up
+    session.set_call_result(call_result(vec![
+        Variant::from(vec![1u32, 2u32]),
+        Variant::from(vec![100u32]),
+    ]));
+    assert_eq!(
+        session.get_monitored_items(5).unwrap_err(),
+        StatusCode::BadUnexpectedError
+    );
+}
+
+fn argument(name: &str, data_type: DataTypeId, value_rank: i32) -> Argument {
+    Argument {
+        name: UAString::from(name),
+        data_type: data_type.into(),
+        value_rank,
+        array_dimensions: None,
+        description: LocalizedText::null(),
+    }
+}
+
+#[test]
+fn validate_method_inputs() {
+    let method_arguments = MethodArguments {
+        input_arguments: vec![
+            argument("count", DataTypeId::UInt32, -1),
+            argument("values", DataTypeId::Double, 1),
+            argument("anything", DataTypeId::BaseDataType, -2),
+            argument("number", DataTypeId::Number, -1),
+        ],
+        output_arguments: Vec::new(),
+    };
+    let inputs = |count: Variant, values: Variant| {
+        vec![count, values, Variant::from("x"), Variant::from(1i16)]
+    };
+
+    method_arguments
+        .validate_inputs(&inputs(5u32.into(), vec![1.0f64, 2.0].into()))
+        .unwrap();
+
+    // Wrong data type
+    assert_eq!(
+        method_arguments
+            .validate_inputs(&inputs(5i32.into(), vec![1.0f64].into()))
+            .unwrap_err(),
+        StatusCode::BadInvalidArgument
+    );
+    assert_eq!(
+        method_arguments
+            .validate_inputs(&inputs(5u32.into(), vec![1i32].into()))
+            .unwrap_err(),
+        StatusCode::BadInvalidArgument
+    );
+    // A scalar where an array is expected and the other way around
+    assert_eq!(
+        method_arguments
+            .validate_inputs(&inputs(5u32.into(), 1.0f64.into()))
+            .unwrap_err(),
+        StatusCode::BadInvalidArgument
+    );
+    assert_eq!(
+        method_arguments
+            .validate_inputs(&inputs(vec![5u32].into(), vec![1.0f64].into()))
+            .unwrap_err(),
+        StatusCode::BadInvalidArgument
+    );
+    // Too few inputs
+    assert_eq!(
+        method_arguments
+            .validate_inputs(&[5u32.into()])
+            .unwrap_err(),
+        StatusCode::BadInvalidArgument
+    );
+
+    // A data type that isn't built in is not checked
+    let method_arguments = MethodArguments {
+        input_arguments: vec![Argument {
+            data_type: NodeId::new(2, 5000),
+            ..argument("custom", DataTypeId::BaseDataType, -1)
+        }],
+        output_arguments: Vec::new(),
+    };
+    method_arguments
+        .validate_inputs(&[Variant::from("x")])
+        .unwrap();
+}
@@ -0,0 +1,2427 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    client::{
+        callbacks::DataChangeCallback,
+        message_queue::Message,
+        rate_limiter::RateLimitPolicy,
+        session::session_state::{
+            PartialResultsPolicy, PreActivationPolicy, ReconnectPolicy, RequestError, RetryPolicy,
+            SecureChannelRetryPolicy, SessionState, SessionStateConfig,
+        },
+        subscription::{CreateMonitoredItem, Subscription},
+        subscription_state::SubscriptionState,
+    },
+    core::{comms::secure_channel::SecureChannel, supported_message::SupportedMessage},
+    crypto::{SecurityPolicy, X509Data, X509},
+    supported_message_as,
+    sync::*,
+    types::{status_code::StatusCode, *},
+};
+
+/// Makes a session state over the secure channel, returning it with the receiving end of its
+/// transmission queue, i.e. what would otherwise be sent to the server.
+pub fn make_session_state(
+    secure_channel: Arc<RwLock<SecureChannel>>,
+) -> (Arc<RwLock<SessionState>>, UnboundedReceiver<Message>) {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    make_session_state_with_subscriptions(secure_channel, subscription_state)
+}
+
+pub fn make_session_state_with_subscriptions(
+    secure_channel: Arc<RwLock<SecureChannel>>,
+    subscription_state: Arc<RwLock<SubscriptionState>>,
+) -> (Arc<RwLock<SessionState>>, UnboundedReceiver<Message>) {
+    let mut session_state = SessionState::new(false, secure_channel, subscription_state);
+    // Tests of publishing assume a session that has been activated
+    session_state.set_activated(true);
+    let rx = {
+        let mut message_queue = trace_write_lock!(session_state.message_queue);
+        message_queue.make_request_channel()
+    };
+    (Arc::new(RwLock::new(session_state)), rx)
+}
+
+pub fn read_request(session_state: &Arc<RwLock<SessionState>>) -> ReadRequest {
+    let mut session_state = trace_write_lock!(session_state);
+    ReadRequest {
+        request_header: session_state.make_request_header(),
+        max_age: 0f64,
+        timestamps_to_return: TimestampsToReturn::Both,
+        nodes_to_read: None,
+    }
+}
+
+/// Returns the next request that the session state has queued for sending
+pub fn next_request(rx: &mut UnboundedReceiver<Message>) -> SupportedMessage {
+    match rx.blocking_recv() {
+        Some(Message::SupportedMessage(request)) => request,
+        message => panic!("Expected a request, got {:?}", message),
+    }
+}
+
+/// Runs an operation on the session state in another thread, answering the request it sends with
+/// the response made by `respond`, and returns what the operation returns
+pub fn respond_to<T, O, F>(
+    session_state: &Arc<RwLock<SessionState>>,
+    rx: &mut UnboundedReceiver<Message>,
+    operation: O,
+    respond: F,
+) -> T
+where
+    T: Send + 'static,
+    O: FnOnce(&mut SessionState) -> T + Send + 'static,
+    F: FnOnce(SupportedMessage) -> SupportedMessage,
+{
+    let message_queue = trace_read_lock!(session_state).message_queue.clone();
+    let session_thread = {
+        let session_state = session_state.clone();
+        thread::spawn(move || {
+            let mut session_state = trace_write_lock!(session_state);
+            operation(&mut session_state)
+        })
+    };
+    let response = respond(next_request(rx));
+    {
+        let mut message_queue = trace_write_lock!(message_queue);
+        message_queue.store_response(response);
+    }
+    session_thread.join().unwrap()
+}
+
+fn security_token(token_id: u32, revised_lifetime: u32) -> ChannelSecurityToken {
+    ChannelSecurityToken {
+        channel_id: 1,
+        token_id,
+        created_at: DateTime::now(),
+        revised_lifetime,
+    }
+}
+
+#[test]
+fn token_near_expiry_handler() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    {
+        let mut secure_channel = trace_write_lock!(secure_channel);
+        secure_channel.set_security_token(security_token(1, 60000));
+    }
+    thread::sleep(Duration::from_millis(5));
+
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let remaining = Arc::new(Mutex::new(None));
+    {
+        let remaining = remaining.clone();
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_token_near_expiry_handler(move |time_left| {
+            *remaining.lock() = Some(time_left);
+        });
+    }
+
+    // A fresh token is nowhere near expiring
+    trace_write_lock!(session_state)
+        .ensure_secure_channel_token()
+        .unwrap();
+    assert!(remaining.lock().is_none());
+
+    // Any of the lifetime elapsed counts as near expiry when the whole lifetime is the margin
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_token_near_expiry_fraction(1.0);
+        session_state.ensure_secure_channel_token().unwrap();
+    }
+    let time_left = remaining.lock().take().unwrap();
+    assert!(time_left < Duration::from_millis(60000));
+    assert!(time_left > Duration::from_millis(50000));
+}
+
+#[test]
+fn shared_secure_channel_renews_once() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    {
+        // A token with no lifetime left is due for renewal straight away
+        let mut secure_channel = trace_write_lock!(secure_channel);
+        secure_channel.set_security_token(security_token(1, 0));
+    }
+    thread::sleep(Duration::from_millis(5));
+
+    let (session_state_1, mut rx_1) = make_session_state(secure_channel.clone());
+    let (session_state_2, mut rx_2) = make_session_state(secure_channel.clone());
+
+    // Session 1 sends a request, first renewing the token and waiting for the response to that
+    let request_1 = read_request(&session_state_1);
+    let request_handle_1 = respond_to(
+        &session_state_1,
+        &mut rx_1,
+        |session_state| session_state.async_send_request(request_1, None),
+        |request| {
+            let renew_request = supported_message_as!(request, OpenSecureChannelRequest);
+            assert_eq!(renew_request.request_type, SecurityTokenRequestType::Renew);
+            assert!(trace_read_lock!(secure_channel).is_token_renewal_in_progress());
+
+            // Session 2 sees the renewal is underway and sends its request straight away
+            let request_2 = read_request(&session_state_2);
+            let request_handle_2 = {
+                let mut session_state = trace_write_lock!(session_state_2);
+                session_state.async_send_request(request_2, None).unwrap()
+            };
+            match next_request(&mut rx_2) {
+                SupportedMessage::ReadRequest(request) => {
+                    assert_eq!(request.request_header.request_handle, request_handle_2)
+                }
+                request => panic!("Expected a read request, got {:?}", request),
+            }
+            assert!(rx_2.try_recv().is_err());
+
+            // Complete the renewal
+            OpenSecureChannelResponse {
+                response_header: ResponseHeader::new_good(&renew_request.request_header),
+                server_protocol_version: 0,
+                security_token: security_token(2, 60000),
+                server_nonce: ByteString::null(),
+            }
+            .into()
+        },
+    )
+    .unwrap();
+    match next_request(&mut rx_1) {
+        SupportedMessage::ReadRequest(request) => {
+            assert_eq!(request.request_header.request_handle, request_handle_1)
+        }
+        request => panic!("Expected a read request, got {:?}", request),
+    }
+
+    // Both sessions now use the renewed token
+    let secure_channel = trace_read_lock!(secure_channel);
+    assert_eq!(secure_channel.token_id(), 2);
+    assert!(!secure_channel.is_token_renewal_in_progress());
+    assert!(!secure_channel.should_renew_security_token());
+}
+
+#[test]
+fn failed_renewal_can_be_retried() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    {
+        let mut secure_channel = trace_write_lock!(secure_channel);
+        secure_channel.set_security_token(security_token(1, 0));
+    }
+    thread::sleep(Duration::from_millis(5));
+
+    let (session_state, mut rx) = make_session_state(secure_channel.clone());
+    let request = read_request(&session_state);
+    let _ = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.async_send_request(request, None),
+        |request| {
+            let renew_request = supported_message_as!(request, OpenSecureChannelRequest);
+            ServiceFault {
+                response_header: ResponseHeader::new_service_result(
+                    &renew_request.request_header,
+                    StatusCode::BadSecureChannelIdInvalid,
+                ),
+            }
+            .into()
+        },
+    );
+
+    // The failure leaves the channel free for the next request to try again
+    assert!(!trace_read_lock!(secure_channel).is_token_renewal_in_progress());
+}
+
+/// Sends a publish request and answers it with a data change for each of the client handles
+fn publish_data_change(
+    session_state: &Arc<RwLock<SessionState>>,
+    rx: &mut UnboundedReceiver<Message>,
+    subscription_id: u32,
+    sequence_number: u32,
+    changes: &[(u32, i32)],
+) {
+    let mut session_state = trace_write_lock!(session_state);
+    session_state.async_publish().unwrap();
+    let request = next_publish_request(rx);
+    let monitored_items = changes
+        .iter()
+        .map(|(client_handle, value)| MonitoredItemNotification {
+            client_handle: *client_handle,
+            value: DataValue::value_only(*value),
+        })
+        .collect();
+    let response = PublishResponse {
+        response_header: ResponseHeader::new_good(&request.request_header),
+        subscription_id,
+        available_sequence_numbers: None,
+        more_notifications: false,
+        notification_message: NotificationMessage::data_change(
+            sequence_number,
+            DateTime::now(),
+            monitored_items,
+            vec![],
+        ),
+        results: None,
+        diagnostic_infos: None,
+    };
+    {
+        let mut message_queue = trace_write_lock!(session_state.message_queue);
+        message_queue.store_response(response.into());
+    }
+    assert!(session_state.handle_publish_responses());
+}
+
+#[test]
+fn transfer_initial_values_are_routed() {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) = make_session_state_with_subscriptions(
+        Arc::new(RwLock::new(SecureChannel::new_no_certificate_store())),
+        subscription_state.clone(),
+    );
+
+    // Record each (client handle, value, initial value flag) delivered to the callback
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let callback = {
+        let received = received.clone();
+        DataChangeCallback::new(move |items| {
+            let mut received = received.lock();
+            items.iter().for_each(|item| {
+                let value = item.last_value().value.clone().unwrap();
+                received.push((item.client_handle(), value, item.is_initial_value()));
+            });
+        })
+    };
+    {
+        let mut subscription = Subscription::new(
+            1,
+            1000f64,
+            100,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(callback)),
+        );
+        subscription.insert_monitored_items(&[1000u32, 1001u32].map(|client_handle| {
+            CreateMonitoredItem {
+                id: client_handle - 990,
+                client_handle,
+                item_to_monitor: NodeId::new(2, client_handle).into(),
+                monitoring_mode: MonitoringMode::Reporting,
+                queue_size: 1,
+                discard_oldest: true,
+                sampling_interval: 100f64,
+            }
+        }));
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+
+    // The first values after creation are initial values, subsequent ones are changes
+    publish_data_change(&session_state, &mut rx, 1, 1, &[(1000, 1), (1001, 2)]);
+    publish_data_change(&session_state, &mut rx, 1, 2, &[(1000, 3)]);
+
+    // The subscription is transferred with initial values, so the burst that follows is flagged
+    // even for items which have not changed
+    {
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.expect_initial_values(1);
+    }
+    publish_data_change(&session_state, &mut rx, 1, 3, &[(1000, 3), (1001, 2)]);
+    publish_data_change(&session_state, &mut rx, 1, 4, &[(1001, 4)]);
+
+    let mut received = received.lock().clone();
+    // Items in one notification are not delivered in any particular order
+    received[0..2].sort_by_key(|(client_handle, _, _)| *client_handle);
+    received[3..5].sort_by_key(|(client_handle, _, _)| *client_handle);
+    assert_eq!(
+        received,
+        vec![
+            (1000, Variant::Int32(1), true),
+            (1001, Variant::Int32(2), true),
+            (1000, Variant::Int32(3), false),
+            (1000, Variant::Int32(3), true),
+            (1001, Variant::Int32(2), true),
+            (1001, Variant::Int32(4), false),
+        ]
+    );
+}
+
+#[test]
+fn deleted_subscription_notifications_are_dropped() {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) = make_session_state_with_subscriptions(
+        Arc::new(RwLock::new(SecureChannel::new_no_certificate_store())),
+        subscription_state.clone(),
+    );
+    let changes = Arc::new(Mutex::new(0));
+    let callback = {
+        let changes = changes.clone();
+        DataChangeCallback::new(move |items| *changes.lock() += items.len())
+    };
+    {
+        let mut subscription = Subscription::new(
+            1,
+            1000f64,
+            100,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(callback)),
+        );
+        subscription.insert_monitored_items(&[CreateMonitoredItem {
+            id: 10,
+            client_handle: 1000,
+            item_to_monitor: NodeId::new(2, 1000).into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            queue_size: 1,
+            discard_oldest: true,
+            sampling_interval: 100f64,
+        }]);
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+
+    publish_data_change(&session_state, &mut rx, 1, 1, &[(1000, 1)]);
+    assert_eq!(*changes.lock(), 1);
+
+    // Delete the subscription while its acknowledgement is pending
+    {
+        let _ = trace_write_lock!(subscription_state).delete_subscription(1);
+        trace_write_lock!(session_state).remove_subscription_acknowledgements(1);
+    }
+
+    // A response that was in flight is neither delivered nor acknowledged
+    publish_data_change(&session_state, &mut rx, 1, 2, &[(1000, 2)]);
+    assert_eq!(*changes.lock(), 1);
+    trace_write_lock!(session_state).async_publish().unwrap();
+    let request = next_publish_request(&mut rx);
+    assert!(request.subscription_acknowledgements.is_none());
+}
+
+#[test]
+fn decoded_size_is_budgeted_per_notification() {
+    // How much decoding one notification of the test allocates
+    let notification_size = {
+        let notification_message = NotificationMessage::data_change(
+            1,
+            DateTime::now(),
+            vec![MonitoredItemNotification {
+                client_handle: 1000,
+                value: DataValue::value_only(1),
+            }],
+            vec![],
+        );
+        let decoding_options = DecodingOptions {
+            max_decoded_size: Some(usize::MAX),
+            ..Default::default()
+        };
+        assert!(notification_message
+            .notifications(&decoding_options)
+            .is_some());
+        decoding_options.decoded_size.load(Ordering::Relaxed)
+    };
+    assert!(notification_size > 0);
+
+    // Each notification is under the limit, but two together are over it
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    trace_write_lock!(secure_channel).set_decoding_options(DecodingOptions {
+        max_decoded_size: Some(notification_size * 3 / 2),
+        ..Default::default()
+    });
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) =
+        make_session_state_with_subscriptions(secure_channel, subscription_state.clone());
+    let changes = Arc::new(Mutex::new(0));
+    let callback = {
+        let changes = changes.clone();
+        DataChangeCallback::new(move |items| *changes.lock() += items.len())
+    };
+    {
+        let mut subscription = Subscription::new(
+            1,
+            1000f64,
+            100,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(callback)),
+        );
+        subscription.insert_monitored_items(&[CreateMonitoredItem {
+            id: 10,
+            client_handle: 1000,
+            item_to_monitor: NodeId::new(2, 1000).into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            queue_size: 1,
+            discard_oldest: true,
+            sampling_interval: 100f64,
+        }]);
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+
+    // Both are delivered
+    publish_data_change(&session_state, &mut rx, 1, 1, &[(1000, 1)]);
+    publish_data_change(&session_state, &mut rx, 1, 2, &[(1000, 2)]);
+    assert_eq!(*changes.lock(), 2);
+}
+
+#[test]
+fn deleted_monitored_items_are_unlinked() {
+    let mut subscription = Subscription::new(
+        1,
+        1000f64,
+        100,
+        10,
+        0,
+        true,
+        0,
+        Arc::new(Mutex::new(DataChangeCallback::new(|_| {}))),
+    );
+    let items = [(10, 1000), (11, 1001)]
+        .iter()
+        .map(|(id, client_handle)| CreateMonitoredItem {
+            id: *id,
+            client_handle: *client_handle,
+            item_to_monitor: NodeId::new(2, *client_handle).into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            queue_size: 1,
+            discard_oldest: true,
+            sampling_interval: 100f64,
+        })
+        .collect::<Vec<_>>();
+    subscription.insert_monitored_items(&items);
+    subscription.set_triggering(10, &[11], &[]);
+
+    // Deleting the triggered item deletes the link to it, and an unknown id is ignored
+    subscription.delete_monitored_items(&[11, 12]);
+    let monitored_items = subscription.monitored_items();
+    assert_eq!(monitored_items.len(), 1);
+    assert!(monitored_items[&10].triggered_items().is_empty());
+}
+
+#[test]
+fn keep_alive_is_reported_and_not_acknowledged() {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) = make_session_state_with_subscriptions(
+        Arc::new(RwLock::new(SecureChannel::new_no_certificate_store())),
+        subscription_state.clone(),
+    );
+    let keep_alives = Arc::new(Mutex::new(Vec::new()));
+    {
+        let keep_alives = keep_alives.clone();
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_keep_alive_handler(move |subscription_id| {
+            keep_alives.lock().push(subscription_id)
+        });
+    }
+    let changes = Arc::new(Mutex::new(0));
+    let callback = {
+        let changes = changes.clone();
+        DataChangeCallback::new(move |items| *changes.lock() += items.len())
+    };
+    {
+        let subscription = Subscription::new(
+            1,
+            1000f64,
+            100,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(callback)),
+        );
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+
+    let mut session_state = trace_write_lock!(session_state);
+    session_state.async_publish().unwrap();
+    let request = next_publish_request(&mut rx);
+    publish_keep_alive(&mut session_state, &request);
+    assert_eq!(*keep_alives.lock(), vec![1]);
+    assert_eq!(*changes.lock(), 0);
+
+    // The next publish request has nothing to acknowledge
+    let request = next_publish_request(&mut rx);
+    assert!(request.subscription_acknowledgements.is_none());
+}
+
+#[test]
+fn acknowledgements_are_capped() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let mut session_state = trace_write_lock!(session_state);
+    {
+        let dropped = dropped.clone();
+        session_state.set_ack_overflow_handler(move |ack| dropped.lock().push(ack.sequence_number));
+    }
+    session_state.set_max_subscription_acknowledgements(3);
+    (1..=5).for_each(|sequence_number| {
+        session_state.add_subscription_acknowledgement(SubscriptionAcknowledgement {
+            subscription_id: 1,
+            sequence_number,
+        })
+    });
+    assert_eq!(*dropped.lock(), vec![1, 2]);
+
+    // The newest are acknowledged
+    session_state.async_publish().unwrap();
+    let request = next_publish_request(&mut rx);
+    let sequence_numbers = request
+        .subscription_acknowledgements
+        .unwrap()
+        .iter()
+        .map(|ack| ack.sequence_number)
+        .collect::<Vec<_>>();
+    assert_eq!(sequence_numbers, vec![3, 4, 5]);
+}
+
+fn publish_fault(
+    session_state: &mut SessionState,
+    request: &PublishRequest,
+    service_result: StatusCode,
+) {
+    let response = ServiceFault {
+        response_header: ResponseHeader::new_service_result(
+            &request.request_header,
+            service_result,
+        ),
+    };
+    {
+        let mut message_queue = trace_write_lock!(session_state.message_queue);
+        message_queue.store_response(response.into());
+    }
+    assert!(session_state.handle_publish_responses());
+}
+
+fn publish_keep_alive(session_state: &mut SessionState, request: &PublishRequest) {
+    let response = PublishResponse {
+        response_header: ResponseHeader::new_good(&request.request_header),
+        subscription_id: 1,
+        available_sequence_numbers: None,
+        more_notifications: false,
+        notification_message: NotificationMessage::keep_alive(1, DateTime::now()),
+        results: None,
+        diagnostic_infos: None,
+    };
+    {
+        let mut message_queue = trace_write_lock!(session_state.message_queue);
+        message_queue.store_response(response.into());
+    }
+    assert!(session_state.handle_publish_responses());
+}
+
+fn next_publish_request(rx: &mut UnboundedReceiver<Message>) -> PublishRequest {
+    match next_request(rx) {
+        SupportedMessage::PublishRequest(request) => *request,
+        request => panic!("Expected a publish request, got {:?}", request),
+    }
+}
+
+#[test]
+fn publish_timeout_covers_keep_alive_interval() {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) = make_session_state_with_subscriptions(
+        Arc::new(RwLock::new(SecureChannel::new_no_certificate_store())),
+        subscription_state.clone(),
+    );
+    let mut session_state = trace_write_lock!(session_state);
+    let request_timeout = session_state.request_timeout();
+    assert_eq!(session_state.publish_timeout(), request_timeout);
+
+    // The keep-alive interval of the slowest subscription is 60 seconds
+    {
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        [(1, 1000f64, 10), (2, 2000f64, 30)].iter().for_each(
+            |(subscription_id, publishing_interval, max_keep_alive_count)| {
+                subscription_state.add_subscription(Subscription::new(
+                    *subscription_id,
+                    *publishing_interval,
+                    100,
+                    *max_keep_alive_count,
+                    0,
+                    true,
+                    0,
+                    Arc::new(Mutex::new(DataChangeCallback::new(|_| {}))),
+                ));
+            },
+        );
+    }
+    session_state.async_publish().unwrap();
+    let request = next_publish_request(&mut rx);
+    assert_eq!(request.request_header.timeout_hint, 60000 + request_timeout);
+
+    // Other requests keep the request timeout
+    assert_eq!(
+        session_state.make_request_header().timeout_hint,
+        request_timeout
+    );
+
+    // The timeout can be set explicitly
+    session_state.set_publish_timeout(Some(120000));
+    session_state.async_publish().unwrap();
+    let request = next_publish_request(&mut rx);
+    assert_eq!(request.request_header.timeout_hint, 120000);
+}
+
+#[test]
+fn too_many_publish_requests_backs_off() {
+    let (session_state, mut rx) = make_session_state(Arc::new(RwLock::new(
+        SecureChannel::new_no_certificate_store(),
+    )));
+    let mut session_state = trace_write_lock!(session_state);
+
+    let mut requests = (0..4)
+        .map(|_| {
+            session_state.async_publish().unwrap();
+            next_publish_request(&mut rx)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(session_state.outstanding_publish_requests(), 4);
+
+    // The server rejects the last one, so the client limits itself to the 3 still outstanding
+    // and does not immediately send another
+    let rejected = requests.pop().unwrap();
+    publish_fault(
+        &mut session_state,
+        &rejected,
+        StatusCode::BadTooManyPublishRequests,
+    );
+    assert_eq!(session_state.outstanding_publish_requests(), 3);
+    assert_eq!(session_state.max_outstanding_publish_requests(), 3);
+    assert!(rx.try_recv().is_err());
+
+    // Holding back is not an error to report
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    {
+        let errors = errors.clone();
+        session_state.set_error_handler(move |operation, status_code| {
+            errors.lock().push((operation.to_string(), status_code));
+        });
+    }
+    assert_eq!(
+        session_state.async_publish().unwrap_err(),
+        StatusCode::BadTooManyPublishRequests
+    );
+    assert_eq!(session_state.outstanding_publish_requests(), 3);
+    assert!(errors.lock().is_empty());
+
+    // Each response is replaced by one new request, keeping within the limit, and the limit
+    // recovers by one after several successes
+    let mut request = requests.remove(0);
+    for _ in 0..10 {
+        publish_keep_alive(&mut session_state, &request);
+        assert_eq!(session_state.outstanding_publish_requests(), 3);
+        request = next_publish_request(&mut rx);
+    }
+    assert_eq!(session_state.max_outstanding_publish_requests(), 4);
+    session_state.async_publish().unwrap();
+    assert_eq!(session_state.outstanding_publish_requests(), 4);
+}
+
+#[test]
+fn only_publish_faults_are_counted() {
+    let (session_state, mut rx) = make_session_state(Arc::new(RwLock::new(
+        SecureChannel::new_no_certificate_store(),
+    )));
+    let request = read_request(&session_state);
+    let mut session_state = trace_write_lock!(session_state);
+
+    session_state.async_publish().unwrap();
+    let _ = next_publish_request(&mut rx);
+    session_state.async_send_request(request, None).unwrap();
+    let request = match next_request(&mut rx) {
+        SupportedMessage::ReadRequest(request) => request,
+        request => panic!("Expected a read request, got {:?}", request),
+    };
+    assert_eq!(session_state.outstanding_publish_requests(), 1);
+
+    // A fault for another request leaves the publish request outstanding and sends no more
+    let response = ServiceFault {
+        response_header: ResponseHeader::new_service_result(
+            &request.request_header,
+            StatusCode::BadTimeout,
+        ),
+    };
+    {
+        let mut message_queue = trace_write_lock!(session_state.message_queue);
+        message_queue.store_response(response.into());
+    }
+    assert!(session_state.handle_publish_responses());
+    assert_eq!(session_state.outstanding_publish_requests(), 1);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn publish_future() {
+    let (session_state, mut rx) = make_session_state(Arc::new(RwLock::new(
+        SecureChannel::new_no_certificate_store(),
+    )));
+    let mut session_state = trace_write_lock!(session_state);
+
+    // The future resolves to the response once it has been processed
+    let response = session_state.async_publish_future().unwrap();
+    let request = next_publish_request(&mut rx);
+    publish_keep_alive(&mut session_state, &request);
+    let response = futures::executor::block_on(response).unwrap();
+    assert_eq!(
+        response.response_header.request_handle,
+        request.request_header.request_handle
+    );
+    let _ = next_publish_request(&mut rx);
+
+    // A fault resolves it to the service result
+    let response = session_state.async_publish_future().unwrap();
+    let request = next_publish_request(&mut rx);
+    publish_fault(&mut session_state, &request, StatusCode::BadTimeout);
+    assert_eq!(
+        futures::executor::block_on(response).unwrap_err(),
+        StatusCode::BadTimeout
+    );
+
+    // As does the connection closing
+    let response = session_state.async_publish_future().unwrap();
+    session_state.abort_all_requests(StatusCode::BadConnectionClosed);
+    assert_eq!(
+        futures::executor::block_on(response).unwrap_err(),
+        StatusCode::BadConnectionClosed
+    );
+}
+
+#[test]
+fn request_authorizer_vetoes_requests() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_request_authorizer(|request| match request {
+            SupportedMessage::WriteRequest(_) => Err(StatusCode::BadUserAccessDenied),
+            _ => Ok(()),
+        });
+    }
+
+    let write_request = {
+        let mut session_state = trace_write_lock!(session_state);
+        WriteRequest {
+            request_header: session_state.make_request_header(),
+            nodes_to_write: None,
+        }
+    };
+    let result = trace_write_lock!(session_state).async_send_request(write_request, None);
+    assert_eq!(result.unwrap_err(), StatusCode::BadUserAccessDenied);
+    assert!(rx.try_recv().is_err());
+
+    // Requests the authorizer allows are sent as normal
+    let request = read_request(&session_state);
+    let request_handle = request.request_header.request_handle;
+    let result = trace_write_lock!(session_state).async_send_request(request, None);
+    assert_eq!(result.unwrap(), request_handle);
+    match next_request(&mut rx) {
+        SupportedMessage::ReadRequest(request) => {
+            assert_eq!(request.request_header.request_handle, request_handle)
+        }
+        request => panic!("Expected a read request, got {:?}", request),
+    }
+}
+
+#[test]
+fn read_only_rejects_mutating_requests() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    trace_write_lock!(session_state).set_read_only(true);
+
+    let requests: Vec<SupportedMessage> = {
+        let mut session_state = trace_write_lock!(session_state);
+        vec![
+            WriteRequest {
+                request_header: session_state.make_request_header(),
+                nodes_to_write: None,
+            }
+            .into(),
+            CallRequest {
+                request_header: session_state.make_request_header(),
+                methods_to_call: None,
+            }
+            .into(),
+            HistoryUpdateRequest {
+                request_header: session_state.make_request_header(),
+                history_update_details: None,
+            }
+            .into(),
+            DeleteNodesRequest {
+                request_header: session_state.make_request_header(),
+                nodes_to_delete: None,
+            }
+            .into(),
+        ]
+    };
+    requests.into_iter().for_each(|request| {
+        let result = trace_write_lock!(session_state).async_send_request(request, None);
+        assert_eq!(result.unwrap_err(), StatusCode::BadUserAccessDenied);
+    });
+    assert!(rx.try_recv().is_err());
+
+    // Reads are still allowed
+    let request = read_request(&session_state);
+    assert!(trace_write_lock!(session_state)
+        .async_send_request(request, None)
+        .is_ok());
+    assert!(matches!(
+        next_request(&mut rx),
+        SupportedMessage::ReadRequest(_)
+    ));
+}
+
+#[test]
+fn dry_run_does_not_send() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    trace_write_lock!(session_state).set_dry_run(true);
+
+    // The request is answered with a placeholder of the response it expects instead of being
+    // sent
+    let node_id: NodeId = VariableId::Server_ServerStatus_State.into();
+    let request = ReadRequest {
+        nodes_to_read: Some(vec![node_id.into()]),
+        ..read_request(&session_state)
+    };
+    let request_handle = request.request_header.request_handle;
+    let response = trace_write_lock!(session_state)
+        .send_request(request)
+        .unwrap();
+    match response {
+        SupportedMessage::ReadResponse(response) => {
+            assert_eq!(response.response_header.request_handle, request_handle);
+            assert_eq!(response.response_header.service_result, StatusCode::Good);
+            assert_eq!(response.results.unwrap().len(), 1);
+        }
+        response => panic!("Expected a read response, got {:?}", response),
+    }
+    assert!(rx.try_recv().is_err());
+
+    // A publish request gets no response in a dry run so it isn't counted as outstanding
+    trace_write_lock!(session_state).async_publish().unwrap();
+    assert_eq!(
+        trace_read_lock!(session_state).outstanding_publish_requests(),
+        0
+    );
+    assert!(rx.try_recv().is_err());
+
+    // The usual checks still apply
+    trace_write_lock!(session_state).set_read_only(true);
+    let request = {
+        let mut session_state = trace_write_lock!(session_state);
+        WriteRequest {
+            request_header: session_state.make_request_header(),
+            nodes_to_write: None,
+        }
+    };
+    assert_eq!(
+        trace_write_lock!(session_state)
+            .send_request(request)
+            .unwrap_err()
+            .status_code,
+        StatusCode::BadUserAccessDenied
+    );
+
+    // Requests are sent again once the dry run is over
+    trace_write_lock!(session_state).set_dry_run(false);
+    let request = read_request(&session_state);
+    trace_write_lock!(session_state)
+        .async_send_request(request, None)
+        .unwrap();
+    assert!(matches!(
+        next_request(&mut rx),
+        SupportedMessage::ReadRequest(_)
+    ));
+}
+
+#[test]
+fn request_error_identifies_request() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    trace_write_lock!(session_state).set_request_timeout(10);
+
+    // Nothing answers the request so it times out
+    let request = read_request(&session_state);
+    let request_handle = request.request_header.request_handle;
+    let error = trace_write_lock!(session_state)
+        .send_request(request)
+        .unwrap_err();
+    assert_eq!(
+        error,
+        RequestError {
+            status_code: StatusCode::BadTimeout,
+            request_handle,
+            request_type: "ReadRequest",
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        format!("ReadRequest {} failed, BadTimeout", request_handle)
+    );
+    assert_eq!(StatusCode::from(error), StatusCode::BadTimeout);
+
+    // The request and its timeout are counted
+    let diagnostics = trace_read_lock!(session_state).diagnostics();
+    assert_eq!(diagnostics.requests_sent, 1);
+    assert_eq!(diagnostics.timeouts, 1);
+}
+
+#[test]
+fn flush_waits_for_requests_to_be_written() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    assert!(trace_read_lock!(session_state).flush().is_ok());
+
+    (0..3).for_each(|_| {
+        let request = read_request(&session_state);
+        trace_write_lock!(session_state)
+            .async_send_request(request, None)
+            .unwrap();
+    });
+    let message_queue = trace_read_lock!(session_state).message_queue.clone();
+    assert_eq!(trace_read_lock!(message_queue).unsent_requests(), 3);
+
+    // Stands in for the transport writing each request
+    let writer = thread::spawn(move || {
+        (0..3).for_each(|_| {
+            let request = next_request(&mut rx);
+            thread::sleep(Duration::from_millis(10));
+            trace_write_lock!(message_queue).request_was_processed(request.request_handle());
+        });
+        rx
+    });
+    assert!(trace_read_lock!(session_state).flush().is_ok());
+    let mut rx = writer.join().unwrap();
+    assert!(rx.try_recv().is_err());
+
+    // The requests must be written within the request timeout
+    trace_write_lock!(session_state).set_request_timeout(20);
+    let request = read_request(&session_state);
+    trace_write_lock!(session_state)
+        .async_send_request(request, None)
+        .unwrap();
+    assert_eq!(
+        trace_read_lock!(session_state).flush().unwrap_err(),
+        StatusCode::BadTimeout
+    );
+}
+
+#[test]
+fn rate_limit_rejects_requests() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_max_requests_per_second(Some(2.0));
+        session_state.set_rate_limit_policy(RateLimitPolicy::Reject);
+    }
+
+    (0..2).for_each(|_| {
+        let request = read_request(&session_state);
+        trace_write_lock!(session_state)
+            .async_send_request(request, None)
+            .unwrap();
+        assert!(matches!(
+            next_request(&mut rx),
+            SupportedMessage::ReadRequest(_)
+        ));
+    });
+    let request = read_request(&session_state);
+    assert_eq!(
+        trace_write_lock!(session_state)
+            .async_send_request(request, None)
+            .unwrap_err(),
+        StatusCode::BadResourceUnavailable
+    );
+    assert!(rx.try_recv().is_err());
+
+    // Publish requests are exempt
+    trace_write_lock!(session_state).async_publish().unwrap();
+    let _ = next_publish_request(&mut rx);
+
+    // So are keep-alives
+    respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.keep_alive(),
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            let server_state: NodeId = VariableId::Server_ServerStatus_State.into();
+            let nodes_to_read = request.nodes_to_read.as_ref().unwrap();
+            assert_eq!(nodes_to_read.len(), 1);
+            assert_eq!(nodes_to_read[0].node_id, server_state);
+            ReadResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                results: Some(vec![DataValue::new_now(0i32)]),
+                diagnostic_infos: None,
+            }
+            .into()
+        },
+    )
+    .unwrap();
+
+    // Requests wait for the limit by default
+    trace_write_lock!(session_state).set_rate_limit_policy(RateLimitPolicy::Wait);
+    let request = read_request(&session_state);
+    trace_write_lock!(session_state)
+        .async_send_request(request, None)
+        .unwrap();
+    assert!(matches!(
+        next_request(&mut rx),
+        SupportedMessage::ReadRequest(_)
+    ));
+}
+
+#[test]
+fn alternate_endpoint_urls() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+    let primary = UAString::from("opc.tcp://primary:4840");
+    let backup1 = UAString::from("opc.tcp://backup1:4840");
+    let backup2 = UAString::from("opc.tcp://backup2:4840");
+    assert_eq!(session_state.endpoint_urls(&primary), vec![primary.clone()]);
+
+    // Invalid urls are rejected
+    assert_eq!(
+        session_state
+            .set_alternate_endpoint_urls(vec![backup1.clone(), UAString::from("http://backup2")])
+            .unwrap_err(),
+        StatusCode::BadTcpEndpointUrlInvalid
+    );
+    assert!(session_state.alternate_endpoint_urls().is_empty());
+
+    session_state
+        .set_alternate_endpoint_urls(vec![backup1.clone(), backup2.clone()])
+        .unwrap();
+    assert_eq!(
+        session_state.endpoint_urls(&primary),
+        vec![primary.clone(), backup1.clone(), backup2.clone()]
+    );
+
+    // The url that last worked is tried first
+    session_state.set_last_endpoint_url(backup2.clone());
+    assert_eq!(
+        session_state.endpoint_urls(&primary),
+        vec![backup2, primary, backup1]
+    );
+}
+
+#[test]
+fn max_chunk_count_from_decoding_options() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel.clone());
+    assert_eq!(
+        trace_read_lock!(session_state).max_chunk_count(),
+        constants::MAX_CHUNK_COUNT
+    );
+
+    trace_write_lock!(secure_channel).set_decoding_options(DecodingOptions {
+        max_chunk_count: 0,
+        ..Default::default()
+    });
+    let (session_state, _rx) = make_session_state(secure_channel);
+    assert_eq!(trace_read_lock!(session_state).max_chunk_count(), 0);
+}
+
+#[test]
+fn error_handler_observes_errors() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    secure_channel
+        .write()
+        .set_security_token(security_token(6, 60000));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    {
+        let errors = errors.clone();
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_read_only(true);
+        session_state.set_error_handler(move |operation, status_code| {
+            errors.lock().push((operation.to_string(), status_code));
+        });
+    }
+
+    // The error is still returned to the caller
+    let write_request = {
+        let mut session_state = trace_write_lock!(session_state);
+        WriteRequest {
+            request_header: session_state.make_request_header(),
+            nodes_to_write: None,
+        }
+    };
+    let result = trace_write_lock!(session_state).async_send_request(write_request, None);
+    assert_eq!(result.unwrap_err(), StatusCode::BadUserAccessDenied);
+    assert_eq!(
+        *errors.lock(),
+        vec![(
+            "async_send_request".to_string(),
+            StatusCode::BadUserAccessDenied
+        )]
+    );
+
+    // Success is not reported
+    let request = read_request(&session_state);
+    assert!(trace_write_lock!(session_state)
+        .async_send_request(request, None)
+        .is_ok());
+    let _ = next_request(&mut rx);
+    assert_eq!(errors.lock().len(), 1);
+
+    // A failed operation is reported once, not as well for the requests it sends
+    errors.lock().clear();
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.restore(
+                NodeId::new(1, 100),
+                NodeId::new(0, ByteString::from(b"token")),
+                ChannelSecurityToken {
+                    created_at: DateTime::now() - chrono::Duration::seconds(10),
+                    ..security_token(7, 60000)
+                },
+            )
+        },
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            ServiceFault::new(&request.request_header, StatusCode::BadSessionIdInvalid).into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadSessionIdInvalid);
+    assert_eq!(
+        *errors.lock(),
+        vec![("restore".to_string(), StatusCode::BadSessionIdInvalid)]
+    );
+}
+
+#[test]
+fn restore_session() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel.clone());
+
+    trace_write_lock!(secure_channel).set_security_token(security_token(6, 60000));
+
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.restore(
+                NodeId::new(1, 100),
+                NodeId::new(0, ByteString::from(b"token")),
+                ChannelSecurityToken {
+                    created_at: DateTime::now() - chrono::Duration::seconds(10),
+                    ..security_token(7, 60000)
+                },
+            )
+        },
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            assert_eq!(
+                request.request_header.authentication_token,
+                NodeId::new(0, ByteString::from(b"token"))
+            );
+            ReadResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                results: Some(vec![DataValue::value_only(0i32)]),
+                diagnostic_infos: None,
+            }
+            .into()
+        },
+    );
+    assert!(result.is_ok());
+    assert_eq!(trace_read_lock!(secure_channel).token_id(), 7);
+    // The lifetime of the token runs from when it was issued
+    assert!(
+        trace_read_lock!(secure_channel).token_created_at()
+            < DateTime::now() - chrono::Duration::seconds(5)
+    );
+    assert_eq!(
+        trace_read_lock!(session_state).session_id(),
+        NodeId::new(1, 100)
+    );
+
+    // The server no longer knows the session
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.restore(
+                NodeId::new(1, 100),
+                NodeId::new(0, ByteString::from(b"token")),
+                ChannelSecurityToken {
+                    created_at: DateTime::now() - chrono::Duration::seconds(10),
+                    ..security_token(7, 60000)
+                },
+            )
+        },
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            ServiceFault {
+                response_header: ResponseHeader::new_service_result(
+                    &request.request_header,
+                    StatusCode::BadSessionClosed,
+                ),
+            }
+            .into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadSessionIdInvalid);
+    assert!(trace_read_lock!(session_state).session_id().is_null());
+
+    // Or says so in the header of a read response
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.restore(
+                NodeId::new(1, 100),
+                NodeId::new(0, ByteString::from(b"token")),
+                ChannelSecurityToken {
+                    created_at: DateTime::now() - chrono::Duration::seconds(10),
+                    ..security_token(7, 60000)
+                },
+            )
+        },
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            ReadResponse {
+                response_header: ResponseHeader::new_service_result(
+                    &request.request_header,
+                    StatusCode::BadSessionIdInvalid,
+                ),
+                results: None,
+                diagnostic_infos: None,
+            }
+            .into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadSessionIdInvalid);
+    assert!(trace_read_lock!(session_state).session_id().is_null());
+}
+
+#[test]
+fn restore_session_on_another_channel() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel.clone());
+    trace_write_lock!(secure_channel).set_security_token(ChannelSecurityToken {
+        channel_id: 2,
+        ..security_token(6, 60000)
+    });
+
+    // The token was issued by channel 1, so nothing is sent and nothing changes
+    let result = trace_write_lock!(session_state).restore(
+        NodeId::new(1, 100),
+        NodeId::new(0, ByteString::from(b"token")),
+        security_token(7, 60000),
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadSecureChannelIdInvalid);
+    assert_eq!(trace_read_lock!(secure_channel).secure_channel_id(), 2);
+    assert_eq!(trace_read_lock!(secure_channel).token_id(), 6);
+    assert!(trace_read_lock!(session_state).session_id().is_null());
+}
+
+#[test]
+fn connect_timeout_is_separate_from_request_timeout() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    {
+        // Both default to the same timeout
+        let session_state = trace_read_lock!(session_state);
+        assert_eq!(
+            session_state.connect_timeout(),
+            session_state.request_timeout()
+        );
+    }
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_connect_timeout(20);
+        session_state.set_request_timeout(60000);
+        assert_eq!(session_state.make_connect_request_header().timeout_hint, 20);
+        assert_eq!(session_state.make_request_header().timeout_hint, 60000);
+    }
+
+    // Nothing answers so the request times out after the connect timeout
+    let request = read_request(&session_state);
+    let start = Instant::now();
+    let error = trace_write_lock!(session_state)
+        .send_connect_request(request)
+        .unwrap_err();
+    assert_eq!(error.status_code, StatusCode::BadTimeout);
+    assert!(start.elapsed() < Duration::from_millis(60000));
+}
+
+#[test]
+fn secure_channel_retry_backoff() {
+    let policy = SecureChannelRetryPolicy {
+        max_retries: 40,
+        backoff_ms: 100,
+        ..Default::default()
+    };
+    assert_eq!(policy.backoff(1), 100);
+    assert_eq!(policy.backoff(2), 200);
+    assert_eq!(policy.backoff(3), 400);
+    assert_eq!(policy.backoff(40), u32::MAX);
+}
+
+#[test]
+fn issue_secure_channel_is_retried() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_connect_timeout(10);
+        session_state.set_request_timeout(10);
+        session_state.set_secure_channel_retry_policy(SecureChannelRetryPolicy {
+            max_retries: 2,
+            backoff_ms: 1,
+            ..Default::default()
+        });
+    }
+    let open_secure_channel_requests = |rx: &mut UnboundedReceiver<Message>| {
+        let mut request_types = Vec::new();
+        while let Ok(Message::SupportedMessage(SupportedMessage::OpenSecureChannelRequest(
+            request,
+        ))) = rx.try_recv()
+        {
+            request_types.push(request.request_type);
+        }
+        request_types
+    };
+
+    // Nothing answers so issuing times out on the first try and both retries
+    let result = trace_write_lock!(session_state)
+        .issue_or_renew_secure_channel(SecurityTokenRequestType::Issue);
+    assert_eq!(result.unwrap_err(), StatusCode::BadTimeout);
+    assert_eq!(
+        open_secure_channel_requests(&mut rx),
+        vec![SecurityTokenRequestType::Issue; 3]
+    );
+
+    // Renewing is not retried
+    let result = trace_write_lock!(session_state)
+        .issue_or_renew_secure_channel(SecurityTokenRequestType::Renew);
+    assert_eq!(result.unwrap_err(), StatusCode::BadTimeout);
+    assert_eq!(
+        open_secure_channel_requests(&mut rx),
+        vec![SecurityTokenRequestType::Renew]
+    );
+}
+
+#[test]
+fn requested_secure_channel_lifetime() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel.clone());
+    trace_write_lock!(session_state).set_requested_secure_channel_lifetime(300000);
+
+    respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.issue_or_renew_secure_channel(SecurityTokenRequestType::Issue)
+        },
+        |request| {
+            let request = supported_message_as!(request, OpenSecureChannelRequest);
+            assert_eq!(request.requested_lifetime, 300000);
+
+            // The server grants less than was asked for and that is what the token lasts for
+            OpenSecureChannelResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                server_protocol_version: 0,
+                security_token: security_token(1, 30000),
+                server_nonce: ByteString::null(),
+            }
+            .into()
+        },
+    )
+    .unwrap();
+    assert_eq!(trace_read_lock!(secure_channel).token_lifetime(), 30000);
+}
+
+#[test]
+fn send_request_with_timeout() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    trace_write_lock!(session_state).set_request_timeout(60000);
+
+    // Nothing answers so the request times out after its own timeout
+    let request = read_request(&session_state);
+    let request_handle = request.request_header.request_handle;
+    let start = Instant::now();
+    let error = trace_write_lock!(session_state)
+        .send_request_with_timeout(request, 10)
+        .unwrap_err();
+    assert!(start.elapsed() < Duration::from_millis(60000));
+    assert_eq!(error.status_code, StatusCode::BadTimeout);
+    assert_eq!(error.request_handle, request_handle);
+    assert_eq!(trace_read_lock!(session_state).diagnostics().timeouts, 1);
+
+    // The server is told the timeout
+    match next_request(&mut rx) {
+        SupportedMessage::ReadRequest(request) => {
+            assert_eq!(request.request_header.timeout_hint, 10)
+        }
+        request => panic!("Expected a read request, got {:?}", request),
+    }
+
+    // A response has no timeout hint to set so it isn't sent
+    let response = ServiceFault::new(&RequestHeader::dummy(), StatusCode::Good);
+    let error = trace_write_lock!(session_state)
+        .send_request_with_timeout(response, 10)
+        .unwrap_err();
+    assert_eq!(error.status_code, StatusCode::BadRequestTypeInvalid);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn timed_out_reads_are_retried() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_request_timeout(10);
+        session_state.set_retry_policy(RetryPolicy {
+            max_retries: 2,
+            backoff_ms: 1,
+        });
+    }
+
+    // Nothing answers so the read is sent three times, each with a new handle
+    let request = read_request(&session_state);
+    let error = trace_write_lock!(session_state)
+        .send_request(request)
+        .unwrap_err();
+    assert_eq!(error.status_code, StatusCode::BadTimeout);
+    let request_handles = (0..3)
+        .map(|_| match next_request(&mut rx) {
+            SupportedMessage::ReadRequest(request) => request.request_header.request_handle,
+            request => panic!("Expected a read request, got {:?}", request),
+        })
+        .collect::<Vec<_>>();
+    assert!(rx.try_recv().is_err());
+    assert_ne!(request_handles[0], request_handles[1]);
+    assert_ne!(request_handles[1], request_handles[2]);
+    // The error is for the last try
+    assert_eq!(error.request_handle, request_handles[2]);
+
+    // A request that changes the server is not retried
+    let request = WriteRequest {
+        request_header: trace_write_lock!(session_state).make_request_header(),
+        nodes_to_write: None,
+    };
+    let error = trace_write_lock!(session_state)
+        .send_request(request)
+        .unwrap_err();
+    assert_eq!(error.status_code, StatusCode::BadTimeout);
+    assert!(matches!(
+        next_request(&mut rx),
+        SupportedMessage::WriteRequest(_)
+    ));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn republish() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+
+    let notification_message = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.republish(1, 5),
+        |request| {
+            let request = supported_message_as!(request, RepublishRequest);
+            assert_eq!(request.subscription_id, 1);
+            assert_eq!(request.retransmit_sequence_number, 5);
+            RepublishResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                notification_message: NotificationMessage {
+                    sequence_number: 5,
+                    publish_time: DateTime::now(),
+                    notification_data: None,
+                },
+            }
+            .into()
+        },
+    )
+    .unwrap();
+    assert_eq!(notification_message.sequence_number, 5);
+
+    // A message the server no longer has is reported as such
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.republish(1, 5),
+        |request| {
+            let request = supported_message_as!(request, RepublishRequest);
+            ServiceFault::new(&request.request_header, StatusCode::BadMessageNotAvailable).into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadMessageNotAvailable);
+}
+
+#[test]
+fn session_state_config() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+    assert_eq!(session_state.config(), SessionStateConfig::default());
+
+    let config = SessionStateConfig {
+        send_buffer_size: 65535,
+        receive_buffer_size: 1024 * 1024,
+        max_message_size: 16 * 1024 * 1024,
+        request_timeout: 30000,
+    };
+    session_state.set_config(config).unwrap();
+    assert_eq!(session_state.config(), config);
+    assert_eq!(session_state.receive_buffer_size(), 1024 * 1024);
+    assert_eq!(session_state.request_timeout(), 30000);
+
+    // Invalid configurations are rejected and change nothing
+    let invalid = [
+        SessionStateConfig {
+            receive_buffer_size: 0,
+            ..config
+        },
+        SessionStateConfig {
+            request_timeout: 0,
+            ..config
+        },
+        SessionStateConfig {
+            max_message_size: 1024,
+            ..config
+        },
+    ];
+    invalid.iter().for_each(|invalid| {
+        assert_eq!(
+            session_state.set_config(*invalid).unwrap_err(),
+            StatusCode::BadConfigurationError
+        );
+    });
+    assert_eq!(session_state.config(), config);
+}
+
+#[test]
+fn return_diagnostics() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+    assert!(session_state
+        .make_request_header()
+        .return_diagnostics
+        .is_empty());
+
+    let return_diagnostics = DiagnosticBits::SERVICE_LEVEL_SYMBOLIC_ID
+        | DiagnosticBits::OPERATIONAL_LEVEL_INNER_STATUS_CODE;
+    session_state.set_return_diagnostics(return_diagnostics);
+    assert_eq!(
+        session_state.make_request_header().return_diagnostics,
+        return_diagnostics
+    );
+    assert_eq!(
+        session_state
+            .make_connect_request_header()
+            .return_diagnostics,
+        return_diagnostics
+    );
+}
+
+#[test]
+fn partial_results_policy() {
+    let results = vec![StatusCode::Good, StatusCode::BadNotWritable];
+
+    // The right number of results is fine either way
+    [PartialResultsPolicy::Strict, PartialResultsPolicy::Lenient]
+        .iter()
+        .for_each(|policy| {
+            assert_eq!(
+                policy
+                    .apply("write()", 2, results.clone(), StatusCode::BadInternalError)
+                    .unwrap(),
+                results
+            );
+        });
+
+    let policy = PartialResultsPolicy::Strict;
+    assert_eq!(
+        policy
+            .apply("write()", 3, results.clone(), StatusCode::BadInternalError)
+            .unwrap_err(),
+        StatusCode::BadUnexpectedError
+    );
+    assert_eq!(
+        policy
+            .apply("write()", 1, results.clone(), StatusCode::BadInternalError)
+            .unwrap_err(),
+        StatusCode::BadUnexpectedError
+    );
+
+    let policy = PartialResultsPolicy::Lenient;
+    assert_eq!(
+        policy
+            .apply("write()", 3, results.clone(), StatusCode::BadInternalError)
+            .unwrap(),
+        vec![
+            StatusCode::Good,
+            StatusCode::BadNotWritable,
+            StatusCode::BadInternalError
+        ]
+    );
+    assert_eq!(
+        policy
+            .apply("write()", 1, results, StatusCode::BadInternalError)
+            .unwrap(),
+        vec![StatusCode::Good]
+    );
+}
+
+#[test]
+fn audit_entry_id() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+    assert!(session_state.make_request_header().audit_entry_id.is_null());
+
+    session_state.set_audit_entry_id(Some("operator-1".to_string()));
+    assert_eq!(
+        session_state.make_request_header().audit_entry_id,
+        UAString::from("operator-1")
+    );
+
+    // A one off audit entry id does not change the session's
+    let request_header = session_state.make_request_header_with_audit("batch-42");
+    assert_eq!(request_header.audit_entry_id, UAString::from("batch-42"));
+    assert_eq!(
+        session_state.make_request_header().audit_entry_id,
+        UAString::from("operator-1")
+    );
+
+    session_state.set_audit_entry_id(None);
+    assert!(session_state.make_request_header().audit_entry_id.is_null());
+}
+
+#[test]
+fn transfer_subscriptions() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+
+    let results = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.transfer_subscriptions(&[1, 2], true),
+        |request| {
+            let request = supported_message_as!(request, TransferSubscriptionsRequest);
+            assert_eq!(request.subscription_ids, Some(vec![1, 2]));
+            assert!(request.send_initial_values);
+            let result = |status_code| TransferResult {
+                status_code,
+                available_sequence_numbers: None,
+            };
+            TransferSubscriptionsResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                results: Some(vec![
+                    result(StatusCode::Good),
+                    result(StatusCode::BadSubscriptionIdInvalid),
+                ]),
+                diagnostic_infos: None,
+            }
+            .into()
+        },
+    )
+    .unwrap();
+
+    // The caller is told which transfer failed
+    let status_codes = results.iter().map(|r| r.status_code).collect::<Vec<_>>();
+    assert_eq!(
+        status_codes,
+        vec![StatusCode::Good, StatusCode::BadSubscriptionIdInvalid]
+    );
+
+    assert_eq!(
+        trace_write_lock!(session_state)
+            .transfer_subscriptions(&[], true)
+            .unwrap_err(),
+        StatusCode::BadNothingToDo
+    );
+}
+
+#[test]
+fn keep_alive() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+
+    // Keep-alives are off by default
+    assert_eq!(trace_read_lock!(session_state).keep_alive_interval(), 0);
+    assert!(!trace_read_lock!(session_state).keep_alive_due());
+
+    // Nothing has been received yet so a keep-alive is due straight away
+    trace_write_lock!(session_state).set_keep_alive_interval(200);
+    assert!(trace_read_lock!(session_state).keep_alive_due());
+
+    // A failed keep-alive flags the connection as lost
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.keep_alive(),
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            ServiceFault::new(&request.request_header, StatusCode::BadServerHalted).into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadServerHalted);
+    assert!(trace_read_lock!(session_state).is_connection_lost());
+
+    // The response shows the connection is alive so another keep-alive isn't due until the
+    // interval has elapsed again
+    assert!(!trace_read_lock!(session_state).keep_alive_due());
+    thread::sleep(Duration::from_millis(250));
+    assert!(trace_read_lock!(session_state).keep_alive_due());
+
+    // A successful keep-alive clears the flag
+    respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.keep_alive(),
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            let server_state: NodeId = VariableId::Server_ServerStatus_State.into();
+            let nodes_to_read = request.nodes_to_read.as_ref().unwrap();
+            assert_eq!(nodes_to_read.len(), 1);
+            assert_eq!(nodes_to_read[0].node_id, server_state);
+            ReadResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                results: Some(vec![DataValue::new_now(0i32)]),
+                diagnostic_infos: None,
+            }
+            .into()
+        },
+    )
+    .unwrap();
+    assert!(!trace_read_lock!(session_state).is_connection_lost());
+
+    // A new connection clears the flag too
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.keep_alive(),
+        |request| {
+            let request = supported_message_as!(request, ReadRequest);
+            ServiceFault::new(&request.request_header, StatusCode::BadServerHalted).into()
+        },
+    );
+    assert!(result.is_err());
+    trace_write_lock!(session_state).reset_connection();
+    assert!(!trace_read_lock!(session_state).is_connection_lost());
+}
+
+#[test]
+fn reissue_channel_on_token_unknown() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel.clone());
+    assert!(!trace_read_lock!(session_state).reissue_channel_on_token_unknown());
+    trace_write_lock!(session_state).set_reissue_channel_on_token_unknown(true);
+    let message_queue = trace_read_lock!(session_state).message_queue.clone();
+    let store_response = |response: SupportedMessage| {
+        let mut message_queue = trace_write_lock!(message_queue);
+        message_queue.store_response(response);
+    };
+
+    let request = read_request(&session_state);
+    let session_thread = {
+        let session_state = session_state.clone();
+        thread::spawn(move || {
+            let mut session_state = trace_write_lock!(session_state);
+            session_state.send_request(request)
+        })
+    };
+
+    // The server doesn't know the token the request was sent with
+    let request = match next_request(&mut rx) {
+        SupportedMessage::ReadRequest(request) => request,
+        request => panic!("Expected a read request, got {:?}", request),
+    };
+    let first_request_handle = request.request_header.request_handle;
+    store_response(
+        ServiceFault::new(
+            &request.request_header,
+            StatusCode::BadSecureChannelTokenUnknown,
+        )
+        .into(),
+    );
+
+    // So a new channel is issued
+    let request = match next_request(&mut rx) {
+        SupportedMessage::OpenSecureChannelRequest(request) => request,
+        request => panic!("Expected an open secure channel request, got {:?}", request),
+    };
+    assert_eq!(request.request_type, SecurityTokenRequestType::Issue);
+    store_response(
+        OpenSecureChannelResponse {
+            response_header: ResponseHeader::new_good(&request.request_header),
+            server_protocol_version: 0,
+            security_token: security_token(2, 60000),
+            server_nonce: ByteString::null(),
+        }
+        .into(),
+    );
+
+    // And the request is sent again as a new request
+    let request = match next_request(&mut rx) {
+        SupportedMessage::ReadRequest(request) => request,
+        request => panic!("Expected a read request, got {:?}", request),
+    };
+    assert_ne!(request.request_header.request_handle, first_request_handle);
+    store_response(
+        ReadResponse {
+            response_header: ResponseHeader::new_good(&request.request_header),
+            results: None,
+            diagnostic_infos: None,
+        }
+        .into(),
+    );
+
+    let response = session_thread.join().unwrap().unwrap();
+    assert!(matches!(response, SupportedMessage::ReadResponse(_)));
+    assert_eq!(trace_read_lock!(secure_channel).token_id(), 2);
+}
+
+#[test]
+fn reconnect_backoff() {
+    let policy = ReconnectPolicy {
+        max_attempts: 4,
+        backoff_ms: 100,
+    };
+    // The first attempt is straight away and each one after waits twice as long
+    assert_eq!(policy.backoff(2), 100);
+    assert_eq!(policy.backoff(3), 200);
+    assert_eq!(policy.backoff(4), 400);
+}
+
+#[test]
+fn reset_keeps_handles() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+    session_state.set_session_id(NodeId::new(1, 100));
+    session_state.set_authentication_token(NodeId::new(1, 200));
+    let monitored_item_handle = session_state.next_monitored_item_handles(1)[0];
+    let request_handle = session_state.make_request_header().request_handle;
+
+    // Reconnecting with a new session replaces the ids
+    session_state.reset();
+    assert!(session_state.session_id().is_null());
+
+    // But the handles carry on, so recreated monitored items don't reuse a client handle
+    assert!(session_state.next_monitored_item_handles(1)[0] > monitored_item_handle);
+    assert!(session_state.make_request_header().request_handle > request_handle);
+}
+
+#[test]
+fn tracks_subscriptions() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let subscription = |id| {
+        Subscription::new(
+            id,
+            500f64,
+            30,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(DataChangeCallback::new(|_| {}))),
+        )
+    };
+    let mut session_state = trace_write_lock!(session_state);
+    assert!(session_state.subscription_ids().is_empty());
+
+    let mut second = subscription(2);
+    let items = [(21, 1001), (20, 1000)]
+        .iter()
+        .map(|(id, client_handle)| CreateMonitoredItem {
+            id: *id,
+            client_handle: *client_handle,
+            item_to_monitor: NodeId::new(2, *client_handle).into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            queue_size: 1,
+            discard_oldest: true,
+            sampling_interval: 100f64,
+        })
+        .collect::<Vec<_>>();
+    second.insert_monitored_items(&items);
+    session_state.add_subscription(second);
+    session_state.add_subscription(subscription(1));
+    assert_eq!(session_state.subscription_ids(), vec![1, 2]);
+
+    let subscriptions = session_state.subscriptions();
+    assert_eq!(subscriptions.len(), 2);
+    assert_eq!(subscriptions[1].subscription_id, 2);
+    assert_eq!(subscriptions[1].revised_publishing_interval, 500f64);
+    assert_eq!(subscriptions[1].revised_max_keep_alive_count, 10);
+    assert_eq!(subscriptions[1].revised_lifetime_count, 30);
+    assert_eq!(subscriptions[1].monitored_item_handles, vec![1000, 1001]);
+    assert!(subscriptions[0].monitored_item_handles.is_empty());
+
+    // Removing a subscription returns it, and an unknown id is ignored
+    assert!(session_state.remove_subscription(1).is_some());
+    assert!(session_state.remove_subscription(3).is_none());
+    assert_eq!(session_state.subscription_ids(), vec![2]);
+}
+
+#[test]
+fn pre_activation_publish_responses() {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) = make_session_state_with_subscriptions(
+        Arc::new(RwLock::new(SecureChannel::new_no_certificate_store())),
+        subscription_state.clone(),
+    );
+    let changes = Arc::new(Mutex::new(0));
+    let callback = {
+        let changes = changes.clone();
+        DataChangeCallback::new(move |items| *changes.lock() += items.len())
+    };
+    {
+        let mut subscription = Subscription::new(
+            1,
+            1000f64,
+            100,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(callback)),
+        );
+        subscription.insert_monitored_items(&[CreateMonitoredItem {
+            id: 10,
+            client_handle: 1000,
+            item_to_monitor: NodeId::new(2, 1000).into(),
+            monitoring_mode: MonitoringMode::Reporting,
+            queue_size: 1,
+            discard_oldest: true,
+            sampling_interval: 100f64,
+        }]);
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+    trace_write_lock!(session_state).set_activated(false);
+
+    // By default data received before activation is dropped, and its publish request no longer
+    // counts as outstanding
+    publish_data_change(&session_state, &mut rx, 1, 1, &[(1000, 1)]);
+    assert_eq!(*changes.lock(), 0);
+    assert_eq!(
+        trace_read_lock!(session_state).outstanding_publish_requests(),
+        0
+    );
+    trace_write_lock!(session_state).set_activated(true);
+    assert_eq!(*changes.lock(), 0);
+
+    // Or it is held until the session is activated
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_activated(false);
+        session_state.set_pre_activation_policy(PreActivationPolicy::Buffer);
+    }
+    publish_data_change(&session_state, &mut rx, 1, 2, &[(1000, 2)]);
+    assert_eq!(*changes.lock(), 0);
+    trace_write_lock!(session_state).set_activated(true);
+    assert_eq!(*changes.lock(), 1);
+
+    // What is held is forgotten if the connection is reset before the session is activated
+    trace_write_lock!(session_state).set_activated(false);
+    publish_data_change(&session_state, &mut rx, 1, 3, &[(1000, 3)]);
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.reset_connection();
+        session_state.set_activated(true);
+    }
+    assert_eq!(*changes.lock(), 1);
+}
+
+#[test]
+fn short_server_nonce_is_rejected() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    {
+        let mut secure_channel = trace_write_lock!(secure_channel);
+        secure_channel.set_security_mode(MessageSecurityMode::SignAndEncrypt);
+        secure_channel.set_security_policy(SecurityPolicy::Basic256Sha256);
+        secure_channel.set_security_token(security_token(1, 60000));
+    }
+    let (session_state, mut rx) = make_session_state(secure_channel.clone());
+
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.issue_or_renew_secure_channel(SecurityTokenRequestType::Renew)
+        },
+        |request| {
+            let request = supported_message_as!(request, OpenSecureChannelRequest);
+            assert_eq!(request.client_nonce.as_ref().len(), 32);
+
+            // The server answers with a nonce too short for the policy
+            OpenSecureChannelResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                server_protocol_version: 0,
+                security_token: security_token(2, 60000),
+                server_nonce: ByteString::from(b"0123456789012345"),
+            }
+            .into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadNonceInvalid);
+
+    // And the new token is not used
+    assert_eq!(trace_read_lock!(secure_channel).token_id(), 1);
+}
+
+#[test]
+fn expected_session_id() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+
+    // A fresh session adopts whatever id the server returns
+    assert!(session_state
+        .check_created_session_id(&NodeId::new(1, 100))
+        .is_ok());
+
+    // Resuming a session expects the server to return the same id
+    session_state.set_expected_session_id(Some(NodeId::new(1, 100)));
+    assert!(session_state
+        .check_created_session_id(&NodeId::new(1, 100))
+        .is_ok());
+    assert_eq!(
+        session_state
+            .check_created_session_id(&NodeId::new(1, 101))
+            .unwrap_err(),
+        StatusCode::BadSessionIdInvalid
+    );
+}
+
+#[test]
+fn notification_sender() {
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, mut rx) = make_session_state_with_subscriptions(
+        Arc::new(RwLock::new(SecureChannel::new_no_certificate_store())),
+        subscription_state.clone(),
+    );
+    {
+        let mut subscription = Subscription::new(
+            1,
+            1000f64,
+            100,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(DataChangeCallback::new(|_| {}))),
+        );
+        subscription.insert_monitored_items(&[1000u32, 1001u32].map(|client_handle| {
+            CreateMonitoredItem {
+                id: client_handle - 990,
+                client_handle,
+                item_to_monitor: NodeId::new(2, client_handle).into(),
+                monitoring_mode: MonitoringMode::Reporting,
+                queue_size: 1,
+                discard_oldest: true,
+                sampling_interval: 100f64,
+            }
+        }));
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.add_subscription(subscription);
+    }
+    let (sender, receiver) = std::sync::mpsc::sync_channel(2);
+    trace_write_lock!(session_state).set_notification_sender(Some(sender));
+
+    // Notifications are sent to the channel until it is full, then dropped
+    publish_data_change(&session_state, &mut rx, 1, 1, &[(1000, 1)]);
+    publish_data_change(&session_state, &mut rx, 1, 2, &[(1001, 2), (1000, 3)]);
+    let received = receiver.try_iter().collect::<Vec<_>>();
+    assert_eq!(
+        received,
+        vec![
+            (1000, DataValue::value_only(1)),
+            (1001, DataValue::value_only(2))
+        ]
+    );
+
+    // Notifications carry on after the receiver has gone, without the channel
+    drop(receiver);
+    publish_data_change(&session_state, &mut rx, 1, 3, &[(1000, 4)]);
+    publish_data_change(&session_state, &mut rx, 1, 4, &[(1000, 5)]);
+}
+
+#[test]
+fn timeout_hint() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+
+    // By default the server is given the request timeout
+    session_state.set_request_timeout(5000);
+    assert_eq!(session_state.timeout_hint(), 5000);
+    assert_eq!(session_state.make_request_header().timeout_hint, 5000);
+
+    // Or a shorter hint, leaving the client's wait for the response alone
+    session_state.set_timeout_hint(Some(4000));
+    assert_eq!(session_state.make_request_header().timeout_hint, 4000);
+    assert_eq!(session_state.request_timeout(), 5000);
+    session_state.set_timeout_hint(None);
+    assert_eq!(session_state.make_request_header().timeout_hint, 5000);
+}
+
+#[test]
+fn close_session_closes_channel_after_failure() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    {
+        let mut session_state = trace_write_lock!(session_state);
+        session_state.set_session_id(NodeId::new(1, 100));
+        session_state.set_authentication_token(NodeId::new(1, 200));
+    }
+
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| session_state.close_session(true),
+        |request| {
+            let request = supported_message_as!(request, CloseSessionRequest);
+            assert!(request.delete_subscriptions);
+
+            // The server fails to close the session
+            ServiceFault {
+                response_header: ResponseHeader::new_service_result(
+                    &request.request_header,
+                    StatusCode::BadSessionIdInvalid,
+                ),
+            }
+            .into()
+        },
+    );
+    assert_eq!(result.unwrap_err(), StatusCode::BadSessionIdInvalid);
+
+    // But the secure channel is still closed and the session is forgotten
+    match next_request(&mut rx) {
+        SupportedMessage::CloseSecureChannelRequest(_) => {}
+        request => panic!("Expected a close secure channel request, got {:?}", request),
+    }
+    let mut session_state = trace_write_lock!(session_state);
+    assert!(session_state.session_id().is_null());
+    assert!(session_state
+        .make_request_header()
+        .authentication_token
+        .is_null());
+}
+
+#[test]
+fn max_write_array_length() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, mut rx) = make_session_state(secure_channel);
+    let mut session_state = trace_write_lock!(session_state);
+    session_state.set_max_write_array_length(Some(3));
+    let mut write_request = |values: Vec<i32>| {
+        let request = WriteRequest {
+            request_header: session_state.make_request_header(),
+            nodes_to_write: Some(vec![
+                WriteValue {
+                    node_id: NodeId::new(2, 1),
+                    attribute_id: AttributeId::Value as u32,
+                    index_range: UAString::null(),
+                    value: Variant::Int32(1).into(),
+                },
+                WriteValue {
+                    node_id: NodeId::new(2, 2),
+                    attribute_id: AttributeId::Value as u32,
+                    index_range: UAString::null(),
+                    value: Variant::from(values).into(),
+                },
+            ]),
+        };
+        session_state.async_send_request(request, None)
+    };
+
+    // An array longer than the limit is rejected without being sent
+    assert_eq!(
+        write_request(vec![1, 2, 3, 4]).unwrap_err(),
+        StatusCode::BadOutOfRange
+    );
+    assert!(rx.try_recv().is_err());
+
+    // But one within the limit is sent
+    assert!(write_request(vec![1, 2, 3]).is_ok());
+    match next_request(&mut rx) {
+        SupportedMessage::WriteRequest(_) => {}
+        request => panic!("Expected a write request, got {:?}", request),
+    }
+}
+
+#[test]
+fn auto_close_idle() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let (session_state, _rx) = make_session_state(secure_channel);
+    let request = read_request(&session_state);
+    let mut session_state = trace_write_lock!(session_state);
+
+    // Never idle by default
+    thread::sleep(Duration::from_millis(60));
+    assert!(!session_state.idle_close_due());
+
+    // Idle once the period passes without a request from the client
+    session_state.set_auto_close_idle_after(Some(Duration::from_millis(50)));
+    assert!(session_state.idle_close_due());
+    session_state.set_activated(true);
+    assert!(!session_state.idle_close_due());
+    thread::sleep(Duration::from_millis(60));
+    assert!(session_state.idle_close_due());
+
+    // A request makes it active again
+    let _ = session_state.async_send_request(request, None).unwrap();
+    assert!(session_state.idle_time() < Duration::from_millis(50));
+    assert!(!session_state.idle_close_due());
+
+    // A session with subscriptions is never idle
+    session_state.add_subscription(Subscription::new(
+        1,
+        500f64,
+        30,
+        10,
+        0,
+        true,
+        0,
+        Arc::new(Mutex::new(DataChangeCallback::new(|_| {}))),
+    ));
+    thread::sleep(Duration::from_millis(60));
+    assert!(!session_state.idle_close_due());
+
+    // Nor is a session that isn't activated
+    let _ = session_state.remove_subscription(1);
+    assert!(session_state.idle_close_due());
+    session_state.set_activated(false);
+    assert!(!session_state.idle_close_due());
+}
+
+#[test]
+fn certificate_verifier() {
+    let (server_cert, _) = X509::cert_and_pkey(&X509Data::sample_cert()).unwrap();
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    {
+        let mut secure_channel = trace_write_lock!(secure_channel);
+        secure_channel.set_security_mode(MessageSecurityMode::SignAndEncrypt);
+        secure_channel.set_security_policy(SecurityPolicy::Basic256Sha256);
+    }
+    let (session_state, mut rx) = make_session_state(secure_channel.clone());
+
+    // Only the pinned thumbprint is trusted
+    let thumbprint = server_cert.thumbprint();
+    trace_write_lock!(session_state).set_certificate_verifier(move |cert| {
+        if cert.thumbprint() == thumbprint {
+            Ok(())
+        } else {
+            Err(StatusCode::BadCertificateInvalid)
+        }
+    });
+
+    // A server without a certificate, or with another one, is not trusted and nothing is sent
+    let issue = || {
+        trace_write_lock!(session_state)
+            .issue_or_renew_secure_channel(SecurityTokenRequestType::Issue)
+    };
+    assert_eq!(issue().unwrap_err(), StatusCode::BadCertificateUntrusted);
+    let (other_cert, _) = X509::cert_and_pkey(&X509Data::sample_cert()).unwrap();
+    trace_write_lock!(secure_channel).set_remote_cert(Some(other_cert));
+    assert_eq!(issue().unwrap_err(), StatusCode::BadCertificateUntrusted);
+    assert!(rx.try_recv().is_err());
+
+    // The pinned certificate is trusted
+    trace_write_lock!(secure_channel).set_remote_cert(Some(server_cert));
+    let result = respond_to(
+        &session_state,
+        &mut rx,
+        |session_state| {
+            session_state.issue_or_renew_secure_channel(SecurityTokenRequestType::Issue)
+        },
+        |request| {
+            let request = supported_message_as!(request, OpenSecureChannelRequest);
+            OpenSecureChannelResponse {
+                response_header: ResponseHeader::new_good(&request.request_header),
+                server_protocol_version: 0,
+                security_token: security_token(2, 60000),
+                server_nonce: ByteString::from(&[1u8; 32][..]),
+            }
+            .into()
+        },
+    );
+    assert!(result.is_ok());
+    assert_eq!(trace_read_lock!(secure_channel).token_id(), 2);
+}
+
+#[test]
+fn max_total_monitored_items() {
+    let secure_channel = Arc::new(RwLock::new(SecureChannel::new_no_certificate_store()));
+    let subscription_state = Arc::new(RwLock::new(SubscriptionState::new()));
+    let (session_state, _rx) =
+        make_session_state_with_subscriptions(secure_channel, subscription_state.clone());
+    let subscription = |id| {
+        Subscription::new(
+            id,
+            500f64,
+            30,
+            10,
+            0,
+            true,
+            0,
+            Arc::new(Mutex::new(DataChangeCallback::new(|_| {}))),
+        )
+    };
+    let items = |ids: &[u32]| {
+        ids.iter()
+            .map(|id| CreateMonitoredItem {
+                id: *id,
+                client_handle: *id + 1000,
+                item_to_monitor: NodeId::new(2, *id).into(),
+                monitoring_mode: MonitoringMode::Reporting,
+                queue_size: 1,
+                discard_oldest: true,
+                sampling_interval: 100f64,
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut session_state = trace_write_lock!(session_state);
+    session_state.add_subscription(subscription(1));
+    session_state.add_subscription(subscription(2));
+    {
+        let mut subscription_state = trace_write_lock!(subscription_state);
+        subscription_state.insert_monitored_items(1, &items(&[10, 11]));
+        // An item the server failed to create isn't counted
+        subscription_state.insert_monitored_items(2, &items(&[20, 0]));
+    }
+    assert_eq!(session_state.total_monitored_items(), 3);
+
+    // No limit by default
+    assert!(session_state.check_total_monitored_items(100).is_ok());
+
+    // The total across all subscriptions is limited
+    session_state.set_max_total_monitored_items(Some(4));
+    assert!(session_state.check_total_monitored_items(1).is_ok());
+    assert_eq!(
+        session_state.check_total_monitored_items(2).unwrap_err(),
+        StatusCode::BadTooManyMonitoredItems
+    );
+
+    // Deleting items, or their subscription, makes room again
+    trace_write_lock!(subscription_state).delete_monitored_items(1, &[10]);
+    assert_eq!(session_state.total_monitored_items(), 2);
+    assert!(session_state.check_total_monitored_items(2).is_ok());
+    let _ = session_state.remove_subscription(2);
+    assert_eq!(session_state.total_monitored_items(), 1);
+    assert!(session_state.check_total_monitored_items(3).is_ok());
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use crate::{
+    client::comms::tcp_transport::ReceivedChunks,
+    core::{
+        comms::{
+            chunker::Chunker, message_chunk::MessageChunk, secure_channel::SecureChannel,
+            tcp_types::MIN_CHUNK_SIZE,
+        },
+        supported_message::SupportedMessage,
+    },
+    sync::*,
+    types::{status_code::StatusCode, *},
+};
+
+fn make_secure_channel() -> Arc<RwLock<SecureChannel>> {
+    let mut secure_channel = SecureChannel::new_no_certificate_store();
+    secure_channel.set_decoding_options(DecodingOptions {
+        max_array_length: 20000,
+        ..Default::default()
+    });
+    Arc::new(RwLock::new(secure_channel))
+}
+
+/// A publish response with enough notifications to span several chunks of the minimum size. The
+/// encoded notification data is also larger than the default limit on a byte string.
+fn make_large_publish_response() -> SupportedMessage {
+    let monitored_items = (0..2000)
+        .map(|i| MonitoredItemNotification {
+            client_handle: i,
+            value: DataValue::new_now(i),
+        })
+        .collect();
+    PublishResponse {
+        response_header: ResponseHeader::null(),
+        subscription_id: 1,
+        available_sequence_numbers: None,
+        more_notifications: false,
+        notification_message: NotificationMessage::data_change(
+            1,
+            DateTime::now(),
+            monitored_items,
+            vec![],
+        ),
+        results: None,
+        diagnostic_infos: None,
+    }
+    .into()
+}
+
+fn encode_chunks(
+    secure_channel: &Arc<RwLock<SecureChannel>>,
+    sequence_number: u32,
+    request_id: u32,
+    response: &SupportedMessage,
+) -> Vec<MessageChunk> {
+    let secure_channel = trace_read_lock!(secure_channel);
+    Chunker::encode(
+        sequence_number,
+        request_id,
+        0,
+        MIN_CHUNK_SIZE,
+        &secure_channel,
+        response,
+    )
+    .unwrap()
+}
+
+#[test]
+fn multi_chunk_publish_response() {
+    let secure_channel = make_secure_channel();
+    let mut received_chunks = ReceivedChunks::new(secure_channel.clone(), 0);
+
+    let response = make_large_publish_response();
+    let chunks = encode_chunks(&secure_channel, 1, 10, &response);
+    assert!(chunks.len() > 1);
+
+    // Nothing is delivered until the final chunk
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let message = received_chunks.process_chunk(chunk).unwrap();
+        if i < last {
+            assert!(message.is_none());
+        } else {
+            assert_eq!(message.unwrap(), response);
+        }
+    }
+
+    // The next response carries on from the last sequence number
+    let sequence_number = (last + 2) as u32;
+    let chunks = encode_chunks(&secure_channel, sequence_number, 11, &response);
+    let message = chunks
+        .into_iter()
+        .map(|chunk| received_chunks.process_chunk(chunk).unwrap())
+        .last()
+        .unwrap();
+    assert_eq!(message.unwrap(), response);
+}
+
+#[test]
+fn multi_chunk_missing_chunk() {
+    let secure_channel = make_secure_channel();
+    let mut received_chunks = ReceivedChunks::new(secure_channel.clone(), 0);
+
+    let mut chunks = encode_chunks(&secure_channel, 1, 10, &make_large_publish_response());
+    assert!(chunks.len() > 2);
+    chunks.remove(1);
+    let result = chunks
+        .into_iter()
+        .map(|chunk| received_chunks.process_chunk(chunk))
+        .last()
+        .unwrap();
+    assert_eq!(result.unwrap_err(), StatusCode::BadSequenceNumberInvalid);
+}
+
+#[test]
+fn multi_chunk_max_chunk_count() {
+    let secure_channel = make_secure_channel();
+    let mut received_chunks = ReceivedChunks::new(secure_channel.clone(), 2);
+
+    let chunks = encode_chunks(&secure_channel, 1, 10, &make_large_publish_response());
+    assert!(chunks.len() > 3);
+    let results = chunks
+        .into_iter()
+        .map(|chunk| received_chunks.process_chunk(chunk))
+        .collect::<Vec<_>>();
+    assert!(results[0].as_ref().unwrap().is_none());
+    assert!(results[1].as_ref().unwrap().is_none());
+    assert_eq!(
+        *results[2].as_ref().unwrap_err(),
+        StatusCode::BadTcpMessageTooLarge
+    );
+}
@@ -239,6 +239,7 @@ impl Chunker {
         // elaborate on. Probably because people enjoy debugging why the stream pos is out by 1 byte
         // for hours.
 
+        // Each message gets its own decoded size budget
         let decoding_options = secure_channel.decoding_options();
 
         // Read node id from stream
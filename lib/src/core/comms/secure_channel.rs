@@ -28,6 +28,47 @@ use super::{
     security_header::{AsymmetricSecurityHeader, SecurityHeader, SymmetricSecurityHeader},
 };
 
+/// Symmetric signing key, encryption key and initialization vector
+type SymmetricKeys = (Vec<u8>, AesKey, Vec<u8>);
+
+/// The symmetric keys that secure the messages sent in one direction of a secure channel.
+#[cfg(feature = "unsafe-key-export")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymmetricKeyMaterial {
+    pub signing_key: Vec<u8>,
+    pub encryption_key: Vec<u8>,
+    pub iv: Vec<u8>,
+}
+
+#[cfg(feature = "unsafe-key-export")]
+impl From<&SymmetricKeys> for SymmetricKeyMaterial {
+    fn from(keys: &SymmetricKeys) -> Self {
+        SymmetricKeyMaterial {
+            signing_key: keys.0.clone(),
+            encryption_key: keys.1.value().to_vec(),
+            iv: keys.2.clone(),
+        }
+    }
+}
+
+/// The keys of a secure channel, exported so that a tool such as Wireshark can decrypt captured
+/// traffic, e.g. to diagnose a crypto mismatch with another implementation.
+///
+/// **WARNING** Anyone holding these keys can read and forge the messages of the channel. They
+/// are only available with the `unsafe-key-export` feature, which must never be enabled in
+/// production.
+#[cfg(feature = "unsafe-key-export")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionKeyMaterial {
+    pub secure_channel_id: u32,
+    pub token_id: u32,
+    pub security_policy: SecurityPolicy,
+    /// The keys that secure messages sent by this end
+    pub local_keys: SymmetricKeyMaterial,
+    /// The keys that secure messages sent by the other end
+    pub remote_keys: SymmetricKeyMaterial,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Role {
     Unknown,
@@ -52,6 +93,9 @@ pub struct SecureChannel {
     token_lifetime: u32,
     /// Token identifier
     token_id: u32,
+    /// Set while a client is renewing the token, so that sessions sharing the channel do not
+    /// all try to renew it at once
+    token_renewal_in_progress: bool,
     /// Our certificate
     cert: Option<X509>,
     /// Our private key
@@ -64,6 +108,11 @@ pub struct SecureChannel {
     local_nonce: Vec<u8>,
     /// Client (i.e. other end's set of keys) Symmetric Signing Key, Encrypt Key, IV
     remote_keys: Option<(Vec<u8>, AesKey, Vec<u8>)>,
+    /// The token id that the remote keys were derived for
+    remote_keys_token_id: u32,
+    /// The other end's keys for the previous token, which it may still use for messages until
+    /// it switches to the renewed token
+    previous_remote_keys: Option<(u32, SymmetricKeys)>,
     /// Server (i.e. our end's set of keys) Symmetric Signing Key, Decrypt Key, IV
     local_keys: Option<(Vec<u8>, AesKey, Vec<u8>)>,
     /// Decoding options
@@ -82,6 +131,7 @@ impl SecureChannel {
             token_id: 0,
             token_created_at: DateTime::now(),
             token_lifetime: 0,
+            token_renewal_in_progress: false,
             local_nonce: Vec::new(),
             remote_nonce: Vec::new(),
             cert: None,
@@ -89,6 +139,8 @@ impl SecureChannel {
             remote_cert: None,
             local_keys: None,
             remote_keys: None,
+            remote_keys_token_id: 0,
+            previous_remote_keys: None,
             decoding_options: DecodingOptions::default(),
         }
     }
@@ -115,6 +167,7 @@ impl SecureChannel {
             token_id: 0,
             token_created_at: DateTime::now(),
             token_lifetime: 0,
+            token_renewal_in_progress: false,
             local_nonce: Vec::new(),
             remote_nonce: Vec::new(),
             cert,
@@ -122,6 +175,8 @@ impl SecureChannel {
             remote_cert: None,
             local_keys: None,
             remote_keys: None,
+            remote_keys_token_id: 0,
+            previous_remote_keys: None,
             decoding_options,
         }
     }
@@ -171,6 +226,7 @@ impl SecureChannel {
         self.token_id = 0;
         self.token_created_at = DateTime::now();
         self.token_lifetime = 0;
+        self.token_renewal_in_progress = false;
     }
 
     pub fn set_security_token(&mut self, channel_token: ChannelSecurityToken) {
@@ -180,6 +236,15 @@ impl SecureChannel {
         self.token_lifetime = channel_token.revised_lifetime;
     }
 
+    /// Sets a security token that was issued earlier, keeping the time it was created at so its
+    /// lifetime runs from when it was issued rather than from now.
+    pub fn restore_security_token(&mut self, channel_token: ChannelSecurityToken) {
+        self.secure_channel_id = channel_token.channel_id;
+        self.token_id = channel_token.token_id;
+        self.token_created_at = channel_token.created_at;
+        self.token_lifetime = channel_token.revised_lifetime;
+    }
+
     pub fn set_secure_channel_id(&mut self, secure_channel_id: u32) {
         self.secure_channel_id = secure_channel_id;
     }
@@ -215,8 +280,12 @@ impl SecureChannel {
         }
     }
 
+    /// Returns the decoding options of the channel. Each call gets its own decoded size budget,
+    /// so that what one message decodes doesn't count against the next.
     pub fn decoding_options(&self) -> DecodingOptions {
-        self.decoding_options.clone()
+        let mut decoding_options = self.decoding_options.clone();
+        decoding_options.reset_decoded_size();
+        decoding_options
     }
 
     /// Test if the secure channel token needs to be renewed. The algorithm determines it needs
@@ -233,6 +302,29 @@ impl SecureChannel {
         }
     }
 
+    /// Marks the start of a token renewal. Returns `false` if a renewal is already in progress,
+    /// in which case the caller should not renew. A client channel may be shared by several
+    /// sessions and this ensures only one of them renews the token.
+    pub fn begin_token_renewal(&mut self) -> bool {
+        if self.token_renewal_in_progress {
+            false
+        } else {
+            self.token_renewal_in_progress = true;
+            true
+        }
+    }
+
+    /// Marks the end of a token renewal started with `begin_token_renewal()`, whether it
+    /// succeeded or not.
+    pub fn end_token_renewal(&mut self) {
+        self.token_renewal_in_progress = false;
+    }
+
+    /// Test if a token renewal is in progress
+    pub fn is_token_renewal_in_progress(&self) -> bool {
+        self.token_renewal_in_progress
+    }
+
     /// Makes a security header according to the type of message being sent, symmetric or asymmetric
     pub fn make_security_header(&self, message_type: MessageChunkType) -> SecurityHeader {
         match message_type {
@@ -356,6 +448,12 @@ impl SecureChannel {
     /// are used to secure Messages sent by the Server.
     ///
     pub fn derive_keys(&mut self) {
+        if let Some(remote_keys) = self.remote_keys.take() {
+            if self.remote_keys_token_id != self.token_id {
+                self.previous_remote_keys = Some((self.remote_keys_token_id, remote_keys));
+            }
+        }
+        self.remote_keys_token_id = self.token_id;
         self.remote_keys = Some(
             self.security_policy
                 .make_secure_channel_keys(&self.local_nonce, &self.remote_nonce),
@@ -507,7 +605,7 @@ impl SecureChannel {
         Self::update_message_size_and_truncate(
             stream.into_inner(),
             message_size,
-            &self.decoding_options,
+            &self.decoding_options(),
         )
     }
 
@@ -575,7 +673,7 @@ impl SecureChannel {
             let encrypted_range = chunk_info.sequence_header_offset..data.len();
 
             // Encrypt and sign - open secure channel
-            let encrypted_size = if message_chunk.is_open_secure_channel(&self.decoding_options) {
+            let encrypted_size = if message_chunk.is_open_secure_channel(&self.decoding_options()) {
                 self.asymmetric_sign_and_encrypt(self.security_policy, &data, encrypted_range, dst)?
             } else {
                 // Symmetric encrypt and sign
@@ -615,16 +713,16 @@ impl SecureChannel {
         // Get message & security header from data
         let (message_header, security_header, encrypted_data_offset) = {
             let mut stream = Cursor::new(&src);
-            let message_header = MessageChunkHeader::decode(&mut stream, &self.decoding_options)?;
+            let message_header = MessageChunkHeader::decode(&mut stream, &self.decoding_options())?;
             let security_header = if message_header.message_type.is_open_secure_channel() {
                 SecurityHeader::Asymmetric(AsymmetricSecurityHeader::decode(
                     &mut stream,
-                    &self.decoding_options,
+                    &self.decoding_options(),
                 )?)
             } else {
                 SecurityHeader::Symmetric(SymmetricSecurityHeader::decode(
                     &mut stream,
-                    &self.decoding_options,
+                    &self.decoding_options(),
                 )?)
             };
             let encrypted_data_offset = stream.position() as usize;
@@ -721,13 +819,20 @@ impl SecureChannel {
             Self::update_message_size_and_truncate(
                 decrypted_data,
                 decrypted_size,
-                &self.decoding_options,
+                &self.decoding_options(),
             )?
         } else if self.security_policy != SecurityPolicy::None
             && (self.security_mode == MessageSecurityMode::Sign
                 || self.security_mode == MessageSecurityMode::SignAndEncrypt)
         {
-            // Symmetric decrypt and verify
+            // Symmetric decrypt and verify using the keys of the token the message was secured with
+            let token_id = match security_header {
+                SecurityHeader::Symmetric(security_header) => security_header.token_id,
+                _ => {
+                    panic!();
+                }
+            };
+            let remote_keys = self.remote_keys_for_token(token_id)?;
             let signature_size = self.security_policy.symmetric_signature_size();
             let encrypted_range = encrypted_data_offset..message_size;
             let signed_range = 0..(message_size - signature_size);
@@ -738,18 +843,25 @@ impl SecureChannel {
             );
 
             let mut decrypted_data = vec![0u8; message_size];
-            let decrypted_size = self.symmetric_decrypt_and_verify(
+            let decrypted_size = self.symmetric_decrypt_and_verify_with_keys(
+                Some(remote_keys),
                 src,
                 signed_range,
                 encrypted_range,
                 &mut decrypted_data,
             )?;
 
+            // Once the other end uses the current token, it won't use the previous one again
+            if token_id == self.remote_keys_token_id && self.previous_remote_keys.is_some() {
+                trace!("Discarding keys of the previous token");
+                self.previous_remote_keys = None;
+            }
+
             // Now we need to strip off signature
             Self::update_message_size_and_truncate(
                 decrypted_data,
                 decrypted_size - signature_size,
-                &self.decoding_options,
+                &self.decoding_options(),
             )?
         } else {
             src.to_vec()
@@ -799,7 +911,7 @@ impl SecureChannel {
         Self::update_message_size(
             &mut tmp[..],
             header_size + cipher_text_size,
-            &self.decoding_options,
+            &self.decoding_options(),
         )?;
 
         // Sign the message header, security header, sequence header, body, padding
@@ -1042,6 +1154,23 @@ impl SecureChannel {
         }
     }
 
+    /// Exports the keys derived for the current token, or `None` if no keys have been derived,
+    /// e.g. because the security policy is `None`. See [`SessionKeyMaterial`] for why this must
+    /// never be enabled in production.
+    #[cfg(feature = "unsafe-key-export")]
+    pub fn export_keys(&self) -> Option<SessionKeyMaterial> {
+        match (&self.local_keys, &self.remote_keys) {
+            (Some(local_keys), Some(remote_keys)) => Some(SessionKeyMaterial {
+                secure_channel_id: self.secure_channel_id,
+                token_id: self.token_id,
+                security_policy: self.security_policy,
+                local_keys: local_keys.into(),
+                remote_keys: remote_keys.into(),
+            }),
+            _ => None,
+        }
+    }
+
     fn local_keys(&self) -> &(Vec<u8>, AesKey, Vec<u8>) {
         self.local_keys.as_ref().unwrap()
     }
@@ -1050,6 +1179,26 @@ impl SecureChannel {
         self.remote_keys.as_ref().unwrap()
     }
 
+    /// Returns the other end's keys for the token id in a message's security header, which is
+    /// either the current token or the previous one if the token was recently renewed.
+    fn remote_keys_for_token(&self, token_id: u32) -> Result<&SymmetricKeys, StatusCode> {
+        match self.previous_remote_keys {
+            Some((previous_token_id, ref remote_keys)) if previous_token_id == token_id => {
+                Ok(remote_keys)
+            }
+            _ if token_id == self.remote_keys_token_id && self.remote_keys.is_some() => {
+                Ok(self.remote_keys())
+            }
+            _ => {
+                error!(
+                    "Message is secured with token id {} which is not the current token id {}",
+                    token_id, self.remote_keys_token_id
+                );
+                Err(StatusCode::BadSecureChannelTokenUnknown)
+            }
+        }
+    }
+
     fn encryption_keys(&self) -> (&AesKey, &[u8]) {
         let keys = self.local_keys();
         (&keys.1, &keys.2)
@@ -1059,13 +1208,12 @@ impl SecureChannel {
         &(self.local_keys()).0
     }
 
-    fn decryption_keys(&self) -> (&AesKey, &[u8]) {
-        let keys = self.remote_keys();
-        (&keys.1, &keys.2)
+    fn decryption_keys(remote_keys: &SymmetricKeys) -> (&AesKey, &[u8]) {
+        (&remote_keys.1, &remote_keys.2)
     }
 
-    fn verification_key(&self) -> &[u8] {
-        &(self.remote_keys()).0
+    fn verification_key(remote_keys: &SymmetricKeys) -> &[u8] {
+        &remote_keys.0
     }
 
     /// Encode data using security. Destination buffer is expected to be same size as src and expected
@@ -1177,6 +1325,25 @@ impl SecureChannel {
         signed_range: Range<usize>,
         encrypted_range: Range<usize>,
         dst: &mut [u8],
+    ) -> Result<usize, StatusCode> {
+        self.symmetric_decrypt_and_verify_with_keys(
+            self.remote_keys.as_ref(),
+            src,
+            signed_range,
+            encrypted_range,
+            dst,
+        )
+    }
+
+    /// Decrypts and verifies data with the supplied remote keys, which are required unless the
+    /// security mode is None.
+    fn symmetric_decrypt_and_verify_with_keys(
+        &self,
+        remote_keys: Option<&SymmetricKeys>,
+        src: &[u8],
+        signed_range: Range<usize>,
+        encrypted_range: Range<usize>,
+        dst: &mut [u8],
     ) -> Result<usize, StatusCode> {
         match self.security_mode {
             MessageSecurityMode::None => {
@@ -1196,7 +1363,7 @@ impl SecureChannel {
                     signed_range,
                     signed_range.end
                 );
-                let verification_key = self.verification_key();
+                let verification_key = Self::verification_key(remote_keys.unwrap());
                 self.security_policy.symmetric_verify_signature(
                     verification_key,
                     &dst[signed_range.clone()],
@@ -1220,7 +1387,7 @@ impl SecureChannel {
 
                 // Decrypt encrypted portion
                 let mut decrypted_tmp = vec![0u8; ciphertext_size + 16]; // tmp includes +16 for blocksize
-                let (key, iv) = self.decryption_keys();
+                let (key, iv) = Self::decryption_keys(remote_keys.unwrap());
 
                 trace!(
                     "Secure decrypt called with encrypted range {:?}",
@@ -1248,7 +1415,7 @@ impl SecureChannel {
                     signed_range,
                     signature_range
                 );
-                let verification_key = self.verification_key();
+                let verification_key = Self::verification_key(remote_keys.unwrap());
                 self.security_policy.symmetric_verify_signature(
                     verification_key,
                     &dst[signed_range],
@@ -18,8 +18,12 @@ impl Handle {
     }
 
     /// Returns the next handle to be issued, internally incrementing each time so the handle
-    /// is always different until it wraps back to the start.
+    /// is always different until it wraps back to the start. The handle is never 0, which OPC UA
+    /// treats as no handle, even if the sequence starts at 0.
     pub fn next(&mut self) -> u32 {
+        if self.next == 0 {
+            self.next = 1;
+        }
         let next = self.next;
         // Increment next
         if self.next == u32::MAX {
@@ -30,6 +34,23 @@ impl Handle {
         next
     }
 
+    /// Returns the next `count` handles to be issued, as one contiguous block unless the sequence
+    /// wraps back to the start within it. The handles are never 0, the same as for `next()`.
+    pub fn next_n(&mut self, count: usize) -> Vec<u32> {
+        if self.next == 0 {
+            self.next = 1;
+        }
+        let first = self.next;
+        let remaining = (u32::MAX - first) as usize;
+        if count <= remaining {
+            // The block ends before u32::MAX so the counter can be advanced in one go
+            self.next = first + count as u32;
+            (first..self.next).collect()
+        } else {
+            (0..count).map(|_| self.next()).collect()
+        }
+    }
+
     pub fn set_next(&mut self, next: u32) {
         self.next = next;
     }
@@ -43,10 +64,10 @@ impl Handle {
 #[test]
 fn handle_increment() {
     // Expect sequential handles
-    let mut h = Handle::new(0);
-    assert_eq!(h.next(), 0);
+    let mut h = Handle::new(1);
     assert_eq!(h.next(), 1);
     assert_eq!(h.next(), 2);
+    assert_eq!(h.next(), 3);
     let mut h = Handle::new(100);
     assert_eq!(h.next(), 100);
     assert_eq!(h.next(), 101);
@@ -61,3 +82,37 @@ fn handle_wrap() {
     assert_eq!(h.next(), u32::MAX);
     assert_eq!(h.next(), u32::MAX - 2);
 }
+
+#[test]
+fn handle_never_zero() {
+    // 0 is skipped at the start
+    let mut h = Handle::new(0);
+    assert_eq!(h.next(), 1);
+    assert_eq!(h.next(), 2);
+
+    // And after wrapping around
+    h.set_next(u32::MAX);
+    assert_eq!(h.next(), u32::MAX);
+    assert_ne!(h.next(), 0);
+}
+
+#[test]
+fn handle_next_n() {
+    // A block of handles carries on from the single handles
+    let mut h = Handle::new(0);
+    assert_eq!(h.next(), 1);
+    assert_eq!(h.next_n(3), vec![2, 3, 4]);
+    assert_eq!(h.next(), 5);
+    assert!(h.next_n(0).is_empty());
+    assert_eq!(h.next(), 6);
+
+    // A block that reaches the end of the sequence wraps around, skipping 0
+    h.set_next(u32::MAX - 1);
+    assert_eq!(h.next_n(3), vec![u32::MAX - 1, u32::MAX, 1]);
+    let mut h = Handle::new(u32::MAX - 2);
+    assert_eq!(
+        h.next_n(4),
+        vec![u32::MAX - 2, u32::MAX - 1, u32::MAX, u32::MAX - 2]
+    );
+    assert_eq!(h.next(), u32::MAX - 1);
+}
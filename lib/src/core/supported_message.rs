@@ -80,6 +80,15 @@ macro_rules! supported_messages_enum {
                     $( SupportedMessage::$x(value) => value.object_id().into(), )*
                 }
             }
+
+            /// Returns the name of the message type, e.g. "ReadRequest"
+            pub fn type_name(&self) -> &'static str {
+                match self {
+                    SupportedMessage::Invalid(_) => "Invalid",
+                    SupportedMessage::AcknowledgeMessage(_) => "AcknowledgeMessage",
+                    $( SupportedMessage::$x(_) => stringify!($x), )*
+                }
+            }
         }
     }
 }
@@ -183,6 +192,54 @@ impl SupportedMessage {
         }
     }
 
+    /// Returns the header of the request so it can be changed, or `None` if the message is not
+    /// a request
+    pub fn request_header_mut(&mut self) -> Option<&mut RequestHeader> {
+        match self {
+            SupportedMessage::OpenSecureChannelRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::CloseSecureChannelRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::GetEndpointsRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::FindServersRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::RegisterServerRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::RegisterServer2Request(r) => Some(&mut r.request_header),
+            SupportedMessage::CreateSessionRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::CloseSessionRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::CancelRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::ActivateSessionRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::AddNodesRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::AddReferencesRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::DeleteNodesRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::DeleteReferencesRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::CreateMonitoredItemsRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::ModifyMonitoredItemsRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::DeleteMonitoredItemsRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::SetMonitoringModeRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::SetTriggeringRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::CreateSubscriptionRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::ModifySubscriptionRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::DeleteSubscriptionsRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::TransferSubscriptionsRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::SetPublishingModeRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::QueryFirstRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::QueryNextRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::BrowseRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::BrowseNextRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::PublishRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::RepublishRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::TranslateBrowsePathsToNodeIdsRequest(r) => {
+                Some(&mut r.request_header)
+            }
+            SupportedMessage::RegisterNodesRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::UnregisterNodesRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::ReadRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::HistoryReadRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::WriteRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::HistoryUpdateRequest(r) => Some(&mut r.request_header),
+            SupportedMessage::CallRequest(r) => Some(&mut r.request_header),
+            _ => None,
+        }
+    }
+
     pub fn is_response(&self) -> bool {
         match self {
             SupportedMessage::ServiceFault(_) => true,
@@ -134,3 +134,39 @@ pub fn secure_channel_nonce_none() {
         .set_remote_nonce_from_byte_string(&ByteString::from(b""))
         .is_ok());
 }
+
+#[test]
+pub fn secure_channel_nonce_sha256_policies() {
+    [
+        SecurityPolicy::Basic256Sha256,
+        SecurityPolicy::Aes128Sha256RsaOaep,
+        SecurityPolicy::Aes256Sha256RsaPss,
+    ]
+    .iter()
+    .for_each(|security_policy| {
+        let mut sc = SecureChannel::new_no_certificate_store();
+        sc.set_security_mode(MessageSecurityMode::SignAndEncrypt);
+        sc.set_security_policy(*security_policy);
+        // Nonce which is not 32 bytes long is an error
+        [
+            ByteString::null(),
+            ByteString::from(b""),
+            ByteString::from(b"0123456789012345"),
+            ByteString::from(b"0123456789012345678901234567890"),
+            ByteString::from(b"012345678901234567890123456789012".as_ref()),
+        ]
+        .iter()
+        .for_each(|nonce| {
+            assert_eq!(
+                sc.set_remote_nonce_from_byte_string(nonce).unwrap_err(),
+                StatusCode::BadNonceInvalid
+            );
+        });
+        // Nonce which is 32 bytes long is good
+        assert!(sc
+            .set_remote_nonce_from_byte_string(&ByteString::from(
+                b"01234567890123456789012345678901"
+            ))
+            .is_ok());
+    });
+}
@@ -4,7 +4,7 @@
 use crate::crypto::SecurityPolicy;
 
 use crate::core::{
-    comms::{chunker::*, secure_channel::*},
+    comms::{chunker::*, secure_channel::*, security_header::SecurityHeader},
     tests::*,
 };
 
@@ -203,3 +203,158 @@ fn symmetric_sign_and_encrypt_message_chunk_basic256sha256() {
         SecurityPolicy::Basic256Sha256,
     );
 }
+
+/// Encodes a message to a single chunk and applies the sending secure channel's security to it
+fn secure_message(secure_channel: &SecureChannel, sequence_number: u32) -> Vec<u8> {
+    let chunks = Chunker::encode(
+        sequence_number,
+        1,
+        0,
+        0,
+        secure_channel,
+        &make_sample_message(),
+    )
+    .unwrap();
+    assert_eq!(chunks.len(), 1);
+    let mut data = vec![0u8; chunks[0].data.len() + 4096];
+    let size = secure_channel
+        .apply_security(&chunks[0], &mut data)
+        .unwrap();
+    data.truncate(size);
+    data
+}
+
+/// The OpenSecureChannel request is secured asymmetrically with the certificates, and the
+/// messages that follow symmetrically under the issued token.
+#[test]
+fn open_secure_channel_asymmetric_then_symmetric() {
+    let _ = Test::setup();
+    let (our_cert, our_key) = make_test_cert_2048();
+    let (their_cert, _) = make_test_cert_2048();
+
+    let mut secure_channel = SecureChannel::new_no_certificate_store();
+    secure_channel.set_security_mode(MessageSecurityMode::SignAndEncrypt);
+    secure_channel.set_security_policy(SecurityPolicy::Basic256Sha256);
+    secure_channel.set_cert(Some(our_cert.clone()));
+    secure_channel.set_remote_cert(Some(their_cert.clone()));
+    secure_channel.set_private_key(Some(our_key));
+
+    let request: SupportedMessage = OpenSecureChannelRequest {
+        request_header: RequestHeader::dummy(),
+        client_protocol_version: 0,
+        request_type: SecurityTokenRequestType::Issue,
+        security_mode: MessageSecurityMode::SignAndEncrypt,
+        client_nonce: ByteString::null(),
+        requested_lifetime: 60000,
+    }
+    .into();
+    let chunks = Chunker::encode(1, 1, 0, 0, &secure_channel, &request).unwrap();
+    match chunks[0]
+        .chunk_info(&secure_channel)
+        .unwrap()
+        .security_header
+    {
+        SecurityHeader::Asymmetric(security_header) => {
+            assert_eq!(
+                security_header.security_policy_uri.as_ref(),
+                SecurityPolicy::Basic256Sha256.to_uri()
+            );
+            assert_eq!(
+                security_header.sender_certificate,
+                our_cert.as_byte_string()
+            );
+            assert_eq!(
+                security_header.receiver_certificate_thumbprint,
+                their_cert.thumbprint().as_byte_string()
+            );
+        }
+        security_header => panic!("Expected an asymmetric header, got {:?}", security_header),
+    }
+
+    // The channel is issued a token
+    secure_channel.set_security_token(ChannelSecurityToken {
+        channel_id: 1,
+        token_id: 3,
+        created_at: DateTime::now(),
+        revised_lifetime: 60000,
+    });
+    let chunks = Chunker::encode(2, 2, 0, 0, &secure_channel, &make_sample_message()).unwrap();
+    match chunks[0]
+        .chunk_info(&secure_channel)
+        .unwrap()
+        .security_header
+    {
+        SecurityHeader::Symmetric(security_header) => assert_eq!(security_header.token_id, 3),
+        security_header => panic!("Expected a symmetric header, got {:?}", security_header),
+    }
+}
+
+/// After the token is renewed, messages secured with the previous token's keys are still
+/// accepted until the other end uses the new token.
+#[test]
+fn symmetric_keys_by_token_id() {
+    let _ = Test::setup();
+    let (mut secure_channel1, mut secure_channel2) = make_secure_channels(
+        MessageSecurityMode::SignAndEncrypt,
+        SecurityPolicy::Basic256Sha256,
+    );
+    let old_message = secure_message(&secure_channel1, 1);
+
+    // Renew the token with new nonces on both ends
+    let local_nonce = vec![32u8; 32];
+    let remote_nonce = vec![64u8; 32];
+    secure_channel1.set_token_id(1);
+    secure_channel1.set_local_nonce(&local_nonce);
+    secure_channel1.set_remote_nonce(&remote_nonce);
+    secure_channel1.derive_keys();
+    secure_channel2.set_token_id(1);
+    secure_channel2.set_local_nonce(&remote_nonce);
+    secure_channel2.set_remote_nonce(&local_nonce);
+    secure_channel2.derive_keys();
+    let new_message = secure_message(&secure_channel1, 2);
+
+    assert!(secure_channel2
+        .verify_and_remove_security(&old_message)
+        .is_ok());
+    assert!(secure_channel2
+        .verify_and_remove_security(&new_message)
+        .is_ok());
+    // The new token has been used so the previous one is no longer valid
+    assert_eq!(
+        secure_channel2
+            .verify_and_remove_security(&old_message)
+            .unwrap_err(),
+        StatusCode::BadSecureChannelTokenUnknown
+    );
+
+    // A token that was never issued
+    secure_channel1.set_token_id(5);
+    let unknown_message = secure_message(&secure_channel1, 3);
+    assert_eq!(
+        secure_channel2
+            .verify_and_remove_security(&unknown_message)
+            .unwrap_err(),
+        StatusCode::BadSecureChannelTokenUnknown
+    );
+}
+
+#[cfg(feature = "unsafe-key-export")]
+#[test]
+fn export_keys() {
+    let (secure_channel1, secure_channel2) = make_secure_channels(
+        MessageSecurityMode::SignAndEncrypt,
+        SecurityPolicy::Basic256Sha256,
+    );
+    let keys1 = secure_channel1.export_keys().unwrap();
+    let keys2 = secure_channel2.export_keys().unwrap();
+    assert_eq!(keys1.security_policy, SecurityPolicy::Basic256Sha256);
+    assert!(!keys1.local_keys.encryption_key.is_empty());
+    // Each end's keys for sending are the other end's keys for receiving
+    assert_eq!(keys1.local_keys, keys2.remote_keys);
+    assert_eq!(keys1.remote_keys, keys2.local_keys);
+
+    // No keys are derived without security
+    assert!(SecureChannel::new_no_certificate_store()
+        .export_keys()
+        .is_none());
+}
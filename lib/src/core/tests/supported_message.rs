@@ -8,3 +8,41 @@ fn size() {
     println!("SupportedMessage size = {}", size);
     assert!(size <= 16);
 }
+
+#[test]
+fn type_name() {
+    use crate::types::{node_ids::ObjectId, status_code::StatusCode, *};
+    let request: SupportedMessage = ReadRequest {
+        request_header: RequestHeader::dummy(),
+        max_age: 0f64,
+        timestamps_to_return: TimestampsToReturn::Both,
+        nodes_to_read: None,
+    }
+    .into();
+    assert_eq!(request.type_name(), "ReadRequest");
+    let response: SupportedMessage =
+        ServiceFault::new(&RequestHeader::dummy(), StatusCode::BadTimeout).into();
+    assert_eq!(response.type_name(), "ServiceFault");
+    assert_eq!(
+        SupportedMessage::Invalid(ObjectId::ReadRequest_Encoding_DefaultBinary).type_name(),
+        "Invalid"
+    );
+}
+
+#[test]
+fn request_header_mut() {
+    use crate::types::{status_code::StatusCode, *};
+    let mut request: SupportedMessage = ReadRequest {
+        request_header: RequestHeader::dummy(),
+        max_age: 0f64,
+        timestamps_to_return: TimestampsToReturn::Both,
+        nodes_to_read: None,
+    }
+    .into();
+    request.request_header_mut().unwrap().timeout_hint = 1000;
+    assert_eq!(request.request_header().timeout_hint, 1000);
+    // A response has no request header
+    let mut response: SupportedMessage =
+        ServiceFault::new(&RequestHeader::dummy(), StatusCode::BadTimeout).into();
+    assert!(response.request_header_mut().is_none());
+}
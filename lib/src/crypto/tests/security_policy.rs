@@ -150,3 +150,41 @@ fn is_valid_keylength() {
     assert!(!SecurityPolicy::Aes256Sha256RsaPss.is_valid_keylength(1024));
     assert!(!SecurityPolicy::Aes256Sha256RsaPss.is_valid_keylength(8192));
 }
+
+#[test]
+fn secure_channel_nonce_length() {
+    assert_eq!(
+        SecurityPolicy::Basic128Rsa15.secure_channel_nonce_length(),
+        16
+    );
+    assert_eq!(SecurityPolicy::Basic256.secure_channel_nonce_length(), 32);
+    assert_eq!(
+        SecurityPolicy::Basic256Sha256.secure_channel_nonce_length(),
+        32
+    );
+    assert_eq!(
+        SecurityPolicy::Aes128Sha256RsaOaep.secure_channel_nonce_length(),
+        32
+    );
+    assert_eq!(
+        SecurityPolicy::Aes256Sha256RsaPss.secure_channel_nonce_length(),
+        32
+    );
+
+    // A random nonce is the right length for the policy
+    [
+        SecurityPolicy::Basic128Rsa15,
+        SecurityPolicy::Basic256,
+        SecurityPolicy::Basic256Sha256,
+        SecurityPolicy::Aes128Sha256RsaOaep,
+        SecurityPolicy::Aes256Sha256RsaPss,
+    ]
+    .iter()
+    .for_each(|security_policy| {
+        assert_eq!(
+            security_policy.random_nonce().as_ref().len(),
+            security_policy.secure_channel_nonce_length()
+        );
+    });
+    assert!(SecurityPolicy::None.random_nonce().is_null());
+}
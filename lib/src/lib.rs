@@ -19,6 +19,7 @@ extern crate derivative;
 // to reference that other crate.
 pub mod sync {
     pub type RwLock<T> = parking_lot::RwLock<T>;
+    pub type RwLockWriteGuard<'a, T> = parking_lot::RwLockWriteGuard<'a, T>;
     pub type Mutex<T> = parking_lot::Mutex<T>;
 }
 
@@ -130,6 +130,7 @@ impl BinaryEncoder<ByteString> for ByteString {
             Err(StatusCode::BadDecodingError)
         } else {
             // Create a buffer filled with zeroes and read the byte string over the top
+            decoding_options.allocate(len as usize)?;
             let mut buf: Vec<u8> = vec![0u8; len as usize];
             process_decode_io_result(stream.read_exact(&mut buf))?;
             Ok(ByteString { value: Some(buf) })
@@ -29,6 +29,18 @@ bitflags! {
     }
 }
 
+/// Which of the timestamps of a data value to treat as the time of the value.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum TimestampPreference {
+    /// The source timestamp, when the value was produced
+    Source,
+    /// The server timestamp, when the server received or sampled the value
+    Server,
+    /// The source timestamp if there is one, otherwise the server timestamp
+    #[default]
+    SourceElseServer,
+}
+
 /// A data value is a value of a variable in the OPC UA server and contains information about its
 /// value, status and change timestamps.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -464,6 +476,18 @@ impl DataValue {
         }
     }
 
+    /// Returns the timestamp of the value according to the preference, or `None` if the data
+    /// value does not have the preferred timestamp. A null timestamp is treated as missing.
+    pub fn effective_timestamp(&self, preference: TimestampPreference) -> Option<DateTime> {
+        let source_timestamp = self.source_timestamp.filter(|t| !t.is_null());
+        let server_timestamp = self.server_timestamp.filter(|t| !t.is_null());
+        match preference {
+            TimestampPreference::Source => source_timestamp,
+            TimestampPreference::Server => server_timestamp,
+            TimestampPreference::SourceElseServer => source_timestamp.or(server_timestamp),
+        }
+    }
+
     /// Returns the status code or Good if there is no code on the value
     pub fn status(&self) -> StatusCode {
         self.status.map_or(StatusCode::Good, |s| s)
@@ -68,9 +68,14 @@ impl BinaryEncoder<DateTime> for DateTime {
     fn decode<S: Read>(stream: &mut S, decoding_options: &DecodingOptions) -> EncodingResult<Self> {
         let ticks = read_i64(stream)?;
         let date_time = DateTime::from(ticks);
-        // Client offset is a value that can be overridden to account for time discrepancies between client & server -
-        // note perhaps it is not a good idea to do it right here but it is the lowest point to intercept DateTime values.
-        Ok(date_time - decoding_options.client_offset)
+        if date_time.is_null() || date_time == DateTime::endtimes() {
+            // Null and endtimes mean no value and infinity, which are not moved by the offset
+            Ok(date_time)
+        } else {
+            // Client offset is a value that can be overridden to account for time discrepancies between client & server -
+            // note perhaps it is not a good idea to do it right here but it is the lowest point to intercept DateTime values.
+            Ok(date_time - decoding_options.client_offset)
+        }
     }
 }
 
@@ -160,9 +165,14 @@ impl From<DateTimeUtc> for DateTime {
 }
 
 impl From<i64> for DateTime {
+    /// Makes a date time from ticks. Ticks outside of the valid range saturate, so 0 or less is
+    /// null, i.e. the epoch, and the endtimes or more, including `i64::MAX`, is the endtimes.
     fn from(value: i64) -> Self {
-        if value == i64::max_value() {
-            // Max signifies end times
+        if value <= 0 {
+            // Zero signifies no value, and anything before the epoch is clamped to it
+            Self::null()
+        } else if value >= Self::endtimes_ticks() {
+            // Max signifies end times, and anything after them is clamped to them
             Self::endtimes()
         } else {
             let secs = value / TICKS_PER_SECOND;
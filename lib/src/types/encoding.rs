@@ -8,7 +8,11 @@
 use std::{
     fmt::Debug,
     io::{Cursor, Read, Result, Write},
-    sync::Arc,
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
@@ -91,6 +95,17 @@ impl DepthGauge {
     }
 }
 
+/// What decoding does with an extension object whose type is not recognized, e.g. a vendor
+/// specific structure.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum DecodeUnknownPolicy {
+    /// Keep the type id and the raw body so the caller can decode it. This is the default.
+    #[default]
+    PreserveRaw,
+    /// Fail the decode with `BadDecodingError`.
+    Error,
+}
+
 #[derive(Clone, Debug)]
 pub struct DecodingOptions {
     /// Time offset between the client and the server, only used by the client when it's configured
@@ -108,6 +123,14 @@ pub struct DecodingOptions {
     pub max_array_length: usize,
     /// Decoding depth gauge is used to check for recursion
     pub decoding_depth_gauge: Arc<Mutex<DepthGauge>>,
+    /// Maximum number of bytes that decoding a message may allocate for strings, byte strings and
+    /// arrays, or `None` for no limit. Unlike `max_message_size`, which limits the encoded size,
+    /// this limits the decoded size, which can be far larger, e.g. an array of empty structures.
+    pub max_decoded_size: Option<usize>,
+    /// Number of bytes allocated so far decoding the current message
+    pub decoded_size: Arc<AtomicUsize>,
+    /// What to do with an extension object whose type is not recognized
+    pub decode_unknown_policy: DecodeUnknownPolicy,
 }
 
 impl Default for DecodingOptions {
@@ -120,6 +143,9 @@ impl Default for DecodingOptions {
             max_byte_string_length: constants::MAX_BYTE_STRING_LENGTH,
             max_array_length: constants::MAX_ARRAY_LENGTH,
             decoding_depth_gauge: Arc::new(Mutex::new(DepthGauge::default())),
+            max_decoded_size: None,
+            decoded_size: Arc::new(AtomicUsize::new(0)),
+            decode_unknown_policy: DecodeUnknownPolicy::default(),
         }
     }
 }
@@ -146,6 +172,31 @@ impl DecodingOptions {
     pub fn depth_lock(&self) -> core::result::Result<DepthLock, StatusCode> {
         DepthLock::obtain(self.decoding_depth_gauge.clone())
     }
+
+    /// Accounts for the bytes that decoding is about to allocate, failing with `BadDecodingError`
+    /// if the total for the message would exceed `max_decoded_size`.
+    pub fn allocate(&self, size: usize) -> EncodingResult<()> {
+        if let Some(max_decoded_size) = self.max_decoded_size {
+            let decoded_size = self
+                .decoded_size
+                .fetch_add(size, Ordering::Relaxed)
+                .saturating_add(size);
+            if decoded_size > max_decoded_size {
+                error!(
+                    "Decoded size {} exceeds decoding limit {}",
+                    decoded_size, max_decoded_size
+                );
+                return Err(StatusCode::BadDecodingError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts decoding a new message with nothing allocated against its `max_decoded_size`. The
+    /// count is not shared with clones made before the call.
+    pub fn reset_decoded_size(&mut self) {
+        self.decoded_size = Arc::new(AtomicUsize::new(0));
+    }
 }
 
 /// OPC UA Binary Encoding interface. Anything that encodes to binary must implement this. It provides
@@ -234,6 +285,7 @@ pub fn read_array<S: Read, T: BinaryEncoder<T>>(
         );
         Err(StatusCode::BadDecodingError)
     } else {
+        decoding_options.allocate(len as usize * mem::size_of::<T>())?;
         let mut values: Vec<T> = Vec::with_capacity(len as usize);
         for _ in 0..len {
             values.push(T::decode(stream, decoding_options)?);
@@ -98,7 +98,29 @@ impl BinaryEncoder<ExtensionObject> for ExtensionObject {
         let body = match encoding_type {
             0x0 => ExtensionObjectEncoding::None,
             0x1 => {
-                ExtensionObjectEncoding::ByteString(ByteString::decode(stream, decoding_options)?)
+                // The body is an encoded structure rather than an opaque byte string, e.g. the
+                // notifications of a publish response, so it may be as large as the message it
+                // is contained in. A max message size of 0 means there is no limit.
+                let max_body_length = if decoding_options.max_message_size == 0 {
+                    usize::MAX
+                } else {
+                    decoding_options.max_message_size
+                };
+                if max_body_length > decoding_options.max_byte_string_length {
+                    let body_decoding_options = DecodingOptions {
+                        max_byte_string_length: max_body_length,
+                        ..decoding_options.clone()
+                    };
+                    ExtensionObjectEncoding::ByteString(ByteString::decode(
+                        stream,
+                        &body_decoding_options,
+                    )?)
+                } else {
+                    ExtensionObjectEncoding::ByteString(ByteString::decode(
+                        stream,
+                        decoding_options,
+                    )?)
+                }
             }
             0x2 => {
                 ExtensionObjectEncoding::XmlElement(XmlElement::decode(stream, decoding_options)?)
@@ -108,7 +130,18 @@ impl BinaryEncoder<ExtensionObject> for ExtensionObject {
                 return Err(StatusCode::BadDecodingError);
             }
         };
-        Ok(ExtensionObject { node_id, body })
+        let extension_object = ExtensionObject { node_id, body };
+        if decoding_options.decode_unknown_policy == DecodeUnknownPolicy::Error
+            && !extension_object.is_empty()
+            && extension_object.object_id().is_err()
+        {
+            error!(
+                "Extension object has unrecognized type {}",
+                extension_object.node_id
+            );
+            return Err(StatusCode::BadDecodingError);
+        }
+        Ok(extension_object)
     }
 }
 
@@ -139,6 +172,15 @@ impl ExtensionObject {
             .map_err(|_| ExtensionObjectError)
     }
 
+    /// Returns the body if it is binary encoded, e.g. to decode a structure whose type is not
+    /// known to this crate.
+    pub fn raw_body(&self) -> Option<&ByteString> {
+        match self.body {
+            ExtensionObjectEncoding::ByteString(ref byte_string) => Some(byte_string),
+            _ => None,
+        }
+    }
+
     /// Creates an extension object with the specified node id and the encodable object as its payload.
     /// The body is set to a byte string containing the encoded struct.
     pub fn from_encodable<N, T>(node_id: N, encodable: &T) -> ExtensionObject
@@ -1,4 +1,4 @@
-use std::{self, fmt};
+use std::{self, convert::TryFrom, fmt};
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -54,6 +54,22 @@ impl UserTokenPolicy {
     }
 }
 
+impl TryFrom<i32> for DataChangeTrigger {
+    type Error = StatusCode;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DataChangeTrigger::Status),
+            1 => Ok(DataChangeTrigger::StatusValue),
+            2 => Ok(DataChangeTrigger::StatusValueTimestamp),
+            value => {
+                error!("Invalid data change trigger {}", value);
+                Err(StatusCode::BadMonitoredItemFilterInvalid)
+            }
+        }
+    }
+}
+
 impl DataChangeFilter {
     /// Compares one data value to another and returns true if they differ, according to their trigger
     /// type of status, status/value or status/value/timestamp
@@ -116,8 +132,8 @@ impl DataChangeFilter {
         } else {
             // Absolute
             match (v1.as_f64(), v2.as_f64()) {
-                (None, _) | (_, None) => Ok(false),
-                (Some(v1), Some(v2)) => {
+                (Err(_), _) | (_, Err(_)) => Ok(false),
+                (Ok(v1), Ok(v2)) => {
                     if self.deadband_value < 0f64 {
                         Err(StatusCode::BadDeadbandFilterInvalid)
                     } else if self.deadband_type == DeadbandType::Absolute as u32 {
@@ -133,6 +133,7 @@ impl BinaryEncoder<UAString> for UAString {
             Err(StatusCode::BadDecodingError)
         } else {
             // Create a buffer filled with zeroes and read the string over the top
+            decoding_options.allocate(len as usize)?;
             let mut buf = vec![0u8; len as usize];
             process_decode_io_result(stream.read_exact(&mut buf))?;
             let value = String::from_utf8(buf).map_err(|err| {
@@ -0,0 +1,41 @@
+use crate::types::*;
+
+#[test]
+fn effective_timestamp() {
+    let source_timestamp = DateTime::ymd_hms(2022, 1, 1, 0, 0, 0);
+    let server_timestamp = DateTime::ymd_hms(2022, 1, 1, 0, 0, 1);
+    let mut v = DataValue::value_only(1i32);
+    v.set_timestamps(TimestampsToReturn::Both, source_timestamp, server_timestamp);
+    assert_eq!(
+        v.effective_timestamp(TimestampPreference::Source),
+        Some(source_timestamp)
+    );
+    assert_eq!(
+        v.effective_timestamp(TimestampPreference::Server),
+        Some(server_timestamp)
+    );
+    assert_eq!(
+        v.effective_timestamp(TimestampPreference::SourceElseServer),
+        Some(source_timestamp)
+    );
+
+    // Falls back to the server timestamp when there is no source timestamp
+    v.source_timestamp = None;
+    assert_eq!(v.effective_timestamp(TimestampPreference::Source), None);
+    assert_eq!(
+        v.effective_timestamp(TimestampPreference::SourceElseServer),
+        Some(server_timestamp)
+    );
+
+    // A null timestamp is as good as none
+    v.source_timestamp = Some(DateTime::null());
+    assert_eq!(
+        v.effective_timestamp(TimestampPreference::SourceElseServer),
+        Some(server_timestamp)
+    );
+    v.server_timestamp = None;
+    assert_eq!(
+        v.effective_timestamp(TimestampPreference::SourceElseServer),
+        None
+    );
+}
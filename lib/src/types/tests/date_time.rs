@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{io::Cursor, str::FromStr};
 
 use crate::types::*;
 
@@ -39,6 +39,48 @@ fn endtimes() {
     assert_eq!(endtimes.checked_ticks(), i64::max_value());
 }
 
+#[test]
+fn from_ticks() {
+    assert!(DateTime::from(0i64).is_null());
+    assert_eq!(DateTime::from(1i64).ticks(), 1);
+
+    // Ticks out of range saturate rather than making dates outside of it
+    assert!(DateTime::from(-1i64).is_null());
+    assert!(DateTime::from(i64::MIN).is_null());
+    assert_eq!(DateTime::from(i64::MAX), DateTime::endtimes());
+    assert_eq!(
+        DateTime::from(DateTime::endtimes_ticks() + 1),
+        DateTime::endtimes()
+    );
+    assert_eq!(
+        DateTime::from(DateTime::endtimes_ticks() - 1).ticks(),
+        DateTime::endtimes_ticks() - 1
+    );
+}
+
+#[test]
+fn decode_ticks() {
+    let decoding_options = DecodingOptions {
+        client_offset: chrono::Duration::seconds(10),
+        ..Default::default()
+    };
+    let decode = |ticks: i64| {
+        let mut stream = Cursor::new(ticks.to_le_bytes().to_vec());
+        DateTime::decode(&mut stream, &decoding_options).unwrap()
+    };
+
+    // No value and infinity are kept as they are, not moved by the client offset
+    assert!(decode(0).is_null());
+    assert_eq!(decode(i64::MAX), DateTime::endtimes());
+    assert!(decode(-1000).is_null());
+
+    let date_time = DateTime::ymd_hms(2020, 1, 1, 0, 0, 0);
+    assert_eq!(
+        decode(date_time.ticks()),
+        date_time - chrono::Duration::seconds(10)
+    );
+}
+
 #[test]
 fn time() {
     use chrono::Datelike;
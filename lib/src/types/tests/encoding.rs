@@ -251,6 +251,87 @@ fn extension_object() {
     serialize_test(eo);
 }
 
+#[test]
+fn extension_object_large_body() {
+    // A body larger than the byte string limit but within the message size limit
+    let decoding_options = DecodingOptions {
+        max_byte_string_length: 1024,
+        max_message_size: 8192,
+        ..Default::default()
+    };
+    let eo = ExtensionObject {
+        node_id: ObjectId::DataChangeNotification_Encoding_DefaultBinary.into(),
+        body: ExtensionObjectEncoding::ByteString(ByteString::from(vec![1u8; 4096])),
+    };
+    let mut stream = serialize_as_stream(eo.clone());
+    let new_eo = ExtensionObject::decode(&mut stream, &decoding_options).unwrap();
+    assert_eq!(eo, new_eo);
+
+    // A plain byte string is still limited
+    let mut stream = serialize_as_stream(ByteString::from(vec![1u8; 4096]));
+    assert!(ByteString::decode(&mut stream, &decoding_options).is_err());
+
+    // A body larger than the message size limit is not decoded
+    let eo = ExtensionObject {
+        node_id: ObjectId::DataChangeNotification_Encoding_DefaultBinary.into(),
+        body: ExtensionObjectEncoding::ByteString(ByteString::from(vec![1u8; 10000])),
+    };
+    let mut stream = serialize_as_stream(eo.clone());
+    assert!(ExtensionObject::decode(&mut stream, &decoding_options).is_err());
+
+    // Unless the message size is unlimited
+    let decoding_options = DecodingOptions {
+        max_message_size: 0,
+        ..decoding_options
+    };
+    let mut stream = serialize_as_stream(eo.clone());
+    let new_eo = ExtensionObject::decode(&mut stream, &decoding_options).unwrap();
+    assert_eq!(eo, new_eo);
+}
+
+#[test]
+fn extension_object_unknown_type() {
+    let body = ByteString::from(vec![1u8, 2, 3, 4]);
+    let eo = ExtensionObject {
+        node_id: NodeId::new(2, 5000),
+        body: ExtensionObjectEncoding::ByteString(body.clone()),
+    };
+
+    // The raw body is preserved by default
+    let mut stream = serialize_as_stream(eo.clone());
+    let new_eo = ExtensionObject::decode(&mut stream, &DecodingOptions::test()).unwrap();
+    assert_eq!(new_eo.node_id, NodeId::new(2, 5000));
+    assert_eq!(new_eo.raw_body(), Some(&body));
+
+    // Or rejected
+    let decoding_options = DecodingOptions {
+        decode_unknown_policy: DecodeUnknownPolicy::Error,
+        ..Default::default()
+    };
+    let mut stream = serialize_as_stream(eo);
+    assert_eq!(
+        ExtensionObject::decode(&mut stream, &decoding_options).unwrap_err(),
+        StatusCode::BadDecodingError
+    );
+
+    // Known types and empty extension objects are still decoded
+    let eo = ExtensionObject::from_encodable(
+        ObjectId::DataChangeFilter_Encoding_DefaultBinary,
+        &DataChangeFilter {
+            trigger: DataChangeTrigger::Status,
+            deadband_type: 0,
+            deadband_value: 0f64,
+        },
+    );
+    let mut stream = serialize_as_stream(eo.clone());
+    assert_eq!(
+        ExtensionObject::decode(&mut stream, &decoding_options).unwrap(),
+        eo
+    );
+    let mut stream = serialize_as_stream(ExtensionObject::null());
+    assert!(ExtensionObject::decode(&mut stream, &decoding_options).is_ok());
+}
+
 #[test]
 fn localized_text() {
     let t = LocalizedText {
@@ -450,6 +531,39 @@ fn variant_multi_dimension_array() {
     serialize_test(v);
 }
 
+#[test]
+fn variant_multi_dimension_array_mismatch() {
+    // Encodes an Int32 matrix of 6 values with the supplied dimensions
+    let encode_matrix = |dimensions: &[u32]| {
+        let mut stream = Cursor::new(Vec::new());
+        let mask = EncodingMask::INT32 | EncodingMask::ARRAY_MASK;
+        mask.encode(&mut stream).unwrap();
+        let values: Vec<i32> = vec![100, 200, 300, 400, 500, 600];
+        write_array(&mut stream, &Some(values)).unwrap();
+        write_array(&mut stream, &Some(dimensions.to_vec())).unwrap();
+        stream.set_position(0);
+        stream
+    };
+
+    let mut stream = encode_matrix(&[3, 2]);
+    let v = Variant::decode(&mut stream, &DecodingOptions::test()).unwrap();
+    match v {
+        Variant::Array(array) => assert_eq!(array.dimensions, Some(vec![3, 2])),
+        v => panic!("Expected an array, got {:?}", v),
+    }
+
+    // Too many, too few and zero dimensions are not a matrix of the values
+    [&[3u32, 3u32][..], &[2, 2], &[6, 0]]
+        .iter()
+        .for_each(|dimensions| {
+            let mut stream = encode_matrix(dimensions);
+            assert_eq!(
+                Variant::decode(&mut stream, &DecodingOptions::test()).unwrap_err(),
+                StatusCode::BadDecodingError
+            );
+        });
+}
+
 #[test]
 fn diagnostic_info() {
     let mut d = DiagnosticInfo {
@@ -559,6 +673,46 @@ fn deep_encoding() {
     assert_eq!(res.unwrap_err(), StatusCode::BadDecodingError);
 }
 
+#[test]
+fn max_decoded_size() {
+    let strings: Vec<UAString> = (0..10).map(|_| UAString::from("x".repeat(100))).collect();
+    let v = Some(strings);
+    let serialize_as_stream = |v: Option<Vec<UAString>>| {
+        let mut stream = Cursor::new(Vec::new());
+        write_array(&mut stream, &v).unwrap();
+        stream.set_position(0);
+        stream
+    };
+
+    // No limit by default
+    let mut stream = serialize_as_stream(v.clone());
+    let decoding_options = DecodingOptions::default();
+    assert_eq!(read_array(&mut stream, &decoding_options).unwrap(), v);
+
+    // The strings and the array add up to more than the limit
+    let mut stream = serialize_as_stream(v.clone());
+    let decoding_options = DecodingOptions {
+        max_decoded_size: Some(1000),
+        ..Default::default()
+    };
+    let res: EncodingResult<Option<Vec<UAString>>> = read_array(&mut stream, &decoding_options);
+    assert_eq!(res.unwrap_err(), StatusCode::BadDecodingError);
+
+    // The budget is used up until it is reset
+    let mut decoding_options = DecodingOptions {
+        max_decoded_size: Some(2000),
+        ..Default::default()
+    };
+    let mut stream = serialize_as_stream(v.clone());
+    assert_eq!(read_array(&mut stream, &decoding_options).unwrap(), v);
+    let mut stream = serialize_as_stream(v.clone());
+    let res: EncodingResult<Option<Vec<UAString>>> = read_array(&mut stream, &decoding_options);
+    assert_eq!(res.unwrap_err(), StatusCode::BadDecodingError);
+    decoding_options.reset_decoded_size();
+    let mut stream = serialize_as_stream(v.clone());
+    assert_eq!(read_array(&mut stream, &decoding_options).unwrap(), v);
+}
+
 #[test]
 fn depth_gauge() {
     let dg = Arc::new(Mutex::new(DepthGauge::default()));
@@ -1,3 +1,4 @@
+mod data_value;
 mod date_time;
 mod encoding;
 mod node_id;
@@ -124,6 +124,132 @@ fn variant_u32_array() {
     }
 }
 
+#[test]
+fn variant_from_native() {
+    let v: Variant = 42i32.into();
+    assert_eq!(v, Variant::Int32(42));
+    let v: Variant = String::from("hello").into();
+    assert_eq!(v, Variant::String(UAString::from("hello")));
+    let v: Variant = None::<f64>.into();
+    assert_eq!(v, Variant::Empty);
+    let v: Variant = Some(true).into();
+    assert_eq!(v, Variant::Boolean(true));
+
+    // Arrays of scalars, from vecs, slices and fixed size arrays
+    let arrays: Vec<(Variant, VariantTypeId, usize)> = vec![
+        ([1i32, 2, 3].into(), VariantTypeId::Int32, 3),
+        (vec![1.5f64, 2.5].into(), VariantTypeId::Double, 2),
+        (vec!["a", "b"].into(), VariantTypeId::String, 2),
+        (vec![UAString::from("a")].into(), VariantTypeId::String, 1),
+        (
+            vec![DateTime::now(), DateTime::now()].into(),
+            VariantTypeId::DateTime,
+            2,
+        ),
+        (vec![Guid::new()].into(), VariantTypeId::Guid, 1),
+        (vec![StatusCode::Good].into(), VariantTypeId::StatusCode, 1),
+        (
+            vec![ByteString::from(b"x")].into(),
+            VariantTypeId::ByteString,
+            1,
+        ),
+        (
+            vec![QualifiedName::null()].into(),
+            VariantTypeId::QualifiedName,
+            1,
+        ),
+        (
+            vec![LocalizedText::null()].into(),
+            VariantTypeId::LocalizedText,
+            1,
+        ),
+        (
+            vec![NodeId::new(2, 1), NodeId::new(2, 2)].into(),
+            VariantTypeId::NodeId,
+            2,
+        ),
+    ];
+    arrays.into_iter().for_each(|(v, value_type, len)| {
+        assert!(v.is_array_of_type(value_type));
+        assert!(v.is_valid());
+        match v {
+            Variant::Array(array) => assert_eq!(array.values.len(), len),
+            v => panic!("Expected an array, got {:?}", v),
+        }
+    });
+}
+
+#[test]
+fn variant_as_native() {
+    assert_eq!(Variant::Int32(-5).as_i32().unwrap(), -5);
+    // Smaller integers widen
+    assert_eq!(Variant::SByte(-5).as_i32().unwrap(), -5);
+    assert_eq!(Variant::Byte(5).as_i32().unwrap(), 5);
+    assert_eq!(Variant::Int16(-300).as_i32().unwrap(), -300);
+    assert_eq!(Variant::UInt16(60000).as_i32().unwrap(), 60000);
+    // Types that could lose information do not
+    [
+        Variant::UInt32(5),
+        Variant::Int64(5),
+        Variant::Double(5.0),
+        Variant::Boolean(true),
+        Variant::from("5"),
+    ]
+    .iter()
+    .for_each(|v| assert_eq!(v.as_i32().unwrap_err(), StatusCode::BadTypeMismatch));
+
+    assert_eq!(Variant::Int16(3).as_f64().unwrap(), 3.0);
+    assert_eq!(Variant::Float(1.5).as_f64().unwrap(), 1.5);
+    // 64-bit integers are rounded rather than rejected
+    assert_eq!(
+        Variant::Int64(i64::MAX).as_f64().unwrap(),
+        9_223_372_036_854_775_808.0
+    );
+    assert_eq!(
+        Variant::from("1.5").as_f64().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+
+    assert!(Variant::Boolean(true).as_bool().unwrap());
+    assert_eq!(
+        Variant::Int32(1).as_bool().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+
+    assert_eq!(Variant::from("hello").as_string().unwrap(), "hello");
+    assert_eq!(Variant::String(UAString::null()).as_string().unwrap(), "");
+    assert_eq!(
+        Variant::Int32(1).as_string().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+
+    // Arrays
+    assert_eq!(
+        Variant::from(vec![1i16, 2, 3]).as_i32_array().unwrap(),
+        vec![1, 2, 3]
+    );
+    assert_eq!(
+        Variant::from(vec![1i32, 2]).as_f64_array().unwrap(),
+        vec![1.0, 2.0]
+    );
+    assert_eq!(
+        Variant::from(vec![true, false]).as_bool_array().unwrap(),
+        vec![true, false]
+    );
+    assert_eq!(
+        Variant::from(vec!["a", "b"]).as_string_array().unwrap(),
+        vec!["a".to_string(), "b".to_string()]
+    );
+    assert_eq!(
+        Variant::from(vec![true]).as_i32_array().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+    assert_eq!(
+        Variant::Int32(1).as_i32_array().unwrap_err(),
+        StatusCode::BadTypeMismatch
+    );
+}
+
 #[test]
 fn variant_try_into_u32_array() {
     let vars = [1u32, 2u32, 3u32];
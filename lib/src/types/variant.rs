@@ -9,6 +9,7 @@ use std::{
     convert::TryFrom,
     fmt,
     io::{Read, Write},
+    mem,
     str::FromStr,
     {i16, i32, i64, i8, u16, u32, u64, u8},
 };
@@ -419,6 +420,49 @@ from_array_to_variant_impl!(VariantTypeId::Int64, i64);
 from_array_to_variant_impl!(VariantTypeId::UInt64, u64);
 from_array_to_variant_impl!(VariantTypeId::Float, f32);
 from_array_to_variant_impl!(VariantTypeId::Double, f64);
+from_array_to_variant_impl!(VariantTypeId::String, UAString);
+from_array_to_variant_impl!(VariantTypeId::DateTime, DateTime);
+from_array_to_variant_impl!(VariantTypeId::Guid, Guid);
+from_array_to_variant_impl!(VariantTypeId::StatusCode, StatusCode);
+from_array_to_variant_impl!(VariantTypeId::ByteString, ByteString);
+from_array_to_variant_impl!(VariantTypeId::QualifiedName, QualifiedName);
+from_array_to_variant_impl!(VariantTypeId::LocalizedText, LocalizedText);
+from_array_to_variant_impl!(VariantTypeId::NodeId, NodeId);
+
+impl<'a, 'b> From<&'a [&'b str]> for Variant {
+    fn from(v: &'a [&'b str]) -> Self {
+        Variant::from((VariantTypeId::String, v))
+    }
+}
+
+impl<'a> From<Vec<&'a str>> for Variant {
+    fn from(v: Vec<&'a str>) -> Self {
+        Variant::from(v.as_slice())
+    }
+}
+
+/// A fixed size array converts like a `Vec` of the same type, e.g. `[1i32, 2, 3].into()`
+impl<T, const N: usize> From<[T; N]> for Variant
+where
+    Variant: From<Vec<T>>,
+{
+    fn from(v: [T; N]) -> Self {
+        Variant::from(Vec::from(v))
+    }
+}
+
+/// An optional value converts to `Variant::Empty` when it is `None`
+impl<T> From<Option<T>> for Variant
+where
+    T: Into<Variant>,
+{
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => Variant::Empty,
+        }
+    }
+}
 
 /// This macro tries to return a `Vec<foo>` from a `Variant::Array<Variant::Foo>>`, e.g.
 /// If the Variant holds
@@ -615,6 +659,7 @@ impl BinaryEncoder<Variant> for Variant {
                 return Err(StatusCode::BadEncodingLimitsExceeded);
             }
 
+            decoding_options.allocate(array_length * mem::size_of::<Variant>())?;
             let mut values: Vec<Variant> = Vec::with_capacity(array_length);
             for _ in 0..array_length {
                 values.push(Variant::decode_variant_value(
@@ -643,8 +688,8 @@ impl BinaryEncoder<Variant> for Variant {
                         }
                         if array_dimensions_length != array_length as u32 {
                             error!(
-                                "Array dimensions does not match array length {}",
-                                array_length
+                                "Array dimensions {:?} do not match array length {}",
+                                dimensions, array_length
                             );
                             Err(StatusCode::BadDecodingError)
                         } else {
@@ -1387,26 +1432,88 @@ impl Variant {
         }
     }
 
-    /// Converts the numeric type to a double or returns None
-    pub fn as_f64(&self) -> Option<f64> {
+    /// Converts the numeric type to a double, or returns `BadTypeMismatch` if it is not numeric.
+    /// Unlike [`Variant::as_i32`], a conversion that loses precision is accepted, so an `Int64` or
+    /// `UInt64` beyond 2^53 is rounded to the nearest double.
+    pub fn as_f64(&self) -> Result<f64, StatusCode> {
         match *self {
-            Variant::SByte(value) => Some(value as f64),
-            Variant::Byte(value) => Some(value as f64),
-            Variant::Int16(value) => Some(value as f64),
-            Variant::UInt16(value) => Some(value as f64),
-            Variant::Int32(value) => Some(value as f64),
-            Variant::UInt32(value) => Some(value as f64),
+            Variant::SByte(value) => Ok(value as f64),
+            Variant::Byte(value) => Ok(value as f64),
+            Variant::Int16(value) => Ok(value as f64),
+            Variant::UInt16(value) => Ok(value as f64),
+            Variant::Int32(value) => Ok(value as f64),
+            Variant::UInt32(value) => Ok(value as f64),
             Variant::Int64(value) => {
-                // NOTE: Int64 could overflow
-                Some(value as f64)
+                // NOTE: Int64 could lose precision
+                Ok(value as f64)
             }
             Variant::UInt64(value) => {
-                // NOTE: UInt64 could overflow
-                Some(value as f64)
+                // NOTE: UInt64 could lose precision
+                Ok(value as f64)
             }
-            Variant::Float(value) => Some(value as f64),
-            Variant::Double(value) => Some(value),
-            _ => None,
+            Variant::Float(value) => Ok(value as f64),
+            Variant::Double(value) => Ok(value),
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Returns the value as an `i32`, or `BadTypeMismatch` if it is not an `Int32` or a smaller
+    /// integer type that widens to one without loss, i.e. `SByte`, `Byte`, `Int16` or `UInt16`.
+    pub fn as_i32(&self) -> Result<i32, StatusCode> {
+        match *self {
+            Variant::SByte(value) => Ok(value as i32),
+            Variant::Byte(value) => Ok(value as i32),
+            Variant::Int16(value) => Ok(value as i32),
+            Variant::UInt16(value) => Ok(value as i32),
+            Variant::Int32(value) => Ok(value),
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Returns the value as a `bool`, or `BadTypeMismatch` if it is not a `Boolean`
+    pub fn as_bool(&self) -> Result<bool, StatusCode> {
+        match *self {
+            Variant::Boolean(value) => Ok(value),
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Returns the value as a `String`, or `BadTypeMismatch` if it is not a `String`. A null
+    /// string is returned as an empty string.
+    pub fn as_string(&self) -> Result<String, StatusCode> {
+        match self {
+            Variant::String(value) => Ok(value.as_ref().to_string()),
+            _ => Err(StatusCode::BadTypeMismatch),
+        }
+    }
+
+    /// Returns the values of an array as `i32`, converting each as [`Variant::as_i32`] does
+    pub fn as_i32_array(&self) -> Result<Vec<i32>, StatusCode> {
+        self.as_array_of(Variant::as_i32)
+    }
+
+    /// Returns the values of an array as `f64`, converting each as [`Variant::as_f64`] does
+    pub fn as_f64_array(&self) -> Result<Vec<f64>, StatusCode> {
+        self.as_array_of(Variant::as_f64)
+    }
+
+    /// Returns the values of an array as `bool`, converting each as [`Variant::as_bool`] does
+    pub fn as_bool_array(&self) -> Result<Vec<bool>, StatusCode> {
+        self.as_array_of(Variant::as_bool)
+    }
+
+    /// Returns the values of an array as `String`, converting each as [`Variant::as_string`] does
+    pub fn as_string_array(&self) -> Result<Vec<String>, StatusCode> {
+        self.as_array_of(Variant::as_string)
+    }
+
+    fn as_array_of<T, F>(&self, f: F) -> Result<Vec<T>, StatusCode>
+    where
+        F: Fn(&Variant) -> Result<T, StatusCode>,
+    {
+        match self {
+            Variant::Array(array) => array.values.iter().map(f).collect(),
+            _ => Err(StatusCode::BadTypeMismatch),
         }
     }
 
@@ -95,6 +95,15 @@ macro_rules! supported_messages_enum {
                     $( SupportedMessage::$x(value) => value.object_id().into(), )*
                 }
             }
+
+            /// Returns the name of the message type, e.g. "ReadRequest"
+            pub fn type_name(&self) -> &'static str {
+                match self {
+                    SupportedMessage::Invalid(_) => "Invalid",
+                    SupportedMessage::AcknowledgeMessage(_) => "AcknowledgeMessage",
+                    $( SupportedMessage::$x(_) => stringify!($x), )*
+                }
+            }
         }
     }
 }
@@ -136,6 +145,21 @@ impl SupportedMessage {
         }
     }
 
+    /// Returns the header of the request so it can be changed, or \`None\` if the message is not
+    /// a request
+    pub fn request_header_mut(&mut self) -> Option<&mut RequestHeader> {
+        match self {
+`;
+    _.each(message_types, message_type => {
+        if (message_type.endsWith("Request")) {
+            contents += `            SupportedMessage::${message_type}(r) => Some(&mut r.request_header),
+`;
+        }
+    });
+    contents += `            _ => None,
+        }
+    }
+
     pub fn is_response(&self) -> bool {
         match self {
 `;